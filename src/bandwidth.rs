@@ -0,0 +1,455 @@
+//! # Bandwidth
+//! Token bucket rate limiters, for the whole client, each torrent and each peer.
+//!
+//! Buckets may go into debt : bytes are charged once they're read or written,
+//! and the connection waits the debt off before its next read or write.
+//! Nothing is lost when that wait gets cancelled, so reads stay cancel safe.
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::Notify,
+    time::{self, Instant},
+};
+
+use crate::{config::Config, peer::Message, torrent::InfoHash};
+
+/// Longest a limiter saves up for, idle links don't get to burst past a second worth of bytes
+const BURST: Duration = Duration::from_secs(1);
+/// Classes that have moved bytes within this long share the rate, the rest get left out
+const ACTIVE: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may go out right away, negative when in debt
+    balance: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            balance: rate as f64 * BURST.as_secs_f64(),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = (now - self.updated).as_secs_f64();
+        self.balance = (self.balance + rate * elapsed).min(rate * BURST.as_secs_f64());
+        self.updated = now;
+    }
+
+    /// Time until debt is paid off, at given rate
+    fn debt(&self, rate: f64) -> Duration {
+        match self.balance < 0.0 {
+            true => Duration::from_secs_f64(-self.balance / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Class {
+    bucket: Bucket,
+    last_charged: Instant,
+}
+
+#[derive(Debug)]
+struct State<K> {
+    /// Bytes per second, `None` when unlimited
+    rate: Option<u64>,
+    total: Bucket,
+    /// Share of each class, see [`Limiter`]
+    classes: HashMap<K, Class>,
+}
+
+#[derive(Debug)]
+struct Shared<K> {
+    state: Mutex<State<K>>,
+    /// Rate changes wake whoever's waiting
+    changed: Notify,
+}
+
+/// # [`Limiter`]
+/// Token bucket, shared by every connection going through it, cheap to clone.
+///
+/// Bytes are charged against a class too (torrents, for the global limiter, peers for a torrent's one).
+/// While the limiter is saturated, classes moving more than their even share of the rate wait longer,
+/// so a torrent with plenty of peers doesn't crowd out one with a few.
+#[derive(Debug)]
+pub struct Limiter<K = ()> {
+    shared: Arc<Shared<K>>,
+}
+
+impl<K> Clone for Limiter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<K> Default for Limiter<K> {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl<K> Limiter<K> {
+    /// Limiter of given bytes per second, `None` (or zero) lets everything through
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|&rate| rate > 0);
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    rate,
+                    total: Bucket::new(rate.unwrap_or(0)),
+                    classes: HashMap::new(),
+                }),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state().rate
+    }
+
+    /// Changes the rate, for everyone going through this limiter, waiting ones included
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.state().rate = rate.filter(|&rate| rate > 0);
+        self.shared.changed.notify_waiters();
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State<K>> {
+        self.shared
+            .state
+            .lock()
+            .expect("Limiter lock is never held across a panic")
+    }
+}
+
+impl<K: Hash + Eq + Clone> Limiter<K> {
+    /// Charges bytes read or written by given class
+    pub fn charge(&self, class: &K, bytes: u64) {
+        let now = Instant::now();
+        let mut state = self.state();
+        let Some(rate) = state.rate else {
+            return;
+        };
+        state.refill(rate, now);
+        state.total.balance -= bytes as f64;
+        let class = state.classes.entry(class.clone()).or_insert_with(|| Class {
+            bucket: Bucket::new(rate),
+            last_charged: now,
+        });
+        class.bucket.balance -= bytes as f64;
+        class.last_charged = now;
+    }
+
+    /// Time given class has to wait before moving any more bytes
+    pub fn delay(&self, class: &K) -> Duration {
+        let now = Instant::now();
+        let mut state = self.state();
+        let Some(rate) = state.rate else {
+            return Duration::ZERO;
+        };
+        state.refill(rate, now);
+        let total = state.total.debt(rate as f64);
+        if total.is_zero() {
+            return total;
+        }
+        let share = state.share(rate, now);
+        let class = state
+            .classes
+            .get(class)
+            .map_or(Duration::ZERO, |class| class.bucket.debt(share));
+        total.max(class)
+    }
+
+    /// Waits until given class may move bytes again, cancel safe
+    pub async fn ready(&self, class: &K) {
+        loop {
+            let changed = self.shared.changed.notified();
+            let delay = self.delay(class);
+            if delay.is_zero() {
+                return;
+            }
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = changed => {}
+            }
+        }
+    }
+}
+
+impl<K> State<K> {
+    /// Rate each active class gets
+    fn share(&self, rate: u64, now: Instant) -> f64 {
+        let active = self
+            .classes
+            .values()
+            .filter(|class| now - class.last_charged < ACTIVE)
+            .count();
+        rate as f64 / active.max(1) as f64
+    }
+
+    fn refill(&mut self, rate: u64, now: Instant) {
+        self.total.refill(rate as f64, now);
+        let share = self.share(rate, now);
+        // Classes long gone are forgotten, they start afresh should they come back
+        self.classes.retain(|_, class| now - class.last_charged < BURST * 10);
+        for class in self.classes.values_mut() {
+            class.bucket.refill(share, now);
+        }
+    }
+}
+
+/// # [`Limits`]
+/// Download and upload limiters of one level
+#[derive(Debug, Default)]
+pub struct Limits<K = ()> {
+    pub download: Limiter<K>,
+    pub upload: Limiter<K>,
+}
+
+impl<K> Clone for Limits<K> {
+    fn clone(&self) -> Self {
+        Self {
+            download: self.download.clone(),
+            upload: self.upload.clone(),
+        }
+    }
+}
+
+impl<K> Limits<K> {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: Limiter::new(download),
+            upload: Limiter::new(upload),
+        }
+    }
+}
+
+/// # [`Bandwidth`]
+/// Client wide limits, every torrent's connections go through them, cheap to clone
+#[derive(Clone, Debug, Default)]
+pub struct Bandwidth {
+    pub global: Limits<InfoHash>,
+    /// Rates each new peer gets, on top of global and torrent ones
+    pub peer_rates: (Option<u64>, Option<u64>),
+    /// Whether whole messages count against limits, or only the blocks they carry
+    count_overhead: Arc<AtomicBool>,
+}
+
+impl Bandwidth {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            global: Limits::new(config.download_limit, config.upload_limit),
+            peer_rates: (config.peer_download_limit, config.peer_upload_limit),
+            count_overhead: Arc::new(AtomicBool::new(config.count_overhead)),
+        }
+    }
+
+    pub fn set_count_overhead(&self, count_overhead: bool) {
+        self.count_overhead.store(count_overhead, Ordering::Relaxed);
+    }
+
+    /// Limits of a single torrent, going through these global ones
+    pub fn torrent(&self, info_hash: InfoHash, download: Option<u64>, upload: Option<u64>) -> TorrentBandwidth {
+        TorrentBandwidth {
+            global: self.clone(),
+            info_hash,
+            limits: Limits::new(download, upload),
+        }
+    }
+}
+
+/// # [`TorrentBandwidth`]
+/// Limits of a torrent, along with the global ones, cheap to clone
+#[derive(Clone, Debug)]
+pub struct TorrentBandwidth {
+    global: Bandwidth,
+    info_hash: InfoHash,
+    pub limits: Limits<SocketAddr>,
+}
+
+impl TorrentBandwidth {
+    /// Throttle for a connection with given peer, with a peer limiter of its own
+    pub fn throttle(&self, peer: SocketAddr) -> Throttle {
+        let (download, upload) = self.global.peer_rates;
+        Throttle {
+            torrent: Some(self.clone()),
+            peer,
+            limits: Limits::new(download, upload),
+        }
+    }
+}
+
+/// # [`Throttle`]
+/// Every limiter a connection goes through : its own, its torrent's and the global ones
+#[derive(Debug)]
+pub struct Throttle {
+    torrent: Option<TorrentBandwidth>,
+    peer: SocketAddr,
+    pub limits: Limits,
+}
+
+impl Default for Throttle {
+    /// Lets everything through
+    fn default() -> Self {
+        Self {
+            torrent: None,
+            peer: SocketAddr::from(([0, 0, 0, 0], 0)),
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Which way bytes go
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+impl Throttle {
+    /// Waits until every limiter lets bytes through in given direction, cancel safe
+    pub async fn ready(&self, direction: Direction) {
+        loop {
+            pick(&self.limits, direction).ready(&()).await;
+            let Some(torrent) = &self.torrent else {
+                return;
+            };
+            pick(&torrent.limits, direction).ready(&self.peer).await;
+            pick(&torrent.global.global, direction).ready(&torrent.info_hash).await;
+            // Waiting on the later ones may have let the earlier ones go into debt again
+            if pick(&self.limits, direction).delay(&()).is_zero()
+                && pick(&torrent.limits, direction).delay(&self.peer).is_zero()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Charges a message read or written to every limiter
+    pub fn charge(&self, direction: Direction, message: &Message) {
+        let count_overhead = self
+            .torrent
+            .as_ref()
+            .is_some_and(|torrent| torrent.global.count_overhead.load(Ordering::Relaxed));
+        let bytes = match (message, count_overhead) {
+            (_, true) => message.encode_length() as u64,
+            (Message::Piece { data, .. }, false) => data.len() as u64,
+            (_, false) => return,
+        };
+        pick(&self.limits, direction).charge(&(), bytes);
+        if let Some(torrent) = &self.torrent {
+            pick(&torrent.limits, direction).charge(&self.peer, bytes);
+            pick(&torrent.global.global, direction).charge(&torrent.info_hash, bytes);
+        }
+    }
+}
+
+fn pick<K>(limits: &Limits<K>, direction: Direction) -> &Limiter<K> {
+    match direction {
+        Direction::Download => &limits.download,
+        Direction::Upload => &limits.upload,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn debt_is_waited_off_at_rate() {
+        let limiter = Limiter::new(Some(1000));
+        // A second worth of burst, then as much again in debt
+        limiter.charge(&(), 2000);
+        assert_eq!(limiter.delay(&()), Duration::from_secs(1));
+
+        let start = Instant::now();
+        limiter.ready(&()).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_changes_wake_waiters() {
+        let limiter = Limiter::new(Some(10));
+        limiter.charge(&(), 1010);
+
+        let start = Instant::now();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.ready(&()).await }
+        });
+        time::sleep(Duration::from_secs(1)).await;
+        limiter.set_rate(None);
+        waiting.await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn greedy_classes_wait_longer() {
+        let limiter = Limiter::new(Some(1000));
+        limiter.charge(&1, 1900);
+        limiter.charge(&2, 100);
+
+        // Both torrents are active, each gets half the rate
+        assert_eq!(limiter.delay(&2), Duration::from_secs(1));
+        assert!(limiter.delay(&1) > Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_blocks_count_unless_overhead_does() {
+        let mut config = Config {
+            download_limit: Some(1000),
+            ..Default::default()
+        };
+        let bandwidth = Bandwidth::new(&config);
+        let throttle = bandwidth
+            .torrent(InfoHash::from([0; 20]), None, None)
+            .throttle(SocketAddr::from(([10, 0, 0, 1], 6881)));
+
+        throttle.charge(Direction::Download, &Message::Have(0));
+        assert!(bandwidth.global.download.delay(&InfoHash::from([0; 20])).is_zero());
+        bandwidth.set_count_overhead(true);
+        for _ in 0..200 {
+            throttle.charge(Direction::Download, &Message::Have(0));
+        }
+        assert!(!bandwidth.global.download.delay(&InfoHash::from([0; 20])).is_zero());
+
+        config.count_overhead = false;
+        let throttle = Bandwidth::new(&config)
+            .torrent(InfoHash::from([0; 20]), None, None)
+            .throttle(SocketAddr::from(([10, 0, 0, 1], 6881)));
+        throttle.charge(
+            Direction::Download,
+            &Message::Piece {
+                index: 0,
+                offset: 0,
+                data: vec![0; 3000].into(),
+            },
+        );
+        assert_eq!(
+            throttle
+                .torrent
+                .unwrap()
+                .global
+                .global
+                .download
+                .delay(&InfoHash::from([0; 20])),
+            Duration::from_secs(2)
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! Serde helpers for bencoded structures
+
+/// bendy maps `Option<T>` to a list holding zero or one element, which isn't how
+/// optional keys look in the wild, they're simply left out of the dictionary.
+///
+/// Use along `#[serde(default, skip_serializing_if = "Option::is_none", with = "crate::bencode::optional")]`
+pub(crate) mod optional {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        match value {
+            Some(value) => value.serialize(serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        T::deserialize(deserializer).map(Some)
+    }
+}
@@ -2,14 +2,18 @@ use std::io::Read;
 
 use qbit::{
     torrent::Metadata,
-    tracker,
+    tracker::{self, Announce, Event},
 };
 
 #[tokio::main]
 async fn main() {
     let torrent = Metadata::from_file("test/debian.torrent").unwrap();
 
-    let url = tracker::get_url(&torrent);
+    let url = Announce::new(torrent.info_hash, torrent.info.total_length())
+        .with_event(Event::Started)
+        .with_ipv6(tracker::local_ipv6())
+        .url(&torrent.announce)
+        .unwrap();
 
     let response = reqwest::get(url)
         .await
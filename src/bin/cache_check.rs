@@ -1,12 +1,16 @@
 use std::time::Duration;
 
-use qbit::{torrent::Metadata, tracker::load_cache_or_fetch_tracker};
+use qbit::{
+    torrent::Metadata,
+    tracker::{load_cache_or_fetch_tracker, Announce},
+};
 use tokio::task::JoinSet;
 
 #[tokio::main]
 async fn main() {
     let torrent = Metadata::from_file("test/debian.torrent").unwrap();
-    let response = load_cache_or_fetch_tracker(&torrent).await.unwrap();
+    let announce = Announce::new(torrent.info_hash, torrent.info.total_length());
+    let response = load_cache_or_fetch_tracker(&torrent, &announce).await.unwrap();
 
     println!("{response:?}");
 
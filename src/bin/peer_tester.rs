@@ -1,11 +1,17 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::Ipv4Addr, sync::Arc};
 
 use qbit::{
-    peer::{Handshake, PeerSession},
-    torrent::{self, info::NormalisedInfo, Committer, FileLayout, Metadata, State},
-    tracker::{self},
+    bandwidth::Bandwidth,
+    config::Config,
+    peer::{Connector, Listener},
+    stream::StreamServer,
+    torrent::{
+        self, info::NormalisedInfo, Choker, Committer, ConnectionLimits, ConnectionManager, FileLayout, Handle, Metadata,
+        PeerPool, PeerSource, PiecePicker, Registry, State, WebSeed,
+    },
+    tracker::{self, Announce, Announcer},
 };
-use tokio::{sync::Mutex, task::JoinSet, time::timeout};
+use tokio::sync::{Mutex, oneshot};
 
 #[tokio::main]
 async fn main() {
@@ -17,71 +23,81 @@ async fn main() {
     let state: Arc<Mutex<State>> = Arc::new(Mutex::new(State::load_or_new(&torrent).await));
     let info = NormalisedInfo::try_from(torrent.as_ref()).unwrap().atomic();
     let file_layout = Arc::new(FileLayout::try_from(info.as_ref()).unwrap());
-    let peers: tracker::Response = tracker::load_cache_or_fetch_tracker(&torrent)
+    match tracker::load_cache_or_scrape(&torrent.announce, &[torrent.info_hash]).await {
+        Ok(swarms) => match swarms.get(&torrent.info_hash) {
+            Some(swarm) => println!("Swarm : {swarm}"),
+            None => println!("Tracker doesn't know this torrent's swarm"),
+        },
+        Err(err) => eprintln!("Couldn't scrape tracker : {err}"),
+    }
+    let config = Config::default();
+    // Cached peers to start with, announcer tells tracker about us from there on
+    let announce = Announce::from_state(torrent.info_hash, &torrent_info, &*state.lock().await)
+        .with_port(config.listen_port)
+        .with_ipv6(tracker::local_ipv6());
+    let peers: tracker::Response = tracker::load_cache_or_fetch_tracker(&torrent, &announce)
         .await
         .expect("Failed fetching tracker")
         .try_into()
         .expect("Failed parsing tracker's response into struct");
 
-    let connection_list = Arc::new(Mutex::new(Vec::new()));
-    let mut join_set = JoinSet::new();
+    let mut pool = PeerPool::new()
+        .with_ban_threshold(config.max_hash_failures)
+        .with_listen_port(config.listen_port);
+    pool.extend(peers.peers.iter().copied(), PeerSource::Tracker);
+    let pool = pool.atomic();
+
+    let registry = Registry::new();
+    let listener = Listener::bind(&config, registry.clone()).await;
+    let (connector, limits) = match &listener {
+        Ok(listener) => (listener.connector(), listener.limits()),
+        Err(_) => (Connector::new(&config), ConnectionLimits::new(&config)),
+    };
     let mut committer = Committer::new(state.clone(), torrent.info_hash, info.clone(), file_layout);
 
-    for (index, &peer) in peers.peers.iter().enumerate() {
-        let handshake = Handshake::new(&torrent.info_hash);
-        let timeout_session = timeout(Duration::from_secs(10), {
-            let connection_list = connection_list.clone();
-            async move {
-                if let Ok(mut connection) = peer.connect().await {
-                    if let Ok(()) = connection.handshake(handshake).await {
-                        eprintln!("Peer {index:2} handshake success!!");
-                        let mut connection_list = connection_list.lock().await;
-                        connection_list.push(connection);
-                    }
-                }
-            }
-        });
-        join_set.spawn(async move {
-            if timeout_session.await.is_err() {
-                println!("Peer {index} : Timed out");
+    let picker = PiecePicker::new(&torrent_info, &*state.lock().await)
+        .with_block_len(config.pipeline.block_len())
+        .atomic();
+    let choker = Choker::new(config.upload_slots).atomic();
+    let bandwidth = Bandwidth::new(&config);
+    let handle = Handle::new(torrent.info_hash, torrent_info.clone(), state.clone(), pool.clone(), picker, choker, &committer)
+        .with_bandwidth(&bandwidth, None, None);
+    registry.register(handle.clone()).await;
+    tokio::spawn(handle.clone().run_choker());
+    let (stop_announcer, stopped) = oneshot::channel::<()>();
+    let announcer = Announcer::new(&torrent.announce, handle.clone())
+        .with_port(config.listen_port)
+        .with_ipv6(tracker::local_ipv6());
+    let announcer = tokio::spawn(announcer.run(async {
+        let _ = stopped.await;
+    }));
+    tokio::spawn(ConnectionManager::new(handle.clone(), connector, limits, &config).run());
+    for (id, url) in torrent.url_list.iter().enumerate() {
+        let seed = WebSeed::new(url, id as u16, handle.clone()).with_max_hash_failures(config.max_hash_failures);
+        tokio::spawn(async move {
+            if let Err(err) = seed.run().await {
+                eprintln!("\x1b[033mWeb seed gave up : {err}\x1b[0m");
             }
         });
     }
-    join_set.join_all().await;
-
-    println!(
-        "\x1b[32m{} peers responded, in total\x1b[0m",
-        connection_list.clone().lock().await.len()
-    );
-    
-    let connection_list = {
-        let mut guard = connection_list.lock().await;
-        std::mem::take(&mut *guard)
-    };
-    
-    let mut join_set = JoinSet::new();
-    let count = Arc::new(Mutex::new(0usize));
-    for i in connection_list {
-        join_set.spawn({
-            let state = state.clone();
-            let torrent_info = torrent_info.clone();
-            let count = count.clone();
-            let receiver = committer.listener();
-            let sender = committer.sender().clone();
-            async move {
-                let mut session = PeerSession::new(i, torrent_info, state, sender, receiver);
-                if let Err(x) = session.run().await {
-                    eprintln!("\x1b[033mSession Error {x:?}\x1b[0m");
-                }
-                *count.lock().await += 1;
+    match listener {
+        Ok(listener) => {
+            tokio::spawn(listener.run());
+        }
+        Err(err) => eprintln!("Not accepting incoming peers : {err}"),
+    }
+    match StreamServer::bind((Ipv4Addr::LOCALHOST, config.stream_port), registry.clone()).await {
+        Ok(server) => {
+            if let Ok(url) = server.url(&torrent.info_hash, 0) {
+                println!("Streaming first file at {url}");
             }
-        });
+            tokio::spawn(server.run());
+        }
+        Err(err) => eprintln!("Not streaming : {err}"),
     }
-    
-    join_set.spawn(async move {
-        committer.run().await.unwrap();
-    });
 
-    join_set.join_all().await;
-    eprintln!("All connections closed, {}, failed", count.lock().await);
+    let committed = committer.run().await;
+    let _ = stop_announcer.send(());
+    let _ = announcer.await;
+    committed.unwrap();
 }
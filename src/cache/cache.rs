@@ -26,6 +26,7 @@ impl Cache {
             CacheType::TrackerResponse => cache_dir
                 .join("tracker-response")
                 .join(info_hash.to_hex_lower()),
+            CacheType::Scrape => cache_dir.join("scrape").join(info_hash.to_hex_lower()),
         };
         let cache = Self {
             info_hash: info_hash,
@@ -61,7 +62,7 @@ impl Cache {
 
     fn load_or_create(&self) -> io::Result<File> {
         match self.r#type {
-            CacheType::TrackerResponse => self.tracker_response(),
+            CacheType::TrackerResponse | CacheType::Scrape => self.tracker_response(),
         }
     }
 
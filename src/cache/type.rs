@@ -1,3 +1,4 @@
 pub enum Type {
-    TrackerResponse
+    TrackerResponse,
+    Scrape,
 }
\ No newline at end of file
@@ -0,0 +1,69 @@
+//! # Config
+//! Client wide settings, shared by every torrent.
+use crate::{
+    peer::{codec::DEFAULT_MAX_MESSAGE_LEN, encryption, PipelineLimits},
+    stream::DEFAULT_STREAM_PORT,
+    torrent::{choker::DEFAULT_UPLOAD_SLOTS, pool::DEFAULT_BAN_THRESHOLD},
+};
+
+/// Port we listen for incoming peers on, unless configured otherwise
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Port incoming peers connect to (TCP and uTP), also the one announced to trackers
+    pub listen_port: u16,
+    /// Upper limit of open connections (incoming and outgoing ones), across all torrents
+    pub max_connections: usize,
+    /// Upper limit of connections we're still dialing, across all torrents
+    pub max_half_open: usize,
+    /// Upper limit of connected peers for a single torrent
+    pub max_peers_per_torrent: usize,
+    /// Largest message a peer may send us, longer length prefixes drop the connection
+    pub max_message_len: usize,
+    /// Whether peer connections get encrypted, both the ones we open and the ones we accept
+    pub encryption: encryption::Policy,
+    /// Whether peers get reached (and accepted) over uTP too, on the same port as TCP
+    pub enable_utp: bool,
+    /// How many block requests are kept on fly with each peer, and how big those blocks are
+    pub pipeline: PipelineLimits,
+    /// Pieces failing their hash check a peer can be blamed for, before it's banned
+    pub max_hash_failures: u32,
+    /// Peers of a torrent we upload to at once, the optimistic unchoke aside
+    pub upload_slots: usize,
+    /// Bytes per second downloaded across all torrents, `None` for no limit
+    pub download_limit: Option<u64>,
+    /// Bytes per second uploaded across all torrents, `None` for no limit
+    pub upload_limit: Option<u64>,
+    /// Bytes per second downloaded from a single peer, `None` for no limit
+    pub peer_download_limit: Option<u64>,
+    /// Bytes per second uploaded to a single peer, `None` for no limit
+    pub peer_upload_limit: Option<u64>,
+    /// Whether protocol messages count against limits too, rather than only the blocks they carry
+    pub count_overhead: bool,
+    /// Port media players get files from, on localhost, see [`crate::stream`]
+    pub stream_port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_port: DEFAULT_LISTEN_PORT,
+            max_connections: 200,
+            max_half_open: 16,
+            max_peers_per_torrent: 50,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            encryption: encryption::Policy::default(),
+            enable_utp: true,
+            pipeline: PipelineLimits::default(),
+            max_hash_failures: DEFAULT_BAN_THRESHOLD,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            download_limit: None,
+            upload_limit: None,
+            peer_download_limit: None,
+            peer_upload_limit: None,
+            count_overhead: false,
+            stream_port: DEFAULT_STREAM_PORT,
+        }
+    }
+}
@@ -6,4 +6,12 @@ pub mod peer;
 
 pub mod cache;
 
+pub mod config;
+
+pub mod bandwidth;
+
+pub mod stream;
+
+mod bencode;
+
 
@@ -10,10 +10,18 @@ use ratatui::{
     widgets::{Block, Paragraph, Widget},
     DefaultTerminal, Frame,
 };
+use qbit::{
+    torrent::Metadata,
+    tracker::{self, ScrapeStats},
+};
 
 fn main() -> io::Result<()> {
+    // Swarm of the test torrent, as peer_tester last scraped it
+    let swarm = Metadata::from_file("test/debian.torrent")
+        .ok()
+        .and_then(|torrent| tracker::cached_scrape(torrent.info_hash));
     let mut terminal = ratatui::init();
-    let app_default = App::default().run(&mut terminal);
+    let app_default = App { swarm, ..Default::default() }.run(&mut terminal);
     ratatui::restore();
     app_default
 }
@@ -22,6 +30,7 @@ fn main() -> io::Result<()> {
 struct App {
     count : u8,
     quitting : bool,
+    swarm : Option<ScrapeStats>,
 }
 
 impl App {
@@ -82,10 +91,14 @@ impl Widget for &mut App {
             .title_bottom(instructions.centered())
             .border_set(border::THICK);
 
-        let counter_text = Text::from(vec![Line::from(vec![
-            "Value: ".into(),
-            self.count.to_string().yellow(),
-        ])]);
+        let swarm = match self.swarm {
+            Some(swarm) => swarm.to_string(),
+            None => "not scraped yet".to_string(),
+        };
+        let counter_text = Text::from(vec![
+            Line::from(vec!["Value: ".into(), self.count.to_string().yellow()]),
+            Line::from(vec!["Swarm: ".into(), swarm.yellow()]),
+        ]);
 
         Paragraph::new(counter_text)
             .centered()
@@ -109,7 +122,7 @@ mod tests {
         let mut expected = Buffer::with_lines(vec![
             "┏━━━━━━━━━━━━━ Counter App Tutorial ━━━━━━━━━━━━━┓",
             "┃                    Value: 0                    ┃",
-            "┃                                                ┃",
+            "┃             Swarm: not scraped yet             ┃",
             "┗━ Decrement <Left> Increment <Right> Quit <Q> ━━┛",
         ]);
         let title_style = Style::new().bold();
@@ -117,10 +130,29 @@ mod tests {
         let key_style = Style::new().blue().bold();
         expected.set_style(Rect::new(14, 0, 22, 1), title_style);
         expected.set_style(Rect::new(28, 1, 1, 1), counter_style);
+        expected.set_style(Rect::new(21, 2, 15, 1), counter_style);
         expected.set_style(Rect::new(13, 3, 6, 1), key_style);
         expected.set_style(Rect::new(30, 3, 7, 1), key_style);
         expected.set_style(Rect::new(43, 3, 4, 1), key_style);
 
         assert_eq!(buf, expected);
     }
+
+    #[test]
+    fn render_swarm() {
+        let mut app = App {
+            swarm: Some(ScrapeStats {
+                complete: 5,
+                downloaded: 9,
+                incomplete: 2,
+            }),
+            ..Default::default()
+        };
+        let mut buf = Buffer::empty(Rect::new(0, 0, 50, 4));
+
+        app.render(buf.area, &mut buf);
+
+        let swarm: String = (1..49).map(|x| buf[(x, 2)].symbol()).collect();
+        assert_eq!(swarm, "    Swarm: 5 seeders, 2 leechers, 9 downloads   ");
+    }
 }
\ No newline at end of file
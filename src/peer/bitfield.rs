@@ -1,15 +1,35 @@
-use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
 
+/// # [`Bitfield`]
+/// One bit per piece, highest bit of first byte being piece `0`, as peers send it.
+/// Saved along with torrent's state, see [`crate::torrent::State`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawBitfield")]
 pub struct Bitfield {
-    bitfield: BytesMut,
+    #[serde(with = "serde_bytes")]
+    bitfield: Vec<u8>,
     len: usize,
 }
 
+/// Bitfield as read from disk, checked before use
+#[derive(Deserialize)]
+struct RawBitfield {
+    #[serde(with = "serde_bytes")]
+    bitfield: Vec<u8>,
+    len: usize,
+}
+
+impl TryFrom<RawBitfield> for Bitfield {
+    type Error = Error;
+    fn try_from(raw: RawBitfield) -> Result<Self> {
+        Self::from_bytes(raw.len, raw.bitfield)
+    }
+}
+
 impl Bitfield {
     pub fn new(total_pieces: usize) -> Self {
         let bitfield_size = (total_pieces as f64 / 8.0).ceil() as usize;
-        let mut bitfield = BytesMut::with_capacity(bitfield_size);
-        bitfield.resize(bitfield_size, 0);
+        let bitfield = vec![0; bitfield_size];
 
         Self {
             bitfield,
@@ -17,6 +37,42 @@ impl Bitfield {
         }
     }
 
+    /// Bitfield with every piece marked, as a seed's
+    pub fn full(total_pieces: usize) -> Self {
+        let mut full = Self::new(total_pieces);
+        full.bitfield.fill(0xff);
+        if total_pieces > 0 {
+            full.clear_unused_trail_units();
+        }
+        full
+    }
+
+    /// Bitfield of `total_pieces` out of bytes, as strict as peers are held to.
+    ///
+    /// ## Error
+    /// - [`Error::InvalidLength`], when there are more or less bytes than `total_pieces` need
+    /// - [`Error::SpareBits`], when bits past the last piece are set
+    pub fn from_bytes<T>(total_pieces: usize, bytes: T) -> Result<Self>
+    where
+        T: Into<Vec<u8>>,
+    {
+        let bitfield = Self {
+            bitfield: bytes.into(),
+            len: total_pieces,
+        };
+        let expected = bitfield.byte_len();
+        if bitfield.bitfield.len() != expected {
+            return Err(Error::InvalidLength {
+                expected,
+                got: bitfield.bitfield.len(),
+            });
+        }
+        if !total_pieces.is_multiple_of(8) && bitfield.bitfield[expected - 1] & (0xff >> (total_pieces % 8)) != 0 {
+            return Err(Error::SpareBits);
+        }
+        Ok(bitfield)
+    }
+
     fn byte_len(&self) -> usize {
         (self.len + 7) / 8
     }
@@ -58,6 +114,21 @@ impl Bitfield {
         Ok(self.bitfield[byte_index] & cmp_byte != 0)
     }
 
+    /// Whether bit is set, index must be in bounds
+    fn bit(&self, index: usize) -> bool {
+        self.bitfield[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Number of pieces marked
+    pub fn count_ones(&self) -> usize {
+        self.bitfield.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    /// Whether every piece is marked
+    pub fn is_full(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
     /// Returns underlying bitfield as slice
     pub fn as_bytes(&self) -> &[u8] {
         self.as_ref()
@@ -146,9 +217,45 @@ impl Bitfield {
         *self.bitfield.last_mut().expect("Bitfield's length is zero") &= mask;
     }
     
-    // I may need these for seeding
-    // pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_
-    // pub fn iter_unset(&self) -> impl Iterator<Item = usize> + '_
+    /// Indices of marked pieces, in order
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.bit(index))
+    }
+
+    /// Indices of pieces not marked yet, in order
+    pub fn iter_unset(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| !self.bit(index))
+    }
+
+    /// Pieces marked in both
+    ///
+    /// ## Error
+    /// Fails with [`Error::InvalidLength`] when bitfields aren't of the same torrent
+    pub fn and(&self, other: &Bitfield) -> Result<Bitfield> {
+        self.combine(other, |this, that| this & that)
+    }
+
+    /// Pieces marked in either, see [`Bitfield::and`] for errors
+    pub fn or(&self, other: &Bitfield) -> Result<Bitfield> {
+        self.combine(other, |this, that| this | that)
+    }
+
+    /// Pieces marked in this one only, those a peer could give us when `other` is ours.
+    /// See [`Bitfield::and`] for errors
+    pub fn andnot(&self, other: &Bitfield) -> Result<Bitfield> {
+        self.combine(other, |this, that| this & !that)
+    }
+
+    fn combine(&self, other: &Bitfield, op: impl Fn(u8, u8) -> u8) -> Result<Bitfield> {
+        if self.len != other.len {
+            return Err(Error::InvalidLength {
+                expected: self.len,
+                got: other.len,
+            });
+        }
+        let bitfield = self.bitfield.iter().zip(&other.bitfield).map(|(&this, &that)| op(this, that)).collect();
+        Ok(Self { bitfield, len: self.len })
+    }
 }
 
 impl AsRef<[u8]> for Bitfield {
@@ -165,6 +272,8 @@ pub enum Error {
     IndexOutOfBound,
     #[error("Unexpected length of bitfield")]
     InvalidLength { expected: usize, got: usize },
+    #[error("Bitfield has bits set past the last piece")]
+    SpareBits,
 }
 
 #[cfg(test)]
@@ -192,7 +301,7 @@ mod tests {
         assert!(bf.update(10).is_err());
         assert!(!bf.update(8).is_err());
         
-        assert_eq!(bf.bitfield.as_ref(), &[0b00010000, 0b10000000])
+        assert_eq!(bf.as_bytes(), &[0b00010000, 0b10000000])
     }
     
     #[test]
@@ -260,4 +369,51 @@ mod tests {
         assert!(bitfield2.has_any(&bitfield1));
     }
     
+    #[test]
+    fn set_and_unset_pieces_are_iterated_in_order() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.update(9).unwrap();
+        bitfield.update(1).unwrap();
+        bitfield.update(4).unwrap();
+
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), [1, 4, 9]);
+        assert_eq!(bitfield.iter_unset().collect::<Vec<_>>(), [0, 2, 3, 5, 6, 7, 8]);
+        assert_eq!(bitfield.count_ones(), 3);
+        assert!(!bitfield.is_full());
+        assert!(Bitfield::full(10).is_full());
+        assert_eq!(Bitfield::full(10).as_bytes(), &[0xff, 0b1100_0000]);
+    }
+
+    #[test]
+    fn set_operations_need_same_lengths() {
+        let ours = Bitfield::from_bytes(10, [0b1100_0000, 0b0100_0000]).unwrap();
+        let theirs = Bitfield::from_bytes(10, [0b1010_0000, 0b1100_0000]).unwrap();
+
+        assert_eq!(ours.and(&theirs).unwrap().iter_set().collect::<Vec<_>>(), [0, 9]);
+        assert_eq!(ours.or(&theirs).unwrap().iter_set().collect::<Vec<_>>(), [0, 1, 2, 8, 9]);
+        assert_eq!(theirs.andnot(&ours).unwrap().iter_set().collect::<Vec<_>>(), [2, 8]);
+        assert!(ours.and(&Bitfield::new(11)).is_err());
+    }
+
+    #[test]
+    fn from_bytes_rejects_spare_bits() {
+        assert!(matches!(Bitfield::from_bytes(10, [0, 0b0010_0000]), Err(super::Error::SpareBits)));
+        assert!(matches!(Bitfield::from_bytes(10, [0]), Err(super::Error::InvalidLength { .. })));
+        assert!(Bitfield::from_bytes(16, [0xff, 0xff]).unwrap().is_full());
+    }
+
+    #[test]
+    fn bitfields_survive_cbor() {
+        let bitfield = Bitfield::from_bytes(12, [0b1000_0001, 0b0011_0000]).unwrap();
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&bitfield, &mut bytes).unwrap();
+        assert_eq!(ciborium::from_reader::<Bitfield, _>(bytes.as_slice()).unwrap(), bitfield);
+
+        // Lengths that don't add up are no bitfield
+        let mut short = Bitfield::new(12);
+        short.len = 20;
+        let mut short_bytes = Vec::new();
+        ciborium::into_writer(&short, &mut short_bytes).unwrap();
+        assert!(ciborium::from_reader::<Bitfield, _>(short_bytes.as_slice()).is_err());
+    }
 }
@@ -0,0 +1,254 @@
+//! # Message framing
+//! Every message on the wire is `<length prefix : u32><message id : u8><payload>`,
+//! with a zero length standing for keep alive.
+//!
+//! [`Codec`] cuts messages out of a read buffer, [`Framed`] drives it over any async stream.
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::peer::Message;
+
+/// Large enough for a 16 KiB block, along with bitfields of torrents with up to ~8 million pieces
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1 << 20;
+
+const LENGTH_PREFIX: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Codec {
+    max_message_len: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_LEN)
+    }
+}
+
+impl Codec {
+    pub fn new(max_message_len: usize) -> Self {
+        Self { max_message_len }
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
+
+    /// Cuts a single message out of the buffer, returns `None` until the whole frame has arrived.
+    /// Payloads are sliced out of the buffer, nothing gets copied.
+    ///
+    /// ## Error
+    /// Fails when length prefix is beyond the limit (before anything of it gets buffered),
+    /// or the message itself doesn't make sense.
+    pub fn decode(&self, buffer: &mut BytesMut) -> Result<Option<Message>> {
+        if buffer.len() < LENGTH_PREFIX {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(buffer[..LENGTH_PREFIX].try_into().expect("Slice is 4 bytes long")) as usize;
+        if length > self.max_message_len {
+            return Err(Error::MessageTooLarge {
+                length,
+                max: self.max_message_len,
+            });
+        }
+        if buffer.len() < LENGTH_PREFIX + length {
+            buffer.reserve(LENGTH_PREFIX + length - buffer.len());
+            return Ok(None);
+        }
+
+        buffer.advance(LENGTH_PREFIX);
+        if length == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+        let mut frame = buffer.split_to(length).freeze();
+        let id = frame.get_u8();
+        Message::decode(id, frame).map(Some).map_err(Error::InvalidMessage)
+    }
+
+    /// Appends encoded message to the buffer
+    pub fn encode(&self, message: &Message, buffer: &mut BytesMut) {
+        buffer.extend_from_slice(&message.encode());
+    }
+}
+
+/// # [`Framed`]
+/// Reads and writes whole messages over a stream.
+///
+/// Reading is cancel safe, a partially received frame stays buffered until the rest of it shows up,
+/// so [`Framed::read_message`] can be raced in `tokio::select!` without losing bytes.
+#[derive(Debug)]
+pub struct Framed<S> {
+    stream: S,
+    codec: Codec,
+    read_buffer: BytesMut,
+}
+
+impl<S> Framed<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S, codec: Codec) -> Self {
+        Self {
+            stream,
+            codec,
+            read_buffer: BytesMut::with_capacity(4096),
+        }
+    }
+
+    pub async fn read_message(&mut self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.read_buffer)? {
+                return Ok(message);
+            }
+            if self.stream.read_buf(&mut self.read_buffer).await? == 0 {
+                return Err(Error::ConnectionClosed);
+            }
+        }
+    }
+
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        let mut buffer = BytesMut::new();
+        self.codec.encode(message, &mut buffer);
+        self.stream.write_all(&buffer).await?;
+        self.stream.flush().await
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Underlying stream, for whatever comes before framing (handshakes)
+    ///
+    /// Reading from it directly after messages have started flowing will skip buffered bytes.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+pub type Result<T> = std::result::Result<T, self::Error>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Message of {length} bytes is beyond the limit of {max} bytes")]
+    MessageTooLarge { length: usize, max: usize },
+    #[error("Invalid message : {0}")]
+    InvalidMessage(io::Error),
+    #[error("Peer closed the connection")]
+    ConnectionClosed,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::{io::duplex, time::timeout};
+
+    use super::*;
+
+    fn piece() -> Message {
+        Message::Piece {
+            index: 1,
+            offset: 16384,
+            data: Bytes::from_static(&[7; 32]),
+        }
+    }
+
+    #[test]
+    fn decoding_waits_for_whole_frames() {
+        let codec = Codec::default();
+        let encoded = piece().encode();
+        let mut buffer = BytesMut::new();
+
+        buffer.extend_from_slice(&encoded[..2]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(&encoded[2..20]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(&encoded[20..]);
+
+        let Some(Message::Piece { index, offset, data }) = codec.decode(&mut buffer).unwrap() else {
+            panic!("Expected a piece");
+        };
+        assert_eq!((index, offset, data.as_ref()), (1, 16384, [7; 32].as_ref()));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn decoding_several_messages_from_one_buffer() {
+        let codec = Codec::default();
+        let mut buffer = BytesMut::new();
+        for message in [Message::KeepAlive, Message::Unchoke, Message::Have(9)] {
+            codec.encode(&message, &mut buffer);
+        }
+
+        assert!(matches!(codec.decode(&mut buffer), Ok(Some(Message::KeepAlive))));
+        assert!(matches!(codec.decode(&mut buffer), Ok(Some(Message::Unchoke))));
+        assert!(matches!(codec.decode(&mut buffer), Ok(Some(Message::Have(9)))));
+        assert!(matches!(codec.decode(&mut buffer), Ok(None)));
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_upfront() {
+        let codec = Codec::new(64);
+        let mut buffer = BytesMut::from(&u32::MAX.to_be_bytes()[..]);
+
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(Error::MessageTooLarge { length, max: 64 }) if length == u32::MAX as usize
+        ));
+        // Nothing was reserved for it either
+        assert!(buffer.capacity() < 64);
+    }
+
+    #[test]
+    fn invalid_messages_are_errors() {
+        let codec = Codec::default();
+        let mut buffer = BytesMut::from(&[0, 0, 0, 2, 4, 0][..]);
+
+        assert!(matches!(codec.decode(&mut buffer), Err(Error::InvalidMessage(_))));
+    }
+
+    #[tokio::test]
+    async fn framed_reads_messages_split_across_writes() {
+        let (client, mut server) = duplex(64);
+        let mut framed = Framed::new(client, Codec::default());
+
+        let encoded = piece().encode();
+        server.write_all(&encoded[..10]).await.unwrap();
+        assert!(timeout(Duration::from_millis(20), framed.read_message()).await.is_err());
+
+        // Frame started above is still buffered, even though the read got cancelled
+        server.write_all(&encoded[10..]).await.unwrap();
+        assert!(matches!(framed.read_message().await, Ok(Message::Piece { .. })));
+    }
+
+    #[tokio::test]
+    async fn framed_writes_encoded_messages() {
+        let (client, mut server) = duplex(64);
+        let mut framed = Framed::new(client, Codec::default());
+
+        framed.send(&Message::Interested).await.unwrap();
+
+        let mut buffer = [0u8; 5];
+        server.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [0, 0, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn framed_reports_closed_connections() {
+        let (client, mut server) = duplex(64);
+        let mut framed = Framed::new(client, Codec::default());
+
+        server.write_all(&[0, 0, 0]).await.unwrap();
+        drop(server);
+
+        assert!(matches!(framed.read_message().await, Err(Error::ConnectionClosed)));
+    }
+}
@@ -0,0 +1,130 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::peer::Peer;
+
+/// Length of a single IPv4 peer in compact form, 4 bytes of address followed by 2 bytes of port
+pub const V4_LEN: usize = 6;
+
+/// Length of a single IPv6 peer in compact form, 16 bytes of address followed by 2 bytes of port
+pub const V6_LEN: usize = 18;
+
+/// Parses a compact IPv4 peer list, more on https://www.bittorrent.org/beps/bep_0023.html
+///
+/// Trailing bytes that don't make up a whole peer are ignored.
+pub fn decode_v4(bytes: &[u8]) -> Vec<Peer> {
+    bytes
+        .chunks_exact(V4_LEN)
+        .map(|chunk| {
+            let ip: [u8; 4] = chunk[..4].try_into().expect("Chunk is 6 bytes long");
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            Peer::new(Ipv4Addr::from(ip), port)
+        })
+        .collect()
+}
+
+/// Parses a compact IPv6 peer list (`peers6`), more on https://www.bittorrent.org/beps/bep_0007.html
+///
+/// Trailing bytes that don't make up a whole peer are ignored.
+pub fn decode_v6(bytes: &[u8]) -> Vec<Peer> {
+    bytes
+        .chunks_exact(V6_LEN)
+        .map(|chunk| {
+            let ip: [u8; 16] = chunk[..16].try_into().expect("Chunk is 18 bytes long");
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            Peer::new(Ipv6Addr::from(ip), port)
+        })
+        .collect()
+}
+
+/// Packs IPv4 peers into compact form, see [`decode_v4`]. IPv6 peers are left out.
+pub fn encode_v4<'a, T>(peers: T) -> BytesMut
+where
+    T: IntoIterator<Item = &'a Peer>,
+{
+    let mut bytes = BytesMut::new();
+    for peer in peers {
+        if let IpAddr::V4(ip) = peer.addr.ip() {
+            bytes.put_slice(&ip.octets());
+            bytes.put_u16(peer.addr.port());
+        }
+    }
+    bytes
+}
+
+/// Packs IPv6 peers into compact form, see [`decode_v6`]. IPv4 peers are left out.
+pub fn encode_v6<'a, T>(peers: T) -> BytesMut
+where
+    T: IntoIterator<Item = &'a Peer>,
+{
+    let mut bytes = BytesMut::new();
+    for peer in peers {
+        if let IpAddr::V6(ip) = peer.addr.ip() {
+            bytes.put_slice(&ip.octets());
+            bytes.put_u16(peer.addr.port());
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn decoding_compact_peers() {
+        let bytes = [10, 0, 0, 1, 0x1A, 0xE1, 127, 0, 0, 1, 0, 80, 1];
+        let peers = decode_v4(&bytes);
+
+        assert_eq!(
+            peers,
+            vec![
+                Peer::new(Ipv4Addr::new(10, 0, 0, 1), 6881),
+                Peer::new(Ipv4Addr::new(127, 0, 0, 1), 80),
+            ]
+        );
+    }
+
+    #[test]
+    fn decoding_compact_ipv6_peers() {
+        let mut bytes = Ipv6Addr::LOCALHOST.octets().to_vec();
+        bytes.extend_from_slice(&[0x1A, 0xE1]);
+        bytes.extend_from_slice(&[0xFF; 7]);
+
+        assert_eq!(decode_v6(&bytes), vec![Peer::new(Ipv6Addr::LOCALHOST, 6881)]);
+    }
+
+    #[test]
+    fn encoding_and_decoding_are_symmetric() {
+        let peers = vec![
+            Peer::new(Ipv4Addr::new(1, 2, 3, 4), 51413),
+            Peer::new(Ipv4Addr::new(192, 168, 1, 9), 6881),
+        ];
+        let bytes = encode_v4(&peers);
+
+        assert_eq!(bytes.len(), 2 * V4_LEN);
+        assert_eq!(decode_v4(&bytes), peers);
+    }
+
+    #[test]
+    fn encoding_splits_peers_by_family() {
+        let v4 = Peer::new(Ipv4Addr::new(1, 2, 3, 4), 51413);
+        let v6 = Peer::new("2001:db8::1".parse::<Ipv6Addr>().unwrap(), 6881);
+        let peers = [v4, v6];
+
+        assert_eq!(decode_v4(&encode_v4(&peers)), vec![v4]);
+        assert_eq!(decode_v6(&encode_v6(&peers)), vec![v6]);
+    }
+
+    #[test]
+    fn mapped_addresses_are_canonicalised() {
+        let mapped = Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped();
+        let peer = Peer::new(mapped, 80);
+
+        assert!(peer.addr.is_ipv4());
+        assert_eq!(encode_v4([&peer]).len(), V4_LEN);
+    }
+}
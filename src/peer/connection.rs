@@ -1,63 +1,206 @@
-use bytes::Bytes;
 use bytes::BytesMut;
-use std::net::Ipv4Addr;
-use std::net::{IpAddr::V4, SocketAddr};
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    time::Instant,
 };
 
-use crate::peer::{session, Handshake, Message, Peer};
+use crate::{
+    bandwidth::{Direction, Throttle},
+    peer::{
+        codec::{self, Codec, Framed},
+        encryption::{self, EncryptedStream, Policy},
+        handshake::{self, Remote, Reserved, PROTOCOL},
+        session,
+        transport::{Connector, Kind, Transport},
+        Handshake, Message, Peer,
+    },
+    torrent::InfoHash,
+};
 
 #[derive(Debug)]
 pub struct Connection {
     pub(crate) peer: Peer,
-    stream: TcpStream,
+    stream: Framed<EncryptedStream<Box<dyn Transport>>>,
+    /// Reserved bits remote peer has sent in its handshake
+    pub(crate) reserved: Reserved,
+    /// Whether we're the ones who dialed
+    pub(crate) outbound: bool,
+    last_read: Instant,
+    last_written: Instant,
+    /// Bandwidth limits reads and writes go through, none until set
+    throttle: Throttle,
 }
 
 impl Connection {
+    /// Connects over plaintext TCP
     pub async fn connect(peer: Peer) -> Result<Self, std::io::Error> {
-        let socket_addr = SocketAddr::new(V4(peer.ip), peer.port);
-        let stream = TcpStream::connect(socket_addr).await?;
+        let stream: Box<dyn Transport> = Box::new(TcpStream::connect(peer.addr).await?);
+        Ok(Self::new(peer, EncryptedStream::plaintext(stream), true))
+    }
 
-        Ok(Self { peer, stream })
+    /// Connects through connector (uTP first, when it has a socket for it),
+    /// then encrypts the connection as its policy asks for.
+    /// Under [`Policy::Prefer`], peers that fail the encryption handshake get another try over plaintext.
+    pub async fn connect_with(peer: Peer, info_hash: &InfoHash, connector: &Connector) -> encryption::Result<Self> {
+        let policy = connector.encryption;
+        let stream = connector.open(peer.addr).await?;
+        if policy == Policy::Disabled {
+            return Ok(Self::new(peer, EncryptedStream::plaintext(stream), true));
+        }
+
+        match encryption::initiate(stream, info_hash, policy).await {
+            Ok(stream) => Ok(Self::new(peer, stream, true)),
+            Err(_) if policy == Policy::Prefer => {
+                let stream = connector.open(peer.addr).await?;
+                Ok(Self::new(peer, EncryptedStream::plaintext(stream), true))
+            }
+            Err(err) => Err(err),
+        }
     }
 
-    pub async fn handshake(&mut self, handshake: Handshake) -> Result<(), std::io::Error> {
-        self.stream.write_all(handshake.bytes()).await?;
+    /// Wraps a stream some peer has opened to us, running the encryption handshake when peer starts with one.
+    /// `info_hashes` are the torrents an encrypted peer may ask for.
+    ///
+    /// ## Error
+    /// Fails when the way peer connects isn't allowed by policy, or encryption handshake fails
+    pub async fn from_incoming(mut stream: Box<dyn Transport>, info_hashes: &[InfoHash], policy: Policy) -> encryption::Result<Self> {
+        let peer = Peer::from(stream.peer_addr()?);
 
-        let mut response_buffer = [0u8; 68];
-        self.stream.read_exact(&mut response_buffer).await?;
-        Ok(())
+        // Plaintext peers start with the protocol header, encrypted ones with a public key way longer than it
+        let mut received = BytesMut::with_capacity(PROTOCOL.len() + 1);
+        while received.len() < PROTOCOL.len() + 1 {
+            if stream.read_buf(&mut received).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        let plaintext = received[0] as usize == PROTOCOL.len() && received[1..] == PROTOCOL[..];
+
+        let stream = match (plaintext, policy) {
+            (true, Policy::Require) => return Err(encryption::Error::PlaintextRefused),
+            (true, _) => EncryptedStream::new(stream, received, None),
+            (false, Policy::Disabled) => return Err(encryption::Error::EncryptionRefused),
+            (false, _) => encryption::accept(stream, received, info_hashes, policy).await?,
+        };
+        Ok(Self::new(peer, stream, false))
     }
 
-    pub async fn read_message(&mut self) -> Result<Message, io::Error> {
-        let length = self.stream.read_u32().await?;
+    /// Plaintext connection over any stream, for tests that drive peer's side by hand
+    #[cfg(test)]
+    pub(crate) fn over(peer: Peer, stream: impl Transport + 'static) -> Self {
+        let stream: Box<dyn Transport> = Box::new(stream);
+        Self::new(peer, EncryptedStream::plaintext(stream), true)
+    }
 
-        if length == 0 {
-            return Ok(Message::KeepAlive);
+    fn new(peer: Peer, stream: EncryptedStream<Box<dyn Transport>>, outbound: bool) -> Self {
+        Self {
+            peer,
+            stream: Framed::new(stream, Codec::default()),
+            reserved: Reserved::default(),
+            outbound,
+            last_read: Instant::now(),
+            last_written: Instant::now(),
+            throttle: Throttle::default(),
         }
-        let id = self.stream.read_u8().await?;
-        let payload = Self::scrap_payload(&mut self.stream, length as usize).await?;
-        let message = Message::decode(id, payload)?;
-        #[cfg(debug_assertions)]
-        eprintln!("\x1b[30mSESSION {:>15} | Recieved : {:?}\x1b[0m", self.peer.ip, message);
-        Ok(message)
     }
 
-    async fn scrap_payload(stream: &mut TcpStream, len: usize) -> io::Result<Bytes> {
-        let mut buffer = BytesMut::with_capacity(len - 1);
-        buffer.resize(len - 1, 0);
-        stream.read_exact(&mut buffer).await?;
+    /// When peer's last message arrived (or the connection was opened, before that)
+    pub fn last_read(&self) -> Instant {
+        self.last_read
+    }
+
+    /// When we last sent peer a message
+    pub fn last_written(&self) -> Instant {
+        self.last_written
+    }
+
+    /// Whether bytes on the wire are RC4 encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.stream.get_ref().is_encrypted()
+    }
+
+    /// Whether we're talking over TCP or uTP
+    pub fn transport(&self) -> Kind {
+        self.stream.get_ref().get_ref().kind()
+    }
+
+    /// Puts reads and writes through given bandwidth limits
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+
+    /// Caps the size of messages accepted from this peer
+    pub fn set_max_message_len(&mut self, max_message_len: usize) {
+        self.stream.set_codec(Codec::new(max_message_len));
+    }
+
+    /// Exchanges handshakes with the peer, and validates the one peer responds with.
+    /// On success, peer's id gets stored in [`Peer::id`]
+    ///
+    /// ## Error
+    /// Fails when peer speaks some other protocol, serves some other torrent, or turns out to be us
+    pub async fn handshake(&mut self, handshake: Handshake) -> handshake::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(handshake.bytes()).await?;
+        stream.flush().await?;
+
+        let remote = self.read_handshake().await?;
+        remote.validate(&handshake.info_hash())?;
+
+        self.reserved = remote.reserved;
+        self.peer.id = Some(remote.peer_id);
+        Ok(())
+    }
+
+    /// Reads handshake of a peer that connected to us.
+    /// Our response is held back until caller knows which torrent it's about, see [`Connection::respond_handshake`]
+    pub async fn receive_handshake(&mut self) -> handshake::Result<Remote> {
+        self.read_handshake().await
+    }
+
+    /// Answers a handshake read with [`Connection::receive_handshake`].
+    /// Nothing is sent when remote turns out to be us.
+    pub async fn respond_handshake(&mut self, remote: Remote, handshake: Handshake) -> handshake::Result<()> {
+        remote.validate(&handshake.info_hash())?;
+        let stream = self.stream.get_mut();
+        stream.write_all(handshake.bytes()).await?;
+        stream.flush().await?;
+
+        self.reserved = remote.reserved;
+        self.peer.id = Some(remote.peer_id);
+        Ok(())
+    }
+
+    async fn read_handshake(&mut self) -> handshake::Result<Remote> {
+        let mut response_buffer = [0u8; 68];
+        let stream = self.stream.get_mut();
+        stream.read_exact(&mut response_buffer[..1]).await?;
+        if response_buffer[0] != 19 {
+            return Err(handshake::Error::InvalidProtocol);
+        }
+        stream.read_exact(&mut response_buffer[1..]).await?;
+        Remote::try_from(&response_buffer)
+    }
 
-        Ok(buffer.freeze())
+    /// Reads next message off the stream, once download limits allow it, cancel safe
+    pub async fn read_message(&mut self) -> codec::Result<Message> {
+        self.throttle.ready(Direction::Download).await;
+        let message = self.stream.read_message().await?;
+        self.throttle.charge(Direction::Download, &message);
+        self.last_read = Instant::now();
+        #[cfg(debug_assertions)]
+        eprintln!("\x1b[30mSESSION {:>15} | Recieved : {:?}\x1b[0m", self.peer.addr.ip(), message);
+        Ok(message)
     }
 
-    /// Writes the encoded message to the TCP stream
+    /// Writes the encoded message to the TCP stream, once upload limits allow it
     pub(crate) async fn send(&mut self, message : Message) -> Result<(), session::Error> {
-        self.stream.write_all(&message.encode()).await?;
+        self.throttle.ready(Direction::Upload).await;
+        self.stream.send(&message).await?;
+        self.throttle.charge(Direction::Upload, &message);
+        self.last_written = Instant::now();
         #[cfg(debug_assertions)]
-        println!("SESSION {:>15} | Sent     : {:?}", self.peer.ip, message);
+        println!("SESSION {:>15} | Sent     : {:?}", self.peer.addr.ip(), message);
         Ok(())
     }
 }
@@ -65,5 +208,158 @@ impl Connection {
 
 #[cfg(test)]
 mod tests {
-    
-}
\ No newline at end of file
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        config::Config,
+        peer::{
+            encryption::{self, Policy},
+            handshake,
+            transport::Connector,
+            Connection, Handshake, Peer,
+        },
+    };
+
+    /// Spawns a peer that answers any handshake with given info hash and peer id
+    async fn remote_peer(info_hash: [u8; 20], peer_id: [u8; 20]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 68];
+            stream.read_exact(&mut buffer).await.unwrap();
+
+            let mut response = *Handshake::new(&info_hash.into()).as_ref();
+            response[48..].copy_from_slice(&peer_id);
+            stream.write_all(&response).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn handshake_stores_remote_peer_id() {
+        let addr = remote_peer([3; 20], [9; 20]).await;
+        let mut connection = Peer::from(addr).connect().await.unwrap();
+
+        connection.handshake(Handshake::new(&[3; 20].into())).await.unwrap();
+
+        assert_eq!(connection.peer.id, Some([9; 20].into()));
+        assert!(connection.reserved.supports_extensions());
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_other_torrents() {
+        let addr = remote_peer([3; 20], [9; 20]).await;
+        let mut connection = Peer::from(addr).connect().await.unwrap();
+
+        let result = connection.handshake(Handshake::new(&[4; 20].into())).await;
+
+        assert!(matches!(result, Err(handshake::Error::InfoHashMismatch)));
+        assert_eq!(connection.peer.id, None);
+    }
+
+    /// Handshake of some other peer, so it doesn't look like we've connected to ourselves
+    fn foreign_handshake(info_hash: [u8; 20]) -> [u8; 68] {
+        let mut handshake = *Handshake::new(&info_hash.into()).as_ref();
+        handshake[48..].copy_from_slice(&[1; 20]);
+        handshake
+    }
+
+    /// Accepts connections the way listener does, answering handshakes for `[3; 20]` (if peer sends one)
+    /// Task yields whether each accepted connection got encrypted, hanging up on all of them.
+    async fn incoming_peer(policy: Policy) -> (SocketAddr, tokio::task::JoinHandle<Vec<encryption::Result<bool>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            // A failed encryption attempt may be followed by a plaintext one
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let result = Connection::from_incoming(Box::new(stream), &[[3; 20].into()], policy).await;
+                let failed = result.is_err();
+                accepted.push(result);
+                if !failed {
+                    break;
+                }
+            }
+            if let Some(Ok(connection)) = accepted.last_mut()
+                && let Ok(remote) = connection.receive_handshake().await
+            {
+                // Refused when it's our own handshake, looping back
+                let _ = connection.respond_handshake(remote, Handshake::new(&[3; 20].into())).await;
+            }
+            accepted
+                .into_iter()
+                .map(|result| result.map(|connection| connection.is_encrypted()))
+                .collect()
+        });
+        (addr, task)
+    }
+
+    #[tokio::test]
+    async fn encrypted_handshake_over_loopback() {
+        let (addr, peer) = incoming_peer(Policy::Require).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = encryption::initiate(stream, &[3; 20].into(), Policy::Require).await.unwrap();
+        stream.write_all(&foreign_handshake([3; 20])).await.unwrap();
+        stream.flush().await.unwrap();
+        let mut response = [0u8; 68];
+        stream.read_exact(&mut response).await.unwrap();
+
+        assert!(stream.is_encrypted());
+        assert_eq!(&response[28..48], &[3; 20]);
+        let accepted = peer.await.unwrap();
+        assert!(matches!(accepted[0], Ok(true)));
+    }
+
+    #[tokio::test]
+    async fn preferred_encryption_falls_back_to_plaintext() {
+        let (addr, peer) = incoming_peer(Policy::Disabled).await;
+
+        let connector = Connector::new(&Config {
+            encryption: Policy::Prefer,
+            ..Default::default()
+        });
+        let mut connection = Connection::connect_with(Peer::from(addr), &[3; 20].into(), &connector).await.unwrap();
+        assert!(!connection.is_encrypted());
+        let _ = connection.handshake(Handshake::new(&[3; 20].into())).await;
+
+        let accepted = peer.await.unwrap();
+        assert!(matches!(accepted[0], Err(encryption::Error::EncryptionRefused)));
+        assert!(matches!(accepted[1], Ok(false)));
+    }
+
+    #[tokio::test]
+    async fn required_encryption_refuses_plaintext_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&foreign_handshake([3; 20])).await.unwrap();
+        let (incoming, _) = listener.accept().await.unwrap();
+
+        let result = Connection::from_incoming(Box::new(incoming), &[[3; 20].into()], Policy::Require).await;
+        assert!(matches!(result, Err(encryption::Error::PlaintextRefused)));
+    }
+
+    #[tokio::test]
+    async fn plaintext_peers_keep_their_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&foreign_handshake([3; 20])).await.unwrap();
+        let (incoming, _) = listener.accept().await.unwrap();
+
+        let mut connection = Connection::from_incoming(Box::new(incoming), &[], Policy::Prefer).await.unwrap();
+        let remote = connection.receive_handshake().await.unwrap();
+        assert_eq!(remote.info_hash, [3; 20].into());
+        assert!(!connection.is_encrypted());
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::LazyLock;
+
+use num_bigint::BigUint;
+use rand::{rng, RngCore};
+
+use crate::peer::encryption::{Error, Result};
+
+/// Public keys and the shared secret are all this long, big endian, zero padded
+pub(crate) const KEY_LEN: usize = 96;
+
+/// 768 bit safe prime MSE has settled on, generator being 2
+static PRIME: LazyLock<BigUint> = LazyLock::new(|| {
+    BigUint::parse_bytes(
+        b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563",
+        16,
+    )
+    .expect("Prime is valid hex")
+});
+
+/// # [`KeyPair`]
+/// Our half of the Diffie-Hellman exchange, private key being 160 random bits
+pub(crate) struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let mut private = [0u8; 20];
+        rng().fill_bytes(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let public = BigUint::from(2u8).modpow(&private, &PRIME);
+
+        Self {
+            public: to_bytes(&public),
+            private,
+        }
+    }
+
+    pub fn public(&self) -> &[u8; KEY_LEN] {
+        &self.public
+    }
+
+    /// ## Error
+    /// Fails with [`Error::InvalidKey`] for keys that would give away the secret (0, 1 and p - 1 or beyond)
+    pub fn shared_secret(&self, remote: &[u8]) -> Result<[u8; KEY_LEN]> {
+        let remote = BigUint::from_bytes_be(remote);
+        let one = BigUint::from(1u8);
+        if remote <= one || remote >= &*PRIME - &one {
+            return Err(Error::InvalidKey);
+        }
+        Ok(to_bytes(&remote.modpow(&self.private, &PRIME)))
+    }
+}
+
+fn to_bytes(number: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = number.to_bytes_be();
+    let mut padded = [0u8; KEY_LEN];
+    padded[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_derive_the_same_secret() {
+        let a = KeyPair::generate();
+        let b = KeyPair::generate();
+
+        let secret = a.shared_secret(b.public()).unwrap();
+        assert_eq!(secret, b.shared_secret(a.public()).unwrap());
+        assert_ne!(secret, [0; KEY_LEN]);
+    }
+
+    #[test]
+    fn trivial_keys_are_refused() {
+        let keys = KeyPair::generate();
+        let mut one = [0u8; KEY_LEN];
+        one[KEY_LEN - 1] = 1;
+
+        assert!(matches!(keys.shared_secret(&[0; KEY_LEN]), Err(Error::InvalidKey)));
+        assert!(matches!(keys.shared_secret(&one), Err(Error::InvalidKey)));
+        assert!(matches!(keys.shared_secret(&[0xff; KEY_LEN]), Err(Error::InvalidKey)));
+    }
+}
@@ -0,0 +1,316 @@
+use bytes::{Buf, BufMut, BytesMut};
+use rand::{rng, Rng, RngCore};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    peer::encryption::{
+        dh::{KeyPair, KEY_LEN},
+        rc4::Rc4,
+        stream::Ciphers,
+        EncryptedStream, Error, Policy, Result,
+    },
+    torrent::InfoHash,
+};
+
+/// Verification constant, encrypted so each side can find where the other's cipher starts
+const VC: [u8; 8] = [0; 8];
+const MAX_PADDING: usize = 512;
+
+pub(crate) const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub(crate) const CRYPTO_RC4: u32 = 0x02;
+
+/// Runs the encryption handshake as the side that has connected.
+///
+/// ## Error
+/// Fails when peer doesn't speak MSE, or can't agree on a crypto method
+pub async fn initiate<S>(mut stream: S, info_hash: &InfoHash, policy: Policy) -> Result<EncryptedStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let keys = KeyPair::generate();
+    let mut hello = BytesMut::from(&keys.public()[..]);
+    hello.extend_from_slice(&padding());
+    stream.write_all(&hello).await?;
+
+    let mut reader = Reader::new(&mut stream, BytesMut::new());
+    let secret = keys.shared_secret(&reader.read_exact(KEY_LEN).await?)?;
+    let mut encryptor = Rc4::mse(&hash(&[b"keyA", &secret, info_hash.as_ref()]));
+    let mut decryptor = Rc4::mse(&hash(&[b"keyB", &secret, info_hash.as_ref()]));
+
+    let provide = policy.crypto_provide();
+    let mut request = BytesMut::new();
+    request.extend_from_slice(&hash(&[b"req1", &secret]));
+    request.extend_from_slice(&xor(hash(&[b"req2", info_hash.as_ref()]), hash(&[b"req3", &secret])));
+    let mut encrypted = BytesMut::new();
+    encrypted.extend_from_slice(&VC);
+    encrypted.put_u32(provide);
+    // No padding, and no initial payload, our handshake goes out once the stream's set up
+    encrypted.put_u16(0);
+    encrypted.put_u16(0);
+    encryptor.apply(&mut encrypted);
+    request.extend_from_slice(&encrypted);
+    reader.stream.write_all(&request).await?;
+
+    let mut vc = VC;
+    decryptor.apply(&mut vc);
+    reader.sync(&vc, MAX_PADDING).await?;
+
+    let mut fields = reader.read_exact(6).await?;
+    decryptor.apply(&mut fields);
+    let select = fields.get_u32();
+    let padding_len = fields.get_u16() as usize;
+    if padding_len > MAX_PADDING {
+        return Err(Error::PaddingTooLong);
+    }
+    decryptor.apply(&mut reader.read_exact(padding_len).await?);
+
+    if select & provide == 0 || select.count_ones() != 1 {
+        return Err(Error::UnsupportedCrypto(select));
+    }
+    let leftover = reader.buffer;
+    Ok(finish(stream, BytesMut::new(), leftover, select, decryptor, encryptor))
+}
+
+/// Runs the encryption handshake as the side that was connected to.
+/// `received` is whatever has already been read off the stream, `info_hashes` are the torrents peer may ask for.
+///
+/// ## Error
+/// Fails when peer doesn't speak MSE, asks for a torrent not in `info_hashes`, or can't agree on a crypto method
+pub async fn accept<S>(
+    mut stream: S,
+    received: BytesMut,
+    info_hashes: &[InfoHash],
+    policy: Policy,
+) -> Result<EncryptedStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reader = Reader::new(&mut stream, received);
+    let remote = reader.read_exact(KEY_LEN).await?;
+
+    let keys = KeyPair::generate();
+    let secret = keys.shared_secret(&remote)?;
+    let mut hello = BytesMut::from(&keys.public()[..]);
+    hello.extend_from_slice(&padding());
+    reader.stream.write_all(&hello).await?;
+
+    reader.sync(&hash(&[b"req1", &secret]), MAX_PADDING).await?;
+    let requested = xor(
+        reader.read_exact(20).await?[..].try_into().expect("Read exactly 20 bytes"),
+        hash(&[b"req3", &secret]),
+    );
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_ref()]) == requested)
+        .ok_or(Error::UnknownTorrent)?;
+    let mut decryptor = Rc4::mse(&hash(&[b"keyA", &secret, info_hash.as_ref()]));
+    let mut encryptor = Rc4::mse(&hash(&[b"keyB", &secret, info_hash.as_ref()]));
+
+    let mut fields = reader.read_exact(14).await?;
+    decryptor.apply(&mut fields);
+    if fields.split_to(8)[..] != VC {
+        return Err(Error::VcMismatch);
+    }
+    let provide = fields.get_u32();
+    let padding_len = fields.get_u16() as usize;
+    if padding_len > MAX_PADDING {
+        return Err(Error::PaddingTooLong);
+    }
+    decryptor.apply(&mut reader.read_exact(padding_len).await?);
+
+    let mut initial_len = reader.read_exact(2).await?;
+    decryptor.apply(&mut initial_len);
+    let mut initial_payload = reader.read_exact(initial_len.get_u16() as usize).await?;
+    decryptor.apply(&mut initial_payload);
+
+    let select = policy.select(provide)?;
+    let mut response = BytesMut::new();
+    response.extend_from_slice(&VC);
+    response.put_u32(select);
+    response.put_u16(0);
+    encryptor.apply(&mut response);
+    reader.stream.write_all(&response).await?;
+
+    let leftover = reader.buffer;
+    Ok(finish(stream, initial_payload, leftover, select, decryptor, encryptor))
+}
+
+/// Wraps up the stream, with whatever was read past the handshake decrypted (if needed) and put in front of it
+fn finish<S>(
+    stream: S,
+    mut prefix: BytesMut,
+    mut leftover: BytesMut,
+    select: u32,
+    mut decryptor: Rc4,
+    encryptor: Rc4,
+) -> EncryptedStream<S> {
+    let ciphers = if select == CRYPTO_RC4 {
+        decryptor.apply(&mut leftover);
+        Some(Ciphers {
+            read: decryptor,
+            write: encryptor,
+        })
+    } else {
+        None
+    };
+    prefix.extend_from_slice(&leftover);
+    EncryptedStream::new(stream, prefix, ciphers)
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(mut a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    a.iter_mut().zip(b).for_each(|(a, b)| *a ^= b);
+    a
+}
+
+fn padding() -> Vec<u8> {
+    let mut padding = vec![0u8; rng().random_range(0..=MAX_PADDING)];
+    rng().fill_bytes(&mut padding);
+    padding
+}
+
+/// Reads handshake off the stream through a buffer, as the handshake has to be searched through.
+/// Whatever's left in the buffer belongs to the stream after handshake.
+struct Reader<'a, S> {
+    stream: &'a mut S,
+    buffer: BytesMut,
+}
+
+impl<'a, S: AsyncRead + Unpin> Reader<'a, S> {
+    fn new(stream: &'a mut S, buffer: BytesMut) -> Self {
+        Self { stream, buffer }
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        if self.stream.read_buf(&mut self.buffer).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    async fn read_exact(&mut self, length: usize) -> Result<BytesMut> {
+        while self.buffer.len() < length {
+            self.fill().await?;
+        }
+        Ok(self.buffer.split_to(length))
+    }
+
+    /// Skips past `pattern`, which has to show up within `max_skip` bytes
+    async fn sync(&mut self, pattern: &[u8], max_skip: usize) -> Result<()> {
+        loop {
+            if let Some(position) = self.buffer.windows(pattern.len()).position(|window| window == pattern) {
+                self.buffer.advance(position + pattern.len());
+                return Ok(());
+            }
+            if self.buffer.len() >= max_skip + pattern.len() {
+                return Err(Error::SyncFailed);
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    async fn handshake(
+        initiator: Policy,
+        acceptor: Policy,
+        info_hashes: &[InfoHash],
+    ) -> (Result<EncryptedStream<DuplexStream>>, Result<EncryptedStream<DuplexStream>>) {
+        let (a, b) = duplex(4096);
+        let info_hash = InfoHash::from([5; 20]);
+        tokio::join!(
+            initiate(a, &info_hash, initiator),
+            accept(b, BytesMut::new(), info_hashes, acceptor)
+        )
+    }
+
+    #[tokio::test]
+    async fn both_sides_agree_on_rc4() {
+        let (a, b) = handshake(Policy::Prefer, Policy::Require, &[[4; 20].into(), [5; 20].into()]).await;
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert!(a.is_encrypted() && b.is_encrypted());
+
+        a.write_all(b"ping").await.unwrap();
+        b.write_all(b"pong").await.unwrap();
+        let mut buffer = [0u8; 4];
+        b.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+        a.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"pong");
+    }
+
+    #[tokio::test]
+    async fn unknown_torrents_are_refused() {
+        let (_, b) = handshake(Policy::Prefer, Policy::Prefer, &[[4; 20].into()]).await;
+        assert!(matches!(b, Err(Error::UnknownTorrent)));
+    }
+
+    #[tokio::test]
+    async fn initial_payload_reaches_the_stream() {
+        let (mut a, b) = duplex(4096);
+        let keys = KeyPair::generate();
+        let info_hash = InfoHash::from([5; 20]);
+
+        let initiator = async {
+            a.write_all(keys.public()).await.unwrap();
+            let mut remote = [0u8; KEY_LEN];
+            a.read_exact(&mut remote).await.unwrap();
+            let secret = keys.shared_secret(&remote).unwrap();
+
+            // Peer offers plaintext only, with its own handshake as initial payload
+            let mut encryptor = Rc4::mse(&hash(&[b"keyA", &secret, info_hash.as_ref()]));
+            let mut encrypted = BytesMut::from(&VC[..]);
+            encrypted.put_u32(CRYPTO_PLAINTEXT);
+            encrypted.put_u16(0);
+            encrypted.put_u16(5);
+            encrypted.extend_from_slice(b"hello");
+            encryptor.apply(&mut encrypted);
+
+            a.write_all(&hash(&[b"req1", &secret])).await.unwrap();
+            a.write_all(&xor(hash(&[b"req2", info_hash.as_ref()]), hash(&[b"req3", &secret]))).await.unwrap();
+            a.write_all(&encrypted).await.unwrap();
+            a.write_all(b" world").await.unwrap();
+            a
+        };
+        let info_hashes = [info_hash];
+        let (_a, b) = tokio::join!(initiator, accept(b, BytesMut::new(), &info_hashes, Policy::Prefer));
+
+        let mut b = b.unwrap();
+        assert!(!b.is_encrypted());
+        let mut buffer = [0u8; 11];
+        b.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello world");
+    }
+
+    #[test]
+    fn required_encryption_refuses_plaintext_only_peers() {
+        assert!(matches!(Policy::Require.select(CRYPTO_PLAINTEXT), Err(Error::UnsupportedCrypto(1))));
+        assert_eq!(Policy::Prefer.select(CRYPTO_PLAINTEXT).unwrap(), CRYPTO_PLAINTEXT);
+        assert_eq!(Policy::Prefer.select(CRYPTO_PLAINTEXT | CRYPTO_RC4).unwrap(), CRYPTO_RC4);
+    }
+
+    #[tokio::test]
+    async fn garbage_fails_to_sync() {
+        let (mut a, b) = duplex(4096);
+        let garbage = async {
+            a.write_all(&[1; KEY_LEN + MAX_PADDING + 40]).await.unwrap();
+            a
+        };
+        let info_hashes = [InfoHash::from([5; 20])];
+        let (_a, b) = tokio::join!(garbage, accept(b, BytesMut::new(), &info_hashes, Policy::Prefer));
+        assert!(matches!(b, Err(Error::SyncFailed)));
+    }
+}
@@ -0,0 +1,75 @@
+//! # Message Stream Encryption
+//! Diffie-Hellman key exchange followed by RC4 over the whole connection,
+//! mostly to get past networks that throttle BitTorrent traffic.
+//!
+//! Handshake is run right after connecting, before the BitTorrent handshake,
+//! see [`initiate`] and [`accept`].
+mod dh;
+mod handshake;
+mod rc4;
+mod stream;
+
+use std::io;
+
+pub use handshake::{accept, initiate};
+pub use stream::EncryptedStream;
+
+use handshake::{CRYPTO_PLAINTEXT, CRYPTO_RC4};
+
+/// # [`Policy`]
+/// Whether connections get encrypted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Plaintext only, encrypted peers are turned away
+    Disabled,
+    /// Encrypt whenever the other side can, plaintext otherwise
+    #[default]
+    Prefer,
+    /// Encrypted connections only
+    Require,
+}
+
+impl Policy {
+    /// Crypto methods we offer when connecting
+    fn crypto_provide(self) -> u32 {
+        match self {
+            Policy::Require => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    /// Picks one of the crypto methods peer has offered, RC4 whenever possible
+    fn select(self, provide: u32) -> Result<u32> {
+        if provide & CRYPTO_RC4 != 0 {
+            Ok(CRYPTO_RC4)
+        } else if provide & CRYPTO_PLAINTEXT != 0 && self != Policy::Require {
+            Ok(CRYPTO_PLAINTEXT)
+        } else {
+            Err(Error::UnsupportedCrypto(provide))
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Peer sent an invalid public key")]
+    InvalidKey,
+    #[error("Couldn't find where peer's handshake continues")]
+    SyncFailed,
+    #[error("Verification constant mismatch")]
+    VcMismatch,
+    #[error("No common crypto method, peer has offered {0:#x}")]
+    UnsupportedCrypto(u32),
+    #[error("Padding longer than 512 bytes")]
+    PaddingTooLong,
+    #[error("Peer asked for a torrent we don't serve")]
+    UnknownTorrent,
+    #[error("Plaintext connections aren't allowed")]
+    PlaintextRefused,
+    #[error("Encrypted connections aren't allowed")]
+    EncryptionRefused,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
@@ -0,0 +1,80 @@
+use std::fmt::Debug;
+
+/// Keystream bytes MSE throws away, before anything gets encrypted
+const DISCARD: usize = 1024;
+
+/// # [`Rc4`]
+/// RC4 keystream, xor'ed over whatever passes through it
+#[derive(Clone)]
+pub(crate) struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, byte) in state.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Keyed the way MSE does it, with first 1024 bytes of keystream discarded
+    pub fn mse(key: &[u8]) -> Self {
+        let mut rc4 = Self::new(key);
+        rc4.apply(&mut [0u8; DISCARD]);
+        rc4
+    }
+
+    /// Encrypts (or decrypts, same thing) data in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+impl Debug for Rc4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Rc4 [...]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rc4;
+
+    fn encrypt(key: &[u8], plaintext: &[u8]) -> String {
+        let mut data = plaintext.to_vec();
+        Rc4::new(key).apply(&mut data);
+        hex::encode(data)
+    }
+
+    #[test]
+    fn known_vectors() {
+        assert_eq!(encrypt(b"Key", b"Plaintext"), "bbf316e8d940af0ad3");
+        assert_eq!(encrypt(b"Wiki", b"pedia"), "1021bf0420");
+        assert_eq!(encrypt(b"Secret", b"Attack at dawn"), "45a01f645fc35b383552544b9bf5");
+    }
+
+    #[test]
+    fn decrypting_with_same_key_restores_data() {
+        let mut data = *b"Some block of a piece";
+        Rc4::mse(b"key").apply(&mut data);
+        assert_ne!(&data, b"Some block of a piece");
+
+        Rc4::mse(b"key").apply(&mut data);
+        assert_eq!(&data, b"Some block of a piece");
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::peer::encryption::rc4::Rc4;
+
+/// # [`EncryptedStream`]
+/// Runs RC4 over everything passing through the stream, if encryption was agreed upon.
+/// Plaintext connections go through it untouched, so every connection looks the same to [`crate::peer::Connection`].
+#[derive(Debug)]
+pub struct EncryptedStream<S> {
+    inner: S,
+    /// Decrypted bytes, read off the stream during the handshake, served before anything else
+    prefix: BytesMut,
+    ciphers: Option<Ciphers>,
+    /// Encrypted bytes inner stream hasn't taken yet
+    pending: BytesMut,
+}
+
+#[derive(Debug)]
+pub(crate) struct Ciphers {
+    pub read: Rc4,
+    pub write: Rc4,
+}
+
+impl<S> EncryptedStream<S> {
+    pub fn plaintext(inner: S) -> Self {
+        Self::new(inner, BytesMut::new(), None)
+    }
+
+    pub(crate) fn new(inner: S, prefix: BytesMut, ciphers: Option<Ciphers>) -> Self {
+        Self {
+            inner,
+            prefix,
+            ciphers,
+            pending: BytesMut::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> EncryptedStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EncryptedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let length = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix.split_to(length));
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(ciphers) = this.ciphers.as_mut() {
+            ciphers.read.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // Keystream has already moved past the pending bytes, they have to go out first
+        ready!(this.poll_pending(cx))?;
+        this.pending.extend_from_slice(buf);
+        if let Some(ciphers) = this.ciphers.as_mut() {
+            ciphers.write.apply(&mut this.pending);
+        }
+        // Bytes are taken either way, whatever inner stream doesn't take now goes out on next write or flush
+        if let Poll::Ready(Err(err)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn ciphers(read: &[u8], write: &[u8]) -> Option<Ciphers> {
+        Some(Ciphers {
+            read: Rc4::mse(read),
+            write: Rc4::mse(write),
+        })
+    }
+
+    #[tokio::test]
+    async fn both_ends_see_plaintext_while_wire_carries_ciphertext() {
+        let (a, b) = duplex(16);
+        let mut a = EncryptedStream::new(a, BytesMut::new(), ciphers(b"b", b"a"));
+        let mut b = EncryptedStream::new(b, BytesMut::from(&b"early "[..]), ciphers(b"a", b"b"));
+
+        // Longer than the duplex buffer, so writes have to wait on reads
+        let message = [7u8; 100];
+        let ((), read) = tokio::join!(
+            async {
+                a.write_all(&message).await.unwrap();
+                a.flush().await.unwrap();
+            },
+            async {
+                let mut buffer = [0u8; 106];
+                b.read_exact(&mut buffer).await.unwrap();
+                buffer
+            }
+        );
+        assert_eq!(&read[..6], b"early ");
+        assert_eq!(&read[6..], &message);
+    }
+
+    #[tokio::test]
+    async fn plaintext_streams_pass_bytes_through() {
+        let (a, mut b) = duplex(64);
+        let mut a = EncryptedStream::plaintext(a);
+
+        a.write_all(b"hello").await.unwrap();
+        let mut buffer = [0u8; 5];
+        b.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+        assert!(!a.is_encrypted());
+    }
+}
@@ -0,0 +1,106 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::peer::{
+    extension::{UT_PEX, UT_PEX_ID},
+    session::upload::MAX_QUEUED_UPLOADS,
+};
+
+/// Bencoded dictionary sent as the very first extended message.
+/// Only the keys we care about are kept, everything else is ignored.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Handshake {
+    /// Maps extension names to message ids
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// Local TCP listen port
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::bencode::optional")]
+    pub p: Option<u16>,
+
+    /// Client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::bencode::optional")]
+    pub v: Option<ByteBuf>,
+
+    /// Number of outstanding requests the peer is willing to queue
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::bencode::optional")]
+    pub reqq: Option<u32>,
+
+    /// Our own address, as the peer sees it, 4 or 16 bytes
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::bencode::optional")]
+    pub yourip: Option<ByteBuf>,
+}
+
+impl Handshake {
+    /// Our own handshake, `ut_pex` is left out for private torrents.
+    /// Our `reqq` is as deep as upload queue goes.
+    pub fn local(private: bool) -> Self {
+        let mut m = BTreeMap::new();
+        if !private {
+            m.insert(UT_PEX.to_string(), UT_PEX_ID);
+        }
+        Self {
+            m,
+            v: Some(ByteBuf::from(format!("qbit {}", env!("CARGO_PKG_VERSION")))),
+            reqq: Some(MAX_QUEUED_UPLOADS as u32),
+            ..Default::default()
+        }
+    }
+
+    /// [`Handshake::yourip`], unless it's neither IPv4 nor IPv6
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let bytes = self.yourip.as_deref()?;
+        match bytes.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes.as_slice()).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes.as_slice()).ok()?).to_canonical()),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        bendy::serde::to_bytes(self)
+            .expect("Extension handshake is always serializable")
+            .into()
+    }
+}
+
+impl TryFrom<&[u8]> for Handshake {
+    type Error = bendy::serde::Error;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        bendy::serde::from_bytes(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_a_handshake_with_unknown_keys() {
+        let bytes = b"d1:md11:ut_metadatai2e6:ut_pexi1ee1:pi6881e1:v13:\xc2\xb5Torrent 1.26:yourip4:\x7f\x00\x00\x01e";
+        let handshake = Handshake::try_from(bytes.as_ref()).unwrap();
+
+        assert_eq!(handshake.m.get("ut_pex"), Some(&1));
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&2));
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.reqq, None);
+        assert_eq!(handshake.your_ip(), Some(IpAddr::from([127, 0, 0, 1])));
+    }
+
+    #[test]
+    fn private_torrents_do_not_advertise_pex() {
+        assert!(Handshake::local(false).m.contains_key(UT_PEX));
+        assert!(!Handshake::local(true).m.contains_key(UT_PEX));
+    }
+
+    #[test]
+    fn local_handshake_roundtrips() {
+        let bytes = Handshake::local(false).to_bytes();
+        let parsed = Handshake::try_from(bytes.as_ref()).unwrap();
+
+        assert_eq!(parsed.m.get(UT_PEX), Some(&UT_PEX_ID));
+    }
+}
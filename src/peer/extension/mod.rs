@@ -0,0 +1,64 @@
+//! # Extension Protocol
+//! https://www.bittorrent.org/beps/bep_0010.html
+//!
+//! Extension messages ride on top of [`Message::Extended`](crate::peer::Message::Extended),
+//! the first one exchanged is always the extension [`Handshake`], which tells the other side
+//! what extensions we understand and which message id each of them is expected on.
+mod handshake;
+pub mod pex;
+
+use std::collections::BTreeMap;
+
+pub use handshake::Handshake;
+
+/// Extended message id of the extension handshake, fixed by the spec
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Message id peers should use when sending us `ut_pex`
+pub const UT_PEX_ID: u8 = 1;
+pub const UT_PEX: &str = "ut_pex";
+
+/// Keeps track of extensions the remote peer has told us about in its handshake
+#[derive(Default, Debug)]
+pub struct Extensions {
+    remote: BTreeMap<String, u8>,
+}
+
+impl Extensions {
+    /// Remembers the message ids the remote peer has picked for each of its extensions.
+    /// An id of `0` means that extension got disabled.
+    pub fn update(&mut self, handshake: &Handshake) {
+        for (name, id) in handshake.m.iter() {
+            if *id == 0 {
+                self.remote.remove(name);
+            } else {
+                self.remote.insert(name.clone(), *id);
+            }
+        }
+    }
+
+    /// Returns the message id the remote peer expects for given extension, if it supports it at all
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.get(name).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_follow_remote_handshakes() {
+        let mut extensions = Extensions::default();
+        let mut handshake = Handshake::default();
+        handshake.m.insert(UT_PEX.to_string(), 3);
+        extensions.update(&handshake);
+
+        assert_eq!(extensions.remote_id(UT_PEX), Some(3));
+        assert_eq!(extensions.remote_id("ut_metadata"), None);
+
+        handshake.m.insert(UT_PEX.to_string(), 0);
+        extensions.update(&handshake);
+        assert_eq!(extensions.remote_id(UT_PEX), None);
+    }
+}
@@ -0,0 +1,289 @@
+//! # Peer Exchange
+//! https://www.bittorrent.org/beps/bep_0011.html
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    ops::{BitOr, BitOrAssign},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::time::Instant;
+
+use crate::peer::{Peer, compact};
+
+/// Peers must not send more than one `ut_pex` message a minute
+pub const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Upper limit of peers in each of `added` and `dropped` lists
+pub const MAX_PEERS: usize = 50;
+
+/// Per peer flags sent along `added` peers
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const ENCRYPTION: Flags = Flags(0x01);
+    pub const SEED: Flags = Flags(0x02);
+    pub const UTP: Flags = Flags(0x04);
+    pub const HOLEPUNCH: Flags = Flags(0x08);
+    pub const REACHABLE: Flags = Flags(0x10);
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl From<u8> for Flags {
+    fn from(value: u8) -> Self {
+        Self(value)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Flags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Flags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Payload of a `ut_pex` message, IPv6 peers go in their own `*6` keys
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Pex {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default, rename = "added.f")]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+impl Pex {
+    pub fn new(added: &[(Peer, Flags)], dropped: &[Peer]) -> Self {
+        let added_v4: Vec<(Peer, Flags)> = added.iter().filter(|(peer, _)| peer.addr.is_ipv4()).copied().collect();
+        let added_v6: Vec<(Peer, Flags)> = added.iter().filter(|(peer, _)| peer.addr.is_ipv6()).copied().collect();
+        let flags = |peers: &[(Peer, Flags)]| -> ByteBuf {
+            ByteBuf::from(peers.iter().map(|(_, flags)| flags.bits()).collect::<Vec<_>>())
+        };
+
+        Self {
+            added: ByteBuf::from(compact::encode_v4(added_v4.iter().map(|(peer, _)| peer)).to_vec()),
+            added_flags: flags(&added_v4),
+            dropped: ByteBuf::from(compact::encode_v4(dropped).to_vec()),
+            added6: ByteBuf::from(compact::encode_v6(added_v6.iter().map(|(peer, _)| peer)).to_vec()),
+            added6_flags: flags(&added_v6),
+            dropped6: ByteBuf::from(compact::encode_v6(dropped).to_vec()),
+        }
+    }
+
+    /// Peers that joined the swarm since the last message, along with their flags.
+    /// Missing flags are treated as empty.
+    pub fn added(&self) -> Vec<(Peer, Flags)> {
+        let with_flags = |peers: Vec<Peer>, flags: &ByteBuf| {
+            peers
+                .into_iter()
+                .enumerate()
+                .map(|(i, peer)| (peer, flags.get(i).copied().unwrap_or_default().into()))
+                .collect::<Vec<_>>()
+        };
+        let mut added = with_flags(compact::decode_v4(&self.added), &self.added_flags);
+        added.extend(with_flags(compact::decode_v6(&self.added6), &self.added6_flags));
+        added
+    }
+
+    /// Peers that left the swarm since the last message
+    pub fn dropped(&self) -> Vec<Peer> {
+        let mut dropped = compact::decode_v4(&self.dropped);
+        dropped.extend(compact::decode_v6(&self.dropped6));
+        dropped
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.dropped.is_empty()
+            && self.added6.is_empty()
+            && self.dropped6.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        bendy::serde::to_bytes(self)
+            .expect("Pex message is always serializable")
+            .into()
+    }
+}
+
+impl TryFrom<&[u8]> for Pex {
+    type Error = bendy::serde::Error;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        bendy::serde::from_bytes(value)
+    }
+}
+
+/// Outgoing half of peer exchange with a single peer.
+/// Remembers what has already been advertised, so only the difference gets sent next time.
+#[derive(Debug)]
+pub struct Exchange {
+    remote_id: u8,
+    last_sent: Option<Instant>,
+    advertised: HashSet<SocketAddr>,
+}
+
+impl Exchange {
+    pub fn new(remote_id: u8) -> Self {
+        Self {
+            remote_id,
+            last_sent: None,
+            advertised: HashSet::new(),
+        }
+    }
+
+    /// Message id the remote peer expects `ut_pex` on
+    pub fn remote_id(&self) -> u8 {
+        self.remote_id
+    }
+
+    /// Diffs currently connected peers against the ones advertised before.
+    ///
+    /// Returns `None` when a message was already sent within [`INTERVAL`], or there's nothing new to tell.
+    pub fn next_message(&mut self, connected: &[(Peer, Flags)], now: Instant) -> Option<Pex> {
+        if let Some(last_sent) = self.last_sent
+            && now.duration_since(last_sent) < INTERVAL
+        {
+            return None;
+        }
+
+        let current: HashSet<SocketAddr> = connected.iter().map(|(peer, _)| peer.addr).collect();
+
+        let added: Vec<(Peer, Flags)> = connected
+            .iter()
+            .filter(|(peer, _)| !self.advertised.contains(&peer.addr))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .iter()
+            .filter(|addr| !current.contains(addr))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        for addr in dropped.iter() {
+            self.advertised.remove(addr);
+        }
+        self.advertised.extend(added.iter().map(|(peer, _)| peer.addr));
+        self.last_sent = Some(now);
+
+        let dropped: Vec<Peer> = dropped.into_iter().map(Peer::from).collect();
+        Some(Pex::new(&added, &dropped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn peer(last: u8) -> Peer {
+        Peer::new(Ipv4Addr::new(10, 0, 0, last), 6881)
+    }
+
+    #[test]
+    fn pex_message_roundtrips() {
+        let added = [(peer(1), Flags::SEED | Flags::REACHABLE), (peer(2), Flags::default())];
+        let dropped = [peer(3)];
+        let bytes = Pex::new(&added, &dropped).to_bytes();
+
+        let parsed = Pex::try_from(bytes.as_ref()).unwrap();
+        assert_eq!(parsed.added(), added);
+        assert_eq!(parsed.dropped(), dropped);
+    }
+
+    #[test]
+    fn ipv6_peers_roundtrip_through_their_own_keys() {
+        let v6 = Peer::new("2001:db8::7".parse::<std::net::Ipv6Addr>().unwrap(), 6881);
+        let added = [(peer(1), Flags::default()), (v6, Flags::UTP)];
+        let pex = Pex::new(&added, &[v6]);
+        assert_eq!(pex.added6.len(), compact::V6_LEN);
+
+        let parsed = Pex::try_from(pex.to_bytes().as_ref()).unwrap();
+        assert_eq!(parsed.added(), added);
+        assert_eq!(parsed.dropped(), vec![v6]);
+    }
+
+    #[test]
+    fn missing_keys_and_flags_are_tolerated() {
+        let parsed = Pex::try_from(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e".as_ref()).unwrap();
+
+        assert_eq!(parsed.added(), vec![(peer(1), Flags::default())]);
+        assert!(parsed.dropped().is_empty());
+    }
+
+    #[test]
+    fn exchange_is_rate_limited() {
+        let mut exchange = Exchange::new(1);
+        let now = Instant::now();
+
+        assert!(exchange.next_message(&[(peer(1), Flags::default())], now).is_some());
+        assert!(exchange
+            .next_message(&[(peer(2), Flags::default())], now + Duration::from_secs(30))
+            .is_none());
+        assert!(exchange
+            .next_message(&[(peer(2), Flags::default())], now + INTERVAL)
+            .is_some());
+    }
+
+    #[test]
+    fn exchange_only_sends_differences() {
+        let mut exchange = Exchange::new(1);
+        let now = Instant::now();
+        exchange.next_message(&[(peer(1), Flags::default()), (peer(2), Flags::default())], now);
+
+        let pex = exchange
+            .next_message(&[(peer(2), Flags::default()), (peer(3), Flags::SEED)], now + INTERVAL)
+            .unwrap();
+        assert_eq!(pex.added(), vec![(peer(3), Flags::SEED)]);
+        assert_eq!(pex.dropped(), vec![peer(1)]);
+
+        let later = now + INTERVAL * 2;
+        assert!(exchange
+            .next_message(&[(peer(2), Flags::default()), (peer(3), Flags::SEED)], later)
+            .is_none());
+    }
+
+    #[test]
+    fn exchange_caps_peers_per_message() {
+        let mut exchange = Exchange::new(1);
+        let connected: Vec<(Peer, Flags)> = (0..80).map(|i| (peer(i), Flags::default())).collect();
+        let now = Instant::now();
+
+        let first = exchange.next_message(&connected, now).unwrap();
+        assert_eq!(first.added().len(), MAX_PEERS);
+
+        let second = exchange.next_message(&connected, now + INTERVAL).unwrap();
+        assert_eq!(second.added().len(), 80 - MAX_PEERS);
+    }
+}
@@ -1,5 +1,11 @@
-use crate::{peer::{self}, torrent::{self, InfoHash}};
+use std::io;
 
+use crate::{peer::{self, id::Id}, torrent::{self, InfoHash}};
+
+/// Reserved bit (20th from the right) announcing support for BEP 10 extension protocol
+pub(crate) const EXTENSION_PROTOCOL: u8 = 0x10;
+
+pub(crate) const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
 
 pub struct Handshake([u8;68]);
 
@@ -9,7 +15,8 @@ impl Handshake {
     fn raw() -> [u8; 68] {
         let mut buffer = [0u8; 68];
         buffer[0] = 19;
-        buffer[1..20].copy_from_slice(b"BitTorrent protocol");
+        buffer[1..20].copy_from_slice(PROTOCOL);
+        buffer[25] |= EXTENSION_PROTOCOL;
         buffer[48..].copy_from_slice(&peer::ID);
         
         buffer
@@ -26,6 +33,12 @@ impl Handshake {
     pub fn bytes(&self) -> &[u8] {
         &self.0
     }
+
+    /// Info hash this handshake is made for
+    pub fn info_hash(&self) -> InfoHash {
+        let hash: [u8; 20] = self.0[28..48].try_into().expect("Info hash is 20 bytes long");
+        hash.into()
+    }
 }
 
 impl From<torrent::Metadata> for Handshake {
@@ -60,3 +73,127 @@ impl AsMut<[u8;68]> for Handshake {
     }
 }
 
+/// The 8 reserved bytes of a handshake, each set bit announces support for some extension
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Reserved([u8; 8]);
+
+impl Reserved {
+    /// BEP 10, extension protocol
+    pub fn supports_extensions(&self) -> bool {
+        self.0[5] & EXTENSION_PROTOCOL != 0
+    }
+}
+
+impl AsRef<[u8]> for Reserved {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// # [`Remote`]
+/// Handshake as received from other end of the connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Remote {
+    pub protocol: [u8; 19],
+    pub reserved: Reserved,
+    pub info_hash: InfoHash,
+    pub peer_id: Id,
+}
+
+impl Remote {
+    /// Makes sure remote is talking about the very torrent we asked for, and it isn't us on the other end
+    pub fn validate(&self, info_hash: &InfoHash) -> Result<()> {
+        if self.info_hash.as_ref() != info_hash.as_ref() {
+            return Err(Error::InfoHashMismatch);
+        }
+        if self.peer_id == *peer::ID {
+            return Err(Error::SelfConnection);
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&[u8; 68]> for Remote {
+    type Error = Error;
+    /// Parses a handshake, only the `BitTorrent protocol` is accepted
+    fn try_from(bytes: &[u8; 68]) -> Result<Self> {
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(Error::InvalidProtocol);
+        }
+        let protocol: [u8; 19] = bytes[1..20].try_into().expect("Slice is 19 bytes long");
+        let reserved: [u8; 8] = bytes[20..28].try_into().expect("Slice is 8 bytes long");
+        let info_hash: [u8; 20] = bytes[28..48].try_into().expect("Slice is 20 bytes long");
+        let peer_id: [u8; 20] = bytes[48..68].try_into().expect("Slice is 20 bytes long");
+
+        Ok(Self {
+            protocol,
+            reserved: Reserved(reserved),
+            info_hash: info_hash.into(),
+            peer_id: peer_id.into(),
+        })
+    }
+}
+
+pub type Result<T> = std::result::Result<T, self::Error>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Peer doesn't speak BitTorrent protocol")]
+    InvalidProtocol,
+    #[error("Peer responded with a different info hash")]
+    InfoHashMismatch,
+    #[error("Connected to ourselves")]
+    SelfConnection,
+    #[error("Already connected to this peer")]
+    DuplicatePeer,
+    #[error("Peer asked for a torrent we aren't serving")]
+    UnknownTorrent,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote_bytes(info_hash: [u8; 20], peer_id: &[u8]) -> [u8; 68] {
+        let mut bytes = *Handshake::new(&info_hash.into()).as_ref();
+        bytes[48..].copy_from_slice(peer_id);
+        bytes
+    }
+
+    #[test]
+    fn parsing_a_valid_handshake() {
+        let bytes = remote_bytes([7; 20], &[1; 20]);
+        let remote = Remote::try_from(&bytes).unwrap();
+
+        assert_eq!(&remote.protocol, PROTOCOL);
+        assert!(remote.reserved.supports_extensions());
+        assert_eq!(remote.info_hash.as_ref(), &[7; 20]);
+        assert_eq!(remote.peer_id, Id::from([1; 20]));
+        assert!(remote.validate(&[7; 20].into()).is_ok());
+    }
+
+    #[test]
+    fn rejecting_unknown_protocols() {
+        let mut bytes = remote_bytes([7; 20], &[1; 20]);
+        bytes[1..20].copy_from_slice(b"BitTorrent protocoL");
+        assert!(matches!(Remote::try_from(&bytes), Err(Error::InvalidProtocol)));
+
+        let mut bytes = remote_bytes([7; 20], &[1; 20]);
+        bytes[0] = 18;
+        assert!(matches!(Remote::try_from(&bytes), Err(Error::InvalidProtocol)));
+    }
+
+    #[test]
+    fn rejecting_other_torrents() {
+        let remote = Remote::try_from(&remote_bytes([7; 20], &[1; 20])).unwrap();
+        assert!(matches!(remote.validate(&[8; 20].into()), Err(Error::InfoHashMismatch)));
+    }
+
+    #[test]
+    fn detecting_self_connections() {
+        let remote = Remote::try_from(&remote_bytes([7; 20], &peer::ID)).unwrap();
+        assert!(matches!(remote.validate(&[7; 20].into()), Err(Error::SelfConnection)));
+    }
+}
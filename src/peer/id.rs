@@ -11,8 +11,7 @@ pub static PEER_ID : LazyLock<Id> = LazyLock::new(|| {
     Id::new()
 });
 
-#[derive(Deserialize, Copy, Clone, Debug)]
-#[cfg_attr(test, derive(PartialEq))]
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Id([u8;20]);
 
 impl Id {
@@ -33,6 +32,12 @@ impl Id {
     }
 }
 
+impl From<[u8; 20]> for Id {
+    fn from(value: [u8; 20]) -> Self {
+        Self(value)
+    }
+}
+
 impl Deref for Id {
     type Target = [u8];
     fn deref(&self) -> &Self::Target {
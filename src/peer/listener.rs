@@ -0,0 +1,308 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use tokio::{io, net::TcpListener, time::timeout};
+
+use crate::{
+    config::Config,
+    peer::{
+        session,
+        transport::{Connector, Transport, UtpSocket, UtpStream},
+        Connection, Handshake, HandshakeError, SessionError as Error,
+    },
+    torrent::{ConnectionLimits, Registry},
+};
+
+/// Time a peer gets to send its handshake, after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// # [`Listener`]
+/// Accepts incoming peers on a single port, and hands each of them over to the torrent
+/// it has asked for in its handshake.
+pub struct Listener {
+    listener: TcpListener,
+    /// Takes uTP peers on the same port, unless disabled or the port is taken
+    utp: Option<Arc<UtpSocket>>,
+    registry: Registry,
+    limits: ConnectionLimits,
+    config: Config,
+}
+
+impl Listener {
+    /// Binds to configured port, on every interface.
+    /// A single dual stack socket takes both IPv4 and IPv6 peers, falling back to IPv4 only
+    /// when host has no IPv6 at all.
+    pub async fn bind(config: &Config, registry: Registry) -> io::Result<Self> {
+        let listener = match bind_dual_stack(config.listen_port) {
+            Ok(listener) => TcpListener::from_std(listener)?,
+            Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, config.listen_port)).await?,
+        };
+        let utp = match config.enable_utp {
+            true => match UtpSocket::bind(listener.local_addr()?.port()).await {
+                Ok(socket) => Some(Arc::new(socket)),
+                Err(err) => {
+                    eprintln!("\x1b[33mLISTENER | Not taking uTP peers : {err}\x1b[0m");
+                    None
+                }
+            },
+            false => None,
+        };
+        Ok(Self {
+            listener,
+            utp,
+            registry,
+            limits: ConnectionLimits::new(config),
+            config: config.clone(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Connection limits incoming peers count against, outgoing ones should too
+    pub fn limits(&self) -> ConnectionLimits {
+        self.limits.clone()
+    }
+
+    /// Connector for outgoing peers, sharing our uTP socket (if any)
+    pub fn connector(&self) -> Connector {
+        let connector = Connector::new(&self.config);
+        match &self.utp {
+            Some(socket) => connector.with_utp(socket.clone()),
+            None => connector,
+        }
+    }
+
+    /// Accepts peers forever (over both TCP and uTP), each one is served on its own task.
+    /// Peers beyond the connection limit are dropped right away.
+    pub async fn run(self) {
+        loop {
+            let accepted: io::Result<Box<dyn Transport>> = tokio::select! {
+                accepted = self.listener.accept() => accepted.map(|(stream, _)| Box::new(stream) as _),
+                accepted = accept_utp(self.utp.as_deref()) => accepted.map(|stream| Box::new(stream) as _),
+            };
+            let (stream, addr) = match accepted.and_then(|stream| Ok((stream.peer_addr()?, stream))) {
+                Ok((addr, stream)) => (stream, addr),
+                Err(err) => {
+                    eprintln!("\x1b[31mLISTENER | Accept failed : {err}\x1b[0m");
+                    continue;
+                }
+            };
+            let Some(permit) = self.limits.try_connection() else {
+                eprintln!("LISTENER | Connection limit reached, dropping {addr}");
+                continue;
+            };
+
+            let registry = self.registry.clone();
+            let config = self.config.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                if let Err(err) = serve(stream, registry, config).await {
+                    eprintln!("\x1b[033mLISTENER | {addr} : {err}\x1b[0m");
+                }
+            });
+        }
+    }
+}
+
+/// Never resolves when we aren't taking uTP peers
+async fn accept_utp(socket: Option<&UtpSocket>) -> io::Result<UtpStream> {
+    match socket {
+        Some(socket) => socket.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn bind_dual_stack(port: u16) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Sets up encryption (if peer asks for it), reads peer's handshake,
+/// routes it to the torrent it's asking for, then runs a session for it
+async fn serve(stream: Box<dyn Transport>, registry: Registry, config: Config) -> session::Result<()> {
+    let info_hashes = registry.info_hashes().await;
+    let (mut connection, remote) = timeout(HANDSHAKE_TIMEOUT, async {
+        let mut connection = Connection::from_incoming(stream, &info_hashes, config.encryption).await?;
+        let remote = connection.receive_handshake().await?;
+        session::Result::Ok((connection, remote))
+    })
+    .await
+    .map_err(|_| Error::TimeOut)??;
+    connection.set_max_message_len(config.max_message_len);
+
+    let Some(torrent) = registry.get(&remote.info_hash).await else {
+        return Err(HandshakeError::UnknownTorrent.into());
+    };
+    // Slot is held from here on, so that peers accepted at once or dialed meanwhile can't overshoot the limit
+    let addr = connection.peer.addr;
+    {
+        let mut pool = torrent.pool.lock().await;
+        // Banned peers come back from whatever port, they're turned away before we answer
+        if pool.is_banned(addr.ip()) {
+            return Err(Error::Banned);
+        }
+        if !pool.reserve(connection.peer, config.max_peers_per_torrent) {
+            return Err(Error::PeerLimitReached);
+        }
+    }
+
+    let result = async {
+        connection
+            .respond_handshake(remote, Handshake::new(&torrent.info_hash))
+            .await?;
+        let mut session = torrent.session(connection);
+        session.set_pipeline_limits(config.pipeline);
+        session.run().await
+    }
+    .await;
+    torrent.pool.lock().await.release(&addr);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::torrent::{pool::DEFAULT_BAN_THRESHOLD, Handle};
+
+    async fn listen(config: Config) -> SocketAddr {
+        listen_for(Handle::fake(), config).await
+    }
+
+    async fn listen_for(torrent: Handle, config: Config) -> SocketAddr {
+        let registry = Registry::new();
+        registry.register(torrent).await;
+
+        let listener = Listener::bind(&config, registry).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(listener.run());
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    fn config() -> Config {
+        Config {
+            listen_port: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Connects as some other peer, returns the stream along with the handshake we've got back (if any)
+    async fn handshake(addr: SocketAddr, info_hash: [u8; 20]) -> (TcpStream, Option<[u8; 68]>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut request = *Handshake::new(&info_hash.into()).as_ref();
+        request[48..].copy_from_slice(&[1; 20]);
+        stream.write_all(&request).await.unwrap();
+
+        let mut response = [0u8; 68];
+        let response = match stream.read_exact(&mut response).await {
+            Ok(_) => Some(response),
+            Err(_) => None,
+        };
+        (stream, response)
+    }
+
+    #[tokio::test]
+    async fn incoming_peers_are_routed_by_info_hash() {
+        let addr = listen(config()).await;
+
+        let (_stream, response) = handshake(addr, [0; 20]).await;
+        let response = response.expect("Listener should answer known torrents");
+        assert_eq!(&response[28..48], &[0; 20]);
+        assert_eq!(&response[48..], crate::peer::ID.as_ref());
+
+        let (_, response) = handshake(addr, [1; 20]).await;
+        assert!(response.is_none());
+    }
+
+    #[tokio::test]
+    async fn listener_takes_ipv6_peers_too() {
+        let addr = listen(config()).await;
+        let addr = SocketAddr::from((Ipv6Addr::LOCALHOST, addr.port()));
+        // Hosts without IPv6 get an IPv4 only listener, nothing to test there
+        if TcpStream::connect(addr).await.is_err() {
+            return;
+        }
+
+        let (_stream, response) = handshake(addr, [0; 20]).await;
+        assert!(response.is_some());
+    }
+
+    #[tokio::test]
+    async fn listener_takes_utp_peers_on_same_port() {
+        let addr = listen(config()).await;
+        let socket = UtpSocket::bind(0).await.unwrap();
+
+        let mut stream = socket.connect(addr).await.unwrap();
+        let mut request = *Handshake::new(&[0; 20].into()).as_ref();
+        request[48..].copy_from_slice(&[1; 20]);
+        stream.write_all(&request).await.unwrap();
+
+        let mut response = [0u8; 68];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response[28..48], &[0; 20]);
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_limit_are_dropped() {
+        let addr = listen(Config {
+            max_connections: 1,
+            ..config()
+        })
+        .await;
+
+        let (_first, response) = handshake(addr, [0; 20]).await;
+        assert!(response.is_some());
+
+        let (_, response) = handshake(addr, [0; 20]).await;
+        assert!(response.is_none());
+    }
+
+    #[tokio::test]
+    async fn torrents_beyond_peer_limit_refuse_peers() {
+        let addr = listen(Config {
+            max_peers_per_torrent: 1,
+            ..config()
+        })
+        .await;
+
+        let (mut first, response) = handshake(addr, [0; 20]).await;
+        assert!(response.is_some());
+        // Session sends bitfield only after it has registered itself in pool
+        let mut length = [0u8; 4];
+        first.read_exact(&mut length).await.unwrap();
+
+        let (_, response) = handshake(addr, [0; 20]).await;
+        assert!(response.is_none());
+    }
+
+    #[tokio::test]
+    async fn banned_peers_are_refused_on_any_port() {
+        let torrent = Handle::fake();
+        {
+            let mut pool = torrent.pool.lock().await;
+            for _ in 0..DEFAULT_BAN_THRESHOLD {
+                pool.record_hash_failure(&SocketAddr::from((Ipv4Addr::LOCALHOST, 51413)));
+            }
+        }
+        let addr = listen_for(torrent, config()).await;
+
+        let (_, response) = handshake(addr, [0; 20]).await;
+        assert!(response.is_none());
+    }
+}
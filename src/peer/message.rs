@@ -4,6 +4,7 @@ use std::{
     io::{self, Error, ErrorKind},
 };
 
+#[cfg_attr(test, derive(PartialEq))]
 pub enum Message {
     KeepAlive,
     Choke,
@@ -22,7 +23,18 @@ pub enum Message {
         offset: u32,
         data: Bytes,
     },
-    // Cancel,
+    Cancel {
+        index: u32,
+        offset: u32,
+        length: u32,
+    },
+    /// Port peer's DHT node listens on (BEP 5)
+    Port(u16),
+    /// BEP 10 extension message, `id` is the extended message id (0 being the extension handshake)
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
     UnexpectedId(u8),
 }
 
@@ -56,6 +68,22 @@ impl Debug for Message {
                 .field("offset", offset)
                 .field("data", &"[...]")
                 .finish(),
+            Message::Cancel {
+                index,
+                offset,
+                length,
+            } => f
+                .debug_struct("Cancel")
+                .field("index", index)
+                .field("offset", offset)
+                .field("length", length)
+                .finish(),
+            Message::Port(port) => f.debug_tuple("Port").field(port).finish(),
+            Message::Extended { id, payload } => f
+                .debug_struct("Extended")
+                .field("id", id)
+                .field("length", &payload.len())
+                .finish(),
             Message::UnexpectedId(i) => f.write_str(&format!("Unexpected Id : {i}")),
         }
     }
@@ -70,9 +98,17 @@ impl Message {
             3 => Ok(Self::NotInterested),
             4 => Self::handle_have(payload),
             5 => Self::handle_bitfield(payload),
-            6 => todo!("`Request`, isn't implemented yet"),
+            6 => {
+                let (index, offset, length) = Self::parse_block(payload)?;
+                Ok(Self::Request { index, offset, length })
+            }
             7 => Self::handle_piece(payload),
-            8 => todo!("`Cancel`, isn't implemented yet"),
+            8 => {
+                let (index, offset, length) = Self::parse_block(payload)?;
+                Ok(Self::Cancel { index, offset, length })
+            }
+            9 => Self::handle_port(payload),
+            20 => Self::handle_extended(payload),
             x => Err(Error::new(ErrorKind::InvalidData, format!("Invalid Id : {x}"))),
         }
     }
@@ -116,6 +152,34 @@ impl Message {
         })
     }
 
+    /// Splits `<index><offset><length>` payload of requests and cancels
+    fn parse_block(payload: Bytes) -> io::Result<(u32, u32, u32)> {
+        if payload.len() != 12 {
+            return Err(Error::new(ErrorKind::InvalidData, "Expected index, offset and length of a block"));
+        }
+        let index = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+        let offset = u32::from_be_bytes(payload[4..8].try_into().unwrap());
+        let length = u32::from_be_bytes(payload[8..12].try_into().unwrap());
+        Ok((index, offset, length))
+    }
+
+    fn handle_port(payload: Bytes) -> io::Result<Self> {
+        if payload.len() != 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "Expected a 2 byte port"));
+        }
+        Ok(Self::Port(u16::from_be_bytes([payload[0], payload[1]])))
+    }
+
+    fn handle_extended(payload: Bytes) -> io::Result<Self> {
+        if payload.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Empty extended message"));
+        }
+        Ok(Self::Extended {
+            id: payload[0],
+            payload: payload.slice(1..),
+        })
+    }
+
     pub fn encode(&self) -> Bytes {
         let mut bytes = BytesMut::with_capacity(self.encode_length());
         match self {
@@ -167,9 +231,31 @@ impl Message {
                 bytes.put_u32(*offset);
                 bytes.put_slice(data);
             }
+            Message::Cancel {
+                index,
+                offset,
+                length,
+            } => {
+                bytes.put_u32(13);
+                bytes.put_u8(8);
+                bytes.put_u32(*index);
+                bytes.put_u32(*offset);
+                bytes.put_u32(*length);
+            }
+            Message::Port(port) => {
+                bytes.put_u32(3);
+                bytes.put_u8(9);
+                bytes.put_u16(*port);
+            }
             Message::KeepAlive => {
                 bytes.put_u32(0);
             }
+            Message::Extended { id, payload } => {
+                bytes.put_u32(2 + payload.len() as u32);
+                bytes.put_u8(20);
+                bytes.put_u8(*id);
+                bytes.put_slice(payload);
+            }
             Message::UnexpectedId(_) => {
                 panic!("No way you'll do that??")
             }
@@ -177,22 +263,21 @@ impl Message {
         bytes.freeze()
     }
 
-    fn encode_length(&self) -> usize {
+    /// Bytes the message takes on the wire, length prefix included
+    pub(crate) fn encode_length(&self) -> usize {
         match self {
             Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => 5,
             Message::Have(_) => 9,
             Message::Bitfield(bitfield) => 5 + bitfield.len(),
-            Message::Request {
-                index: _,
-                offset: _,
-                length: _,
-            } => 17,
+            Message::Request { .. } | Message::Cancel { .. } => 17,
+            Message::Port(_) => 7,
             Message::Piece {
                 index: _,
                 offset: _,
                 data,
             } => 13 + data.len(),
             Message::KeepAlive => 1,
+            Message::Extended { id: _, payload } => 6 + payload.len(),
             Message::UnexpectedId(_) => 0,
         }
     }
@@ -215,7 +300,54 @@ mod test {
 
     #[test]
     fn decoding_invalid_message_id() {
-        assert!(Message::decode(9, Bytes::new()).is_err());
+        assert!(Message::decode(10, Bytes::new()).is_err());
+        assert!(Message::decode(21, Bytes::new()).is_err());
+    }
+
+    fn round_trip(message: &Message) -> Message {
+        let encoded = message.encode();
+        let length = u32::from_be_bytes(encoded[..4].try_into().unwrap()) as usize;
+        assert_eq!(length + 4, encoded.len());
+        if length == 0 {
+            return Message::KeepAlive;
+        }
+        Message::decode(encoded[4], encoded.slice(5..)).unwrap()
+    }
+
+    #[test]
+    fn every_message_survives_a_round_trip() {
+        let messages = [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(42),
+            Message::Bitfield(Bytes::from_static(&[0b1010_0000, 0xff])),
+            Message::Request { index: 1, offset: 16384, length: 16384 },
+            Message::Piece { index: 1, offset: 16384, data: Bytes::from_static(&[9; 16]) },
+            Message::Cancel { index: 1, offset: 16384, length: 16384 },
+            Message::Port(6881),
+            Message::Extended { id: 1, payload: Bytes::from_static(b"de") },
+        ];
+        for message in messages {
+            let decoded = round_trip(&message);
+            assert_eq!(message, decoded);
+        }
+    }
+
+    #[test]
+    fn encoding_cancel_and_port_messages() {
+        let cancel = Message::Cancel { index: 3, offset: 4, length: 16384 };
+        assert_eq!([0, 0, 0, 13, 8, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 64, 0], cancel.encode().as_ref());
+        assert_eq!([0, 0, 0, 3, 9, 0x1a, 0xe1], Message::Port(6881).encode().as_ref());
+    }
+
+    #[test]
+    fn decoding_truncated_block_messages() {
+        assert!(Message::decode(6, Bytes::from_static(&[0; 11])).is_err());
+        assert!(Message::decode(8, Bytes::from_static(&[0; 13])).is_err());
+        assert!(Message::decode(9, Bytes::from_static(&[0])).is_err());
     }
 
     #[test]
@@ -286,4 +418,25 @@ mod test {
 
         assert_eq!(expected_bytes, message.encode().as_ref());
     }
+
+    #[test]
+    fn encoding_and_decoding_extended_message() {
+        let message = Message::Extended {
+            id: 1,
+            payload: Bytes::from_static(b"de"),
+        };
+        let encoded = message.encode();
+        assert_eq!([0, 0, 0, 4, 20, 1, b'd', b'e'], encoded.as_ref());
+
+        let Message::Extended { id, payload } = Message::decode(20, encoded.slice(5..)).unwrap() else {
+            panic!("Expected an extended message");
+        };
+        assert_eq!(id, 1);
+        assert_eq!(payload.as_ref(), b"de");
+    }
+
+    #[test]
+    fn decoding_empty_extended_message() {
+        assert!(Message::decode(20, Bytes::new()).is_err());
+    }
 }
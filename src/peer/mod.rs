@@ -4,18 +4,27 @@ mod connection;
 mod message;
 mod session;
 mod bitfield;
+mod listener;
+pub mod compact;
+pub mod codec;
+pub mod encryption;
+pub mod extension;
+pub mod transport;
 
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
 
 pub use id::PEER_ID as ID;
-use serde::Deserialize;
-pub use handshake::Handshake;
+pub use handshake::{Error as HandshakeError, Handshake, Remote as RemoteHandshake, Reserved};
 pub use connection::Connection as Connection;
 pub use message::*;
 pub use session::Session as PeerSession;
+pub use session::Violation;
 pub(crate) use session::Error as SessionError;
-pub(crate) use session::Piece;
-pub use bitfield::Bitfield;
+pub use session::{BlockRequest, Piece, PieceError, PipelineLimits, Received};
+pub use bitfield::{Bitfield, Error as BitfieldError};
+pub use listener::Listener;
+pub use extension::pex::Flags as PexFlags;
+pub use transport::Connector;
 
 use crate::peer::id::Id;
 
@@ -23,18 +32,32 @@ use crate::peer::id::Id;
 /// https://www.bittorrent.org/beps/bep_0003.html#peer-protocol:~:text=protocol%20as%20well.-,peer%20protocol,-BitTorrent%27s%20peer%20protocol
 
 
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Copy, Clone, Debug)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct Peer {
-    pub ip : Ipv4Addr,
-    pub port : u16,
-
-    #[serde(default)]
+    pub addr : SocketAddr,
     pub id : Option<Id>,
 }
 
 impl Peer {
+    /// IPv4-mapped IPv6 addresses (as seen on dual stack sockets) are turned back into IPv4 ones
+    pub fn new<T>(ip: T, port: u16) -> Self
+    where
+        T: Into<IpAddr>,
+    {
+        Self {
+            addr: SocketAddr::new(ip.into().to_canonical(), port),
+            id: None,
+        }
+    }
+
     pub async fn connect(&self) -> Result<Connection, std::io::Error> {
         Connection::connect(*self).await
     }
-}
\ No newline at end of file
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.ip(), addr.port())
+    }
+}
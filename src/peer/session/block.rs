@@ -0,0 +1,27 @@
+use crate::peer::Message;
+
+/// A single block of a piece, as it goes in requests and cancels (both ways)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    pub fn request(self) -> Message {
+        Message::Request {
+            index: self.index,
+            offset: self.offset,
+            length: self.length,
+        }
+    }
+
+    pub fn cancel(self) -> Message {
+        Message::Cancel {
+            index: self.index,
+            offset: self.offset,
+            length: self.length,
+        }
+    }
+}
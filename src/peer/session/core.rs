@@ -1,50 +1,102 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use tokio::sync::{Mutex, broadcast, mpsc};
+use tokio::{
+    sync::{Mutex, broadcast},
+    time::Instant,
+};
 
 use crate::{
-    peer::{session, Connection, Message, Piece},
-    torrent::{self, commit, CommitEvent, CommitJob},
+    peer::{
+        extension::{pex, Extensions},
+        session::{
+            self, pipeline::Pipeline, rate::Rate, timeout::REQUEST_TIMEOUT, upload::UploadQueue, BlockRequest,
+            PipelineLimits,
+        },
+        Bitfield, Connection, Message,
+    },
+    torrent::{
+        self, commit, picker::Cancel, AtomicChoker, AtomicPeerPool, AtomicPiecePicker, CommitEvent,
+        FileLayout, Handle, PeerStats, TorrentStats,
+    },
 };
 
+/// Peers that can get a whole piece done within this long take pieces to themselves, going by their recent rate
+pub(crate) const WHOLE_PIECE_TIME: Duration = Duration::from_secs(10);
+
 pub struct Session {
-    pub(crate) commit_tx: mpsc::Sender<CommitJob>,
     pub(crate) commit_rx: broadcast::Receiver<commit::Event>,
     pub(crate) connection: Connection,
     pub(crate) torrent_info: Arc<torrent::Info>,
-    pub(crate) current_piece: Option<Piece>,
+    /// Blocks we've asked this peer for, along with when we did
+    pub(crate) requests: Vec<(BlockRequest, Instant)>,
+    /// Blocks we've cancelled lately, peer may have sent them before it got the cancel
+    pub(crate) cancelled: Vec<(BlockRequest, Instant)>,
+    pub(crate) download_rate: Rate,
+    pub(crate) pipeline: Pipeline,
     pub(crate) state: Arc<Mutex<torrent::State>>,
     pub(crate) is_choking: bool,
     pub(crate) is_interested: bool,
     pub(crate) am_choking: bool,
     pub(crate) am_interested: bool,
-    pub(crate) bit_field: Vec<u8>,
+    pub(crate) bit_field: Bitfield,
+    pub(crate) pool: AtomicPeerPool,
+    pub(crate) picker: AtomicPiecePicker,
+    pub(crate) choker: AtomicChoker,
+    /// Shared with choker, which ranks peers by it
+    pub(crate) stats: Arc<PeerStats>,
+    /// Torrent's, counting along with `stats`
+    pub(crate) totals: Arc<TorrentStats>,
+    /// Where blocks peer asks for are read from
+    pub(crate) layout: Arc<FileLayout>,
+    /// Torrent as a whole, for what sessions share with web seeds
+    pub(crate) torrent: Handle,
+    pub(crate) extensions: Extensions,
+    pub(crate) pex: Option<pex::Exchange>,
+    pub(crate) uploads: UploadQueue,
+    /// When the last block arrived, or when we started waiting on one
+    pub(crate) last_block: Instant,
+    /// Peer sat on our requests for too long, no pieces are reserved for it until it unchokes us again
+    pub(crate) snubbed: bool,
 }
 
 impl Session {
-    pub fn new(
-        connection: Connection,
-        torrent_info: Arc<torrent::Info>,
-        state: Arc<Mutex<torrent::State>>,
-        commit_tx: mpsc::Sender<CommitJob>,
-        commit_rx: broadcast::Receiver<commit::Event>,
-    ) -> Self {
-        let num_pieces = ((torrent_info.pieces.len() / 20) + 7)/8;
-        let bit_field = vec![0u8; num_pieces];
+    /// Session taking part in torrent of given handle, see [`Handle::session`]
+    pub fn new(mut connection: Connection, torrent: &Handle) -> Self {
+        connection.set_throttle(torrent.bandwidth.throttle(connection.peer.addr));
+        let bit_field = Bitfield::new(torrent.info.pieces.len() / 20);
         Self {
-            commit_tx,
-            commit_rx,
+            commit_rx: torrent.commit_events.subscribe(),
             connection,
-            torrent_info,
-            state,
-            current_piece: None,
+            torrent_info: torrent.info.clone(),
+            state: torrent.state.clone(),
+            requests: Vec::new(),
+            cancelled: Vec::new(),
+            download_rate: Rate::default(),
+            pipeline: Pipeline::default(),
             is_choking: true,
             is_interested: false,
             am_choking: true,
             am_interested: false,
             bit_field,
+            pool: torrent.pool.clone(),
+            picker: torrent.picker.clone(),
+            choker: torrent.choker.clone(),
+            stats: Arc::new(PeerStats::default()),
+            totals: torrent.totals.clone(),
+            layout: torrent.layout.clone(),
+            torrent: torrent.clone(),
+            extensions: Extensions::default(),
+            pex: None,
+            uploads: UploadQueue::default(),
+            last_block: Instant::now(),
+            snubbed: false,
         }
     }
+
+    /// Bounds request pipeline with peer, defaults otherwise, see [`crate::config::Config::pipeline`]
+    pub fn set_pipeline_limits(&mut self, limits: PipelineLimits) {
+        self.pipeline.set_limits(limits);
+    }
 }
 
 
@@ -54,29 +106,99 @@ impl Session {
         event: commit::Event,
     ) -> session::Result<()> {
         match event {
-            CommitEvent::PieceCommit(index) => self.connection.send(Message::Have(index)).await?,
-            CommitEvent::FailedCommit => todo!("Fokin failed?"),
+            CommitEvent::PieceCommit(index) => {
+                self.picker.lock().await.mark_have(index);
+                self.cancel_finished_piece(index).await?;
+                self.connection.send(Message::Have(index)).await?
+            }
+            // Piece is lost, picker offers it again
+            CommitEvent::FailedCommit(index) => self.picker.lock().await.abandon(index),
         }
         Ok(())
     }
 
-    /// Repeatedly places piece block requests in pipeline, upto Piece's on-fly capacity
-    /// So let's say if Piece has capacity of handling 4 blocks on-fly, only 4 blocks will be asked at a time
-    pub(crate) async fn pump_requests(&mut self) -> session::Result<()> {
-        if self.is_choking || !self.am_interested {
+    /// Some other session got the piece committed while we still had blocks of it on fly,
+    /// they're of no use anymore
+    async fn cancel_finished_piece(&mut self, index: u32) -> session::Result<()> {
+        let (finished, requests) = std::mem::take(&mut self.requests)
+            .into_iter()
+            .partition(|(block, _)| block.index == index);
+        self.requests = requests;
+        for (block, _) in finished {
+            self.send_cancel(block).await?;
+        }
+        Ok(())
+    }
+
+    /// Some other peer has sent a block we're waiting on from this one, in endgame
+    pub(crate) async fn handle_cancel(&mut self, (peer, block): Cancel) -> session::Result<()> {
+        if peer != self.connection.peer.addr {
             return Ok(());
         }
+        let Some(position) = self.requests.iter().position(|(request, _)| *request == block) else {
+            return Ok(());
+        };
+        self.requests.swap_remove(position);
+        self.send_cancel(block).await
+    }
+
+    /// Cancels a request that's been taken off our list,
+    /// block is still taken (and counted as wasted) if it's on its way already
+    pub(crate) async fn send_cancel(&mut self, block: BlockRequest) -> session::Result<()> {
+        let now = Instant::now();
+        self.cancelled.retain(|(_, at)| now < *at + REQUEST_TIMEOUT);
+        self.cancelled.push((block, now));
+        self.connection.send(block.cancel()).await
+    }
 
-        let Some(ref mut current_piece) = self.current_piece else {
+    /// Tops up block requests on fly with peer, upto what [`Pipeline::depth`] allows at peer's rate,
+    /// with whatever picker hands out. Snubbing peers get nothing.
+    pub(crate) async fn pump_requests(&mut self) -> session::Result<()> {
+        if self.is_choking || !self.am_interested || self.snubbed {
+            return Ok(());
+        }
+        let rate = self.download_rate.per_second();
+        let depth = self.pipeline.depth(rate);
+        if self.requests.len() >= depth {
             return Ok(());
+        }
+
+        let fast = rate * WHOLE_PIECE_TIME.as_secs() >= self.torrent_info.piece_length as u64;
+        let now = Instant::now();
+        let blocks = {
+            let mut picker = self.picker.lock().await;
+            let mut blocks =
+                picker.pick_blocks(self.connection.peer.addr, &self.bit_field, fast, depth - self.requests.len());
+            // Blocks we've given up on with this peer lately are left to others, peer may still be sitting on them
+            blocks.retain(|block| {
+                let cancelled = self
+                    .cancelled
+                    .iter()
+                    .any(|(cancelled, at)| cancelled == block && now < *at + REQUEST_TIMEOUT);
+                if cancelled {
+                    picker.abort_block(self.connection.peer.addr, *block);
+                }
+                !cancelled
+            });
+            blocks
         };
 
-        while current_piece.can_request_more() {
-            let Some(piece_request) = current_piece.next_block() else {
-                break;
-            };
-            self.connection.send(piece_request).await?;
+        // Snub timer starts with the first block we wait on,
+        // unless we've given up on some since peer last sent one, it's been waiting all along then
+        let gave_up = self.cancelled.iter().any(|(_, at)| *at > self.last_block);
+        if self.requests.is_empty() && !blocks.is_empty() && !gave_up {
+            self.last_block = now;
+        }
+        for block in blocks {
+            self.connection.send(block.request()).await?;
+            self.requests.push((block, now));
         }
         Ok(())
     }
+
+    /// Gives every block on fly back to picker, for when peer won't be sending them
+    pub(crate) async fn abort_requests(&mut self) {
+        self.requests.clear();
+        self.picker.lock().await.abort_peer(self.connection.peer.addr);
+    }
 }
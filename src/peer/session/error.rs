@@ -1,20 +1,68 @@
-use crate::{peer::session::piece, torrent::CommitJob};
+use crate::{peer::{codec, encryption, session::piece, HandshakeError}, torrent::CommitJob};
 use tokio::sync::mpsc::error::SendError;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("Protocol violation")]
-    ProtocolViolation,
-    #[error("Bad Request")]
-    BadRequest,
+    #[error("Protocol violation : {0}")]
+    ProtocolViolation(Violation),
     #[error("Session timed out")]
     TimeOut,
+    #[error("Torrent has reached its peer limit")]
+    PeerLimitReached,
+    #[error("Committer has shut down")]
+    CommitterClosed,
+    #[error("Peer is banned for sending bad blocks")]
+    Banned,
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
+    Codec(#[from] codec::Error),
+    #[error(transparent)]
+    Encryption(#[from] encryption::Error),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error(transparent)]
     PieceError(#[from] piece::Error),
     #[error(transparent)]
     CommitError(#[from] SendError<CommitJob>)
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
\ No newline at end of file
+impl Error {
+    /// What the peer did wrong, if the session ended because of it
+    pub fn violation(&self) -> Option<Violation> {
+        match self {
+            Error::ProtocolViolation(violation) => Some(*violation),
+            Error::Codec(codec::Error::MessageTooLarge { .. }) => Some(Violation::OversizedMessage),
+            Error::Codec(codec::Error::InvalidMessage(_)) => Some(Violation::MalformedMessage),
+            Error::PieceError(piece::Error::HashMismatch) => None,
+            Error::PieceError(_) => Some(Violation::BadBlock),
+            _ => None,
+        }
+    }
+}
+
+/// # [`Violation`]
+/// Ways a peer can break the protocol, recorded against it in torrent's peer pool
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    #[error("Bitfield of wrong length")]
+    BitfieldLength,
+    #[error("Bitfield has spare bits set")]
+    SpareBits,
+    #[error("Piece index out of range")]
+    InvalidPieceIndex,
+    #[error("Requested a block past the end of its piece")]
+    InvalidBlock,
+    #[error("Sent a block that was never requested")]
+    UnrequestedBlock,
+    #[error("Sent a block that doesn't fit the request")]
+    BadBlock,
+    #[error("Message couldn't be decoded")]
+    MalformedMessage,
+    #[error("Message exceeds size limit")]
+    OversizedMessage,
+    #[error("Extension message couldn't be decoded")]
+    MalformedExtension,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -1,5 +1,7 @@
 use bytes::Bytes;
 
+use crate::peer::extension::{self, pex::Pex};
+
 #[derive(Debug)]
 pub enum Event {
     BitFieldUpdated,
@@ -11,5 +13,9 @@ pub enum Event {
     KeepAlive,
     PeerNotInterested,
     PieceRequested { index : u32, offset : u32, length : u32 },
+    RequestCancelled { index : u32, offset : u32, length : u32 },
+    DhtPort(u16),
+    ExtensionHandshake(extension::Handshake),
+    PeerExchange(Pex),
     Ignore,
 }
\ No newline at end of file
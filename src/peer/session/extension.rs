@@ -0,0 +1,130 @@
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::{
+    peer::{
+        extension::{self, pex::{self, Pex}},
+        session::{self, Event, Violation},
+        transport::Kind,
+        Message, PeerSession as Session, PexFlags, SessionError as Error,
+    },
+    torrent::PeerSource,
+};
+
+impl Session {
+    /// Tells the peer which extensions we speak, only if it has announced support for extension protocol
+    pub(crate) async fn send_extension_handshake(&mut self) -> session::Result<()> {
+        if !self.connection.reserved.supports_extensions() {
+            return Ok(());
+        }
+        let handshake = extension::Handshake::local(self.torrent_info.is_private());
+        self.connection
+            .send(Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload: handshake.to_bytes(),
+            })
+            .await
+    }
+
+    /// Decodes payload of an extended message, based on the ids we've handed out in our handshake
+    ///
+    /// ## Error
+    /// Fails with [`Violation::MalformedExtension`] when payload of a known extension isn't valid bencode
+    pub(crate) fn handle_extended(&self, id: u8, payload: Bytes) -> session::Result<Event> {
+        match id {
+            extension::HANDSHAKE_ID => extension::Handshake::try_from(payload.as_ref())
+                .map(Event::ExtensionHandshake)
+                .map_err(|_| Error::ProtocolViolation(Violation::MalformedExtension)),
+            extension::UT_PEX_ID if !self.torrent_info.is_private() => Pex::try_from(payload.as_ref())
+                .map(Event::PeerExchange)
+                .map_err(|_| Error::ProtocolViolation(Violation::MalformedExtension)),
+            _ => Ok(Event::Ignore),
+        }
+    }
+
+    /// Remembers peer's extensions, request queue and listen port,
+    /// and starts exchanging peers if both of us are up for it.
+    /// Our own address, if peer tells it, is a vote towards the one pool prioritises connections by.
+    pub(crate) async fn handle_extension_handshake(&mut self, handshake: extension::Handshake) {
+        self.extensions.update(&handshake);
+        if handshake.reqq.is_some() {
+            self.pipeline.set_peer_queue(handshake.reqq);
+        }
+        {
+            let mut pool = self.pool.lock().await;
+            if let Some(port) = handshake.p.filter(|&port| port != 0) {
+                pool.set_listen_port(&self.connection.peer.addr, port);
+            }
+            if let Some(ip) = handshake.your_ip() {
+                pool.vote_external_ip(self.connection.peer.addr, ip);
+            }
+        }
+
+        let remote_id = self
+            .extensions
+            .remote_id(extension::UT_PEX)
+            .filter(|_| !self.torrent_info.is_private());
+
+        self.pex = match (remote_id, self.pex.take()) {
+            (Some(id), Some(exchange)) if exchange.remote_id() == id => Some(exchange),
+            (Some(id), _) => Some(pex::Exchange::new(id)),
+            (None, _) => None,
+        };
+    }
+
+    /// Adds peers learnt from `ut_pex` to torrent's pool.
+    /// Dropped peers are kept around, they may still be reachable by us.
+    pub(crate) async fn handle_peer_exchange(&mut self, pex: Pex) {
+        let added = pex.added();
+        let mut pool = self.pool.lock().await;
+        let new_peers = added
+            .into_iter()
+            .take(pex::MAX_PEERS)
+            .filter(|(peer, flags)| pool.insert_with_flags(*peer, PeerSource::Pex, *flags))
+            .count();
+        #[cfg(debug_assertions)]
+        eprintln!("SESSION {:>15} | PEX : {new_peers} new peers, pool has {}", self.connection.peer.addr.ip(), pool.len());
+    }
+
+    /// Sends connected peers others can dial (leaving out this very peer) to remote,
+    /// see [`crate::torrent::PeerPool::pex_peers`]. [`pex::Exchange`] takes care of rate limiting
+    pub(crate) async fn send_pex(&mut self) -> session::Result<()> {
+        if self.pex.is_none() {
+            return Ok(());
+        }
+        let connected = self.pool.lock().await.pex_peers(&self.connection.peer.addr);
+
+        let Some(exchange) = self.pex.as_mut() else {
+            return Ok(());
+        };
+        if let Some(message) = exchange.next_message(&connected, Instant::now()) {
+            let id = exchange.remote_id();
+            self.connection
+                .send(Message::Extended {
+                    id,
+                    payload: message.to_bytes(),
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Flags other peers get to know about this peer through `ut_pex`.
+    /// Only peers we've dialed ourselves are known to be reachable.
+    pub(crate) fn pex_flags(&self) -> PexFlags {
+        let mut flags = PexFlags::default();
+        if self.connection.outbound {
+            flags |= PexFlags::REACHABLE;
+        }
+        if self.connection.is_encrypted() {
+            flags |= PexFlags::ENCRYPTION;
+        }
+        if self.connection.transport() == Kind::Utp {
+            flags |= PexFlags::UTP;
+        }
+        if self.peer_is_seed() {
+            flags |= PexFlags::SEED;
+        }
+        flags
+    }
+}
@@ -1,82 +1,77 @@
-use crate::peer::{session::{self, Error}, PeerSession as Session};
+use crate::peer::{
+    session::{self, pipeline::MAX_BLOCK_LEN, Error, Violation},
+    Bitfield, BitfieldError, PeerSession as Session,
+};
 
 impl Session {
-    /// Looks for a piece in peer's bitfield, if there's anything interesting, it'll reserve it, then return the index to user
-    /// Else it responds with None
-    /// > NOTE: Global piece reservation is temporary.
-    /// > This will be removed once upload + choking are stable
-    pub(crate) async fn reserve_interesting_piece(&self) -> Option<u32> {
-        let mut state = self.state.lock().await;
-        for (byte_idx, (peer, mine)) in self
+    /// Update peer's bitfield, helps keeping track of peer's bitfield.
+    /// Pieces peer didn't have before count towards their availability.
+    ///
+    /// ## Error
+    /// Fails when piece index is beyond torrent's piece count
+    pub(crate) async fn update_bitfield(&mut self, index: u32) -> session::Result<()> {
+        let had = self
             .bit_field
-            .iter()
-            .zip(state.bit_field.iter())
-            .enumerate()
-        {
-            let difference = peer & !mine;
-            if difference == 0 {
-                continue;
-            }
-            for bit in 0..8 {
-                let mask = 1 << (7 - bit);
-                if difference & mask == 0 {
-                    continue;
-                }
-                let piece = (byte_idx * 8 + bit) as u32;
-                if piece >= state.num_pieces() {
-                    return None;
-                }
-                if state.is_in_flight(piece) {
-                    continue;
-                }
-
-                state.add_in_flight(piece);
-                return Some(piece);
-            }
+            .update(index)
+            .map_err(|_| Error::ProtocolViolation(Violation::InvalidPieceIndex))?;
+        if !had {
+            self.picker.lock().await.peer_has(index);
         }
-        None
+        Ok(())
     }
 
-    /// Update peer's bitfield, helps keeping track of peer's bitfield
-    /// 
+    /// Replaces peer's bitfield with the one it has sent, availability of pieces follows
+    ///
     /// ## Error
-    /// Fails when Piece requested larger in size than bitfields is supposed to hold
-    pub(crate) fn update_bitfield(&mut self, index: u32) -> session::Result<()> {
-        let piece = index as usize;
-        let byte = piece / 8;
-        if byte >= self.bit_field.len() {
-            return Err(Error::BadRequest);
-        }
-        let bit = piece % 8;
-
-        let mask = 1 << (7 - bit);
-
-        self.bit_field[byte] |= mask;
+    /// Fails when bitfield isn't exactly as long as torrent needs, or has bits set past the last piece
+    pub(crate) async fn replace_bitfield(&mut self, bitfield: &[u8]) -> session::Result<()> {
+        let bitfield = Bitfield::from_bytes(self.num_pieces(), bitfield).map_err(|err| {
+            Error::ProtocolViolation(match err {
+                BitfieldError::SpareBits => Violation::SpareBits,
+                _ => Violation::BitfieldLength,
+            })
+        })?;
+        let mut picker = self.picker.lock().await;
+        picker.remove_peer(&self.bit_field);
+        picker.add_peer(&bitfield);
+        self.bit_field = bitfield;
         Ok(())
     }
 
-    /// Only checks if I have to be interested in peer.
-    /// To reserve an interesting piece, use `reserve_interesting_piece()` instead
+    fn num_pieces(&self) -> usize {
+        self.torrent_info.pieces.len() / 20
+    }
+
+    /// Only checks if I have to be interested in peer, skipped files aside.
+    /// Blocks to request are picked in `pump_requests()`
     pub(crate) async fn should_be_interested(&self) -> bool {
-        let my_state = self.state.lock().await;
-        my_state
-            .bit_field
-            .iter()
-            .zip(self.bit_field.iter())
-            .any(|(mine, peer)| !mine & peer != 0)
+        self.picker.lock().await.is_interesting(&self.bit_field)
+    }
+
+    /// Whether peer has told us it has every piece
+    pub(crate) fn peer_is_seed(&self) -> bool {
+        self.bit_field.is_full()
     }
 
-    pub(crate) async fn is_valid_block(&self, index : u32, offset : u32, length : u32) -> bool {
-        if self.am_choking {
-            return false;
+    /// Whether a block peer has requested is one we'd send : we're not choking peer,
+    /// we have its piece, and it isn't longer than [`MAX_BLOCK_LEN`].
+    ///
+    /// ## Error
+    /// Fails with [`Violation::InvalidPieceIndex`] when piece is beyond torrent's piece count,
+    /// and [`Violation::InvalidBlock`] when block runs past the end of its piece
+    pub(crate) async fn is_valid_block(&self, index: u32, offset: u32, length: u32) -> session::Result<bool> {
+        if index as usize >= self.num_pieces() {
+            return Err(Error::ProtocolViolation(Violation::InvalidPieceIndex));
+        }
+        let fits = offset
+            .checked_add(length)
+            .is_some_and(|end| end <= self.torrent_info.piece_len(index));
+        if !fits {
+            return Err(Error::ProtocolViolation(Violation::InvalidBlock));
         }
-        {
-            let piece_len = self.torrent_info.piece_len(index);
-            let state = self.state.lock().await;
-            if !state.have_piece(index) || piece_len < offset + length {
-                return false;
-            }
+        if self.am_choking || length > MAX_BLOCK_LEN {
+            return Ok(false);
         }
-        return true;
+        Ok(self.state.lock().await.have_piece(index))
     }
 }
@@ -1,13 +1,25 @@
 mod error;
+mod block;
 mod event;
 mod piece;
 mod core;
 mod runtime;
 mod protocol;
+mod extension;
+pub(crate) mod upload;
+mod timeout;
+mod rate;
+pub(crate) mod pipeline;
 pub(crate) mod interest;
 
 pub use core::Session;
-pub use error::Error;
+pub use error::{Error, Violation};
 pub use event::Event;
 pub use error::Result;
-pub use piece::Piece;
\ No newline at end of file
+pub use piece::{Piece, Received};
+pub use piece::Error as PieceError;
+pub use block::BlockRequest;
+pub use pipeline::Limits as PipelineLimits;
+
+#[cfg(test)]
+mod tests;
\ No newline at end of file
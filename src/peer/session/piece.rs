@@ -1,50 +1,56 @@
-use std::{collections::{HashSet, VecDeque}, time::Duration};
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
 
 use bytes::{Bytes, BytesMut};
-use rand::Fill;
-
 use sha1::{Digest, Sha1};
 use tokio::time::sleep;
 
-use crate::{peer::{session::{self, piece}, Message, PeerSession as Session}, torrent::CommitJob};
+use crate::peer::{session::{self, BlockRequest}, PeerSession as Session};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Block {
+    Pending,
+    /// Requested from more than one peer only in endgame
+    Requested { peers: Vec<SocketAddr> },
+    Received { peer: SocketAddr },
+}
+
+/// What became of a block handed to [`Piece::store`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received {
+    /// First copy of the block, other peers it was requested from are of no use for it now
+    Fresh { others: Vec<SocketAddr> },
+    /// Some other peer got the block here first
+    Duplicate,
+}
 
+/// # [`Piece`]
+/// A piece being assembled, block by block, from whichever peers the blocks are requested from.
+/// Remembers who sent what, so a piece failing its hash check can be traced back to its peers.
 pub struct Piece {
     index: u32,
-
     piece_len: u32,
-    max_block_len: u32,
-
-    pending: VecDeque<Block>,
-    on_fly: HashSet<u32>,
-    received: HashSet<u32>,
-
+    block_len: u32,
+    blocks: Vec<Block>,
+    received: usize,
+    /// Fast peer that has taken the whole piece, others keep off it while it's around
+    pub(crate) owner: Option<SocketAddr>,
+    /// Piece failed its hash check before, its owner downloads it alone so a failure is all on it
+    pub(crate) on_parole: bool,
     buffer: BytesMut,
 }
 
 impl Piece {
-    pub fn new(index: u32, piece_len: u32) -> Self {
-        let mut offset = 0;
-        let max_block_len = 16384; // HARD CODED, i'll take care of it
-
-        let mut pending = VecDeque::with_capacity((piece_len / max_block_len + 1) as usize);
-        while offset < piece_len {
-            if offset + max_block_len > piece_len {
-                pending.push_back(Block::new(offset, piece_len - offset));
-            } else {
-                pending.push_back(Block::new(offset, max_block_len));
-            }
-            offset += max_block_len;
-        }
-        let buffer = BytesMut::zeroed(piece_len as usize);
+    /// Piece split into blocks of given length, the last one may fall short
+    pub fn new(index: u32, piece_len: u32, block_len: u32) -> Self {
         Self {
             index,
-            max_block_len,
-            pending,
             piece_len,
-            on_fly: HashSet::new(),
-            received: HashSet::new(),
-
-            buffer,
+            block_len,
+            blocks: vec![Block::Pending; piece_len.div_ceil(block_len) as usize],
+            received: 0,
+            owner: None,
+            on_parole: false,
+            buffer: BytesMut::zeroed(piece_len as usize),
         }
     }
 
@@ -52,119 +58,155 @@ impl Piece {
         self.index
     }
 
-    fn total_blocks(&self) -> u32 {
-        (self.piece_len + self.max_block_len - 1) / self.max_block_len
-    }
-
-
-    /// Returns a `Some(Message::Request)` of a Piece block\
-    /// Returns None up when when pipeline's on-fly capacity is reached
-    /// ## Example
-    /// ```rs
-    /// while let Some(request) = piece.next_block() {
-    ///         /* Request here maybe */
-    /// }
-    /// ```
-    pub fn next_block(&mut self) -> Option<Message> {
-        if let Some(Block { offset, length }) = self.pending.pop_front() {
-            self.on_fly.insert(offset);
-            return Some(Message::Request {
-                index: self.index,
-                offset,
-                length,
-            });
+    fn block(&self, block: usize) -> BlockRequest {
+        let offset = block as u32 * self.block_len;
+        BlockRequest {
+            index: self.index,
+            offset,
+            length: self.block_len.min(self.piece_len - offset),
         }
-        None
     }
-    
-    /// Loses ownership of the buffer, also Piece gets moved
-    pub fn owned_buffer(self) -> Bytes {
-        self.buffer.freeze()
+
+    /// Requests next block nobody has asked for yet, on behalf of given peer
+    pub fn request_next(&mut self, peer: SocketAddr) -> Option<BlockRequest> {
+        let block = self.blocks.iter().position(|block| *block == Block::Pending)?;
+        self.blocks[block] = Block::Requested { peers: vec![peer] };
+        Some(self.block(block))
     }
 
-    pub fn can_request_more(&self) -> bool {
-        self.on_fly.len() < 4
+    /// Requests a block some other peer is on already, for endgame.
+    /// Goes for the block requested from the fewest peers, as long as that's fewer than `copies`.
+    pub fn request_copy(&mut self, peer: SocketAddr, copies: usize) -> Option<BlockRequest> {
+        let (block, peers) = self
+            .blocks
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(block, state)| match state {
+                Block::Requested { peers } if peers.len() < copies && !peers.contains(&peer) => Some((block, peers)),
+                _ => None,
+            })
+            .min_by_key(|(_, peers)| peers.len())?;
+        peers.push(peer);
+        Some(self.block(block))
     }
 
-    pub fn has_pending_req(&self) -> bool {
-        !self.pending.is_empty()
+    pub fn has_pending(&self) -> bool {
+        self.blocks.contains(&Block::Pending)
     }
 
-    pub fn progress(&self) -> (usize, usize) {
-        (self.received.len(), self.total_blocks() as usize)
+    /// Whether peer has some block of this piece requested
+    pub fn is_requested_by(&self, peer: SocketAddr) -> bool {
+        self.blocks
+            .iter()
+            .any(|block| matches!(block, Block::Requested { peers } if peers.contains(&peer)))
     }
 
-    
-    fn in_flight_count(&self) -> usize {
-        self.on_fly.len()
+    /// Whether anybody is on this piece, or has been
+    pub fn is_started(&self) -> bool {
+        self.blocks.iter().any(|block| *block != Block::Pending)
     }
 
-    pub fn pending_count(&self) -> usize {
-        self.pending.len()
+    /// Puts back every block peer was asked for
+    pub fn abort_peer(&mut self, peer: SocketAddr) {
+        for block in self.blocks.iter_mut() {
+            if let Block::Requested { peers } = block {
+                peers.retain(|requested| *requested != peer);
+                if peers.is_empty() {
+                    *block = Block::Pending;
+                }
+            }
+        }
+        if self.owner == Some(peer) {
+            self.owner = None;
+        }
     }
 
-    pub fn update_buffer(&mut self, index: u32, offset: u32, data: &[u8]) -> piece::Result<()> {
-        let expected_len = if offset + self.max_block_len > self.piece_len {
-            self.piece_len - offset
-        } else {
-            self.max_block_len
+    /// Puts back a single block peer was asked for, the piece isn't peer's own anymore either
+    pub fn abort_block(&mut self, peer: SocketAddr, offset: u32) {
+        let Some(Block::Requested { peers }) = self.blocks.get_mut((offset / self.block_len) as usize) else {
+            return;
         };
+        peers.retain(|requested| *requested != peer);
+        if peers.is_empty() {
+            self.blocks[(offset / self.block_len) as usize] = Block::Pending;
+        }
+        if self.owner == Some(peer) {
+            self.owner = None;
+        }
+    }
 
-        if expected_len > self.max_block_len
-            || self.index != index
-            || expected_len != data.len() as u32
-        {
-            eprintln!("\x1b[37mSelf.index : {}, found : {}\x1b[0m",
-                self.index(),
-                index
-            );
-
-            return Err(Error::BadPiece);
-        } else if self.received.contains(&offset) {
-            return Err(Error::DuplicateBlock {
-                index: self.index,
-                block: offset,
-            });
-        } else if !self.on_fly.contains(&offset) {
-            eprintln!("{:?}", self.on_fly);
+    /// Stores a block sent by given peer, see [`Piece::is_complete`] for whether that was the last one.
+    /// Blocks received already are ignored, whoever sends them.
+    ///
+    /// ## Error
+    /// Fails when block doesn't line up with the piece's blocks
+    pub fn store(&mut self, peer: SocketAddr, offset: u32, data: &[u8]) -> Result<Received> {
+        let block = (offset / self.block_len) as usize;
+        if !offset.is_multiple_of(self.block_len) || block >= self.blocks.len() {
             return Err(Error::UnexpectedBlock {
                 index: self.index,
                 block: offset,
             });
         }
-
-        if offset + expected_len > self.buffer.len() as u32 {
-            eprintln!("\x1b[31mAccessing offset {}, but length is {}\x1b[0m", offset + expected_len, self.buffer.len());
+        if self.block(block).length != data.len() as u32 {
+            return Err(Error::InvalidBlockLength);
         }
-        
-        self.buffer[offset as usize..(offset + expected_len) as usize].copy_from_slice(data);
-        self.on_fly.remove(&offset);
-        self.received.insert(offset);
-        Ok(())
+        let others = match &mut self.blocks[block] {
+            Block::Received { .. } => return Ok(Received::Duplicate),
+            Block::Requested { peers } => {
+                let mut others = std::mem::take(peers);
+                others.retain(|requested| *requested != peer);
+                others
+            }
+            Block::Pending => Vec::new(),
+        };
+        self.blocks[block] = Block::Received { peer };
+
+        self.buffer[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        self.received += 1;
+        Ok(Received::Fresh { others })
     }
 
-    
-    pub fn rebuild_pending(&mut self) {
-        self.pending.clear();
-        let mut offset = 0;
-        while offset < self.piece_len {
-            let len = self.max_block_len.min(self.piece_len - offset);
-            self.pending.push_back(Block::new(offset, len));
-            offset += self.max_block_len;
-        }
+    pub fn is_complete(&self) -> bool {
+        self.received == self.blocks.len()
     }
 
-    pub fn reset(&mut self) {
-        self.buffer = BytesMut::with_capacity(self.piece_len as usize);
-        self.buffer.fill(0);
-        self.on_fly.clear();
-        self.pending = VecDeque::with_capacity(self.total_blocks() as usize);
-        self.received.clear();
-        self.rebuild_pending();
+    pub fn progress(&self) -> (usize, usize) {
+        (self.received, self.blocks.len())
+    }
+
+    /// Peers that have sent some block of this piece
+    pub fn contributors(&self) -> HashSet<SocketAddr> {
+        self.blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Received { peer } => Some(*peer),
+                _ => None,
+            })
+            .collect()
     }
 
+    /// Peers whose blocks differ from the ones in a verified copy of this piece
+    pub fn corrupted_by(&self, verified: &Piece) -> HashSet<SocketAddr> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(block, state)| match state {
+                Block::Received { peer } => {
+                    let range = block * self.block_len as usize..(block + 1) * self.block_len as usize;
+                    let range = range.start..range.end.min(self.buffer.len());
+                    (self.buffer[range.clone()] != verified.buffer[range]).then_some(*peer)
+                }
+                _ => None,
+            })
+            .collect()
+    }
 
-    pub fn verify(&self, pieces : &[u8]) -> bool {
+    /// Checks piece against its hash in `pieces`
+    ///
+    /// ## Error
+    /// Fails with [`Error::HashMismatch`] when hashes don't match
+    pub fn verify(&self, pieces: &[u8]) -> Result<()> {
         let mut hasher = Sha1::new();
         hasher.update(&self.buffer);
         let result = hasher.finalize();
@@ -173,30 +215,37 @@ impl Piece {
         let range = index * 20..index * 20 + 20;
         let pieces = &pieces[range];
 
-        *pieces == *result
+        if *pieces != *result {
+            return Err(Error::HashMismatch);
+        }
+        Ok(())
     }
 
     pub fn data(&self) -> &[u8] {
         &self.buffer
     }
 
-    pub fn is_complete(&self) -> bool {
-        let expected_blocks = self.total_blocks();
-        return expected_blocks == self.received.len() as u32 && self.on_fly.is_empty();
+    /// Loses ownership of the buffer, also Piece gets moved
+    pub fn owned_buffer(self) -> Bytes {
+        self.buffer.freeze()
     }
 }
 
-impl Session {
-    /// Forwards downloaded piece to committer, leaving self.current_piece with None
-    pub(crate) async fn handle_completed_piece(&mut self) -> session::Result<()> {
-        let commit_job = CommitJob::from(
-            self.current_piece
-                // Self piece is none, from this point
-                .take()
-                .expect("Tried to take out a None Piece, got smacked"),
-        );
+impl std::fmt::Debug for Piece {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Piece")
+            .field("index", &self.index)
+            .field("received", &self.received)
+            .field("blocks", &self.blocks.len())
+            .field("owner", &self.owner)
+            .finish()
+    }
+}
 
-        self.commit_tx.send(commit_job).await?;
+impl Session {
+    /// Hands a complete piece over to torrent, see [`crate::torrent::Handle::check_piece`]
+    pub(crate) async fn handle_completed_piece(&mut self, piece: Piece) -> session::Result<()> {
+        self.torrent.check_piece(piece).await?;
 
         // Shutup, I'm having a break
         sleep(Duration::from_millis(50)).await;
@@ -228,13 +277,81 @@ pub enum Error {
     HashMismatch,
 }
 
-struct Block {
-    offset: u32,
-    length: u32,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_LEN: u32 = 16384;
+
+    fn peer(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 6881))
+    }
+
+    #[test]
+    fn blocks_come_from_several_peers() {
+        let mut piece = Piece::new(3, BLOCK_LEN * 2 + 100, BLOCK_LEN);
+        let first = piece.request_next(peer(1)).unwrap();
+        let second = piece.request_next(peer(2)).unwrap();
+        let last = piece.request_next(peer(1)).unwrap();
+        assert_eq!((first.offset, second.offset, last.length), (0, BLOCK_LEN, 100));
+        assert!(piece.request_next(peer(3)).is_none());
+
+        piece.store(peer(2), second.offset, &[2; BLOCK_LEN as usize]).unwrap();
+        piece.store(peer(1), first.offset, &[1; BLOCK_LEN as usize]).unwrap();
+        assert!(!piece.is_complete());
+        piece.store(peer(1), last.offset, &[1; 100]).unwrap();
+        assert!(piece.is_complete());
+
+        assert_eq!(piece.contributors(), HashSet::from([peer(1), peer(2)]));
+        assert_eq!(piece.data()[BLOCK_LEN as usize], 2);
+    }
+
+    #[test]
+    fn aborted_blocks_are_requested_again() {
+        let mut piece = Piece::new(0, BLOCK_LEN * 2, BLOCK_LEN);
+        piece.request_next(peer(1));
+        piece.request_next(peer(2));
+        piece.abort_peer(peer(1));
+
+        assert!(!piece.is_requested_by(peer(1)));
+        assert_eq!(piece.request_next(peer(3)).unwrap().offset, 0);
+    }
+
+    #[test]
+    fn copies_go_to_least_requested_blocks() {
+        let mut piece = Piece::new(0, BLOCK_LEN * 2, BLOCK_LEN);
+        piece.request_next(peer(1));
+        piece.request_next(peer(2));
+
+        assert_eq!(piece.request_copy(peer(1), 2).unwrap().offset, BLOCK_LEN);
+        assert_eq!(piece.request_copy(peer(3), 2).unwrap().offset, 0);
+        // Every block has two peers on it by now
+        assert!(piece.request_copy(peer(4), 2).is_none());
+
+        let received = piece.store(peer(3), 0, &[0; BLOCK_LEN as usize]).unwrap();
+        assert_eq!(received, Received::Fresh { others: vec![peer(1)] });
+        let received = piece.store(peer(1), 0, &[0; BLOCK_LEN as usize]).unwrap();
+        assert_eq!(received, Received::Duplicate);
+        assert_eq!(piece.contributors(), HashSet::from([peer(3)]));
+    }
+
+    #[test]
+    fn corrupt_blocks_are_traced_to_their_peer() {
+        let mut failed = Piece::new(0, BLOCK_LEN * 2 + 100, BLOCK_LEN);
+        let mut verified = Piece::new(0, BLOCK_LEN * 2 + 100, BLOCK_LEN);
+        for (piece, bad) in [(&mut failed, 1), (&mut verified, 0)] {
+            piece.store(peer(1), 0, &[0; BLOCK_LEN as usize]).unwrap();
+            piece.store(peer(2), BLOCK_LEN, &[0; BLOCK_LEN as usize]).unwrap();
+            piece.store(peer(3), BLOCK_LEN * 2, &[bad; 100]).unwrap();
+        }
+        assert_eq!(failed.corrupted_by(&verified), HashSet::from([peer(3)]));
+        assert!(failed.verify(&[0; 20]).is_err());
+    }
 
-impl Block {
-    fn new(offset: u32, length: u32) -> Self {
-        Self { offset, length }
+    #[test]
+    fn misaligned_blocks_are_refused() {
+        let mut piece = Piece::new(0, BLOCK_LEN * 2, BLOCK_LEN);
+        assert!(matches!(piece.store(peer(1), 1, &[0; 10]), Err(Error::UnexpectedBlock { .. })));
+        assert!(matches!(piece.store(peer(1), BLOCK_LEN, &[0; 10]), Err(Error::InvalidBlockLength)));
     }
 }
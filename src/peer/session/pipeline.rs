@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+/// Largest block every client serves, bigger requests get a peer to drop us
+pub const MAX_BLOCK_LEN: u32 = 16384;
+/// Smallest block we go for, anything smaller is mostly message overhead
+pub const MIN_BLOCK_LEN: u32 = 1024;
+
+/// # [`Limits`]
+/// Bounds of the request pipeline kept with each peer, and size of the blocks requested
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Requests kept on fly no matter how slow the peer is
+    pub min_requests: usize,
+    /// Requests kept on fly no matter how fast the peer is, peer's own `reqq` may cap it further
+    pub max_requests: usize,
+    /// Length of blocks pieces are split into, see [`Limits::block_len`]
+    pub block_len: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            min_requests: 4,
+            max_requests: 256,
+            block_len: MAX_BLOCK_LEN,
+        }
+    }
+}
+
+impl Limits {
+    /// Configured block length, kept within what peers accept
+    pub fn block_len(&self) -> u32 {
+        self.block_len.clamp(MIN_BLOCK_LEN, MAX_BLOCK_LEN)
+    }
+}
+
+/// # [`Pipeline`]
+/// Decides how many requests are kept on fly with a peer.
+/// Enough blocks to cover twice the bandwidth-delay product, so the link never runs dry
+/// while a request makes its way to the peer, and so the rate keeps room to grow.
+#[derive(Debug, Default)]
+pub(crate) struct Pipeline {
+    limits: Limits,
+    /// Outstanding requests peer is willing to queue, from its extension handshake
+    peer_queue: Option<usize>,
+    /// Quickest a block has come back, queueing at the peer only ever adds to it
+    min_rtt: Option<Duration>,
+}
+
+impl Pipeline {
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_peer_queue(&mut self, reqq: Option<u32>) {
+        self.peer_queue = reqq.map(|reqq| reqq as usize);
+    }
+
+    /// Time between requesting a block and receiving it
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+    }
+
+    /// Requests to keep on fly, given peer's current download rate in bytes per second
+    pub fn depth(&self, rate: u64) -> usize {
+        let ceiling = self
+            .peer_queue
+            .map_or(self.limits.max_requests, |queue| queue.min(self.limits.max_requests))
+            .max(1);
+        let Some(rtt) = self.min_rtt else {
+            return self.limits.min_requests.min(ceiling);
+        };
+        let in_flight = 2.0 * rate as f64 * rtt.as_secs_f64() / self.limits.block_len() as f64;
+        (in_flight.ceil() as usize).clamp(self.limits.min_requests.min(ceiling), ceiling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth_follows_bandwidth_delay_product() {
+        let mut pipeline = Pipeline::default();
+        assert_eq!(pipeline.depth(10_000_000), 4);
+
+        // 10 MB/s over 100ms, twice the ~61 blocks on the wire
+        pipeline.record_rtt(Duration::from_millis(300));
+        pipeline.record_rtt(Duration::from_millis(100));
+        assert_eq!(pipeline.depth(10_000_000), 123);
+        // Slow peers still get a few
+        assert_eq!(pipeline.depth(1_000), 4);
+    }
+
+    #[test]
+    fn depth_honors_limits_and_peer_queue() {
+        let mut pipeline = Pipeline::default();
+        pipeline.record_rtt(Duration::from_secs(1));
+        assert_eq!(pipeline.depth(100_000_000), 256);
+
+        pipeline.set_peer_queue(Some(50));
+        assert_eq!(pipeline.depth(100_000_000), 50);
+        pipeline.set_peer_queue(Some(2));
+        assert_eq!(pipeline.depth(0), 2);
+    }
+
+    #[test]
+    fn block_len_stays_within_what_peers_accept() {
+        let limits = |block_len| Limits {
+            block_len,
+            ..Default::default()
+        };
+        assert_eq!(limits(1 << 17).block_len(), MAX_BLOCK_LEN);
+        assert_eq!(limits(8192).block_len(), 8192);
+        assert_eq!(limits(0).block_len(), MIN_BLOCK_LEN);
+    }
+}
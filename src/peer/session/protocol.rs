@@ -1,10 +1,14 @@
 use std::io::{self};
 
 use bytes::Bytes;
+use tokio::time::Instant;
 
-use crate::peer::{
-    self, Message, PeerSession as Session, Piece, SessionError as Error,
-    session::{self, Event},
+use crate::{
+    peer::{
+        self, Message, PeerSession as Session, SessionError as Error,
+        session::{self, BlockRequest, Event, Violation},
+    },
+    torrent::picker::Stored,
 };
 
 impl Session {
@@ -21,11 +25,12 @@ impl Session {
                 offset,
                 data,
             } => self.handle_piece(index, offset, data).await?,
+            // Choker decides whether peer gets unchoked
             Event::PeerInterested => {
-                // Yea event driven unchoke
-                self.connection.send(Message::Unchoke).await?;
-                eprintln!("SESSION : {} | SENT Unchoke", self.connection.peer.ip);
+                self.stats.set_interested(true);
+                self.choker.lock().await.peer_interested(self.connection.peer.addr);
             }
+            Event::PeerNotInterested => self.stats.set_interested(false),
             Event::ChokedMe => self.handle_choked_me().await?,
             Event::KeepAlive => {}
             Event::PieceRequested {
@@ -33,18 +38,53 @@ impl Session {
                 offset,
                 length,
             } => {
-                // self.handle_piece_request(index, offset, length).await?;
-                panic!("SENT A FUCKING PIECE");
-            },
+                // Nothing gets queued before it's bounds checked, reading it off disk trusts it
+                if self.is_valid_block(index, offset, length).await? {
+                    self.uploads.push(BlockRequest { index, offset, length });
+                    self.serve_uploads().await?;
+                }
+            }
+            Event::RequestCancelled {
+                index,
+                offset,
+                length,
+            } => {
+                self.uploads.cancel(&BlockRequest { index, offset, length });
+            }
+            // No DHT node to hand it to, yet
+            Event::DhtPort(port) => {
+                eprintln!("SESSION : {} | DHT port {port}", self.connection.peer.addr.ip());
+            }
+            Event::ExtensionHandshake(handshake) => self.handle_extension_handshake(handshake).await,
+            Event::PeerExchange(pex) => self.handle_peer_exchange(pex).await,
             Event::Ignore => {
                 eprintln!("\n\n\n\nDUH\n\n\n\n");
             }
-            x => eprintln!("\x1b[034mUnimplemented event recieved : {x:?}\x1b[0m"),
         }
         Ok(())
     }
 
+    /// Chokes or unchokes peer, as choker has decided.
+    /// Choking drops whatever peer has requested, as BEP 3 has it.
+    pub(crate) async fn follow_choker(&mut self, unchoke: bool) -> session::Result<()> {
+        if unchoke != self.am_choking {
+            return Ok(());
+        }
+        self.am_choking = !unchoke;
+        if unchoke {
+            self.connection.send(Message::Unchoke).await?;
+        } else {
+            self.uploads.clear();
+            self.connection.send(Message::Choke).await?;
+        }
+        eprintln!("SESSION : {} | Choker says unchoke : {unchoke}", self.connection.peer.addr.ip());
+        Ok(())
+    }
+
     async fn handle_bitfield(&mut self) -> Result<(), Error> {
+        let flags = self.pex_flags();
+        self.pool.lock().await.update_flags(&self.connection.peer.addr, flags);
+
         if self.should_be_interested().await {
             self.am_interested = true;
             self.connection.send(Message::Interested).await?;
@@ -53,41 +93,23 @@ impl Session {
     }
     
     pub(crate) async fn send_bitfield(&mut self) -> session::Result<()> {
-        let message = Message::Bitfield(self.state.lock().await.bit_field.clone().into());
+        let message = Message::Bitfield(self.state.lock().await.bit_field.as_bytes().to_vec().into());
         self.connection.send(message).await?;
         Ok(())
     }
 
-    /// When unchoked, I'm ready to request piece from peer
-    /// Because each peer is assigned a piece here, I'll pipeline the block requests
+    /// When unchoked, I'm ready to request blocks from peer, picker decides which ones
     async fn handle_unchoke(&mut self) -> session::Result<()> {
-        if self.am_interested
-            && let Some(index) = self.reserve_interesting_piece().await
-        {
-            let piece = Piece::new(index, self.torrent_info.piece_len(index));
-            self.current_piece = Some(piece);
-            self.pump_requests().await?;
-        }
-        Ok(())
+        self.pump_requests().await
     }
 
     /// Have is the piece a peer wants to tell that they have.
-    /// It's possible to be interested in that very piece, moreover, it's worth updating their bitfield to keep track of their pieces.
+    /// It's possible to be interested in that very piece, bitfield is already updated by now.
     ///
     /// # Error
-    /// Error may occur in the following cases
-    /// - When index is greater than bitfield's index
-    /// - Pipelining request to TcpStream
-    async fn handle_have(&mut self, index: u32) -> Result<(), Error> {
-        self.update_bitfield(index)?;
-
-        if !self.is_choking && self.current_piece.is_none() {
-            if let Some(index) = self.reserve_interesting_piece().await {
-                self.current_piece = Some(Piece::new(index, self.torrent_info.piece_len(index)));
-                self.pump_requests().await?;
-            }
-        }
-        Ok(())
+    /// Fails when pipelining requests to peer does
+    async fn handle_have(&mut self, _index: u32) -> Result<(), Error> {
+        self.pump_requests().await
     }
 
     // todo!();
@@ -129,24 +151,42 @@ impl Session {
     //     Ok(())
     // }
 
+    /// Choking drops every request we've made, blocks go back to picker for whoever's unchoked
     async fn handle_choked_me(&mut self) -> io::Result<()> {
         self.is_choking = true;
-        if let Some(piece) = self.current_piece.as_ref() {
-            self.state.lock().await.remove_in_flight(piece.index());
-            // todo!("Need some rework here")
-        }
+        self.abort_requests().await;
         Ok(())
     }
 
+    /// Hands a block we've asked for over to picker, to be put in its piece.
+    /// The piece's last block, whoever sent the rest, gets it checked and committed from here.
+    ///
+    /// Blocks we've cancelled may still turn up, they're taken all the same.
+    ///
+    /// ## Error
+    /// Fails with [`Violation::UnrequestedBlock`] when we never asked peer for the block
     async fn handle_piece(&mut self, index: u32, offset: u32, data: Bytes) -> Result<(), Error> {
-        if self.current_piece.is_none() {
-            return Err(Error::ProtocolViolation);
+        let is_block = |(block, _): &(BlockRequest, _)| block.index == index && block.offset == offset;
+        if let Some(position) = self.requests.iter().position(is_block) {
+            let (_, requested) = self.requests.swap_remove(position);
+            self.pipeline.record_rtt(requested.elapsed());
+        } else if let Some(position) = self.cancelled.iter().position(is_block) {
+            self.cancelled.swap_remove(position);
+        } else {
+            return Err(Error::ProtocolViolation(Violation::UnrequestedBlock));
         }
-        let piece = self.current_piece.as_mut().unwrap();
-        piece.update_buffer(index, offset, data.as_ref())?;
-        if piece.is_complete() && piece.verify(&self.torrent_info.pieces) {
-            eprintln!("\x1b[35m\x1b[1mDownloaded piece : {index} + VERIFIED CHECKSUM!!!\x1b[0m");
-            self.handle_completed_piece().await?;
+        self.last_block = Instant::now();
+        self.download_rate.record(data.len() as u64);
+        self.stats.add_downloaded(data.len() as u64);
+        self.totals.add_downloaded(data.len() as u64);
+
+        let stored = self
+            .picker
+            .lock()
+            .await
+            .store(self.connection.peer.addr, index, offset, data.as_ref())?;
+        if let Stored::Complete(piece) = stored {
+            self.handle_completed_piece(piece).await?;
         }
         self.pump_requests().await?;
         Ok(())
@@ -158,10 +198,7 @@ impl Session {
     ) -> Result<session::Event, session::Error> {
         match message {
             Message::Bitfield(x) => {
-                if self.bit_field.len() != x.len() {
-                    panic!("Found a guy violating protocol, lmao")
-                }
-                self.bit_field.copy_from_slice(&x);
+                self.replace_bitfield(&x).await?;
                 Ok(Event::BitFieldUpdated)
             }
             Message::Choke => {
@@ -170,24 +207,20 @@ impl Session {
             }
             Message::Unchoke => {
                 self.is_choking = false;
+                self.snubbed = false;
                 Ok(Event::UnchokedMe)
             }
             Message::Request {
                 index,
                 offset,
                 length,
-            } => {
-                if !self.is_valid_block(index, offset, length).await {
-                    return Ok(Event::Ignore);
-                }
-                Ok(Event::PieceRequested {
-                    index,
-                    offset,
-                    length,
-                })
-            }
+            } => Ok(Event::PieceRequested {
+                index,
+                offset,
+                length,
+            }),
             Message::Have(x) => {
-                self.update_bitfield(x)?;
+                self.update_bitfield(x).await?;
                 Ok(Event::Have(x))
             }
             Message::Interested => {
@@ -208,7 +241,18 @@ impl Session {
                 self.is_interested = false;
                 Ok(Event::PeerNotInterested)
             }
-            Message::UnexpectedId(_) => return Err(Error::ProtocolViolation),
+            Message::Cancel {
+                index,
+                offset,
+                length,
+            } => Ok(Event::RequestCancelled {
+                index,
+                offset,
+                length,
+            }),
+            Message::Port(port) => Ok(Event::DhtPort(port)),
+            Message::Extended { id, payload } => self.handle_extended(id, payload),
+            Message::UnexpectedId(_) => Err(Error::ProtocolViolation(Violation::MalformedMessage)),
         }
     }
 }
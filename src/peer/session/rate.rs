@@ -0,0 +1,69 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+/// Span of time transfer rates are averaged over
+const WINDOW: Duration = Duration::from_secs(20);
+
+/// # [`Rate`]
+/// Bytes per second moved over the last few seconds, going by tokio's clock
+#[derive(Debug)]
+pub(crate) struct Rate {
+    samples: VecDeque<(Instant, u64)>,
+    total: u64,
+    started: Instant,
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            total: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Rate {
+    pub fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.expire(now);
+        self.samples.push_back((now, bytes));
+        self.total += bytes;
+    }
+
+    /// Average over the window, or over the time since we started counting, if that's shorter
+    pub fn per_second(&mut self) -> u64 {
+        let now = Instant::now();
+        self.expire(now);
+        let elapsed = (now - self.started).min(WINDOW).as_secs_f64().max(1.0);
+        (self.total as f64 / elapsed) as u64
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(at, bytes)) = self.samples.front()
+            && now - at > WINDOW
+        {
+            self.samples.pop_front();
+            self.total -= bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::advance;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn old_samples_fall_out_of_window() {
+        let mut rate = Rate::default();
+        advance(Duration::from_secs(10)).await;
+        rate.record(10_000);
+        assert_eq!(rate.per_second(), 1_000);
+
+        advance(WINDOW + Duration::from_secs(1)).await;
+        assert_eq!(rate.per_second(), 0);
+    }
+}
@@ -1,29 +1,110 @@
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{self, MissedTickBehavior},
+};
 
+use crate::peer::extension::pex;
+use crate::peer::HandshakeError;
 use crate::peer::Message;
 use crate::peer::PeerSession as Session;
-use crate::peer::Piece;
 use crate::peer::SessionError as Error;
 use crate::peer::session;
 
 impl Session {
+    /// Runs the session until peer disconnects or misbehaves,
+    /// keeping torrent's peer pool aware of this connection meanwhile.
+    /// Protocol violations end only this session, and get recorded against the peer in pool.
+    ///
+    /// ## Error
+    /// Fails right away with [`HandshakeError::DuplicatePeer`] when there's a session running with the same peer,
+    /// and with [`Error::Banned`] when peer is banned, or gets banned meanwhile
     pub async fn run(&mut self) -> Result<(), Error> {
+        let peer = self.connection.peer;
+        let flags = self.pex_flags();
+        {
+            let mut pool = self.pool.lock().await;
+            if pool.is_banned(peer.addr.ip()) {
+                return Err(Error::Banned);
+            }
+            if !pool.mark_connected(peer, flags) {
+                return Err(HandshakeError::DuplicatePeer.into());
+            }
+        }
+        self.choker.lock().await.add_peer(peer.addr, self.stats.clone());
+
+        let result = self.event_loop().await;
+        self.choker.lock().await.remove_peer(&peer.addr);
+        self.abort_requests().await;
+        self.picker.lock().await.remove_peer(&self.bit_field);
+
+        let mut pool = self.pool.lock().await;
+        if let Err(err) = &result
+            && let Some(violation) = err.violation()
+        {
+            pool.record_violation(&peer.addr, violation);
+        }
+        pool.mark_disconnected(&peer.addr);
+        result
+    }
+
+    async fn event_loop(&mut self) -> Result<(), Error> {
         self.send_bitfield().await?;
+        self.send_extension_handshake().await?;
         self.connection.send(Message::Choke).await?;
+
+        let mut cancels = self.picker.lock().await.subscribe_cancels();
+        let mut bans = self.pool.lock().await.subscribe_bans();
+        let mut unchoked = self.choker.lock().await.subscribe();
+        let mut pex_interval = time::interval(pex::INTERVAL);
+        pex_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
+            let deadline = self.next_timeout();
             tokio::select! {
                 message = self.connection.read_message() => {
                     let message = message?;
                     let event = self.handle_message(message).await?;
                     self.handle_event(event).await?;
                 }
-                commit = self.commit_rx.recv() => {
-                    self.handle_commit_event(commit.unwrap()).await?;
+                commit = self.commit_rx.recv() => match commit {
+                    Ok(event) => self.handle_commit_event(event).await?,
+                    Err(RecvError::Lagged(_)) => self.announce_all_pieces().await?,
+                    Err(RecvError::Closed) => return Err(Error::CommitterClosed),
+                },
+                // Missed cancels only cost us the copies peer sends anyway
+                Ok(cancel) = cancels.recv() => self.handle_cancel(cancel).await?,
+                Ok(()) = unchoked.changed() => {
+                    let unchoke = unchoked.borrow_and_update().contains(&self.connection.peer.addr);
+                    self.follow_choker(unchoke).await?;
+                }
+                Ok(banned) = bans.recv() => {
+                    if banned == self.connection.peer.addr.ip() {
+                        return Err(Error::Banned);
+                    }
+                }
+                _ = pex_interval.tick() => {
+                    self.send_pex().await?;
+                }
+                _ = time::sleep_until(deadline) => {
+                    self.handle_timeouts().await?;
                 }
             }
             self.try_reschedule().await?;
         }
     }
 
+    /// Sends `Have` for every piece we've got, for when some commits were missed.
+    /// Peers take repeated haves just fine.
+    async fn announce_all_pieces(&mut self) -> session::Result<()> {
+        let pieces: Vec<u32> = {
+            let state = self.state.lock().await;
+            state.bit_field.iter_set().map(|index| index as u32).collect()
+        };
+        for index in pieces {
+            self.connection.send(Message::Have(index)).await?;
+        }
+        Ok(())
+    }
+
     /// Reschedules actions, whenever one of following event occurs :
     /// - Committer commits a piece (sent by any session)
     /// - A message is recieved (with-in current session)
@@ -31,26 +112,19 @@ impl Session {
         if self.is_choking {
             return Ok(());
         }
-        if self.current_piece.is_none() {
-            // I'm interested, but I'm not supposed to
-            if !self.should_be_interested().await {
-                if self.am_interested {
-                    self.am_interested = false;
-                    self.connection.send(Message::NotInterested).await?;
-                }
-                return Ok(());
-            }
-
-            // I'm supposed to be interested, but I'm not
-            if !self.am_interested {
-                self.am_interested = true;
-                self.connection.send(Message::Interested).await?;
+        // I'm interested, but I'm not supposed to
+        if !self.should_be_interested().await {
+            if self.am_interested && self.requests.is_empty() {
+                self.am_interested = false;
+                self.connection.send(Message::NotInterested).await?;
             }
+            return Ok(());
+        }
 
-            // I'm interested, and I'm supposed to
-            if let Some(index) = self.reserve_interesting_piece().await {
-                self.current_piece = Some(Piece::new(index, self.torrent_info.piece_len(index)));
-            }
+        // I'm supposed to be interested, but I'm not
+        if !self.am_interested {
+            self.am_interested = true;
+            self.connection.send(Message::Interested).await?;
         }
         self.pump_requests().await?;
         Ok(())
@@ -0,0 +1,332 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
+
+use crate::{
+    peer::{
+        session::{
+            self,
+            timeout::{IDLE_TIMEOUT, KEEP_ALIVE_INTERVAL, REQUEST_TIMEOUT, SNUB_TIMEOUT},
+        },
+        Bitfield, Connection, Peer, Violation,
+    },
+    torrent::{pool::DEFAULT_BAN_THRESHOLD, Handle},
+};
+
+/// Runs a session of the fake torrent against a peer that sends given bytes right after connecting, then hangs up.
+/// Returns how the session ended, along with the torrent and remote's address.
+async fn run_against(bytes: &[u8]) -> (session::Result<()>, Handle, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let torrent = Handle::fake();
+
+    let peer = Peer::from(addr);
+    let (connection, remote) = tokio::join!(peer.connect(), listener.accept());
+    let (mut remote, _): (TcpStream, _) = remote.unwrap();
+    remote.write_all(bytes).await.unwrap();
+    // Closing only our write half, dropping the socket with session's messages unread would reset it
+    remote.shutdown().await.unwrap();
+
+    let mut session = torrent.session(connection.unwrap());
+    let result = session.run().await;
+    (result, torrent, addr)
+}
+
+async fn assert_violation(bytes: &[u8], expected: Violation) {
+    let (result, torrent, addr) = run_against(bytes).await;
+
+    let err = result.expect_err("Session should end on a violation");
+    assert_eq!(err.violation(), Some(expected));
+
+    let pool = torrent.pool.lock().await;
+    let entry = pool.get(&addr).expect("Peer should stay in pool");
+    assert_eq!(entry.violations, [expected]);
+    assert!(!entry.connected);
+}
+
+#[tokio::test]
+async fn bitfield_of_wrong_length() {
+    assert_violation(&[0, 0, 0, 3, 5, 0xff, 0xff], Violation::BitfieldLength).await;
+}
+
+#[tokio::test]
+async fn bitfield_with_spare_bits() {
+    // Fake torrent has a single piece, so only the highest bit may be set
+    assert_violation(&[0, 0, 0, 2, 5, 0b1100_0000], Violation::SpareBits).await;
+}
+
+#[tokio::test]
+async fn have_beyond_piece_count() {
+    assert_violation(&[0, 0, 0, 5, 4, 0, 0, 0, 1], Violation::InvalidPieceIndex).await;
+}
+
+#[tokio::test]
+async fn block_that_was_never_requested() {
+    let mut piece = vec![0, 0, 0, 13, 7, 0, 0, 0, 0, 0, 0, 0, 0];
+    piece.extend_from_slice(&[1; 4]);
+    assert_violation(&piece, Violation::UnrequestedBlock).await;
+}
+
+#[tokio::test]
+async fn request_beyond_piece_count() {
+    assert_violation(&[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x40, 0], Violation::InvalidPieceIndex).await;
+}
+
+#[tokio::test]
+async fn request_overflowing_its_piece() {
+    // Offset and length add up past u32::MAX
+    let request = [0, 0, 0, 13, 6, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xf0, 0, 0, 0, 0x20];
+    assert_violation(&request, Violation::InvalidBlock).await;
+}
+
+#[tokio::test]
+async fn unknown_message_id() {
+    assert_violation(&[0, 0, 0, 1, 42], Violation::MalformedMessage).await;
+}
+
+#[tokio::test]
+async fn truncated_request() {
+    assert_violation(&[0, 0, 0, 5, 6, 0, 0, 0, 0], Violation::MalformedMessage).await;
+}
+
+#[tokio::test]
+async fn oversized_length_prefix() {
+    assert_violation(&[0xff, 0xff, 0xff, 0xff], Violation::OversizedMessage).await;
+}
+
+#[tokio::test]
+async fn malformed_extension_handshake() {
+    assert_violation(&[0, 0, 0, 4, 20, 0, b'd', b'i'], Violation::MalformedExtension).await;
+}
+
+#[tokio::test]
+async fn valid_bitfield_is_no_violation() {
+    let (result, torrent, addr) = run_against(&[0, 0, 0, 2, 5, 0b1000_0000]).await;
+
+    // Remote hangs up once it's done sending, that's all that goes wrong
+    assert!(result.unwrap_err().violation().is_none());
+    assert!(torrent.pool.lock().await.get(&addr).unwrap().violations.is_empty());
+}
+
+/// Runs a session of the fake torrent over an in memory pipe, the other end of it is returned to play the peer
+fn spawn_session() -> (JoinHandle<session::Result<()>>, Handle, DuplexStream) {
+    let (local, remote) = duplex(64 * 1024);
+    let torrent = Handle::fake();
+    let connection = Connection::over(Peer::from(SocketAddr::from(([127, 0, 0, 1], 6881))), local);
+    let mut session = torrent.session(connection);
+    (tokio::spawn(async move { session.run().await }), torrent, remote)
+}
+
+/// Reads next message off the pipe, as its id (`None` for keep-alives) along with payload
+async fn next_message(remote: &mut DuplexStream) -> (Option<u8>, Vec<u8>) {
+    let length = remote.read_u32().await.unwrap() as usize;
+    let mut message = vec![0u8; length];
+    remote.read_exact(&mut message).await.unwrap();
+    match message.split_first() {
+        Some((&id, payload)) => (Some(id), payload.to_vec()),
+        None => (None, Vec::new()),
+    }
+}
+
+/// Tells the session we've got the only piece, and unchokes it, returns once it's been requested
+async fn offer_piece(remote: &mut DuplexStream) -> Vec<u8> {
+    remote.write_all(&[0, 0, 0, 2, 5, 0b1000_0000, 0, 0, 0, 1, 1]).await.unwrap();
+    loop {
+        if let (Some(6), request) = next_message(remote).await {
+            return request;
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn quiet_sessions_send_keep_alives() {
+    let (_session, _torrent, mut remote) = spawn_session();
+    let start = Instant::now();
+
+    // Bitfield and choke come first
+    while next_message(&mut remote).await.0.is_some() {}
+    assert!(start.elapsed() >= KEEP_ALIVE_INTERVAL);
+}
+
+#[tokio::test(start_paused = true)]
+async fn quiet_peers_time_out() {
+    let (session, torrent, _remote) = spawn_session();
+    let start = Instant::now();
+
+    let result = session.await.unwrap();
+    assert!(matches!(result, Err(session::Error::TimeOut)));
+    assert!(start.elapsed() >= IDLE_TIMEOUT);
+    assert!(torrent.pool.lock().await.connected().next().is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn late_blocks_are_cancelled_and_handed_back() {
+    let (_session, torrent, mut remote) = spawn_session();
+    let request = offer_piece(&mut remote).await;
+    let start = Instant::now();
+
+    assert_eq!(next_message(&mut remote).await, (Some(8), request));
+    assert!(start.elapsed() >= REQUEST_TIMEOUT);
+    // Nobody else was on the piece, it's up for grabs again
+    assert!(!torrent.picker.lock().await.is_downloading(0));
+}
+
+#[tokio::test(start_paused = true)]
+async fn late_blocks_arriving_after_cancel_are_no_violation() {
+    let (session, torrent, mut remote) = spawn_session();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+    let request = offer_piece(&mut remote).await;
+    assert_eq!(next_message(&mut remote).await, (Some(8), request.clone()));
+
+    // Peer had the block queued already, it sends it anyway
+    let length = u32::from_be_bytes(request[8..12].try_into().unwrap());
+    let mut piece = (9 + length).to_be_bytes().to_vec();
+    piece.push(7);
+    piece.extend_from_slice(&request[..8]);
+    piece.resize(piece.len() + length as usize, 1);
+    remote.write_all(&piece).await.unwrap();
+    sleep(Duration::from_millis(10)).await;
+
+    assert!(!session.is_finished());
+    assert_eq!(torrent.picker.lock().await.wasted(), length as u64);
+    assert!(torrent.pool.lock().await.get(&addr).unwrap().violations.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn snubbing_peers_give_their_piece_back() {
+    let (_session, torrent, mut remote) = spawn_session();
+    offer_piece(&mut remote).await;
+    assert!(torrent.picker.lock().await.is_downloading(0));
+
+    // Peer never sends the block, no matter how many times it's asked for
+    sleep(SNUB_TIMEOUT + Duration::from_secs(1)).await;
+
+    assert!(!torrent.picker.lock().await.is_downloading(0));
+}
+
+#[tokio::test(start_paused = true)]
+async fn endgame_copies_are_cancelled_once_another_peer_sends_them() {
+    let (_session, torrent, mut remote) = spawn_session();
+    let other = SocketAddr::from(([10, 0, 0, 1], 6881));
+    let mut first_piece = Bitfield::new(torrent.info.pieces.len() / 20);
+    first_piece.update(0).unwrap();
+    let blocks = torrent.picker.lock().await.pick_blocks(other, &first_piece, true, 1);
+
+    // Every block is on fly with the other peer already, so this one gets asked for a copy
+    let request = offer_piece(&mut remote).await;
+    assert_eq!(request, blocks[0].request().encode()[5..]);
+
+    let data = vec![0; blocks[0].length as usize];
+    torrent.picker.lock().await.store(other, 0, 0, &data).unwrap();
+    assert_eq!(next_message(&mut remote).await, (Some(8), request));
+}
+
+#[tokio::test(start_paused = true)]
+async fn peers_sending_bad_pieces_get_banned() {
+    let (session, torrent, mut remote) = spawn_session();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+    let mut request = offer_piece(&mut remote).await;
+
+    // Fake torrent's hash matches no data, peer alone is blamed each time it sends the piece
+    for failures in 1..=DEFAULT_BAN_THRESHOLD {
+        let length = u32::from_be_bytes(request[8..12].try_into().unwrap());
+        let mut piece = (9 + length).to_be_bytes().to_vec();
+        piece.push(7);
+        piece.extend_from_slice(&request[..8]);
+        piece.resize(piece.len() + length as usize, 1);
+        remote.write_all(&piece).await.unwrap();
+        if failures == DEFAULT_BAN_THRESHOLD {
+            break;
+        }
+        request = loop {
+            if let (Some(6), request) = next_message(&mut remote).await {
+                break request;
+            }
+        };
+    }
+
+    assert!(matches!(session.await.unwrap(), Err(session::Error::Banned)));
+    let pool = torrent.pool.lock().await;
+    let entry = pool.get(&addr).unwrap();
+    assert_eq!((entry.hash_failures, entry.connected), (DEFAULT_BAN_THRESHOLD, false));
+    assert!(pool.is_banned(addr.ip()));
+}
+
+#[tokio::test(start_paused = true)]
+async fn choker_decides_when_peers_are_unchoked() {
+    let (_session, torrent, mut remote) = spawn_session();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+    remote.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+    while next_message(&mut remote).await.0 != Some(1) {}
+
+    // Peer sent nothing, and has no one to compete with, it keeps its slot
+    torrent.choker.lock().await.rechoke(false);
+    assert!(torrent.choker.lock().await.subscribe().borrow().contains(&addr));
+
+    // Not interested anymore, it's choked come next rechoke
+    remote.write_all(&[0, 0, 0, 1, 3]).await.unwrap();
+    sleep(Duration::from_millis(10)).await;
+    torrent.choker.lock().await.rechoke(false);
+    while next_message(&mut remote).await.0 != Some(0) {}
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn requested_blocks_are_uploaded_and_counted() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let old_home = std::env::var("XDG_DATA_HOME");
+    // See std::env::set_var and std::env::remove_var's docs
+    unsafe { std::env::set_var("XDG_DATA_HOME", temp_dir.path()) };
+
+    let (session, torrent, mut remote) = spawn_session();
+    let data: Vec<u8> = (0..torrent.info.piece_length).map(|i| (i % 251) as u8).collect();
+    let path = &torrent.layout.files[0].path;
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, &data).unwrap();
+    torrent.state.lock().await.mark_piece_complete(0).unwrap();
+
+    // Interested, choker has a slot to spare
+    remote.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+    while next_message(&mut remote).await.0 != Some(1) {}
+    let mut request = vec![0, 0, 0, 13, 6, 0, 0, 0, 0, 0, 0, 0x10, 0];
+    request.extend_from_slice(&0x1000u32.to_be_bytes());
+    remote.write_all(&request).await.unwrap();
+    let piece = loop {
+        if let (Some(7), piece) = next_message(&mut remote).await {
+            break piece;
+        }
+    };
+
+    unsafe {
+        match old_home {
+            Ok(x) => std::env::set_var("XDG_DATA_HOME", x),
+            Err(_) => std::env::remove_var("XDG_DATA_HOME"),
+        }
+    }
+    assert_eq!(piece[..8], [0, 0, 0, 0, 0, 0, 0x10, 0]);
+    assert_eq!(piece[8..], data[0x1000..0x2000]);
+    assert_eq!(torrent.totals.uploaded(), 0x1000);
+    assert!(!session.is_finished());
+}
+
+#[tokio::test]
+async fn unchoked_peers_requesting_out_of_range_blocks_get_nothing() {
+    let (session, torrent, mut remote) = spawn_session();
+    torrent.state.lock().await.mark_piece_complete(0).unwrap();
+
+    remote.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+    while next_message(&mut remote).await.0 != Some(1) {}
+    remote
+        .write_all(&[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x40, 0])
+        .await
+        .unwrap();
+
+    let err = session.await.unwrap().expect_err("Session should end on a violation");
+    assert_eq!(err.violation(), Some(Violation::InvalidPieceIndex));
+    assert_eq!(torrent.totals.uploaded(), 0);
+}
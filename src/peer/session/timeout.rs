@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::peer::{session, Message, PeerSession as Session, SessionError as Error};
+
+/// Longest we stay quiet, before telling peer we're still around
+pub(crate) const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// Longest peer may stay quiet, keep-alives included, before it's dropped
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+/// Time a block gets to arrive, before it's cancelled and handed back to picker
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time without a single block while some are on fly, before peer is taken as snubbing us
+pub(crate) const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl Session {
+    /// Earliest instant one of the timeouts is due, all of them are driven by tokio's clock
+    pub(crate) fn next_timeout(&self) -> Instant {
+        let mut deadline = (self.connection.last_written() + KEEP_ALIVE_INTERVAL)
+            .min(self.connection.last_read() + IDLE_TIMEOUT);
+        if let Some(requested) = self.requests.iter().map(|(_, at)| *at).min() {
+            deadline = deadline
+                .min(requested + REQUEST_TIMEOUT)
+                .min(self.last_block + SNUB_TIMEOUT);
+        }
+        deadline
+    }
+
+    /// Handles whichever timeouts are due :
+    /// - Quiet peers get dropped
+    /// - Snubbing peers give their blocks back to the shared picker, and get no new ones until they unchoke us again
+    /// - Blocks waiting too long get cancelled, and handed back to picker for other peers
+    /// - Keep-alive goes out when we've been quiet for long
+    ///
+    /// ## Error
+    /// Fails with [`Error::TimeOut`] when peer has been quiet for too long
+    pub(crate) async fn handle_timeouts(&mut self) -> session::Result<()> {
+        let now = Instant::now();
+        if now >= self.connection.last_read() + IDLE_TIMEOUT {
+            return Err(Error::TimeOut);
+        }
+
+        if !self.requests.is_empty() && now >= self.last_block + SNUB_TIMEOUT {
+            eprintln!("SESSION : {} | Snubbed us", self.connection.peer.addr.ip());
+            self.snubbed = true;
+            for (block, _) in self.requests.clone() {
+                self.send_cancel(block).await?;
+            }
+            self.abort_requests().await;
+        } else {
+            self.retry_late_requests(now).await?;
+        }
+
+        if now >= self.connection.last_written() + KEEP_ALIVE_INTERVAL {
+            self.connection.send(Message::KeepAlive).await?;
+        }
+        Ok(())
+    }
+
+    /// Cancels blocks that have been on fly for longer than [`REQUEST_TIMEOUT`], peer may have silently dropped
+    /// the requests. They're handed back to picker, for other peers to take.
+    async fn retry_late_requests(&mut self, now: Instant) -> session::Result<()> {
+        let is_late = |requested: &Instant| now >= *requested + REQUEST_TIMEOUT;
+        let late: Vec<_> = self
+            .requests
+            .iter()
+            .filter(|(_, requested)| is_late(requested))
+            .map(|(block, _)| *block)
+            .collect();
+        if late.is_empty() {
+            return Ok(());
+        }
+        self.requests.retain(|(_, requested)| !is_late(requested));
+        {
+            let mut picker = self.picker.lock().await;
+            for block in &late {
+                picker.abort_block(self.connection.peer.addr, *block);
+            }
+        }
+        for block in late {
+            self.send_cancel(block).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::VecDeque;
+
+use crate::peer::{
+    session::{self, BlockRequest},
+    Message, PeerSession as Session,
+};
+
+/// Most requests kept queued for a single peer, anything beyond is dropped, as BEP 3 allows
+pub(crate) const MAX_QUEUED_UPLOADS: usize = 250;
+
+/// Blocks peer has requested, in the order they came in
+#[derive(Debug, Default)]
+pub(crate) struct UploadQueue {
+    requests: VecDeque<BlockRequest>,
+}
+
+impl UploadQueue {
+    /// Queues a request, returns `false` when queue is full, or the block's already queued
+    pub fn push(&mut self, request: BlockRequest) -> bool {
+        if self.requests.len() >= MAX_QUEUED_UPLOADS || self.requests.contains(&request) {
+            return false;
+        }
+        self.requests.push_back(request);
+        true
+    }
+
+    /// Drops a queued request, returns `false` when it wasn't queued (served already, or never asked)
+    pub fn cancel(&mut self, request: &BlockRequest) -> bool {
+        let before = self.requests.len();
+        self.requests.retain(|queued| queued != request);
+        before != self.requests.len()
+    }
+
+    /// Oldest request, next to be served
+    pub fn pop(&mut self) -> Option<BlockRequest> {
+        self.requests.pop_front()
+    }
+
+    /// Drops every queued request, for when peer gets choked
+    pub fn clear(&mut self) {
+        self.requests.clear();
+    }
+}
+
+impl Session {
+    /// Sends every block peer has queued, read off disk, for as long as peer stays unchoked.
+    /// Bytes sent count towards peer's stats, which choker ranks peers by once we're seeding, and torrent's totals.
+    pub(crate) async fn serve_uploads(&mut self) -> session::Result<()> {
+        while !self.am_choking
+            && let Some(block) = self.uploads.pop()
+        {
+            let offset = block.index as u64 * self.torrent_info.piece_length as u64 + block.offset as u64;
+            let data = self.layout.read(offset, block.length as usize).await?;
+            self.connection
+                .send(Message::Piece {
+                    index: block.index,
+                    offset: block.offset,
+                    data: data.into(),
+                })
+                .await?;
+            self.stats.add_uploaded(block.length as u64);
+            self.totals.add_uploaded(block.length as u64);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(index: u32, offset: u32) -> BlockRequest {
+        BlockRequest {
+            index,
+            offset,
+            length: 16384,
+        }
+    }
+
+    #[test]
+    fn cancelling_drops_only_that_request() {
+        let mut queue = UploadQueue::default();
+        assert!(queue.push(request(0, 0)));
+        assert!(queue.push(request(0, 16384)));
+
+        assert!(queue.cancel(&request(0, 0)));
+        assert!(!queue.cancel(&request(0, 0)));
+        assert_eq!(queue.requests, [request(0, 16384)]);
+    }
+
+    #[test]
+    fn duplicates_and_overflow_are_refused() {
+        let mut queue = UploadQueue::default();
+        assert!(queue.push(request(0, 0)));
+        assert!(!queue.push(request(0, 0)));
+
+        for index in 1..MAX_QUEUED_UPLOADS as u32 {
+            assert!(queue.push(request(index, 0)));
+        }
+        assert!(!queue.push(request(u32::MAX, 0)));
+    }
+}
@@ -0,0 +1,133 @@
+//! # Transport
+//! Byte streams peer connections run over, TCP or uTP.
+//! Everything above [`Transport`] (encryption, framing, sessions) works the same on either.
+pub mod utp;
+
+use std::{fmt::Debug, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{config::Config, peer::encryption::Policy};
+pub use utp::{UtpSocket, UtpStream};
+
+/// Time a peer gets to answer over uTP, before we try TCP instead
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Tcp,
+    Utp,
+}
+
+/// # [`Transport`]
+/// A reliable, ordered byte stream to some peer
+pub trait Transport: AsyncRead + AsyncWrite + Debug + Send + Sync + Unpin {
+    fn kind(&self) -> Kind;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for TcpStream {
+    fn kind(&self) -> Kind {
+        Kind::Tcp
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+impl Transport for UtpStream {
+    fn kind(&self) -> Kind {
+        Kind::Utp
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(UtpStream::peer_addr(self))
+    }
+}
+
+/// In memory pipe, stands in for a socket in tests
+#[cfg(test)]
+impl Transport for tokio::io::DuplexStream {
+    fn kind(&self) -> Kind {
+        Kind::Tcp
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// # [`Connector`]
+/// Opens connections to peers, over uTP when we have a socket for it, falling back to TCP.
+/// Also carries the encryption policy those connections are opened with.
+#[derive(Clone, Debug)]
+pub struct Connector {
+    utp: Option<Arc<UtpSocket>>,
+    utp_timeout: Duration,
+    pub(crate) encryption: Policy,
+}
+
+impl Connector {
+    /// Connects over TCP only, see [`Connector::with_utp`]
+    pub fn new(config: &Config) -> Self {
+        Self {
+            utp: None,
+            utp_timeout: UTP_CONNECT_TIMEOUT,
+            encryption: config.encryption,
+        }
+    }
+
+    /// Tries given socket first, for every connection
+    pub fn with_utp(mut self, socket: Arc<UtpSocket>) -> Self {
+        self.utp = Some(socket);
+        self
+    }
+
+    /// ## Error
+    /// Fails when peer can't be reached over TCP either
+    pub async fn open(&self, addr: SocketAddr) -> io::Result<Box<dyn Transport>> {
+        if let Some(socket) = &self.utp
+            && let Ok(Ok(stream)) = timeout(self.utp_timeout, socket.connect(addr)).await
+        {
+            return Ok(Box::new(stream));
+        }
+        Ok(Box::new(TcpStream::connect(addr).await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connector_prefers_utp() {
+        let server = UtpSocket::bind(0).await.unwrap();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port()));
+        let connector = Connector::new(&Config::default()).with_utp(Arc::new(UtpSocket::bind(0).await.unwrap()));
+
+        let (stream, accepted) = tokio::join!(connector.open(addr), server.accept());
+        assert_eq!(stream.unwrap().kind(), Kind::Utp);
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test]
+    async fn connector_falls_back_to_tcp() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        // Holds on to the UDP port, without ever answering
+        let _silent = UdpSocket::bind(listener.local_addr().unwrap()).await.unwrap();
+        let mut connector = Connector::new(&Config::default()).with_utp(Arc::new(UtpSocket::bind(0).await.unwrap()));
+        connector.utp_timeout = Duration::from_millis(200);
+
+        let stream = connector.open(listener.local_addr().unwrap()).await.unwrap();
+        assert_eq!(stream.kind(), Kind::Tcp);
+    }
+}
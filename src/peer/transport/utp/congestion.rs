@@ -0,0 +1,166 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+/// Largest payload of a single packet, small enough to dodge fragmentation on most paths
+pub(crate) const MAX_PAYLOAD: usize = 1400;
+
+/// Queuing delay LEDBAT aims to stay under
+const TARGET_DELAY: u32 = 100_000;
+/// Most the window may grow by, over a single round trip
+const MAX_WINDOW_GAIN: f64 = 3000.0;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// Base delay is the lowest delay seen over this many minutes, so route changes get picked up
+const BASE_DELAY_MINUTES: usize = 2;
+
+/// # [`Ledbat`]
+/// Delay based congestion control (BEP 29), backs off as soon as queues build up along the path,
+/// leaving room for everything else on the link.
+#[derive(Debug)]
+pub(crate) struct Ledbat {
+    window: usize,
+    /// Lowest delay of each minute, newest last
+    base_delays: VecDeque<(Instant, u32)>,
+    rtt: Option<Duration>,
+    rtt_variance: Duration,
+    rto: Duration,
+}
+
+impl Default for Ledbat {
+    fn default() -> Self {
+        Self {
+            window: 2 * MAX_PAYLOAD,
+            base_delays: VecDeque::new(),
+            rtt: None,
+            rtt_variance: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl Ledbat {
+    /// Bytes allowed on fly
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Scales window by how far queuing delay is from target,
+    /// `delay` being one way delay (in microseconds) peer has measured on our packets
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        let base_delay = self.update_base_delay(delay, now);
+        let queuing_delay = delay.wrapping_sub(base_delay).min(u32::MAX / 2);
+
+        let off_target = (TARGET_DELAY as f64 - queuing_delay as f64) / TARGET_DELAY as f64;
+        let gain = MAX_WINDOW_GAIN * off_target.max(-1.0) * bytes_acked as f64 / self.window as f64;
+        self.window = (self.window as f64 + gain).max(MAX_PAYLOAD as f64) as usize;
+    }
+
+    /// Round trip time of a packet that wasn't resent
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(rtt);
+                self.rtt_variance = rtt / 2;
+            }
+            Some(smoothed) => {
+                let delta = smoothed.abs_diff(rtt);
+                self.rtt_variance = (self.rtt_variance * 3 + delta) / 4;
+                self.rtt = Some((smoothed * 7 + rtt) / 8);
+            }
+        }
+        self.rto = (self.rtt.unwrap_or_default() + self.rtt_variance * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// A packet got lost, while later ones made it
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2).max(MAX_PAYLOAD);
+    }
+
+    /// Nothing got acked within the timeout
+    pub fn on_timeout(&mut self) {
+        self.window = MAX_PAYLOAD;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+
+    fn update_base_delay(&mut self, delay: u32, now: Instant) -> u32 {
+        match self.base_delays.back_mut() {
+            Some((minute, lowest)) if now.duration_since(*minute) < Duration::from_secs(60) => {
+                *lowest = (*lowest).min(delay);
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_DELAY_MINUTES {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        self.base_delays.iter().map(|(_, lowest)| *lowest).min().unwrap_or(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_grows_while_queues_are_empty() {
+        let mut ledbat = Ledbat::default();
+        let now = Instant::now();
+        let before = ledbat.window();
+
+        for _ in 0..10 {
+            ledbat.on_ack(MAX_PAYLOAD, 20_000, now);
+        }
+        assert!(ledbat.window() > before);
+    }
+
+    #[test]
+    fn window_shrinks_once_delay_goes_past_target() {
+        let mut ledbat = Ledbat::default();
+        let now = Instant::now();
+        for _ in 0..50 {
+            ledbat.on_ack(MAX_PAYLOAD, 20_000, now);
+        }
+        let grown = ledbat.window();
+
+        for _ in 0..10 {
+            ledbat.on_ack(MAX_PAYLOAD, 20_000 + 2 * TARGET_DELAY, now);
+        }
+        assert!(ledbat.window() < grown);
+        assert!(ledbat.window() >= MAX_PAYLOAD);
+    }
+
+    #[test]
+    fn old_base_delays_are_forgotten() {
+        let mut ledbat = Ledbat::default();
+        let start = Instant::now();
+        assert_eq!(ledbat.update_base_delay(1_000, start), 1_000);
+        assert_eq!(ledbat.update_base_delay(5_000, start + Duration::from_secs(61)), 1_000);
+        assert_eq!(ledbat.update_base_delay(6_000, start + Duration::from_secs(122)), 5_000);
+    }
+
+    #[test]
+    fn timeouts_collapse_window_and_back_off() {
+        let mut ledbat = Ledbat::default();
+        ledbat.on_rtt_sample(Duration::from_millis(40));
+        assert_eq!(ledbat.rto(), MIN_RTO);
+
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MAX_PAYLOAD);
+        assert_eq!(ledbat.rto(), MIN_RTO * 2);
+    }
+
+    #[test]
+    fn losses_halve_the_window() {
+        let mut ledbat = Ledbat::default();
+        ledbat.window = 10 * MAX_PAYLOAD;
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), 5 * MAX_PAYLOAD);
+    }
+}
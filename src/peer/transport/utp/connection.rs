@@ -0,0 +1,610 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::LazyLock,
+    task::{Context, Poll, Waker},
+};
+
+use bytes::{Bytes, BytesMut};
+use rand::random;
+use tokio::{io::ReadBuf, time::Instant};
+
+use crate::peer::transport::utp::{
+    congestion::{Ledbat, MAX_PAYLOAD},
+    packet::{Packet, Type},
+};
+
+/// Bytes a stream may have written, but not yet sent
+const SEND_BUFFER: usize = 256 * 1024;
+/// Bytes received, but not yet read off the stream, also the window we advertise
+const RECEIVE_BUFFER: usize = 1024 * 1024;
+/// How far past the next expected packet, packets are kept for reordering
+const REORDER_LIMIT: u16 = 1024;
+/// Timeouts in a row, before giving up on the connection
+const MAX_TIMEOUTS: u32 = 6;
+const MAX_SYN_TIMEOUTS: u32 = 3;
+/// Packets acked past a missing one, before it's taken as lost
+const LOSS_THRESHOLD: usize = 3;
+
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Microsecond timestamp, as put on the wire, wraps around every ~71 minutes
+fn micros(now: Instant) -> u32 {
+    now.saturating_duration_since(*EPOCH).as_micros() as u32
+}
+
+/// Whether sequence number `a` comes before `b`, with wrapping
+fn seq_before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+#[derive(Debug)]
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Selectively acked, but still waiting on the packets before it
+    acked: bool,
+}
+
+/// # [`Connection`]
+/// State of a single uTP connection, without any IO.
+/// Packets are fed in with [`Connection::on_packet`], and taken out with [`Connection::poll_transmit`].
+#[derive(Debug)]
+pub(crate) struct Connection {
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    /// Sequence number of the next packet we send
+    seq_nr: u16,
+    /// Last packet received in order
+    ack_nr: u16,
+    in_flight: VecDeque<Sent>,
+    resend: VecDeque<u16>,
+    send_buffer: BytesMut,
+    receive_buffer: BytesMut,
+    reordered: HashMap<u16, Packet>,
+    congestion: Ledbat,
+    remote_window: usize,
+    /// Delay measured on the last packet received, sent back with ours
+    reply_delay: u32,
+    eof: bool,
+    /// No more writes, FIN goes out once send buffer drains
+    closing: bool,
+    /// Stream is gone, nobody reads anymore
+    detached: bool,
+    fin_sent: bool,
+    ack_due: bool,
+    timeouts: u32,
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(state: State, recv_id: u16, send_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            state,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr,
+            in_flight: VecDeque::new(),
+            resend: VecDeque::new(),
+            send_buffer: BytesMut::new(),
+            receive_buffer: BytesMut::new(),
+            reordered: HashMap::new(),
+            congestion: Ledbat::default(),
+            remote_window: MAX_PAYLOAD,
+            reply_delay: 0,
+            eof: false,
+            closing: false,
+            detached: false,
+            fin_sent: false,
+            ack_due: false,
+            timeouts: 0,
+            error: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Connection we're opening, SYN is queued right away
+    pub fn outgoing(recv_id: u16, now: Instant) -> Self {
+        let mut connection = Self::new(State::SynSent, recv_id, recv_id.wrapping_add(1), 1, 0);
+        // SYN is the only packet carrying our receive id
+        let syn = Packet::new(Type::Syn, recv_id, 1, 0);
+        connection.queue(syn, now);
+        connection
+    }
+
+    /// Connection peer has opened with given SYN, gets acked on next transmit
+    pub fn incoming(syn: &Packet, now: Instant) -> Self {
+        let mut connection = Self::new(
+            State::Connected,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            random(),
+            syn.seq_nr,
+        );
+        connection.reply_delay = micros(now).wrapping_sub(syn.timestamp);
+        connection.remote_window = syn.window as usize;
+        connection.ack_due = true;
+        connection
+    }
+
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    /// Done for good, driver can stop
+    pub fn is_finished(&self) -> bool {
+        match self.state {
+            State::Closed => true,
+            State::SynSent => self.detached,
+            State::Connected => self.fin_sent && self.in_flight.is_empty() && (self.eof || self.detached),
+        }
+    }
+
+    pub fn on_packet(&mut self, packet: Packet, now: Instant) {
+        match (self.state, packet.kind) {
+            (State::Closed, _) => return,
+            (_, Type::Reset) => return self.fail(io::ErrorKind::ConnectionReset),
+            // Our ack got lost, peer is still trying
+            (State::Connected, Type::Syn) => {
+                self.ack_due = true;
+                return;
+            }
+            (State::SynSent, Type::State) => {
+                self.state = State::Connected;
+                // Peer's STATE doesn't take up a sequence number, its first data packet comes with the same one
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            }
+            (State::SynSent, _) => return,
+            _ => {}
+        }
+        self.reply_delay = micros(now).wrapping_sub(packet.timestamp);
+        self.remote_window = packet.window as usize;
+
+        self.process_acks(&packet, now);
+        if matches!(packet.kind, Type::Data | Type::Fin) {
+            self.process_data(packet);
+        }
+        self.wake();
+    }
+
+    fn process_acks(&mut self, packet: &Packet, now: Instant) {
+        let mut acked_bytes = 0;
+        let mut progress = false;
+        let mut rtt_samples = Vec::new();
+
+        while let Some(front) = self.in_flight.front()
+            && !seq_before(packet.ack_nr, front.packet.seq_nr)
+        {
+            let sent = self.in_flight.pop_front().expect("Front was just checked");
+            progress = true;
+            if !sent.acked {
+                acked_bytes += sent.packet.payload.len();
+                if sent.transmissions == 1 {
+                    rtt_samples.push(now.duration_since(sent.sent_at));
+                }
+            }
+        }
+
+        if let Some(mask) = &packet.selective_ack {
+            for (index, byte) in mask.iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (1 << bit) == 0 {
+                        continue;
+                    }
+                    let seq_nr = packet.ack_nr.wrapping_add(2 + (index * 8 + bit) as u16);
+                    if let Some(sent) = self.in_flight.iter_mut().find(|sent| sent.packet.seq_nr == seq_nr)
+                        && !sent.acked
+                    {
+                        sent.acked = true;
+                        progress = true;
+                        acked_bytes += sent.packet.payload.len();
+                        if sent.transmissions == 1 {
+                            rtt_samples.push(now.duration_since(sent.sent_at));
+                        }
+                    }
+                }
+            }
+
+            // Later packets made it, while the first one didn't, it's most likely lost
+            let acked_past = self.in_flight.iter().filter(|sent| sent.acked).count();
+            if acked_past >= LOSS_THRESHOLD
+                && let Some(front) = self.in_flight.front()
+                && !front.acked
+                && front.transmissions == 1
+            {
+                self.resend.push_back(front.packet.seq_nr);
+                self.congestion.on_loss();
+            }
+        }
+
+        if progress {
+            self.timeouts = 0;
+        }
+        for rtt in rtt_samples {
+            self.congestion.on_rtt_sample(rtt);
+        }
+        if acked_bytes > 0 {
+            self.congestion.on_ack(acked_bytes, packet.timestamp_diff, now);
+        }
+    }
+
+    fn process_data(&mut self, packet: Packet) {
+        self.ack_due = true;
+        let seq_nr = packet.seq_nr;
+        if self.eof || !seq_before(self.ack_nr, seq_nr) || seq_nr.wrapping_sub(self.ack_nr) > REORDER_LIMIT {
+            return;
+        }
+        if seq_nr != self.ack_nr.wrapping_add(1) {
+            self.reordered.insert(seq_nr, packet);
+            return;
+        }
+
+        self.deliver(packet);
+        while !self.eof
+            && let Some(next) = self.reordered.remove(&self.ack_nr.wrapping_add(1))
+        {
+            self.deliver(next);
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+        match packet.kind {
+            Type::Fin => {
+                self.eof = true;
+                self.reordered.clear();
+            }
+            _ if !self.detached => self.receive_buffer.extend_from_slice(&packet.payload),
+            _ => {}
+        }
+    }
+
+    /// Bitmask of packets held for reordering, relative to `ack_nr + 2`
+    fn selective_ack(&self) -> Option<Bytes> {
+        let offsets = self
+            .reordered
+            .keys()
+            .map(|seq_nr| seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize);
+        let furthest = offsets.clone().max()?;
+        let mut mask = vec![0u8; (furthest / 32 + 1) * 4];
+        for offset in offsets {
+            mask[offset / 8] |= 1 << (offset % 8);
+        }
+        Some(mask.into())
+    }
+
+    /// When the oldest unacked packet times out, if there's any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.in_flight
+            .iter()
+            .find(|sent| !sent.acked)
+            .map(|sent| sent.sent_at + self.congestion.rto())
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) {
+        let Some(deadline) = self.next_deadline() else {
+            return;
+        };
+        if now < deadline {
+            return;
+        }
+
+        self.timeouts += 1;
+        let limit = if self.state == State::SynSent { MAX_SYN_TIMEOUTS } else { MAX_TIMEOUTS };
+        if self.timeouts > limit {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        self.congestion.on_timeout();
+        if let Some(sent) = self.in_flight.iter_mut().find(|sent| !sent.acked) {
+            // Won't time out again before it's actually resent
+            sent.sent_at = now;
+            self.resend.push_back(sent.packet.seq_nr);
+        }
+    }
+
+    /// Packets due to be sent : retransmissions first, then new data (as far as window allows), then a FIN or an ack
+    pub fn poll_transmit(&mut self, now: Instant) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        if self.state == State::Closed {
+            return packets;
+        }
+
+        while let Some(seq_nr) = self.resend.pop_front() {
+            if let Some(sent) = self.in_flight.iter_mut().find(|sent| sent.packet.seq_nr == seq_nr && !sent.acked) {
+                sent.sent_at = now;
+                sent.transmissions += 1;
+                packets.push(sent.packet.clone());
+            }
+        }
+
+        if self.state == State::Connected {
+            let window = self.congestion.window().min(self.remote_window).max(MAX_PAYLOAD);
+            while !self.send_buffer.is_empty() && self.bytes_in_flight() + MAX_PAYLOAD.min(self.send_buffer.len()) <= window {
+                let length = MAX_PAYLOAD.min(self.send_buffer.len());
+                let mut packet = Packet::new(Type::Data, self.send_id, self.seq_nr, 0);
+                packet.payload = self.send_buffer.split_to(length).freeze();
+                packets.push(self.queue(packet, now));
+                if let Some(waker) = self.write_waker.take() {
+                    waker.wake();
+                }
+            }
+            if self.closing && self.send_buffer.is_empty() && !self.fin_sent {
+                self.fin_sent = true;
+                let fin = Packet::new(Type::Fin, self.send_id, self.seq_nr, 0);
+                packets.push(self.queue(fin, now));
+            }
+            if self.ack_due && packets.is_empty() {
+                packets.push(Packet::new(Type::State, self.send_id, self.seq_nr, 0));
+            }
+        }
+
+        if !packets.is_empty() {
+            self.ack_due = false;
+        }
+        for packet in packets.iter_mut() {
+            self.stamp(packet, now);
+        }
+        packets
+    }
+
+    /// Puts packet on fly, taking up a sequence number
+    fn queue(&mut self, packet: Packet, now: Instant) -> Packet {
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight.push_back(Sent {
+            packet: packet.clone(),
+            sent_at: now,
+            transmissions: 1,
+            acked: false,
+        });
+        if packet.kind == Type::Syn {
+            // Transmitted through resend queue, along with everything else
+            self.in_flight.back_mut().expect("Pushed right above").transmissions = 0;
+            self.resend.push_back(packet.seq_nr);
+        }
+        packet
+    }
+
+    fn stamp(&self, packet: &mut Packet, now: Instant) {
+        packet.timestamp = micros(now);
+        packet.timestamp_diff = self.reply_delay;
+        packet.window = RECEIVE_BUFFER.saturating_sub(self.receive_buffer.len()) as u32;
+        if packet.kind != Type::Syn {
+            packet.ack_nr = self.ack_nr;
+            packet.selective_ack = self.selective_ack();
+        }
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|sent| !sent.acked)
+            .map(|sent| sent.packet.payload.len())
+            .sum()
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(kind);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.state {
+            State::Connected => Poll::Ready(Ok(())),
+            State::Closed => Poll::Ready(Err(self.error.unwrap_or(io::ErrorKind::NotConnected).into())),
+            State::SynSent => {
+                self.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Returns whether anything was read, reading off a mostly full buffer also queues a window update for peer
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<bool>> {
+        if !self.receive_buffer.is_empty() && buf.remaining() > 0 {
+            self.ack_due |= self.receive_buffer.len() >= RECEIVE_BUFFER / 2;
+            let length = self.receive_buffer.len().min(buf.remaining());
+            buf.put_slice(&self.receive_buffer.split_to(length));
+            return Poll::Ready(Ok(true));
+        }
+        if self.eof || buf.remaining() == 0 {
+            return Poll::Ready(Ok(false));
+        }
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let space = SEND_BUFFER.saturating_sub(self.send_buffer.len());
+        if space == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let length = space.min(buf.len());
+        self.send_buffer.extend_from_slice(&buf[..length]);
+        Poll::Ready(Ok(length))
+    }
+
+    /// No more writes, a FIN follows whatever's buffered
+    pub fn shutdown(&mut self) {
+        self.closing = true;
+    }
+
+    /// Stream has been dropped, connection winds down on its own
+    pub fn detach(&mut self) {
+        self.closing = true;
+        self.detached = true;
+        self.receive_buffer.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn noop_context() -> Context<'static> {
+        Context::from_waker(Waker::noop())
+    }
+
+    /// Delivers every packet `from` has to send, except those `drop` says no to
+    fn deliver(from: &mut Connection, to: &mut Connection, now: Instant, drop: impl Fn(&Packet) -> bool) -> usize {
+        let packets = from.poll_transmit(now);
+        let count = packets.len();
+        for packet in packets.into_iter().filter(|packet| !drop(packet)) {
+            to.on_packet(Packet::decode(packet.encode()).unwrap(), now);
+        }
+        count
+    }
+
+    fn connected_pair(now: Instant) -> (Connection, Connection) {
+        let mut a = Connection::outgoing(100, now);
+        let syn = a.poll_transmit(now).pop().unwrap();
+        assert_eq!((syn.kind, syn.connection_id), (Type::Syn, 100));
+
+        let mut b = Connection::incoming(&syn, now);
+        assert_eq!((b.recv_id(), b.send_id), (101, 100));
+        deliver(&mut b, &mut a, now, |_| false);
+        assert_eq!(a.state, State::Connected);
+        (a, b)
+    }
+
+    fn write(connection: &mut Connection, data: &[u8]) {
+        let Poll::Ready(Ok(written)) = connection.poll_write(&mut noop_context(), data) else {
+            panic!("Send buffer should have room");
+        };
+        assert_eq!(written, data.len());
+    }
+
+    fn read_all(connection: &mut Connection) -> Vec<u8> {
+        let mut data = vec![0u8; RECEIVE_BUFFER];
+        let mut buf = ReadBuf::new(&mut data);
+        while let Poll::Ready(Ok(true)) = connection.poll_read(&mut noop_context(), &mut buf) {}
+        buf.filled().to_vec()
+    }
+
+    #[test]
+    fn data_arrives_in_order() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected_pair(now);
+
+        let data: Vec<u8> = (0..5000u32).map(|x| x as u8).collect();
+        write(&mut a, &data);
+        while deliver(&mut a, &mut b, now, |_| false) > 0 {
+            deliver(&mut b, &mut a, now, |_| false);
+        }
+
+        assert_eq!(read_all(&mut b), data);
+        assert!(a.in_flight.is_empty());
+    }
+
+    #[test]
+    fn lost_packets_get_selectively_acked_around_and_resent() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected_pair(now);
+        a.congestion = Ledbat::default();
+        a.remote_window = RECEIVE_BUFFER;
+        for _ in 0..20 {
+            a.congestion.on_ack(MAX_PAYLOAD, 0, now);
+        }
+
+        let data = vec![7u8; MAX_PAYLOAD * 5];
+        write(&mut a, &data);
+        let first = a.seq_nr;
+        deliver(&mut a, &mut b, now, |packet| packet.seq_nr == first);
+        assert!(read_all(&mut b).is_empty());
+
+        // b acks the ones after the lost packet selectively, a resends the lost one
+        let ack = b.poll_transmit(now).pop().unwrap();
+        assert!(ack.selective_ack.is_some());
+        a.on_packet(ack, now);
+        assert_eq!(a.resend.front(), Some(&first));
+
+        deliver(&mut a, &mut b, now, |_| false);
+        assert_eq!(read_all(&mut b), data);
+    }
+
+    #[test]
+    fn unacked_packets_are_resent_after_timeout_then_given_up_on() {
+        let mut now = Instant::now();
+        let (mut a, _b) = connected_pair(now);
+        write(&mut a, b"hello");
+        assert_eq!(a.poll_transmit(now).len(), 1);
+
+        now += a.congestion.rto();
+        a.on_timeout(now);
+        let resent = a.poll_transmit(now);
+        assert_eq!(resent[0].payload.as_ref(), b"hello");
+
+        for _ in 0..MAX_TIMEOUTS {
+            now = a.next_deadline().unwrap();
+            a.on_timeout(now);
+            a.poll_transmit(now);
+        }
+        assert_eq!(a.state, State::Closed);
+        assert!(matches!(a.poll_write(&mut noop_context(), b"x"), Poll::Ready(Err(err)) if err.kind() == io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn fin_ends_the_stream_once_everything_before_it_arrived() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected_pair(now);
+        write(&mut a, b"last words");
+        a.shutdown();
+
+        let packets = a.poll_transmit(now);
+        assert_eq!(packets.last().unwrap().kind, Type::Fin);
+        // FIN shows up before the data
+        for packet in packets.into_iter().rev() {
+            b.on_packet(packet, now);
+        }
+        assert_eq!(read_all(&mut b), b"last words");
+        assert!(matches!(b.poll_read(&mut noop_context(), &mut ReadBuf::new(&mut [0; 4])), Poll::Ready(Ok(false))));
+
+        deliver(&mut b, &mut a, now, |_| false);
+        b.detach();
+        deliver(&mut b, &mut a, now, |_| false);
+        deliver(&mut a, &mut b, now, |_| false);
+        assert!(a.is_finished() && b.is_finished());
+    }
+
+    #[test]
+    fn resets_fail_the_connection() {
+        let now = Instant::now();
+        let (mut a, _) = connected_pair(now);
+        a.on_packet(Packet::new(Type::Reset, 101, 0, 0), now + Duration::from_millis(1));
+        assert!(a.is_finished());
+    }
+}
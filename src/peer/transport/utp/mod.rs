@@ -0,0 +1,12 @@
+//! # uTP
+//! Micro Transport Protocol (BEP 29), reliable ordered streams over UDP.
+//! Congestion control is delay based (LEDBAT), so bulk transfers get out of the way of
+//! everything else on the link. All connections share a single UDP socket, told apart by connection id.
+mod congestion;
+mod connection;
+mod packet;
+mod socket;
+mod stream;
+
+pub use socket::UtpSocket;
+pub use stream::UtpStream;
@@ -0,0 +1,210 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub(crate) const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+const SELECTIVE_ACK: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Type {
+    Data,
+    Fin,
+    /// Plain ack, carries no payload, and doesn't take up a sequence number
+    State,
+    Reset,
+    Syn,
+}
+
+impl TryFrom<u8> for Type {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Type::Data),
+            1 => Ok(Type::Fin),
+            2 => Ok(Type::State),
+            3 => Ok(Type::Reset),
+            4 => Ok(Type::Syn),
+            x => Err(Error::UnknownType(x)),
+        }
+    }
+}
+
+impl From<Type> for u8 {
+    fn from(kind: Type) -> Self {
+        match kind {
+            Type::Data => 0,
+            Type::Fin => 1,
+            Type::State => 2,
+            Type::Reset => 3,
+            Type::Syn => 4,
+        }
+    }
+}
+
+/// # [`Packet`]
+/// A single uTP datagram, timestamps are in microseconds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub kind: Type,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    /// Delay sender has measured on the last packet it got from us
+    pub timestamp_diff: u32,
+    /// Bytes sender is still willing to receive
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Bitmask of packets received past `ack_nr + 1`, least significant bit of first byte being `ack_nr + 2`
+    pub selective_ack: Option<Bytes>,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    pub fn new(kind: Type, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let extension_len = self.selective_ack.as_ref().map_or(0, |mask| 2 + mask.len());
+        let mut bytes = BytesMut::with_capacity(HEADER_LEN + extension_len + self.payload.len());
+        bytes.put_u8(u8::from(self.kind) << 4 | VERSION);
+        bytes.put_u8(if self.selective_ack.is_some() { SELECTIVE_ACK } else { 0 });
+        bytes.put_u16(self.connection_id);
+        bytes.put_u32(self.timestamp);
+        bytes.put_u32(self.timestamp_diff);
+        bytes.put_u32(self.window);
+        bytes.put_u16(self.seq_nr);
+        bytes.put_u16(self.ack_nr);
+        if let Some(mask) = &self.selective_ack {
+            bytes.put_u8(0);
+            bytes.put_u8(mask.len() as u8);
+            bytes.put_slice(mask);
+        }
+        bytes.put_slice(&self.payload);
+        bytes.freeze()
+    }
+
+    /// ## Error
+    /// Fails on truncated headers or extensions, unknown versions and types.
+    /// Unknown extensions are skipped over.
+    pub fn decode(mut bytes: Bytes) -> Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::TooShort);
+        }
+        let first = bytes.get_u8();
+        if first & 0x0f != VERSION {
+            return Err(Error::UnknownVersion(first & 0x0f));
+        }
+        let kind = Type::try_from(first >> 4)?;
+        let mut extension = bytes.get_u8();
+        let mut packet = Self {
+            kind,
+            connection_id: bytes.get_u16(),
+            timestamp: bytes.get_u32(),
+            timestamp_diff: bytes.get_u32(),
+            window: bytes.get_u32(),
+            seq_nr: bytes.get_u16(),
+            ack_nr: bytes.get_u16(),
+            selective_ack: None,
+            payload: Bytes::new(),
+        };
+
+        while extension != 0 {
+            if bytes.len() < 2 {
+                return Err(Error::MalformedExtension);
+            }
+            let next = bytes.get_u8();
+            let length = bytes.get_u8() as usize;
+            if bytes.len() < length {
+                return Err(Error::MalformedExtension);
+            }
+            let data = bytes.split_to(length);
+            if extension == SELECTIVE_ACK {
+                if length == 0 || !length.is_multiple_of(4) {
+                    return Err(Error::MalformedExtension);
+                }
+                packet.selective_ack = Some(data);
+            }
+            extension = next;
+        }
+        packet.payload = bytes;
+        Ok(packet)
+    }
+}
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum Error {
+    #[error("Packet shorter than uTP header")]
+    TooShort,
+    #[error("Unknown uTP version {0}")]
+    UnknownVersion(u8),
+    #[error("Unknown packet type {0}")]
+    UnknownType(u8),
+    #[error("Malformed extension")]
+    MalformedExtension,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_survive_a_round_trip() {
+        let mut packet = Packet::new(Type::Data, 7, 300, 299);
+        packet.timestamp = 123_456;
+        packet.timestamp_diff = 42;
+        packet.window = 1 << 20;
+        packet.selective_ack = Some(Bytes::from_static(&[0b101, 0, 0, 0]));
+        packet.payload = Bytes::from_static(b"block");
+
+        let encoded = packet.encode();
+        assert_eq!(encoded[0], 0x01);
+        assert_eq!(encoded.len(), HEADER_LEN + 6 + 5);
+        assert_eq!(Packet::decode(encoded).unwrap(), packet);
+    }
+
+    #[test]
+    fn syn_header_layout() {
+        let encoded = Packet::new(Type::Syn, 0x1234, 1, 0).encode();
+        assert_eq!(encoded.len(), HEADER_LEN);
+        assert_eq!(&encoded[..4], &[0x41, 0, 0x12, 0x34]);
+        assert_eq!(&encoded[16..], &[0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn unknown_extensions_are_skipped() {
+        let mut bytes = Packet::new(Type::State, 1, 1, 1).encode().to_vec();
+        bytes[1] = 9;
+        bytes.extend_from_slice(&[0, 2, 0xaa, 0xbb]);
+
+        let packet = Packet::decode(bytes.into()).unwrap();
+        assert!(packet.selective_ack.is_none());
+        assert!(packet.payload.is_empty());
+    }
+
+    #[test]
+    fn malformed_packets_are_refused() {
+        assert!(matches!(Packet::decode(Bytes::from_static(&[0x01; 10])), Err(Error::TooShort)));
+        let mut bytes = Packet::new(Type::State, 1, 1, 1).encode().to_vec();
+        bytes[0] = 0x02;
+        assert!(matches!(Packet::decode(bytes.clone().into()), Err(Error::UnknownVersion(2))));
+        bytes[0] = 0x91;
+        assert!(matches!(Packet::decode(bytes.clone().into()), Err(Error::UnknownType(9))));
+        bytes[0] = 0x21;
+        bytes[1] = 1;
+        bytes.extend_from_slice(&[0, 3, 0, 0, 0]);
+        assert!(matches!(Packet::decode(bytes.into()), Err(Error::MalformedExtension)));
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use rand::random;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, Mutex as AsyncMutex},
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+
+use crate::peer::transport::utp::{
+    connection::Connection,
+    packet::{self, Packet},
+    stream::{Link, UtpStream},
+};
+
+/// Packets queued for a single connection, more than that get dropped (and resent by peer)
+const CONNECTION_QUEUE: usize = 256;
+/// Connections accepted, but not yet picked up with [`UtpSocket::accept`]
+const ACCEPT_QUEUE: usize = 64;
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Connections are told apart by peer's address, along with the id peer puts on its packets
+type Key = (SocketAddr, u16);
+
+#[derive(Debug)]
+struct Shared {
+    socket: UdpSocket,
+    connections: Mutex<HashMap<Key, mpsc::Sender<Packet>>>,
+}
+
+/// # [`UtpSocket`]
+/// UDP socket every uTP connection goes through, both the ones we open and the ones we accept.
+/// Bound on the same port as the TCP listener, so peers find us the same way on both.
+#[derive(Debug)]
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    incoming: AsyncMutex<mpsc::Receiver<UtpStream>>,
+    receiver: JoinHandle<()>,
+}
+
+impl UtpSocket {
+    /// Binds to given port, on every interface.
+    /// Like the TCP listener, a single dual stack socket is preferred, falling back to IPv4 only.
+    pub async fn bind(port: u16) -> io::Result<Self> {
+        let socket = match bind_dual_stack(port) {
+            Ok(socket) => UdpSocket::from_std(socket)?,
+            Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?,
+        };
+        let shared = Arc::new(Shared {
+            socket,
+            connections: Mutex::new(HashMap::new()),
+        });
+        let (accepted, incoming) = mpsc::channel(ACCEPT_QUEUE);
+        let receiver = tokio::spawn(receive(shared.clone(), accepted));
+        Ok(Self {
+            shared,
+            incoming: AsyncMutex::new(incoming),
+            receiver,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    /// Opens a connection, resolving once peer has answered our SYN
+    ///
+    /// ## Error
+    /// Fails when peer doesn't answer after a few tries, or resets the connection
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let addr = canonical(addr);
+        let (packets, link) = {
+            let mut connections = self.shared.lock();
+            let recv_id = loop {
+                let id: u16 = random();
+                // Peer answers with our id, while we send with the one after it
+                if !connections.contains_key(&(addr, id)) && !connections.contains_key(&(addr, id.wrapping_add(1))) {
+                    break id;
+                }
+            };
+            let (sender, packets) = mpsc::channel(CONNECTION_QUEUE);
+            connections.insert((addr, recv_id), sender);
+            (packets, Link::new(Connection::outgoing(recv_id, Instant::now())))
+        };
+        tokio::spawn(drive(self.shared.clone(), addr, link.clone(), packets));
+
+        let stream = UtpStream::new(link, addr);
+        stream.connected().await?;
+        Ok(stream)
+    }
+
+    /// Waits for some peer to connect
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Key, mpsc::Sender<Packet>>> {
+        self.connections.lock().expect("uTP connection table poisoned")
+    }
+
+    async fn send(&self, packet: &Packet, addr: SocketAddr) {
+        // Dual stack sockets only talk in IPv6, with IPv4 peers mapped into it
+        let addr = match (self.socket.local_addr(), addr) {
+            (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+            _ => addr,
+        };
+        if let Err(err) = self.socket.send_to(&packet.encode(), addr).await {
+            eprintln!("\x1b[33mUTP | Sending to {addr} failed : {err}\x1b[0m");
+        }
+    }
+}
+
+/// Routes every datagram to the connection it belongs to, SYNs of new peers open new connections
+async fn receive(shared: Arc<Shared>, accepted: mpsc::Sender<UtpStream>) {
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (length, addr) = match shared.socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            // ICMP errors of earlier sends show up here on some platforms, nothing to do with the next datagram
+            Err(_) => continue,
+        };
+        let addr = canonical(addr);
+        let Ok(packet) = Packet::decode(Bytes::copy_from_slice(&buffer[..length])) else {
+            continue;
+        };
+
+        let is_syn = packet.kind == packet::Type::Syn;
+        // Peer we've accepted sends with SYN's id plus one
+        let key = (addr, if is_syn { packet.connection_id.wrapping_add(1) } else { packet.connection_id });
+        let existing = shared.lock().get(&key).cloned();
+        match existing {
+            Some(sender) => {
+                let _ = sender.try_send(packet);
+            }
+            None if is_syn => {
+                let Ok(permit) = accepted.try_reserve() else {
+                    continue;
+                };
+                let (sender, packets) = mpsc::channel(CONNECTION_QUEUE);
+                let link = Link::new(Connection::incoming(&packet, Instant::now()));
+                shared.lock().insert(key, sender);
+                tokio::spawn(drive(shared.clone(), addr, link.clone(), packets));
+                permit.send(UtpStream::new(link, addr));
+            }
+            // Leftover of a connection we're done with
+            None => {}
+        }
+    }
+}
+
+/// Runs a single connection : feeds it packets and timeouts, sends whatever it has to send,
+/// until it's finished
+async fn drive(shared: Arc<Shared>, addr: SocketAddr, link: Arc<Link>, mut packets: mpsc::Receiver<Packet>) {
+    let key = (addr, link.lock().recv_id());
+    loop {
+        let (outgoing, deadline, finished) = {
+            let mut connection = link.lock();
+            let outgoing = connection.poll_transmit(Instant::now());
+            (outgoing, connection.next_deadline(), connection.is_finished())
+        };
+        for packet in &outgoing {
+            shared.send(packet, addr).await;
+        }
+        if finished {
+            break;
+        }
+
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some(packet) = packet else { break };
+                link.lock().on_packet(packet, Instant::now());
+            }
+            _ = link.notify.notified() => {}
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                link.lock().on_timeout(Instant::now());
+            }
+        }
+    }
+    shared.lock().remove(&key);
+}
+
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+fn bind_dual_stack(port: u16) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn socket() -> (UtpSocket, SocketAddr) {
+        let socket = UtpSocket::bind(0).await.unwrap();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, socket.local_addr().unwrap().port()));
+        (socket, addr)
+    }
+
+    #[tokio::test]
+    async fn streams_carry_data_both_ways_until_shutdown() {
+        let (client, _) = socket().await;
+        let (server, server_addr) = socket().await;
+        let data: Vec<u8> = (0..2_000_000u32).map(|x| (x % 251) as u8).collect();
+
+        let (stream, accepted) = tokio::join!(client.connect(server_addr), server.accept());
+        let (mut stream, mut accepted) = (stream.unwrap(), accepted.unwrap());
+
+        let upload = async {
+            stream.write_all(&data).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        };
+        let echo = async {
+            let mut received = Vec::new();
+            accepted.read_to_end(&mut received).await.unwrap();
+            accepted.write_all(&received[..1000]).await.unwrap();
+            accepted.shutdown().await.unwrap();
+            received
+        };
+        let (echoed, received) = tokio::join!(upload, echo);
+
+        assert_eq!(received, data);
+        assert_eq!(echoed, data[..1000]);
+    }
+
+    #[tokio::test]
+    async fn many_connections_share_a_socket() {
+        let (client, _) = socket().await;
+        let (server, server_addr) = socket().await;
+
+        for index in 0..4u8 {
+            let (stream, accepted) = tokio::join!(client.connect(server_addr), server.accept());
+            let (mut stream, mut accepted) = (stream.unwrap(), accepted.unwrap());
+            stream.write_all(&[index; 10]).await.unwrap();
+            let mut received = [0u8; 10];
+            accepted.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, [index; 10]);
+        }
+    }
+}
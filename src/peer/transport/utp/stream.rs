@@ -0,0 +1,98 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+use crate::peer::transport::utp::connection::Connection;
+
+/// Connection state, shared between its stream and the task driving it
+#[derive(Debug)]
+pub(crate) struct Link {
+    connection: Mutex<Connection>,
+    /// Wakes driver up, whenever stream has something for it to send
+    pub notify: Notify,
+}
+
+impl Link {
+    pub fn new(connection: Connection) -> Arc<Self> {
+        Arc::new(Self {
+            connection: Mutex::new(connection),
+            notify: Notify::new(),
+        })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("uTP connection lock poisoned")
+    }
+}
+
+/// # [`UtpStream`]
+/// A single uTP connection, read and written to like a TCP stream.
+/// Dropping it closes the connection gracefully, in the background.
+#[derive(Debug)]
+pub struct UtpStream {
+    link: Arc<Link>,
+    peer_addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub(crate) fn new(link: Arc<Link>, peer_addr: SocketAddr) -> Self {
+        Self { link, peer_addr }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Resolves once peer has acked our SYN
+    pub(crate) async fn connected(&self) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.link.lock().poll_connected(cx)).await
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let poll = self.link.lock().poll_read(cx, buf);
+        poll.map_ok(|read| {
+            if read {
+                self.link.notify.notify_one();
+            }
+        })
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = self.link.lock().poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = poll {
+            self.link.notify.notify_one();
+        }
+        poll
+    }
+
+    /// Written bytes are already on their way, there's no buffer of ours to flush
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.link.lock().shutdown();
+        self.link.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.link.lock().detach();
+        self.link.notify.notify_one();
+    }
+}
@@ -0,0 +1,503 @@
+//! # Streaming
+//! Embedded HTTP server, handing files of torrents to media players while they download.
+//!
+//! Files are found at `/<info hash, in hex>/<file index>`, anything after is ignored
+//! (so players can see the file name, e.g. `/<info hash>/0/movie.mkv`).
+//! Range requests are honored, and bytes are only ever sent once their piece is verified and written :
+//! reads wait on the committer, while the picker is told to fetch what's being read first.
+use std::{io::SeekFrom, net::SocketAddr, path::Path};
+
+use tokio::{
+    fs::File,
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::torrent::{
+    FileLayout, Handle, InfoHash, Registry, StreamId, commit,
+    info::{FileMode, NormalisedInfo},
+};
+
+/// Port the streaming server listens on, on localhost, unless configured otherwise
+pub const DEFAULT_STREAM_PORT: u16 = 6880;
+/// Pieces ahead of the read position the picker is asked to fetch first, see [`crate::torrent::PiecePicker::stream_file`]
+const STREAM_WINDOW: usize = 8;
+/// Largest request head we read, players send a handful of headers at most
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(thiserror::Error, Debug)]
+enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Malformed request")]
+    BadRequest,
+
+    #[error("Only GET and HEAD are served")]
+    MethodNotAllowed,
+
+    #[error("No such torrent or file")]
+    NotFound,
+
+    #[error("Range is out of file")]
+    RangeNotSatisfiable { length: u64 },
+
+    #[error("Torrent has stopped")]
+    TorrentGone,
+}
+
+impl Error {
+    fn status(&self) -> &'static str {
+        match self {
+            Error::BadRequest => "400 Bad Request",
+            Error::NotFound => "404 Not Found",
+            Error::MethodNotAllowed => "405 Method Not Allowed",
+            Error::RangeNotSatisfiable { .. } => "416 Range Not Satisfiable",
+            Error::Io(_) | Error::TorrentGone => "500 Internal Server Error",
+        }
+    }
+}
+
+/// # [`StreamServer`]
+/// Serves files of every torrent in the registry over HTTP, see [module docs](self)
+pub struct StreamServer {
+    listener: TcpListener,
+    registry: Registry,
+}
+
+impl StreamServer {
+    pub async fn bind<A: ToSocketAddrs>(addr: A, registry: Registry) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            registry,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Where a player finds given file of a torrent
+    pub fn url(&self, info_hash: &InfoHash, file: usize) -> io::Result<String> {
+        Ok(format!(
+            "http://{}/{}/{file}",
+            self.local_addr()?,
+            hex::encode(info_hash.as_ref())
+        ))
+    }
+
+    /// Serves requests forever, each connection on its own task
+    pub async fn run(self) {
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("\x1b[31mSTREAM | Accept failed : {err}\x1b[0m");
+                    continue;
+                }
+            };
+            let registry = self.registry.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve(stream, registry).await {
+                    eprintln!("\x1b[033mSTREAM | {addr} : {err}\x1b[0m");
+                }
+            });
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    head_only: bool,
+    path: String,
+    range: Option<String>,
+}
+
+/// Answers a single request, then closes the connection
+async fn serve(stream: TcpStream, registry: Registry) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let result = async {
+        let request = read_request(&mut stream).await?;
+        let (torrent, layout, file) = find_file(&registry, &request.path).await?;
+        respond(stream.get_mut(), &request, &torrent, &layout, file).await
+    }
+    .await;
+    match result {
+        Err(Error::Io(err)) => Err(err.into()),
+        Err(err) => {
+            let extra = match err {
+                Error::RangeNotSatisfiable { length } => format!("Content-Range: bytes */{length}\r\n"),
+                _ => String::new(),
+            };
+            let head = format!(
+                "HTTP/1.1 {}\r\n{extra}Content-Length: 0\r\nConnection: close\r\n\r\n",
+                err.status()
+            );
+            stream.get_mut().write_all(head.as_bytes()).await?;
+            Err(err)
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Request> {
+    let mut lines = Vec::new();
+    let mut read = 0;
+    loop {
+        let mut line = String::new();
+        read += stream.read_line(&mut line).await?;
+        if read > MAX_HEAD_LEN || line.is_empty() {
+            return Err(Error::BadRequest);
+        }
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines.first().ok_or(Error::BadRequest)?.split(' ');
+    let head_only = match request_line.next() {
+        Some("GET") => false,
+        Some("HEAD") => true,
+        _ => return Err(Error::MethodNotAllowed),
+    };
+    let path = request_line.next().ok_or(Error::BadRequest)?.to_string();
+    let range = lines[1..].iter().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("range").then(|| value.trim().to_string())
+    });
+    Ok(Request { head_only, path, range })
+}
+
+/// Torrent and file a path points to, along with where torrent's files are on disk
+async fn find_file(registry: &Registry, path: &str) -> Result<(Handle, FileLayout, usize)> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    let info_hash: [u8; 20] = segments
+        .next()
+        .and_then(|hash| hex::decode(hash).ok()?.try_into().ok())
+        .ok_or(Error::NotFound)?;
+    let file: usize = segments
+        .next()
+        .and_then(|file| file.parse().ok())
+        .ok_or(Error::NotFound)?;
+    let torrent = registry.get(&info_hash.into()).await.ok_or(Error::NotFound)?;
+
+    // Same layout the committer writes by
+    let info = NormalisedInfo::try_new(&torrent.info, torrent.info_hash).map_err(|_| Error::NotFound)?;
+    let layout = FileLayout::try_from(&info).map_err(|_| Error::NotFound)?;
+    if file >= layout.files.len() {
+        return Err(Error::NotFound);
+    }
+    Ok((torrent, layout, file))
+}
+
+/// Bytes `first..=last` of a file `length` long, as asked for by a `Range` header.
+/// `None` when the whole file is wanted.
+fn parse_range(range: Option<&str>, length: u64) -> Result<Option<(u64, u64)>> {
+    let Some(range) = range else {
+        return Ok(None);
+    };
+    let unsatisfiable = Error::RangeNotSatisfiable { length };
+    // Several ranges at once is more than players ask for, first one is all they get
+    let spec = range
+        .strip_prefix("bytes=")
+        .and_then(|ranges| ranges.split(',').next())
+        .ok_or(Error::BadRequest)?;
+    let (first, last) = spec.trim().split_once('-').ok_or(Error::BadRequest)?;
+    let parse = |bound: &str| bound.parse::<u64>().map_err(|_| Error::BadRequest);
+    let (first, last) = match (first, last) {
+        ("", suffix) => {
+            let suffix = parse(suffix)?.min(length);
+            (length - suffix, length.checked_sub(1).ok_or(unsatisfiable)?)
+        }
+        (first, "") => (parse(first)?, length.saturating_sub(1)),
+        (first, last) => (parse(first)?, parse(last)?.min(length.saturating_sub(1))),
+    };
+    if first >= length || first > last {
+        return Err(Error::RangeNotSatisfiable { length });
+    }
+    Ok(Some((first, last)))
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    request: &Request,
+    torrent: &Handle,
+    layout: &FileLayout,
+    file: usize,
+) -> Result<()> {
+    let entry = &layout.files[file];
+    let range = parse_range(request.range.as_deref(), entry.length)?;
+    let (status, (first, last)) = match range {
+        Some(range) => ("206 Partial Content", range),
+        None => ("200 OK", (0, entry.length.saturating_sub(1))),
+    };
+    let length = match entry.length {
+        0 => 0,
+        _ => last - first + 1,
+    };
+    let content_range = match range {
+        Some((first, last)) => format!("Content-Range: bytes {first}-{last}/{}\r\n", entry.length),
+        None => String::new(),
+    };
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {}\r\nAccept-Ranges: bytes\r\n{content_range}Content-Length: {length}\r\nConnection: close\r\n\r\n",
+        content_type(file_name(torrent, file))
+    );
+    stream.write_all(head.as_bytes()).await?;
+    if request.head_only || length == 0 {
+        return Ok(());
+    }
+    send_body(stream, torrent, layout, file, first, last).await
+}
+
+/// Sends bytes `first..=last` of the file piece by piece, each one as soon as it's on disk.
+/// Pieces are wanted by the picker for as long as the player is there, their deadlines go along with it.
+async fn send_body(
+    stream: &mut TcpStream,
+    torrent: &Handle,
+    layout: &FileLayout,
+    file: usize,
+    first: u64,
+    last: u64,
+) -> Result<()> {
+    let id = torrent.picker.lock().await.open_stream();
+    let result = stream_body(stream, id, torrent, layout, file, first, last).await;
+    torrent.picker.lock().await.close_stream(id);
+    result
+}
+
+async fn stream_body(
+    stream: &mut TcpStream,
+    id: StreamId,
+    torrent: &Handle,
+    layout: &FileLayout,
+    file: usize,
+    first: u64,
+    last: u64,
+) -> Result<()> {
+    let entry = &layout.files[file];
+    let piece_length = torrent.info.piece_length as u64;
+    let mut commits = torrent.commit_events.subscribe();
+    // Opened once there's something to read, committer may not have created it yet
+    let mut disk: Option<File> = None;
+    let mut buffer = Vec::new();
+
+    let mut position = first;
+    while position <= last {
+        let index = ((entry.offset + position) / piece_length) as u32;
+        torrent
+            .picker
+            .lock()
+            .await
+            .stream_file(id, layout, file, position, STREAM_WINDOW);
+        // Player may give up on a piece that's slow to come, nothing's written until then to tell
+        tokio::select! {
+            waited = wait_for_piece(torrent, index, &mut commits) => waited?,
+            _ = hung_up(stream) => return Err(io::Error::from(io::ErrorKind::ConnectionAborted).into()),
+        }
+
+        let piece_end = (index as u64 + 1) * piece_length - entry.offset;
+        let end = piece_end.min(last + 1);
+        let disk = match &mut disk {
+            Some(disk) => disk,
+            None => disk.insert(File::open(&entry.path).await?),
+        };
+        buffer.resize((end - position) as usize, 0);
+        disk.seek(SeekFrom::Start(position)).await?;
+        disk.read_exact(&mut buffer).await?;
+        stream.write_all(&buffer).await?;
+        position = end;
+    }
+    Ok(())
+}
+
+/// Waits until piece is verified and written
+async fn wait_for_piece(torrent: &Handle, index: u32, commits: &mut broadcast::Receiver<commit::Event>) -> Result<()> {
+    loop {
+        if torrent.state.lock().await.have_piece(index) {
+            return Ok(());
+        }
+        match commits.recv().await {
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Err(Error::TorrentGone),
+        }
+    }
+}
+
+/// Resolves once the player has closed the connection, anything it sends after its request is ignored
+async fn hung_up(stream: &mut TcpStream) {
+    let mut buffer = [0; 512];
+    while let Ok(read) = stream.read(&mut buffer).await
+        && read > 0
+    {}
+}
+
+/// Name of a file, as in the torrent, files on disk have a `.tmp` extension instead
+fn file_name(torrent: &Handle, file: usize) -> &str {
+    match torrent.info.file_mode.as_ref() {
+        FileMode::Single { .. } => &torrent.info.name,
+        FileMode::Multiple { files } => files[file].path.last().map_or("", String::as_str),
+    }
+}
+
+/// Players mostly sniff the content anyway, common media types are enough
+fn content_type(name: &str) -> &'static str {
+    match Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .as_deref()
+    {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use serde_bytes::ByteBuf;
+    use serial_test::serial;
+    use sha1::{Digest, Sha1};
+    use tempfile::TempDir;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        peer::Bitfield,
+        torrent::{CommitJob, Info},
+    };
+
+    const PIECE_LEN: usize = 16 * 1024;
+
+    fn info(data: &[u8]) -> Info {
+        let pieces: Vec<u8> = data
+            .chunks(PIECE_LEN)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        Info {
+            name: "movie.mkv".to_string(),
+            piece_length: PIECE_LEN as u32,
+            pieces: ByteBuf::from(pieces),
+            file_mode: FileMode::Single {
+                length: data.len() as u64,
+            },
+            private: None,
+        }
+    }
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(parse_range(None, 100).unwrap(), None);
+        assert_eq!(parse_range(Some("bytes=10-19"), 100).unwrap(), Some((10, 19)));
+        assert_eq!(parse_range(Some("bytes=90-"), 100).unwrap(), Some((90, 99)));
+        assert_eq!(parse_range(Some("bytes=-10"), 100).unwrap(), Some((90, 99)));
+        assert_eq!(parse_range(Some("bytes=50-500"), 100).unwrap(), Some((50, 99)));
+        assert!(matches!(
+            parse_range(Some("bytes=100-"), 100),
+            Err(Error::RangeNotSatisfiable { length: 100 })
+        ));
+        assert!(matches!(parse_range(Some("lines=1-2"), 100), Err(Error::BadRequest)));
+        assert_eq!(content_type("movie.MKV"), "video/x-matroska");
+        assert_eq!(content_type("README"), "application/octet-stream");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn reads_wait_for_pieces_and_bump_them() {
+        let temp_dir = TempDir::new().unwrap();
+        let old_home = std::env::var("XDG_DATA_HOME");
+        unsafe {
+            std::env::set_var("XDG_DATA_HOME", temp_dir.path());
+        }
+
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let (torrent, mut committer) = Handle::fake_with(info(&data));
+        let registry = Registry::new();
+        registry.register(torrent.clone()).await;
+        let server = StreamServer::bind((Ipv4Addr::LOCALHOST, 0), registry).await.unwrap();
+        let url = server.url(&torrent.info_hash, 0).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::spawn(async move { committer.run().await });
+
+        let client = reqwest::Client::new();
+        let request = tokio::spawn({
+            let client = client.clone();
+            let url = format!("{url}/movie.mkv");
+            async move {
+                let response = client.get(url).header("Range", "bytes=20000-").send().await.unwrap();
+                assert_eq!(response.status(), 206);
+                assert_eq!(response.headers()["content-range"], "bytes 20000-39999/40000");
+                assert_eq!(response.headers()["content-type"], "video/x-matroska");
+                response.bytes().await.unwrap()
+            }
+        });
+
+        // Nothing's on disk, read is stuck on piece 1. Picker wants file ends first, then piece 1
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!request.is_finished());
+        let seeded = Bitfield::full(torrent.info.pieces.len() / 20);
+        let picked = torrent
+            .picker
+            .lock()
+            .await
+            .pick_blocks("10.0.0.1:6881".parse().unwrap(), &seeded, true, 3);
+        let mut order: Vec<u32> = picked.iter().map(|block| block.index).collect();
+        order[..2].sort();
+        assert_eq!(order, [0, 2, 1]);
+
+        for index in [2, 1] {
+            let start = index * PIECE_LEN;
+            let bytes = data[start..(start + PIECE_LEN).min(data.len())].to_vec();
+            torrent
+                .commit_tx
+                .send(CommitJob {
+                    index: index as u32,
+                    bytes: bytes.into(),
+                })
+                .await
+                .unwrap();
+        }
+        let body = timeout(Duration::from_secs(5), request).await.unwrap().unwrap();
+        assert_eq!(body, data[20_000..]);
+        // Reader is gone, so are its deadlines
+        assert!(torrent.picker.lock().await.deadline(0).is_none());
+
+        // So are the ones of a player hanging up while its piece is still missing
+        let mut player = TcpStream::connect(server_addr).await.unwrap();
+        let path = format!("/{}/0", hex::encode(torrent.info_hash.as_ref()));
+        let request = format!("GET {path} HTTP/1.1\r\n\r\n");
+        player.write_all(request.as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(torrent.picker.lock().await.deadline(0).is_some());
+        drop(player);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(torrent.picker.lock().await.deadline(0).is_none());
+
+        let response = client.get(&url).header("Range", "bytes=50000-").send().await.unwrap();
+        assert_eq!(response.status(), 416);
+        let response = client.get(format!("{url}9")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        unsafe {
+            match old_home {
+                Ok(x) => std::env::set_var("XDG_DATA_HOME", x),
+                Err(_) => std::env::remove_var("XDG_DATA_HOME"),
+            }
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::sync::{Mutex, watch};
+
+/// How often peers get rechoked
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// Rechokes an optimistic unchoke lasts, 30 seconds worth
+const OPTIMISTIC_ROUNDS: u32 = 3;
+/// Regular upload slots, unless configured otherwise
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// # [`PeerStats`]
+/// What a session tells the choker about its peer, updated as it goes, without locking anything
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    interested: AtomicBool,
+}
+
+impl PeerStats {
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn set_interested(&self, interested: bool) {
+        self.interested.store(interested, Ordering::Relaxed);
+    }
+
+    fn is_interested(&self) -> bool {
+        self.interested.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct Tracked {
+    stats: Arc<PeerStats>,
+    /// Counters as of last rechoke, what's moved since is what peers get ranked by
+    downloaded: u64,
+    uploaded: u64,
+}
+
+/// # [`Choker`]
+/// Decides which peers of a torrent we upload to, tit-for-tat :
+/// every [`RECHOKE_INTERVAL`], interested peers that gave us the most since last time get the upload slots
+/// (the ones we gave the most, once we're seeding). One more slot goes to a random peer every 30 seconds,
+/// the optimistic unchoke, so newcomers get a chance to prove themselves.
+///
+/// Sessions follow its decisions through [`Choker::subscribe`], they don't choke or unchoke on their own.
+#[derive(Debug)]
+pub struct Choker {
+    upload_slots: usize,
+    peers: HashMap<SocketAddr, Tracked>,
+    optimistic: Option<SocketAddr>,
+    /// Rechokes since optimistic unchoke last moved
+    rounds: u32,
+    unchoked: watch::Sender<HashSet<SocketAddr>>,
+}
+
+pub type AtomicChoker = Arc<Mutex<Choker>>;
+
+impl Default for Choker {
+    fn default() -> Self {
+        Self::new(DEFAULT_UPLOAD_SLOTS)
+    }
+}
+
+impl Choker {
+    /// Choker keeping given number of regular upload slots, besides the optimistic one
+    pub fn new(upload_slots: usize) -> Self {
+        Self {
+            upload_slots,
+            peers: HashMap::new(),
+            optimistic: None,
+            rounds: 0,
+            unchoked: watch::channel(HashSet::new()).0,
+        }
+    }
+
+    /// Consumes current `Choker` to give out atomic one, `Arc<Mutex<Choker>>`
+    pub fn atomic(self) -> AtomicChoker {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Peers unchoked as of latest decision, updated on every change
+    pub fn subscribe(&self) -> watch::Receiver<HashSet<SocketAddr>> {
+        self.unchoked.subscribe()
+    }
+
+    pub fn add_peer(&mut self, addr: SocketAddr, stats: Arc<PeerStats>) {
+        self.peers.insert(
+            addr,
+            Tracked {
+                stats,
+                downloaded: 0,
+                uploaded: 0,
+            },
+        );
+    }
+
+    /// Peer has left, its slot goes to someone else right away
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        if self.optimistic == Some(*addr) {
+            self.optimistic = None;
+        }
+        if self.unchoked.borrow().contains(addr) {
+            self.unchoked.send_modify(|unchoked| {
+                unchoked.remove(addr);
+            });
+        }
+    }
+
+    /// Peer got interested, it's unchoked right away if there's a slot to spare,
+    /// otherwise it waits for next rechoke
+    pub fn peer_interested(&mut self, addr: SocketAddr) {
+        let spare = self.unchoked.borrow().len() < self.upload_slots + 1;
+        if spare && self.peers.contains_key(&addr) {
+            self.unchoked.send_if_modified(|unchoked| unchoked.insert(addr));
+        }
+    }
+
+    /// Hands upload slots out again, ranking interested peers by what they've sent us since last time,
+    /// or by what we've sent them, when `seeding`
+    pub fn rechoke(&mut self, seeding: bool) {
+        let mut ranked: Vec<(u64, SocketAddr)> = self
+            .peers
+            .iter_mut()
+            .map(|(addr, tracked)| {
+                let downloaded = tracked.stats.downloaded.load(Ordering::Relaxed);
+                let uploaded = tracked.stats.uploaded.load(Ordering::Relaxed);
+                let moved = match seeding {
+                    true => uploaded - tracked.uploaded,
+                    false => downloaded - tracked.downloaded,
+                };
+                (tracked.downloaded, tracked.uploaded) = (downloaded, uploaded);
+                (moved, *addr, tracked.stats.is_interested())
+            })
+            .filter(|(_, _, interested)| *interested)
+            .map(|(moved, addr, _)| (moved, addr))
+            .collect();
+        ranked.sort_by(|a, b| b.cmp(a));
+
+        let mut unchoked: HashSet<SocketAddr> = ranked.iter().take(self.upload_slots).map(|(_, addr)| *addr).collect();
+
+        self.rounds += 1;
+        let optimistic_stays = self.optimistic.is_some_and(|addr| {
+            self.rounds < OPTIMISTIC_ROUNDS && !unchoked.contains(&addr) && self.peers[&addr].stats.is_interested()
+        });
+        if !optimistic_stays {
+            let choked: Vec<SocketAddr> = ranked
+                .iter()
+                .map(|(_, addr)| *addr)
+                .filter(|addr| !unchoked.contains(addr))
+                .collect();
+            self.optimistic = (!choked.is_empty()).then(|| choked[rand::random_range(0..choked.len())]);
+            self.rounds = 0;
+        }
+        unchoked.extend(self.optimistic);
+
+        self.unchoked.send_if_modified(|current| {
+            let changed = *current != unchoked;
+            *current = unchoked;
+            changed
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 6881))
+    }
+
+    /// Choker with given number of slots, and as many interested peers as there are stats
+    fn choker(slots: usize, downloaded: &[u64]) -> (Choker, Vec<Arc<PeerStats>>) {
+        let mut choker = Choker::new(slots);
+        let stats: Vec<_> = downloaded
+            .iter()
+            .enumerate()
+            .map(|(i, &bytes)| {
+                let stats = Arc::new(PeerStats::default());
+                stats.set_interested(true);
+                stats.add_downloaded(bytes);
+                choker.add_peer(peer(i as u8), stats.clone());
+                stats
+            })
+            .collect();
+        (choker, stats)
+    }
+
+    #[test]
+    fn best_uploaders_get_slots() {
+        let (mut choker, _) = choker(2, &[10, 500, 0, 300]);
+        let unchoked = choker.subscribe();
+        choker.rechoke(false);
+
+        let unchoked = unchoked.borrow();
+        assert!(unchoked.contains(&peer(1)) && unchoked.contains(&peer(3)));
+        // One of the others gets the optimistic slot
+        assert_eq!(unchoked.len(), 3);
+    }
+
+    #[test]
+    fn peers_are_ranked_by_what_they_sent_lately() {
+        let (mut choker, stats) = choker(1, &[1000, 0]);
+        choker.rechoke(false);
+        stats[1].add_downloaded(10);
+        choker.rechoke(false);
+
+        assert!(choker.subscribe().borrow().contains(&peer(1)));
+        assert_ne!(choker.optimistic, Some(peer(1)));
+    }
+
+    #[test]
+    fn seeding_ranks_by_upload() {
+        let (mut choker, stats) = choker(1, &[1000, 0]);
+        stats[1].add_uploaded(10);
+        choker.rechoke(true);
+
+        assert_eq!(choker.optimistic, Some(peer(0)));
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let (mut choker, stats) = choker(4, &[10, 10]);
+        stats[0].set_interested(false);
+        choker.rechoke(false);
+
+        assert_eq!(*choker.subscribe().borrow(), HashSet::from([peer(1)]));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_thirty_seconds() {
+        let (mut choker, _) = choker(0, &[0; 16]);
+        choker.rechoke(false);
+        let first = choker.optimistic.unwrap();
+        for _ in 1..OPTIMISTIC_ROUNDS {
+            choker.rechoke(false);
+            assert_eq!(choker.optimistic, Some(first));
+        }
+
+        let rotated = (0..8).any(|_| {
+            for _ in 0..OPTIMISTIC_ROUNDS {
+                choker.rechoke(false);
+            }
+            choker.optimistic != Some(first)
+        });
+        assert!(rotated);
+    }
+
+    #[test]
+    fn interested_peers_take_spare_slots_right_away() {
+        let (mut choker, _) = choker(1, &[0, 0, 0]);
+        choker.peer_interested(peer(0));
+        choker.peer_interested(peer(1));
+        choker.peer_interested(peer(2));
+
+        // One regular slot, one optimistic
+        assert_eq!(choker.subscribe().borrow().len(), 2);
+        choker.remove_peer(&peer(0));
+        assert!(!choker.subscribe().borrow().contains(&peer(0)));
+    }
+}
@@ -75,6 +75,23 @@ impl Committer {
         self.sender.clone()
     }
 
+    /// Gives out a cloned copy of event broadcaster,
+    /// handy for handing out listeners later on, without holding on to the committer
+    pub fn broadcaster(&self) -> broadcast::Sender<commit::Event> {
+        self.broadcast.clone()
+    }
+
+    /// Where torrent's files are, sessions read blocks they upload from there
+    pub fn file_layout(&self) -> Arc<FileLayout> {
+        self.file_layout.clone()
+    }
+
+    /// Next job sessions have sent, without committing it
+    #[cfg(test)]
+    pub(crate) async fn next_job(&mut self) -> Option<Job> {
+        self.reciever.recv().await
+    }
+
     /// Allocates storage to a file, if it doesn't already exist
     pub(crate) async fn init_storage(&self) -> commit::Result<()> {
         let path = self.base_dir()?;
@@ -140,6 +157,8 @@ impl Committer {
 
             f.seek(SeekFrom::Start(file_offset)).await?;
             f.write_all(&job.bytes[start..end]).await?;
+            // Tokio writes in the background, piece must be on disk before anyone's told about it
+            f.flush().await?;
         }
 
         Ok(())
@@ -147,7 +166,7 @@ impl Committer {
 
     pub async fn update_save_state(&self, index: u32) -> commit::Result<()> {
         let mut state = self.state.lock().await;
-        state.mark_piece_complete(index);
+        state.mark_piece_complete(index)?;
         state.save().await?;
         Ok(())
     }
@@ -174,7 +193,7 @@ impl Committer {
                 self.update_save_state(job.index).await?;
                 self.broadcast.send(Event::PieceCommit(job.index))?;
             } else {
-                self.broadcast.send(Event::FailedCommit)?;
+                self.broadcast.send(Event::FailedCommit(job.index))?;
             }
         }
         eprintln!("\x1b[31mCommitter EXITing, all senders dropped");
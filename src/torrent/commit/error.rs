@@ -1,7 +1,7 @@
 use std::io;
 use tokio::sync::broadcast::error::SendError;
 
-use crate::torrent::commit;
+use crate::{peer::BitfieldError, torrent::commit};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -11,6 +11,8 @@ pub enum Error {
     BaseDirectoryNotFound,
     #[error(transparent)]
     SendErr(#[from] SendError<commit::Event>),
+    #[error(transparent)]
+    Bitfield(#[from] BitfieldError),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
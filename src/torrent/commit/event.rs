@@ -1,5 +1,6 @@
 #[derive(Clone, Debug)]
 pub enum Event {
     PieceCommit(u32),
-    FailedCommit,
+    /// Piece couldn't be written, it has to be downloaded again
+    FailedCommit(u32),
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{self, error::SendError},
+    },
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{
+    bandwidth::{Bandwidth, TorrentBandwidth},
+    peer::{Connection, PeerSession, Piece},
+    torrent::{
+        choker::RECHOKE_INTERVAL, commit, info::AtomicInfo, AtomicChoker, AtomicPeerPool, AtomicPiecePicker,
+        AtomicState, CommitJob, Committer, FileLayout, InfoHash,
+    },
+};
+
+/// # [`TorrentStats`]
+/// Bytes a torrent has moved, over every session and web seed of it, what trackers get told
+#[derive(Debug, Default)]
+pub struct TorrentStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+}
+
+impl TorrentStats {
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+}
+
+/// # [`Handle`]
+/// Everything a session needs to take part in a torrent, cheap to clone.
+#[derive(Clone)]
+pub struct Handle {
+    pub info_hash: InfoHash,
+    pub info: AtomicInfo,
+    pub state: AtomicState,
+    pub pool: AtomicPeerPool,
+    pub picker: AtomicPiecePicker,
+    pub choker: AtomicChoker,
+    /// Torrent's own bandwidth limits, along with the global ones, unlimited until [`Handle::with_bandwidth`]
+    pub bandwidth: TorrentBandwidth,
+    pub totals: Arc<TorrentStats>,
+    /// Where torrent's files are, as committer writes them
+    pub(crate) layout: Arc<FileLayout>,
+    pub(crate) commit_tx: mpsc::Sender<CommitJob>,
+    pub(crate) commit_events: broadcast::Sender<commit::Event>,
+}
+
+impl Handle {
+    pub fn new(
+        info_hash: InfoHash,
+        info: AtomicInfo,
+        state: AtomicState,
+        pool: AtomicPeerPool,
+        picker: AtomicPiecePicker,
+        choker: AtomicChoker,
+        committer: &Committer,
+    ) -> Self {
+        Self {
+            info_hash,
+            info,
+            state,
+            pool,
+            picker,
+            choker,
+            bandwidth: Bandwidth::default().torrent(info_hash, None, None),
+            totals: Arc::new(TorrentStats::default()),
+            layout: committer.file_layout(),
+            commit_tx: committer.sender(),
+            commit_events: committer.broadcaster(),
+        }
+    }
+
+    /// Puts torrent's connections through global `bandwidth` limits, and its own download and upload ones
+    pub fn with_bandwidth(mut self, bandwidth: &Bandwidth, download: Option<u64>, upload: Option<u64>) -> Self {
+        self.bandwidth = bandwidth.torrent(self.info_hash, download, upload);
+        self
+    }
+
+    /// Builds a session over an already handshaked connection
+    pub fn session(&self, connection: Connection) -> PeerSession {
+        PeerSession::new(connection, self)
+    }
+
+    /// Hash checks a piece some session or web seed has put together : good ones go to committer,
+    /// bad ones back to picker. Peers picker blames for bad blocks get a strike in pool,
+    /// and the ones striking out get banned.
+    ///
+    /// Returns who's blamed for the piece failing, nobody when it's passed.
+    ///
+    /// ## Error
+    /// Fails when committer is gone
+    pub(crate) async fn check_piece(&self, piece: Piece) -> Result<HashSet<SocketAddr>, SendError<CommitJob>> {
+        let index = piece.index();
+        let (failed, blamed) = if piece.verify(&self.info.pieces).is_err() {
+            let blamed = self.picker.lock().await.hash_failed(piece);
+            eprintln!("\x1b[31mPiece {index} failed hash check, blaming {blamed:?}\x1b[0m");
+            (true, blamed)
+        } else {
+            eprintln!("\x1b[35m\x1b[1mDownloaded piece : {index} + VERIFIED CHECKSUM!!!\x1b[0m");
+            let blamed = self.picker.lock().await.verified(&piece);
+            self.commit_tx.send(CommitJob::from(piece)).await?;
+            (false, blamed)
+        };
+
+        let mut pool = self.pool.lock().await;
+        for peer in &blamed {
+            if pool.record_hash_failure(peer) {
+                eprintln!("\x1b[31mBanned {peer}, sent too many bad blocks\x1b[0m");
+            }
+        }
+        Ok(match failed {
+            true => blamed,
+            false => HashSet::new(),
+        })
+    }
+
+    /// Rechokes torrent's peers every [`RECHOKE_INTERVAL`], for as long as the torrent runs
+    pub async fn run_choker(self) {
+        let mut interval = time::interval(RECHOKE_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let seeding = self.state.lock().await.is_complete();
+            self.choker.lock().await.rechoke(seeding);
+        }
+    }
+}
+
+#[cfg(test)]
+impl Handle {
+    /// Handle of [`crate::torrent::Metadata::fake`], a single piece torrent.
+    /// Its committer never runs, commit events channel stays open as long as the handle lives.
+    pub(crate) fn fake() -> Self {
+        Self::fake_with(crate::torrent::Metadata::fake().info).0
+    }
+
+    /// Handle of a torrent made of `info`, along with its committer, that never runs
+    pub(crate) fn fake_with(info: crate::torrent::Info) -> (Self, Committer) {
+        use std::sync::Arc;
+
+        use tokio::sync::Mutex;
+
+        use crate::torrent::{info::NormalisedInfo, Choker, FileLayout, Metadata, PeerPool, PiecePicker, State};
+
+        let mut metadata = Metadata::fake();
+        metadata.info = info;
+        let state = State::try_from(&metadata).unwrap();
+        let picker = PiecePicker::new(&metadata.info, &state).atomic();
+        let state = Arc::new(Mutex::new(state));
+        let normalised = NormalisedInfo::try_from(&metadata).unwrap().atomic();
+        let layout = FileLayout::try_from(normalised.as_ref()).unwrap().atomic();
+        let committer = Committer::new(state.clone(), metadata.info_hash, normalised, layout);
+
+        let handle = Self::new(
+            metadata.info_hash,
+            metadata.info.atomic(),
+            state,
+            PeerPool::new().atomic(),
+            picker,
+            Choker::default().atomic(),
+            &committer,
+        );
+        (handle, committer)
+    }
+}
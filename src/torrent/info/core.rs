@@ -19,6 +19,10 @@ pub struct Info {
 
     #[serde(flatten)]
     pub(crate) file_mode : FileMode,
+
+    /// BEP 27, private torrents only get their peers from trackers
+    #[serde(default, with = "crate::bencode::optional")]
+    pub(crate) private: Option<u8>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -54,6 +58,11 @@ impl Info {
         }
     }
 
+    /// Whether torrent is marked private, peers shouldn't be exchanged for such torrents
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
     /// Consumes info and gives out [`AtomicInfo`] (aliased [`Arc<Info>`])
     pub fn atomic(self) -> AtomicInfo {
         Arc::new(self)
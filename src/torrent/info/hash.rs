@@ -7,7 +7,7 @@ use std::{
 
 use crate::torrent::RawInfo;
 
-#[derive(Default, Deserialize, Clone, Copy, Serialize)]
+#[derive(Default, Deserialize, Clone, Copy, Serialize, PartialEq, Eq, Hash)]
 pub struct InfoHash {
     hash: [u8; 20],
 }
@@ -1,6 +1,10 @@
-use std::{path::{Path, PathBuf}, sync::Arc};
+use std::{io::SeekFrom, path::{Path, PathBuf}, sync::Arc};
 
 use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{self, AsyncReadExt, AsyncSeekExt},
+};
 
 use crate::torrent::{self, info::{FileMode, NormalisedInfo}};
 
@@ -49,6 +53,26 @@ impl FileLayout {
     }
 
 
+    /// Reads `length` bytes of the torrent from `offset` on, across every file they span.
+    /// Only pieces that are committed have anything worth reading.
+    pub(crate) async fn read(&self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        let end = offset + length as u64;
+        let mut buffer = vec![0u8; length];
+        for file in self.files.iter() {
+            let read_start = offset.max(file.offset);
+            let read_end = end.min(file.offset + file.length);
+            if read_start >= read_end {
+                continue;
+            }
+
+            let mut f = File::open(&file.path).await?;
+            f.seek(SeekFrom::Start(read_start - file.offset)).await?;
+            let start = (read_start - offset) as usize;
+            f.read_exact(&mut buffer[start..start + (read_end - read_start) as usize]).await?;
+        }
+        Ok(buffer)
+    }
+
     /// **Consumes** FileLayout and returns an Arc<FileLayout>
     pub(crate) fn atomic(self) -> AtomicFileLayout {
         Arc::new(self)
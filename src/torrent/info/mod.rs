@@ -4,7 +4,7 @@ pub mod layout;
 mod normalised;
 mod file_mode;
 
-pub use core::{Info, RawInfo};
+pub use core::{AtomicInfo, Info, RawInfo};
 pub use hash::InfoHash;
 pub use normalised::NormalisedInfo;
 pub(crate) use core::InfoFile;
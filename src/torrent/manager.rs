@@ -0,0 +1,217 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    time::{self, timeout},
+};
+
+use crate::{
+    config::Config,
+    peer::{Connection, Connector, Handshake, Peer},
+    torrent::Handle,
+};
+
+/// Time a peer gets to accept our connection and answer our handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the pool is looked at again, for new peers and peers done backing off
+const REFILL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// # [`ConnectionLimits`]
+/// Caps shared by every torrent, and by the [`crate::peer::Listener`] : connections open at once,
+/// and connections we're still dialing. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimits {
+    connections: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(config.max_connections)),
+            half_open: Arc::new(Semaphore::new(config.max_half_open)),
+        }
+    }
+
+    /// Slot for one more connection, held for as long as it's open, `None` when at the limit
+    pub fn try_connection(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    fn try_half_open(&self) -> Option<OwnedSemaphorePermit> {
+        self.half_open.clone().try_acquire_owned().ok()
+    }
+}
+
+/// # [`ConnectionManager`]
+/// Keeps a torrent connected to as many peers as it's allowed : dials the best candidates of its pool
+/// (see [`crate::torrent::PeerPool::candidates`]) within configured limits, runs a session with each one
+/// that answers, and dials more as sessions end.
+pub struct ConnectionManager {
+    torrent: Handle,
+    connector: Connector,
+    limits: ConnectionLimits,
+    config: Config,
+    /// Woken whenever a connection attempt or a session is over
+    ended: Arc<Notify>,
+}
+
+impl ConnectionManager {
+    pub fn new(torrent: Handle, connector: Connector, limits: ConnectionLimits, config: &Config) -> Self {
+        Self {
+            torrent,
+            connector,
+            limits,
+            config: config.clone(),
+            ended: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Refills torrent's connections every [`REFILL_INTERVAL`], or as soon as one is over, for as long as the torrent runs
+    pub async fn run(self) {
+        loop {
+            self.refill().await;
+            tokio::select! {
+                _ = time::sleep(REFILL_INTERVAL) => {}
+                _ = self.ended.notified() => {}
+            }
+        }
+    }
+
+    /// Dials candidates until torrent has as many peers as allowed, or global limits are hit.
+    /// Returns how many got dialed.
+    pub async fn refill(&self) -> usize {
+        let candidates: Vec<Peer> = {
+            let pool = self.torrent.pool.lock().await;
+            let room = self
+                .config
+                .max_peers_per_torrent
+                .saturating_sub(pool.connected().count() + pool.half_open());
+            pool.candidates().into_iter().take(room).collect()
+        };
+
+        let mut dialed = 0;
+        for peer in candidates {
+            let Some(half_open) = self.limits.try_half_open() else {
+                break;
+            };
+            let Some(permit) = self.limits.try_connection() else {
+                break;
+            };
+            // Incoming peers may have taken the room meanwhile
+            if !self.torrent.pool.lock().await.reserve(peer, self.config.max_peers_per_torrent) {
+                break;
+            }
+            tokio::spawn(connect(
+                peer,
+                self.torrent.clone(),
+                self.connector.clone(),
+                self.config.clone(),
+                (half_open, permit),
+                self.ended.clone(),
+            ));
+            dialed += 1;
+        }
+        dialed
+    }
+}
+
+/// Dials the peer and runs a session with it, holding on to its connection slot meanwhile.
+/// Its half-open slot is given back as soon as handshakes are over.
+async fn connect(
+    peer: Peer,
+    torrent: Handle,
+    connector: Connector,
+    config: Config,
+    (half_open, _permit): (OwnedSemaphorePermit, OwnedSemaphorePermit),
+    ended: Arc<Notify>,
+) {
+    let connection = timeout(CONNECT_TIMEOUT, async {
+        let mut connection = Connection::connect_with(peer, &torrent.info_hash, &connector).await.ok()?;
+        connection.handshake(Handshake::new(&torrent.info_hash)).await.ok()?;
+        Some(connection)
+    })
+    .await
+    .ok()
+    .flatten();
+    drop(half_open);
+
+    match connection {
+        None => torrent.pool.lock().await.connect_failed(&peer.addr),
+        Some(mut connection) => {
+            connection.set_max_message_len(config.max_message_len);
+            let mut session = torrent.session(connection);
+            session.set_pipeline_limits(config.pipeline);
+            if let Err(err) = session.run().await {
+                eprintln!("\x1b[033mMANAGER | {} : {err}\x1b[0m", peer.addr);
+            }
+            // Session was refused before it started (duplicate or banned peer), that's no peer to dial again soon
+            let mut pool = torrent.pool.lock().await;
+            if pool.get(&peer.addr).is_some_and(|entry| entry.connecting) {
+                pool.connect_failed(&peer.addr);
+            }
+        }
+    }
+    ended.notify_one();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::torrent::PeerSource;
+
+    fn config(max_half_open: usize) -> Config {
+        Config {
+            enable_utp: false,
+            encryption: crate::peer::encryption::Policy::Disabled,
+            max_half_open,
+            ..Default::default()
+        }
+    }
+
+    fn manager(config: &Config) -> ConnectionManager {
+        ConnectionManager::new(Handle::fake(), Connector::new(config), ConnectionLimits::new(config), config)
+    }
+
+    #[tokio::test]
+    async fn unreachable_peers_back_off() {
+        // Port nobody listens on anymore
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap().local_addr().unwrap().port();
+        let manager = manager(&config(8));
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, port);
+        manager.torrent.pool.lock().await.insert(peer, PeerSource::Tracker);
+
+        assert_eq!(manager.refill().await, 1);
+        manager.ended.notified().await;
+
+        let pool = manager.torrent.pool.lock().await;
+        let entry = pool.get(&peer.addr).unwrap();
+        assert_eq!(entry.failures, 1);
+        assert!(!entry.connecting);
+        assert!(pool.candidates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dialing_stops_at_half_open_limit() {
+        // Accepts connections, never answers handshakes
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let manager = manager(&config(2));
+        {
+            let mut pool = manager.torrent.pool.lock().await;
+            for last in 1..=3 {
+                pool.insert(Peer::new(Ipv4Addr::new(127, 0, 0, last), port), PeerSource::Tracker);
+            }
+        }
+
+        assert_eq!(manager.refill().await, 2);
+        assert_eq!(manager.torrent.pool.lock().await.half_open(), 2);
+        assert_eq!(manager.refill().await, 0);
+        assert_eq!(manager.torrent.pool.lock().await.candidates().len(), 1);
+        drop(listener);
+    }
+}
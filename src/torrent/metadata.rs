@@ -86,6 +86,7 @@ impl Metadata {
                 name: "fake".to_string(),
                 piece_length : piece_length as u32,
                 pieces: ByteBuf::from(vec![0u8; 20]),
+                private: None,
             },
             info_hash: InfoHash::from([0u8; 20]),
             info_byte: RawInfo(vec![]),
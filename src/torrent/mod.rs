@@ -1,14 +1,29 @@
 pub(crate) mod commit;
+pub mod choker;
 mod error;
+mod handle;
+pub mod manager;
 pub mod info;
 pub mod metadata;
+pub mod picker;
+pub mod pool;
+mod priority;
+mod registry;
 mod state;
+pub mod webseed;
 
+pub use choker::{AtomicChoker, Choker, PeerStats};
 pub use commit::{CommitEvent, Committer, Error as CommitError, Job as CommitJob};
 pub use error::{Error, Result};
+pub use handle::{Handle, TorrentStats};
 pub use info::Info;
 pub use info::InfoHash;
 pub use info::RawInfo;
 pub use info::layout::FileLayout;
+pub use manager::{ConnectionLimits, ConnectionManager};
 pub use metadata::Metadata;
-pub use state::State;
+pub use picker::{AtomicPiecePicker, PiecePicker, Priority, StreamId};
+pub use pool::{AtomicPeerPool, PeerPool, Source as PeerSource};
+pub use registry::Registry;
+pub use state::{AtomicState, State};
+pub use webseed::WebSeed;
@@ -0,0 +1,953 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    ops::RangeInclusive,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    sync::{broadcast, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    peer::{Bitfield, BlockRequest, Piece, PieceError, PipelineLimits, Received},
+    torrent::{info::layout::FileEntry, FileLayout, Info, State},
+};
+
+/// How eager we are to get a file, pieces take the highest priority among files they overlap
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Never downloaded, unless some other file shares the piece
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// # [`PiecePicker`]
+/// Decides which blocks sessions download next, for a whole torrent.
+/// Keeps count of how many connected peers have each piece, and goes for the rarest one first,
+/// so pieces don't vanish along with the last peer having them.
+///
+/// Pieces are assembled here, block by block, so several peers can work on one.
+/// Fast peers take whole pieces to themselves, slow ones rather help finishing pieces already started,
+/// so a slow peer doesn't hold up a piece all on its own.
+///
+/// Pieces can be wanted in order instead, for the whole torrent or some files (see [`PiecePicker::set_sequential`]),
+/// and pieces with a deadline come before anything else (see [`PiecePicker::stream_file`]).
+/// Deadline pieces are never taken by a single peer, their blocks are spread across every fast peer having them.
+///
+/// Once every block left is requested, it's endgame : blocks go out to several peers at once,
+/// whichever copy arrives first is kept, and the other peers are told to cancel, see [`PiecePicker::subscribe_cancels`].
+#[derive(Debug)]
+pub struct PiecePicker {
+    piece_length: u32,
+    total_length: u64,
+    block_len: u32,
+    /// Connected peers having each piece
+    availability: Vec<u32>,
+    have: Bitfield,
+    priorities: Vec<Priority>,
+    /// Pieces picked in order, lowest index first, rather than rarest first
+    sequential: Vec<bool>,
+    /// Pieces wanted by some time, soonest first
+    deadlines: HashMap<u32, Instant>,
+    /// Deadlines of each stream reading the torrent, see [`PiecePicker::stream_file`]
+    streams: HashMap<StreamId, HashMap<u32, Instant>>,
+    next_stream: StreamId,
+    /// Pieces being assembled
+    downloading: HashMap<u32, Piece>,
+    /// Pieces complete and verified, waiting on committer
+    committing: HashSet<u32>,
+    /// Failed copies of pieces several peers had a hand in, kept until a good copy tells who sent the bad blocks
+    parole: HashMap<u32, Piece>,
+    cancels: broadcast::Sender<Cancel>,
+    /// Bytes received that were of no use, copies of blocks some other peer sent first
+    wasted: u64,
+}
+
+/// Most peers a block is requested from at once, in endgame
+pub const ENDGAME_COPIES: usize = 3;
+
+/// Time between deadlines of consecutive pieces of a streaming window, see [`PiecePicker::stream_file`]
+pub const STREAM_PIECE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Some reader of the torrent, as given out by [`PiecePicker::open_stream`]
+pub type StreamId = u64;
+
+/// Block a peer was asked for, that some other peer has sent already
+pub type Cancel = (SocketAddr, BlockRequest);
+
+/// What became of a block handed to [`PiecePicker::store`]
+#[derive(Debug)]
+pub enum Stored {
+    /// Piece still needs some other blocks
+    Partial,
+    /// That was the last block, piece is taken out for hash check
+    Complete(Piece),
+    /// Piece isn't being downloaded (anymore), or block was received already, it's wasted
+    Unwanted,
+}
+
+pub type AtomicPiecePicker = Arc<Mutex<PiecePicker>>;
+
+impl PiecePicker {
+    /// Pieces we already have in `state` are never picked
+    pub fn new(info: &Info, state: &State) -> Self {
+        let num_pieces = info.pieces.len() / 20;
+        Self {
+            piece_length: info.piece_length,
+            total_length: info.total_length(),
+            block_len: PipelineLimits::default().block_len(),
+            availability: vec![0; num_pieces],
+            have: state.bit_field.clone(),
+            priorities: vec![Priority::default(); num_pieces],
+            sequential: vec![false; num_pieces],
+            deadlines: HashMap::new(),
+            streams: HashMap::new(),
+            next_stream: 0,
+            downloading: HashMap::new(),
+            committing: HashSet::new(),
+            parole: HashMap::new(),
+            cancels: broadcast::channel(256).0,
+            wasted: 0,
+        }
+    }
+
+    /// Pieces get split into blocks of given length, see [`crate::peer::PipelineLimits::block_len`]
+    pub fn with_block_len(mut self, block_len: u32) -> Self {
+        self.block_len = block_len;
+        self
+    }
+
+    /// Consumes current `PiecePicker` to give out atomic one, `Arc<Mutex<PiecePicker>>`
+    pub fn atomic(self) -> AtomicPiecePicker {
+        Arc::new(Mutex::new(self))
+    }
+
+    fn num_pieces(&self) -> usize {
+        self.availability.len()
+    }
+
+    fn piece_len(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length as u64;
+        ((start + self.piece_length as u64).min(self.total_length) - start) as u32
+    }
+
+    /// Blocks a whole piece is split into
+    pub fn blocks_per_piece(&self) -> usize {
+        self.piece_length.div_ceil(self.block_len) as usize
+    }
+
+    /// Pieces a file spans, `None` for empty files
+    fn file_pieces(&self, file: &FileEntry) -> Option<RangeInclusive<u32>> {
+        if file.length == 0 {
+            return None;
+        }
+        let first = file.offset / self.piece_length as u64;
+        let last = (file.offset + file.length - 1) / self.piece_length as u64;
+        Some(first as u32..=last.min(self.num_pieces() as u64 - 1) as u32)
+    }
+
+    /// Sets priority of every file, in the order of layout
+    pub fn set_file_priorities(&mut self, layout: &FileLayout, priorities: &[Priority]) {
+        self.priorities.fill(Priority::Skip);
+        for (file, &priority) in layout.files.iter().zip(priorities) {
+            for piece in self.file_pieces(file).into_iter().flatten() {
+                let current = &mut self.priorities[piece as usize];
+                *current = (*current).max(priority);
+            }
+        }
+    }
+
+    /// Whether pieces of the whole torrent get picked in order, lowest index first, rather than rarest first
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential.fill(sequential);
+    }
+
+    /// Same as [`PiecePicker::set_sequential`], for a single file of layout.
+    /// Sequential files come before the rest, among files of the same priority.
+    pub fn set_file_sequential(&mut self, layout: &FileLayout, file: usize, sequential: bool) {
+        if let Some(file) = layout.files.get(file) {
+            for piece in self.file_pieces(file).into_iter().flatten() {
+                self.sequential[piece as usize] = sequential;
+            }
+        }
+    }
+
+    /// Piece is wanted by given time, it's picked before any piece without a deadline, soonest first
+    pub fn set_deadline(&mut self, index: u32, deadline: Instant) {
+        if !self.is_wanted(index as usize) {
+            return;
+        }
+        self.deadlines.insert(index, deadline);
+        self.share_piece(index);
+    }
+
+    /// Some peer may have taken a piece with a deadline to itself, it's everyone's now
+    fn share_piece(&mut self, index: u32) {
+        if let Some(piece) = self.downloading.get_mut(&index)
+            && !piece.on_parole
+        {
+            piece.owner = None;
+        }
+    }
+
+    /// Drops every deadline, streams' ones included
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+        self.streams.clear();
+    }
+
+    /// Earliest deadline of a piece, whoever set it
+    pub fn deadline(&self, index: u32) -> Option<Instant> {
+        self.streams
+            .values()
+            .chain([&self.deadlines])
+            .filter_map(|deadlines| deadlines.get(&index).copied())
+            .min()
+    }
+
+    /// Every piece with a deadline, along with its earliest one
+    fn due(&self) -> HashMap<u32, Instant> {
+        let mut due = self.deadlines.clone();
+        for (&index, &deadline) in self.streams.values().flatten() {
+            due.entry(index)
+                .and_modify(|due| *due = (*due).min(deadline))
+                .or_insert(deadline);
+        }
+        due
+    }
+
+    /// New reader of the torrent, its deadlines are kept apart from other readers' ones,
+    /// until [`PiecePicker::close_stream`]
+    pub fn open_stream(&mut self) -> StreamId {
+        self.next_stream += 1;
+        self.next_stream
+    }
+
+    /// Reader is gone, pieces it wanted are no more due (unless some other reader wants them)
+    pub fn close_stream(&mut self, stream: StreamId) {
+        self.streams.remove(&stream);
+    }
+
+    /// Streams a file of layout, read from `position` (in bytes, within the file) on :
+    /// its first and last pieces are due right away, players look for headers and indexes there,
+    /// then `window` pieces from `position` on, each due [`STREAM_PIECE_INTERVAL`] after the one before.
+    /// Called again as playback moves, the window slides along, deadlines of stream's earlier calls are dropped.
+    /// Other streams keep theirs.
+    pub fn stream_file(&mut self, stream: StreamId, layout: &FileLayout, file: usize, position: u64, window: usize) {
+        let Some((file, pieces)) = layout
+            .files
+            .get(file)
+            .and_then(|file| Some((file, self.file_pieces(file)?)))
+        else {
+            self.close_stream(stream);
+            return;
+        };
+        let now = Instant::now();
+        let mut deadlines = HashMap::from([(*pieces.start(), now), (*pieces.end(), now)]);
+        let current = ((file.offset + position.min(file.length - 1)) / self.piece_length as u64) as u32;
+        for (i, index) in (current..=*pieces.end()).take(window).enumerate() {
+            // File ends are due already
+            deadlines
+                .entry(index)
+                .or_insert(now + STREAM_PIECE_INTERVAL * (i as u32 + 1));
+        }
+        deadlines.retain(|&index, _| self.is_wanted(index as usize));
+        for &index in deadlines.keys() {
+            self.share_piece(index);
+        }
+        self.streams.insert(stream, deadlines);
+    }
+
+    /// Counts in the pieces of a peer's bitfield
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.iter_set() {
+            if let Some(count) = self.availability.get_mut(piece) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Counts out the pieces of a peer's bitfield, when peer leaves or replaces it
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.iter_set() {
+            if let Some(count) = self.availability.get_mut(piece) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Some peer announced a piece it didn't have before
+    pub fn peer_has(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability[index as usize]
+    }
+
+    fn is_wanted(&self, piece: usize) -> bool {
+        !has_piece(&self.have, piece) && self.priorities[piece] != Priority::Skip
+    }
+
+    /// Whether peer has anything we still want, whether someone's downloading it or not
+    pub fn is_interesting(&self, bitfield: &Bitfield) -> bool {
+        (0..self.num_pieces()).any(|piece| self.is_wanted(piece) && has_piece(bitfield, piece))
+    }
+
+    /// Picks up to `count` blocks for a peer to download, in this order :
+    /// 0. Pieces with a deadline, soonest first, only overdue ones for slow peers
+    /// 1. Rest of the pieces peer has taken to itself
+    /// 2. Pieces others have started, when peer is slow
+    /// 3. New pieces, rarest first (fast peers take them to themselves)
+    /// 4. Pieces others have started, whoever's on them, rather than sitting idle
+    /// 5. In endgame, blocks other peers are on already
+    pub fn pick_blocks(
+        &mut self,
+        peer: SocketAddr,
+        bitfield: &Bitfield,
+        fast: bool,
+        count: usize,
+    ) -> Vec<BlockRequest> {
+        let mut blocks = Vec::with_capacity(count);
+        self.take_deadlines(&mut blocks, count, peer, bitfield, fast);
+        self.take_blocks(&mut blocks, count, peer, |piece| piece.owner == Some(peer));
+        // Deadline pieces slow peers are kept off, see take_deadlines
+        let now = Instant::now();
+        let held_back: HashSet<u32> = self
+            .due()
+            .iter()
+            .filter(|&(_, &deadline)| !fast && deadline > now)
+            .map(|(&index, _)| index)
+            .collect();
+        if !fast {
+            self.take_blocks(&mut blocks, count, peer, |piece| {
+                piece.owner.is_none()
+                    && has_piece(bitfield, piece.index() as usize)
+                    && !held_back.contains(&piece.index())
+            });
+        }
+        while blocks.len() < count
+            && let Some(index) = self.pick_new(bitfield)
+        {
+            let mut piece = Piece::new(index, self.piece_len(index), self.block_len);
+            piece.on_parole = self.parole.contains_key(&index);
+            piece.owner = (fast || piece.on_parole).then_some(peer);
+            while blocks.len() < count
+                && let Some(block) = piece.request_next(peer)
+            {
+                blocks.push(block);
+            }
+            self.downloading.insert(index, piece);
+        }
+        self.take_blocks(&mut blocks, count, peer, |piece| {
+            !piece.on_parole && has_piece(bitfield, piece.index() as usize) && !held_back.contains(&piece.index())
+        });
+        if blocks.len() < count && self.is_endgame() {
+            self.take_copies(&mut blocks, count, peer, bitfield);
+        }
+        blocks
+    }
+
+    /// Whether every block we still want has been requested from someone
+    pub fn is_endgame(&self) -> bool {
+        (0..self.num_pieces()).all(|piece| {
+            let index = piece as u32;
+            !self.is_wanted(piece)
+                || self.committing.contains(&index)
+                || self.downloading.get(&index).is_some_and(|piece| !piece.has_pending())
+        })
+    }
+
+    /// Requests blocks of pieces with a deadline, soonest first, starting them as need be.
+    /// They're left without owner, so every fast peer gets to help.
+    /// Slow peers would only hold them up, they get the overdue ones only.
+    fn take_deadlines(
+        &mut self,
+        blocks: &mut Vec<BlockRequest>,
+        count: usize,
+        peer: SocketAddr,
+        bitfield: &Bitfield,
+        fast: bool,
+    ) {
+        let now = Instant::now();
+        let mut due: Vec<(Instant, u32)> = self
+            .due()
+            .iter()
+            .filter(|&(&index, &deadline)| {
+                (fast || deadline <= now)
+                    && has_piece(bitfield, index as usize)
+                    && !self.committing.contains(&index)
+                    && !self.parole.contains_key(&index)
+            })
+            .map(|(&index, &deadline)| (deadline, index))
+            .collect();
+        due.sort();
+        for (_, index) in due {
+            if blocks.len() >= count {
+                return;
+            }
+            let (piece_len, block_len) = (self.piece_len(index), self.block_len);
+            let piece = self
+                .downloading
+                .entry(index)
+                .or_insert_with(|| Piece::new(index, piece_len, block_len));
+            while blocks.len() < count
+                && let Some(block) = piece.request_next(peer)
+            {
+                blocks.push(block);
+            }
+        }
+    }
+
+    /// Requests blocks other peers are on already, upto [`ENDGAME_COPIES`] peers per block
+    fn take_copies(&mut self, blocks: &mut Vec<BlockRequest>, count: usize, peer: SocketAddr, bitfield: &Bitfield) {
+        let mut candidates: Vec<&mut Piece> = self
+            .downloading
+            .values_mut()
+            .filter(|piece| !piece.on_parole && has_piece(bitfield, piece.index() as usize))
+            .collect();
+        candidates.sort_by_key(|piece| (std::cmp::Reverse(piece.progress().0), piece.index()));
+        for piece in candidates {
+            while blocks.len() < count
+                && let Some(block) = piece.request_copy(peer, ENDGAME_COPIES)
+            {
+                blocks.push(block);
+            }
+        }
+    }
+
+    /// Requests pending blocks of pieces being downloaded, the ones closest to completion first
+    fn take_blocks(
+        &mut self,
+        blocks: &mut Vec<BlockRequest>,
+        count: usize,
+        peer: SocketAddr,
+        filter: impl Fn(&Piece) -> bool,
+    ) {
+        if blocks.len() >= count {
+            return;
+        }
+        let mut candidates: Vec<&mut Piece> = self
+            .downloading
+            .values_mut()
+            .filter(|piece| piece.has_pending() && filter(piece))
+            .collect();
+        candidates.sort_by_key(|piece| (std::cmp::Reverse(piece.progress().0), piece.index()));
+        for piece in candidates {
+            while blocks.len() < count
+                && let Some(block) = piece.request_next(peer)
+            {
+                blocks.push(block);
+            }
+        }
+    }
+
+    /// Rarest piece peer has, that nobody has started yet, or lowest one, for sequential pieces.
+    /// Higher priority comes first, then sequential pieces.
+    /// Ties are broken at random, so peers don't all pile onto the same piece.
+    fn pick_new(&self, bitfield: &Bitfield) -> Option<u32> {
+        let mut best = None;
+        let mut ties = 0;
+        for piece in 0..self.num_pieces() {
+            let index = piece as u32;
+            if !self.is_wanted(piece)
+                || !has_piece(bitfield, piece)
+                || self.downloading.contains_key(&index)
+                || self.committing.contains(&index)
+                || self.deadline(index).is_some()
+            {
+                continue;
+            }
+            let order = match self.sequential[piece] {
+                true => index,
+                false => self.availability[piece],
+            };
+            let rank = (self.priorities[piece], self.sequential[piece], std::cmp::Reverse(order));
+            match best {
+                Some((best_rank, _)) if rank < best_rank => continue,
+                Some((best_rank, _)) if rank == best_rank => {
+                    ties += 1;
+                    if rand::random_range(0..ties) != 0 {
+                        continue;
+                    }
+                }
+                _ => ties = 1,
+            }
+            best = Some((rank, index));
+        }
+        best.map(|(_, index)| index)
+    }
+
+    /// Stores a block peer has sent.
+    /// Other peers the block was requested from get a [`Cancel`] for it.
+    /// The last block of a piece takes it out, to be hash checked then committed.
+    ///
+    /// ## Error
+    /// Fails when block doesn't line up with the piece
+    pub fn store(&mut self, peer: SocketAddr, index: u32, offset: u32, data: &[u8]) -> Result<Stored, PieceError> {
+        let Some(piece) = self.downloading.get_mut(&index) else {
+            self.wasted += data.len() as u64;
+            return Ok(Stored::Unwanted);
+        };
+        match piece.store(peer, offset, data)? {
+            Received::Duplicate => {
+                self.wasted += data.len() as u64;
+                return Ok(Stored::Unwanted);
+            }
+            Received::Fresh { others } => {
+                let block = BlockRequest {
+                    index,
+                    offset,
+                    length: data.len() as u32,
+                };
+                for other in others {
+                    // Nobody listening is fine, the copy is just wasted
+                    let _ = self.cancels.send((other, block));
+                }
+            }
+        }
+        if !piece.is_complete() {
+            return Ok(Stored::Partial);
+        }
+        self.committing.insert(index);
+        let piece = self.downloading.remove(&index).expect("Piece was just stored into");
+        Ok(Stored::Complete(piece))
+    }
+
+    /// Blocks some peer has sent first, that other peers were asked for too.
+    /// Sessions pick their own peer's out, and cancel them.
+    pub fn subscribe_cancels(&self) -> broadcast::Receiver<Cancel> {
+        self.cancels.subscribe()
+    }
+
+    /// Bytes received so far that were of no use
+    pub fn wasted(&self) -> u64 {
+        self.wasted
+    }
+
+    /// Puts back every block peer was asked for, and lets go of pieces it has taken to itself
+    pub fn abort_peer(&mut self, peer: SocketAddr) {
+        for piece in self.downloading.values_mut() {
+            piece.abort_peer(peer);
+        }
+        self.forget_unstarted();
+    }
+
+    /// Puts back a single block peer was asked for, for other peers to take
+    pub fn abort_block(&mut self, peer: SocketAddr, block: BlockRequest) {
+        if let Some(piece) = self.downloading.get_mut(&block.index) {
+            piece.abort_block(peer, block.offset);
+        }
+        self.forget_unstarted();
+    }
+
+    /// Pieces nobody is on are as good as never picked, dropping them lets rarest first pick them afresh.
+    /// Pieces on parole that lost their owner start over too, someone else's blocks would muddle the blame.
+    fn forget_unstarted(&mut self) {
+        self.downloading
+            .retain(|_, piece| piece.is_started() && !(piece.on_parole && piece.owner.is_none()));
+    }
+
+    /// Takes back a piece that failed its hash check, it's downloaded all over again.
+    /// Returns the peers to blame for it : the one that sent it alone, if so.
+    ///
+    /// Pieces put together by several peers go on parole instead. They're downloaded again by a single peer,
+    /// and once a good copy comes along, [`PiecePicker::verified`] tells who sent the bad blocks.
+    pub fn hash_failed(&mut self, piece: Piece) -> HashSet<SocketAddr> {
+        let index = piece.index();
+        self.committing.remove(&index);
+        let contributors = piece.contributors();
+        if contributors.len() > 1 {
+            self.parole.entry(index).or_insert(piece);
+            return HashSet::new();
+        }
+        contributors
+    }
+
+    /// Piece has passed its hash check. Returns the peers that had sent bad blocks of it,
+    /// when it's come out of parole.
+    pub fn verified(&mut self, piece: &Piece) -> HashSet<SocketAddr> {
+        self.parole
+            .remove(&piece.index())
+            .map(|failed| failed.corrupted_by(piece))
+            .unwrap_or_default()
+    }
+
+    /// Piece got written to disk, it's never picked again
+    pub fn mark_have(&mut self, index: u32) {
+        // Pieces past the last one were never downloading either
+        let _ = self.have.update(index);
+        self.committing.remove(&index);
+        self.downloading.remove(&index);
+        self.parole.remove(&index);
+        self.deadlines.remove(&index);
+        for deadlines in self.streams.values_mut() {
+            deadlines.remove(&index);
+        }
+    }
+
+    /// Piece was downloaded but couldn't be committed, it has to be downloaded all over again
+    pub fn abandon(&mut self, index: u32) {
+        self.committing.remove(&index);
+    }
+
+    /// Whether some peer is on the piece, or it's waiting to be committed
+    pub fn is_downloading(&self, index: u32) -> bool {
+        self.downloading.contains_key(&index) || self.committing.contains(&index)
+    }
+}
+
+fn has_piece(bitfield: &Bitfield, piece: usize) -> bool {
+    bitfield.has(piece).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::torrent::{
+        info::{layout::FileEntry, FileMode},
+        Metadata,
+    };
+
+    const PIECE_LEN: u32 = 4 * 16 * 1024;
+
+    /// Torrent with given number of pieces, four blocks each
+    fn metadata(num_pieces: usize) -> Metadata {
+        let mut metadata = Metadata::fake();
+        metadata.info.piece_length = PIECE_LEN;
+        metadata.info.pieces = vec![0u8; num_pieces * 20].into();
+        metadata.info.file_mode = FileMode::Single {
+            length: num_pieces as u64 * PIECE_LEN as u64,
+        };
+        metadata
+    }
+
+    fn picker(num_pieces: usize) -> PiecePicker {
+        let metadata = metadata(num_pieces);
+        PiecePicker::new(&metadata.info, &State::try_from(&metadata).unwrap())
+    }
+
+    /// Bitfield of a peer, out of its first byte
+    fn bits(picker: &PiecePicker, byte: u8) -> Bitfield {
+        let mut bitfield = Bitfield::new(picker.num_pieces());
+        bitfield.update_from_peer([byte]).unwrap();
+        bitfield
+    }
+
+    fn peer(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 6881))
+    }
+
+    fn indices(blocks: &[BlockRequest]) -> Vec<u32> {
+        blocks.iter().map(|block| block.index).collect()
+    }
+
+    /// Sends every block of given requests, as the peer
+    fn deliver(picker: &mut PiecePicker, peer: SocketAddr, blocks: &[BlockRequest]) -> Vec<Stored> {
+        blocks
+            .iter()
+            .map(|block| {
+                let data = vec![0; block.length as usize];
+                picker.store(peer, block.index, block.offset, &data).unwrap()
+            })
+            .collect()
+    }
+
+    /// Two files, the first one ends half way into piece 2, the second one takes the rest
+    fn two_files() -> FileLayout {
+        let file = |offset, length| FileEntry {
+            path: PathBuf::new(),
+            length,
+            offset,
+        };
+        FileLayout {
+            files: vec![file(0, 5 * PIECE_LEN as u64 / 2), file(5 * PIECE_LEN as u64 / 2, 11 * PIECE_LEN as u64 / 2)],
+        }
+    }
+
+    #[test]
+    fn rarest_piece_comes_first() {
+        let mut picker = picker(8);
+        picker.add_peer(&bits(&picker, 0b1111_1111));
+        picker.add_peer(&bits(&picker, 0b1101_1111));
+        picker.add_peer(&bits(&picker, 0b1100_1111));
+
+        // Piece 2 is held by one peer, piece 3 by two
+        assert_eq!(indices(&picker.pick_blocks(peer(1), &bits(&picker, 0xff), true, 4)), [2; 4]);
+        assert_eq!(indices(&picker.pick_blocks(peer(2), &bits(&picker, 0xff), true, 4)), [3; 4]);
+        assert!(picker.is_downloading(2) && picker.is_downloading(3));
+    }
+
+    #[test]
+    fn ties_are_broken_at_random() {
+        let picked: HashSet<u32> = (0..64)
+            .map(|_| {
+                let mut picker = picker(8);
+                picker.add_peer(&bits(&picker, 0xff));
+                picker.pick_blocks(peer(1), &bits(&picker, 0xff), true, 1)[0].index
+            })
+            .collect();
+        assert!(picked.len() > 1);
+    }
+
+    #[test]
+    fn pieces_we_have_or_peer_lacks_are_never_picked() {
+        let metadata = metadata(8);
+        let mut state = State::try_from(&metadata).unwrap();
+        state.mark_piece_complete(0).unwrap();
+        let mut picker = PiecePicker::new(&metadata.info, &state);
+
+        assert!(!picker.is_interesting(&bits(&picker, 0b1000_0000)));
+        assert!(picker.pick_blocks(peer(1), &bits(&picker, 0b1000_0000), true, 4).is_empty());
+
+        let blocks = picker.pick_blocks(peer(1), &bits(&picker, 0b1100_0000), true, 8);
+        assert_eq!(indices(&blocks), [1; 4]);
+        assert!(matches!(deliver(&mut picker, peer(1), &blocks).pop(), Some(Stored::Complete(_))));
+
+        picker.mark_have(1);
+        assert!(!picker.is_downloading(1));
+        assert!(!picker.is_interesting(&bits(&picker, 0b1100_0000)));
+    }
+
+    #[test]
+    fn file_priorities_are_honored() {
+        let mut picker = picker(8);
+        picker.set_file_priorities(&two_files(), &[Priority::Skip, Priority::High]);
+        picker.add_peer(&bits(&picker, 0b0000_0001));
+
+        let picked = indices(&picker.pick_blocks(peer(1), &bits(&picker, 0xff), true, 6 * 4));
+        // Pieces 0 and 1 belong to skipped file only
+        assert!(picked.iter().all(|&index| (2..8).contains(&index)));
+        assert_eq!(picked.len(), 6 * 4);
+        assert!(!picker.is_interesting(&bits(&picker, 0b1100_0000)));
+    }
+
+    #[test]
+    fn slow_peers_help_finish_started_pieces() {
+        let mut picker = picker(8);
+        let started = picker.pick_blocks(peer(1), &bits(&picker, 0xff), false, 2);
+
+        // Slow peer joins the piece, fast one takes a whole new piece
+        let joined = picker.pick_blocks(peer(2), &bits(&picker, 0xff), false, 2);
+        assert_eq!(indices(&joined), indices(&started));
+        let fresh = picker.pick_blocks(peer(3), &bits(&picker, 0xff), true, 2);
+        assert_ne!(fresh[0].index, started[0].index);
+
+        // Nobody else joins a piece a fast peer took, while there's something new to pick
+        let other = picker.pick_blocks(peer(4), &bits(&picker, 0xff), false, 2);
+        assert!(other.iter().all(|block| block.index != fresh[0].index));
+    }
+
+    #[test]
+    fn pieces_are_assembled_from_several_peers() {
+        let mut picker = picker(1);
+        let first = picker.pick_blocks(peer(1), &bits(&picker, 0x80), false, 2);
+        let second = picker.pick_blocks(peer(2), &bits(&picker, 0x80), false, 2);
+        assert!(picker.is_endgame());
+
+        assert!(matches!(deliver(&mut picker, peer(1), &first)[..], [Stored::Partial, Stored::Partial]));
+        let Some(Stored::Complete(piece)) = deliver(&mut picker, peer(2), &second).pop() else {
+            panic!("Piece should be complete");
+        };
+        assert_eq!(piece.contributors(), HashSet::from([peer(1), peer(2)]));
+
+        // Failing hash check, every block is downloaded again, nobody's to blame just yet
+        assert!(picker.hash_failed(piece).is_empty());
+        assert!(!picker.is_downloading(0));
+        assert_eq!(picker.pick_blocks(peer(3), &bits(&picker, 0x80), false, 8).len(), 4);
+    }
+
+    #[test]
+    fn lone_senders_of_bad_pieces_are_blamed() {
+        let mut picker = picker(1);
+        let blocks = picker.pick_blocks(peer(1), &bits(&picker, 0x80), true, 8);
+        let Some(Stored::Complete(piece)) = deliver(&mut picker, peer(1), &blocks).pop() else {
+            panic!("Piece should be complete");
+        };
+        assert_eq!(picker.hash_failed(piece), HashSet::from([peer(1)]));
+    }
+
+    #[test]
+    fn pieces_on_parole_find_the_culprit() {
+        let mut picker = picker(1);
+        let first = picker.pick_blocks(peer(1), &bits(&picker, 0x80), false, 2);
+        let second = picker.pick_blocks(peer(2), &bits(&picker, 0x80), false, 2);
+        deliver(&mut picker, peer(1), &first);
+        deliver(&mut picker, peer(2), &second[..1]);
+        let bad = second[1];
+        let Ok(Stored::Complete(failed)) = picker.store(peer(2), 0, bad.offset, &vec![1; bad.length as usize]) else {
+            panic!("Piece should be complete");
+        };
+        assert!(picker.hash_failed(failed).is_empty());
+
+        // Whoever takes it next downloads it alone, slow or not
+        assert_eq!(picker.pick_blocks(peer(3), &bits(&picker, 0x80), false, 1).len(), 1);
+        assert!(picker.pick_blocks(peer(4), &bits(&picker, 0x80), false, 8).is_empty());
+        // Starts over once its peer leaves
+        picker.abort_peer(peer(3));
+        assert!(!picker.is_downloading(0));
+
+        let retry = picker.pick_blocks(peer(4), &bits(&picker, 0x80), false, 8);
+        let Some(Stored::Complete(good)) = deliver(&mut picker, peer(4), &retry).pop() else {
+            panic!("Piece should be complete");
+        };
+        assert_eq!(picker.verified(&good), HashSet::from([peer(2)]));
+        assert!(picker.verified(&good).is_empty());
+    }
+
+    #[test]
+    fn blocks_of_leaving_peers_go_to_others() {
+        let mut picker = picker(1);
+        let blocks = picker.pick_blocks(peer(1), &bits(&picker, 0x80), true, 2);
+        deliver(&mut picker, peer(1), &blocks[..1]);
+        picker.abort_peer(peer(1));
+
+        let taken = picker.pick_blocks(peer(2), &bits(&picker, 0x80), false, 8);
+        assert_eq!(taken.len(), 3);
+        assert!(taken.contains(&blocks[1]));
+    }
+
+    #[test]
+    fn late_blocks_go_to_others() {
+        let mut picker = picker(1);
+        let blocks = picker.pick_blocks(peer(1), &bits(&picker, 0x80), false, 2);
+        picker.abort_block(peer(1), blocks[0]);
+
+        // Piece isn't peer 1's own anymore, and its late block is up for grabs along with the pending ones
+        let taken = picker.pick_blocks(peer(2), &bits(&picker, 0x80), false, 3);
+        assert_eq!(taken.len(), 3);
+        assert!(taken.contains(&blocks[0]));
+        assert!(!taken.contains(&blocks[1]));
+    }
+
+    #[test]
+    fn endgame_requests_blocks_from_several_peers() {
+        let mut picker = picker(1);
+        let first = picker.pick_blocks(peer(1), &bits(&picker, 0x80), true, 8);
+        assert!(picker.is_endgame());
+
+        let copies = picker.pick_blocks(peer(2), &bits(&picker, 0x80), false, 8);
+        assert_eq!(copies, first);
+        picker.pick_blocks(peer(3), &bits(&picker, 0x80), false, 8);
+        // Every block is on fly with as many peers as it gets
+        assert!(picker.pick_blocks(peer(4), &bits(&picker, 0x80), false, 8).is_empty());
+    }
+
+    #[test]
+    fn first_copy_cancels_the_rest() {
+        let mut picker = picker(1);
+        let mut cancels = picker.subscribe_cancels();
+        let blocks = picker.pick_blocks(peer(1), &bits(&picker, 0x80), true, 1);
+        picker.pick_blocks(peer(1), &bits(&picker, 0x80), true, 8);
+        picker.pick_blocks(peer(2), &bits(&picker, 0x80), false, 1);
+
+        assert!(matches!(deliver(&mut picker, peer(2), &blocks)[..], [Stored::Partial]));
+        assert_eq!(cancels.try_recv().unwrap(), (peer(1), blocks[0]));
+
+        // Peer didn't get to cancel in time
+        assert!(matches!(deliver(&mut picker, peer(1), &blocks)[..], [Stored::Unwanted]));
+        assert_eq!(picker.wasted(), blocks[0].length as u64);
+    }
+
+    #[test]
+    fn availability_follows_peers() {
+        let mut picker = picker(8);
+        picker.add_peer(&bits(&picker, 0b1000_0000));
+        picker.peer_has(1);
+        assert_eq!((picker.availability(0), picker.availability(1)), (1, 1));
+
+        picker.remove_peer(&bits(&picker, 0b1100_0000));
+        assert_eq!((picker.availability(0), picker.availability(1)), (0, 0));
+    }
+
+    #[test]
+    fn sequential_pieces_come_in_order() {
+        let mut picker = picker(8);
+        picker.add_peer(&bits(&picker, 0xff));
+        picker.add_peer(&bits(&picker, 0b0000_1111));
+        picker.set_sequential(true);
+
+        let picked = indices(&picker.pick_blocks(peer(1), &bits(&picker, 0xff), true, 3 * 4));
+        assert_eq!(picked, [[0; 4], [1; 4], [2; 4]].concat());
+    }
+
+    #[test]
+    fn sequential_files_come_before_the_rest() {
+        let mut picker = picker(8);
+        picker.add_peer(&bits(&picker, 0xff));
+        picker.set_file_sequential(&two_files(), 1, true);
+
+        let picked = indices(&picker.pick_blocks(peer(1), &bits(&picker, 0xff), true, 6 * 4));
+        assert_eq!(picked, [[2; 4], [3; 4], [4; 4], [5; 4], [6; 4], [7; 4]].concat());
+    }
+
+    #[test]
+    fn deadline_pieces_are_spread_across_fast_peers() {
+        let mut picker = picker(8);
+        picker.add_peer(&bits(&picker, 0xff));
+        picker.set_deadline(5, Instant::now() + Duration::from_secs(60));
+        picker.set_deadline(6, Instant::now() + Duration::from_secs(30));
+
+        // Soonest first, and nobody takes it to itself
+        assert_eq!(indices(&picker.pick_blocks(peer(1), &bits(&picker, 0xff), true, 2)), [6, 6]);
+        assert_eq!(indices(&picker.pick_blocks(peer(2), &bits(&picker, 0xff), true, 4)), [6, 6, 5, 5]);
+        // Slow peers are kept off deadlines until they're overdue, this one can't start piece 7 either
+        assert!(!indices(&picker.pick_blocks(peer(3), &bits(&picker, 0xfe), false, 4)).contains(&5));
+        picker.set_deadline(7, Instant::now());
+        assert_eq!(indices(&picker.pick_blocks(peer(4), &bits(&picker, 0xff), false, 1)), [7]);
+
+        picker.mark_have(7);
+        assert!(picker.deadline(7).is_none());
+    }
+
+    #[test]
+    fn streaming_wants_file_ends_then_a_window_ahead() {
+        let mut picker = picker(8);
+        picker.add_peer(&bits(&picker, 0xff));
+        // Second file spans pieces 2 to 7, playback is in piece 4
+        let stream = picker.open_stream();
+        picker.stream_file(stream, &two_files(), 1, 2 * PIECE_LEN as u64, 2);
+
+        let picked = indices(&picker.pick_blocks(peer(1), &bits(&picker, 0xff), true, 4 * 4));
+        let mut order: Vec<u32> = picked.chunks(4).map(|piece| piece[0]).collect();
+        order[..2].sort();
+        assert_eq!(order, [2, 7, 4, 5]);
+
+        // Playback moves on, window follows
+        picker.stream_file(stream, &two_files(), 1, 4 * PIECE_LEN as u64, 2);
+        let mut due: Vec<u32> = picker.due().into_keys().collect();
+        due.sort();
+        assert_eq!(due, [2, 6, 7]);
+    }
+
+    #[test]
+    fn streams_keep_their_own_deadlines() {
+        let mut picker = picker(8);
+        // One player reads the first file, another one the second file, in piece 5
+        let (first, second) = (picker.open_stream(), picker.open_stream());
+        picker.stream_file(first, &two_files(), 0, 0, 1);
+        picker.stream_file(second, &two_files(), 1, 3 * PIECE_LEN as u64, 1);
+        picker.stream_file(first, &two_files(), 0, PIECE_LEN as u64, 1);
+        let mut due: Vec<u32> = picker.due().into_keys().collect();
+        due.sort();
+        assert_eq!(due, [0, 1, 2, 5, 7]);
+
+        // Piece 2 both want stays due for the first one
+        picker.close_stream(second);
+        let mut due: Vec<u32> = picker.due().into_keys().collect();
+        due.sort();
+        assert_eq!(due, [0, 1, 2]);
+        picker.close_stream(first);
+        assert!(picker.due().is_empty());
+    }
+}
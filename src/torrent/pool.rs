@@ -0,0 +1,593 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    sync::{broadcast, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    config,
+    peer::{Peer, PexFlags, Violation},
+    torrent::priority::canonical_priority,
+};
+
+/// Where did we hear about a peer from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Tracker,
+    /// Learnt from another peer through `ut_pex`
+    Pex,
+    /// Peer connected to us on its own
+    Incoming,
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub peer: Peer,
+    pub source: Source,
+    pub flags: PexFlags,
+    pub connected: bool,
+    /// Every protocol violation that has ended a session with this peer
+    pub violations: Vec<Violation>,
+    /// Pieces failing their hash check that this peer is known to have sent bad blocks of
+    pub hash_failures: u32,
+    /// Port peer takes connections on, as told in its extension handshake (`p`)
+    pub listen_port: Option<u16>,
+    /// We're dialing it, connection isn't handshaked yet
+    pub connecting: bool,
+    /// Connection attempts that failed in a row, see [`PeerPool::connect_failed`]
+    pub failures: u32,
+    /// Peer isn't dialed before then
+    pub retry_at: Option<Instant>,
+}
+
+/// Hash failures a peer is allowed, unless configured otherwise
+pub const DEFAULT_BAN_THRESHOLD: u32 = 3;
+/// Wait after the first failed connection attempt, doubling with every one after
+const BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+/// Failed attempts in a row after which a peer is given up on
+pub const MAX_CONNECT_FAILURES: u32 = 6;
+/// Wait before dialing a peer again, after its session has ended
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Peers that have to tell us the same address before we take it as ours
+const EXTERNAL_IP_VOTES: usize = 3;
+
+/// # [`PeerPool`]
+/// Every peer a torrent knows about, along with where it came from
+/// and whether a session is currently running with it.
+#[derive(Debug)]
+pub struct PeerPool {
+    peers: HashMap<SocketAddr, Entry>,
+    /// Hash failures that get a peer banned
+    ban_threshold: u32,
+    /// Hash failures of each address, whatever port peers came from
+    strikes: HashMap<IpAddr, u32>,
+    /// Addresses that sent too many bad blocks, never connected to again, nor let in.
+    /// Peers coming to us do so from a new port every time, the port can't tell who's banned.
+    banned: HashSet<IpAddr>,
+    bans: broadcast::Sender<IpAddr>,
+    /// Our address as peers see it, connections are prioritised by it, see [`PeerPool::candidates`]
+    external_ip: Option<IpAddr>,
+    /// Peers that told us each address, see [`PeerPool::vote_external_ip`]
+    ip_votes: HashMap<IpAddr, HashSet<SocketAddr>>,
+    /// Port we take peers on, the other half of our address
+    listen_port: u16,
+}
+
+impl Default for PeerPool {
+    fn default() -> Self {
+        Self {
+            peers: HashMap::new(),
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+            bans: broadcast::channel(16).0,
+            external_ip: None,
+            ip_votes: HashMap::new(),
+            listen_port: config::DEFAULT_LISTEN_PORT,
+        }
+    }
+}
+
+pub type AtomicPeerPool = Arc<Mutex<PeerPool>>;
+
+impl PeerPool {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Peers get banned once they're blamed for this many hash failures, see [`crate::config::Config::max_hash_failures`]
+    pub fn with_ban_threshold(mut self, threshold: u32) -> Self {
+        self.ban_threshold = threshold;
+        self
+    }
+
+    /// Port we take peers on, see [`crate::config::Config::listen_port`]
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = port;
+        self
+    }
+
+    /// Consumes current `PeerPool` to give out atomic one, `Arc<Mutex<PeerPool>>`
+    pub fn atomic(self) -> AtomicPeerPool {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Adds a peer to the pool, returns whether it wasn't known before.
+    /// Already known peers keep their original source.
+    pub fn insert(&mut self, peer: Peer, source: Source) -> bool {
+        self.insert_with_flags(peer, source, PexFlags::default())
+    }
+
+    /// Same as [`PeerPool::insert`], also merging flags (as sent in `ut_pex`) into the known ones
+    pub fn insert_with_flags(&mut self, peer: Peer, source: Source, flags: PexFlags) -> bool {
+        match self.peers.get_mut(&peer.addr) {
+            Some(entry) => {
+                entry.flags |= flags;
+                false
+            }
+            None => {
+                self.peers.insert(
+                    peer.addr,
+                    Entry {
+                        peer,
+                        source,
+                        flags,
+                        connected: false,
+                        violations: Vec::new(),
+                        hash_failures: 0,
+                        listen_port: None,
+                        connecting: false,
+                        failures: 0,
+                        retry_at: None,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Adds every peer from given source, returns how many of them were new
+    pub fn extend<T>(&mut self, peers: T, source: Source) -> usize
+    where
+        T: IntoIterator<Item = Peer>,
+    {
+        peers
+            .into_iter()
+            .filter(|peer| self.insert(*peer, source))
+            .count()
+    }
+
+    /// Marks the peer as connected, replacing flags with what the session knows of it.
+    ///
+    /// Returns `false` without touching anything when a session is already running
+    /// with the same address, or with the same peer id.
+    pub fn mark_connected(&mut self, peer: Peer, flags: PexFlags) -> bool {
+        if self.is_connected(&peer) {
+            return false;
+        }
+        self.insert(peer, Source::Incoming);
+        let entry = self.peers.get_mut(&peer.addr).expect("Peer was inserted right above");
+        entry.peer.id = peer.id;
+        entry.connected = true;
+        entry.connecting = false;
+        entry.failures = 0;
+        entry.flags = flags;
+        true
+    }
+
+    /// Whether a session is running with this peer, matched either by address or by peer id
+    pub fn is_connected(&self, peer: &Peer) -> bool {
+        self.peers
+            .values()
+            .filter(|entry| entry.connected)
+            .any(|entry| entry.peer.addr == peer.addr || (peer.id.is_some() && entry.peer.id == peer.id))
+    }
+
+    /// Replaces flags of a known peer
+    pub fn update_flags(&mut self, addr: &SocketAddr, flags: PexFlags) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.flags = flags;
+        }
+    }
+
+    /// Peer told us the port it takes connections on, see [`PeerPool::pex_peers`]
+    pub fn set_listen_port(&mut self, addr: &SocketAddr, port: u16) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.listen_port = Some(port);
+        }
+    }
+
+    /// Session with peer has ended, it's dialed again after a while, if need be
+    pub fn mark_disconnected(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.connected = false;
+            entry.retry_at = Some(Instant::now() + RECONNECT_DELAY);
+        }
+    }
+
+    /// We're dialing the peer, it's counted as half-open until connected or failed
+    pub fn mark_connecting(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.connecting = true;
+        }
+    }
+
+    /// Peer couldn't be reached or handshaked, it's retried after a backoff doubling with every failure,
+    /// until it's given up on after [`MAX_CONNECT_FAILURES`]
+    pub fn connect_failed(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.connecting = false;
+            entry.failures += 1;
+            let backoff = BACKOFF.saturating_mul(1 << (entry.failures - 1).min(16)).min(MAX_BACKOFF);
+            entry.retry_at = Some(Instant::now() + backoff);
+        }
+    }
+
+    /// Connections being dialed, or accepted and not running a session yet
+    pub fn half_open(&self) -> usize {
+        self.peers.values().filter(|entry| entry.connecting).count()
+    }
+
+    /// Takes a slot for a peer we're dialing or admitting, counted as half-open until connected or released.
+    /// Returns `false` when torrent already has `limit` peers, running or half-open.
+    pub fn reserve(&mut self, peer: Peer, limit: usize) -> bool {
+        if self.connected().count() + self.half_open() >= limit {
+            return false;
+        }
+        self.insert(peer, Source::Incoming);
+        self.mark_connecting(&peer.addr);
+        true
+    }
+
+    /// Gives back a slot taken by [`PeerPool::reserve`] that never got to a session
+    pub fn release(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.connecting = false;
+        }
+    }
+
+    /// Some peer told us our address, in the `yourip` of its extension handshake.
+    /// It's taken as ours once [`EXTERNAL_IP_VOTES`] peers agree on it, a single peer could lie,
+    /// private and loopback ones are only what peers on our own network see, they're ignored.
+    pub fn vote_external_ip(&mut self, voter: SocketAddr, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        let local = match ip {
+            IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+            IpAddr::V6(ip) => {
+                ip.is_unique_local() || ip.is_loopback() || ip.is_unicast_link_local() || ip.is_unspecified()
+            }
+        };
+        if local {
+            return;
+        }
+        // Latest vote of a peer is the one that counts
+        for voters in self.ip_votes.values_mut() {
+            voters.remove(&voter);
+        }
+        self.ip_votes.retain(|_, voters| !voters.is_empty());
+        let voters = self.ip_votes.entry(ip).or_default();
+        voters.insert(voter);
+        if voters.len() >= EXTERNAL_IP_VOTES {
+            self.external_ip = Some(ip);
+        }
+    }
+
+    /// Peers worth dialing right now, best first : the ones that failed the least,
+    /// then by canonical priority (BEP 40), once we know our own address
+    pub fn candidates(&self) -> Vec<Peer> {
+        let now = Instant::now();
+        let mut candidates: Vec<(u32, u32, Peer)> = self
+            .peers
+            .values()
+            .filter(|entry| !entry.connected && !entry.connecting && !self.is_banned(entry.peer.addr.ip()))
+            .filter(|entry| entry.source != Source::Incoming || entry.flags.contains(PexFlags::REACHABLE))
+            .filter(|entry| entry.failures < MAX_CONNECT_FAILURES)
+            .filter(|entry| entry.retry_at.is_none_or(|at| at <= now))
+            .map(|entry| {
+                let priority = self.external_ip.map_or(0, |ip| {
+                    canonical_priority(SocketAddr::new(ip, self.listen_port), entry.peer.addr)
+                });
+                (entry.failures, priority, entry.peer)
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        candidates.into_iter().map(|(_, _, peer)| peer).collect()
+    }
+
+    /// Notes down a violation against a known peer
+    pub fn record_violation(&mut self, addr: &SocketAddr, violation: Violation) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.violations.push(violation);
+        }
+    }
+
+    /// Peer sent bad blocks of a piece that failed its hash check, it's held against its address.
+    /// Returns whether that got the address banned, running sessions with it hear about it
+    /// through [`PeerPool::subscribe_bans`].
+    pub fn record_hash_failure(&mut self, addr: &SocketAddr) -> bool {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.hash_failures += 1;
+        }
+        let ip = addr.ip().to_canonical();
+        let strikes = self.strikes.entry(ip).or_default();
+        *strikes += 1;
+        if *strikes < self.ban_threshold || !self.banned.insert(ip) {
+            return false;
+        }
+        // No session listening is fine, banned peers aren't let back in anyway
+        let _ = self.bans.send(ip);
+        true
+    }
+
+    /// Whether peers at this address are banned, on any port
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip.to_canonical())
+    }
+
+    /// Addresses getting banned, as it happens
+    pub fn subscribe_bans(&self) -> broadcast::Receiver<IpAddr> {
+        self.bans.subscribe()
+    }
+
+    /// Peers with a running session, along with their flags
+    pub fn connected(&self) -> impl Iterator<Item = (Peer, PexFlags)> + '_ {
+        self.peers
+            .values()
+            .filter(|entry| entry.connected)
+            .map(|entry| (entry.peer, entry.flags))
+    }
+
+    /// Connected peers to tell `to` about over `ut_pex`, at addresses others can dial :
+    /// peers we've dialed (they're [`PexFlags::REACHABLE`]) as they are, peers that came to us
+    /// at the port they've told they listen on. Address of the latter is some ephemeral port of theirs,
+    /// they're left out until they tell.
+    pub fn pex_peers(&self, to: &SocketAddr) -> Vec<(Peer, PexFlags)> {
+        self.peers
+            .values()
+            .filter(|entry| entry.connected && entry.peer.addr != *to)
+            .filter_map(|entry| {
+                if entry.flags.contains(PexFlags::REACHABLE) {
+                    return Some((entry.peer, entry.flags));
+                }
+                let mut peer = entry.peer;
+                peer.addr.set_port(entry.listen_port?);
+                Some((peer, entry.flags))
+            })
+            .collect()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&Entry> {
+        self.peers.get(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn peer(last: u8) -> Peer {
+        Peer::new(Ipv4Addr::new(10, 0, 0, last), 6881)
+    }
+
+    #[test]
+    fn known_peers_keep_their_source() {
+        let mut pool = PeerPool::new();
+
+        assert!(pool.insert(peer(1), Source::Tracker));
+        assert!(!pool.insert_with_flags(peer(1), Source::Pex, PexFlags::SEED));
+
+        let entry = pool.get(&peer(1).addr).unwrap();
+        assert_eq!(entry.source, Source::Tracker);
+        assert!(entry.flags.contains(PexFlags::SEED));
+    }
+
+    #[test]
+    fn extend_counts_only_new_peers() {
+        let mut pool = PeerPool::new();
+        pool.insert(peer(1), Source::Tracker);
+
+        assert_eq!(pool.extend([peer(1), peer(2), peer(3)], Source::Pex), 2);
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn only_connected_peers_are_listed() {
+        let mut pool = PeerPool::new();
+        pool.extend([peer(1), peer(2)], Source::Tracker);
+        pool.mark_connected(peer(2), PexFlags::REACHABLE);
+        pool.mark_connected(peer(3), PexFlags::default());
+
+        let mut connected: Vec<_> = pool.connected().map(|(peer, _)| peer.addr).collect();
+        connected.sort();
+        assert_eq!(connected, vec![peer(2).addr, peer(3).addr]);
+        assert_eq!(pool.get(&peer(3).addr).unwrap().source, Source::Incoming);
+
+        pool.mark_disconnected(&peer(2).addr);
+        assert_eq!(pool.connected().count(), 1);
+    }
+
+    #[test]
+    fn incoming_peers_are_exchanged_at_their_listen_port_only() {
+        let mut pool = PeerPool::new();
+        let (dialed, incoming, quiet, remote) = (peer(1), peer(2), peer(3), peer(4));
+        pool.mark_connected(dialed, PexFlags::REACHABLE);
+        pool.mark_connected(incoming, PexFlags::default());
+        pool.set_listen_port(&incoming.addr, 51413);
+        pool.mark_connected(quiet, PexFlags::default());
+        pool.mark_connected(remote, PexFlags::REACHABLE);
+
+        let mut exchange = crate::peer::extension::pex::Exchange::new(1);
+        let pex = exchange
+            .next_message(&pool.pex_peers(&remote.addr), Instant::now())
+            .unwrap();
+        let mut added: Vec<SocketAddr> = pex.added().into_iter().map(|(peer, _)| peer.addr).collect();
+        added.sort();
+        assert_eq!(added, [dialed.addr, SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 51413))]);
+    }
+
+    #[test]
+    fn peers_are_banned_past_threshold() {
+        let mut pool = PeerPool::new().with_ban_threshold(2);
+        let mut bans = pool.subscribe_bans();
+        pool.insert(peer(1), Source::Tracker);
+
+        assert!(!pool.record_hash_failure(&peer(1).addr));
+        assert!(!pool.is_banned(peer(1).addr.ip()));
+        assert!(pool.record_hash_failure(&peer(1).addr));
+        assert!(!pool.record_hash_failure(&peer(1).addr));
+
+        assert!(pool.is_banned(peer(1).addr.ip()));
+        assert_eq!(bans.try_recv().unwrap(), peer(1).addr.ip());
+        assert!(bans.try_recv().is_err());
+    }
+
+    #[test]
+    fn bans_hold_whatever_port_peer_comes_back_on() {
+        let mut pool = PeerPool::new().with_ban_threshold(2);
+        let mut elsewhere = peer(1);
+        elsewhere.addr.set_port(51413);
+        pool.insert(peer(1), Source::Tracker);
+        pool.insert(elsewhere, Source::Pex);
+
+        // Strikes add up over both ports
+        assert!(!pool.record_hash_failure(&peer(1).addr));
+        assert!(pool.record_hash_failure(&elsewhere.addr));
+
+        assert!(pool.is_banned(elsewhere.addr.ip()));
+        assert!(pool.candidates().is_empty());
+    }
+
+    #[test]
+    fn duplicate_connections_are_refused() {
+        let mut pool = PeerPool::new();
+        let mut first = peer(1);
+        first.id = Some([5; 20].into());
+        assert!(pool.mark_connected(first, PexFlags::default()));
+
+        // Same address
+        assert!(!pool.mark_connected(peer(1), PexFlags::default()));
+
+        // Same peer, different address
+        let mut second = peer(2);
+        second.id = Some([5; 20].into());
+        assert!(!pool.mark_connected(second, PexFlags::default()));
+
+        pool.mark_disconnected(&first.addr);
+        assert!(pool.mark_connected(second, PexFlags::default()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_peers_back_off_longer_each_time() {
+        let mut pool = PeerPool::new();
+        pool.insert(peer(1), Source::Tracker);
+
+        pool.mark_connecting(&peer(1).addr);
+        assert!(pool.candidates().is_empty());
+        assert_eq!(pool.half_open(), 1);
+        pool.connect_failed(&peer(1).addr);
+        assert_eq!(pool.half_open(), 0);
+        tokio::time::advance(BACKOFF).await;
+        assert_eq!(pool.candidates(), vec![peer(1)]);
+
+        pool.connect_failed(&peer(1).addr);
+        tokio::time::advance(BACKOFF).await;
+        assert!(pool.candidates().is_empty());
+        tokio::time::advance(BACKOFF).await;
+        assert_eq!(pool.candidates(), vec![peer(1)]);
+
+        for _ in 2..MAX_CONNECT_FAILURES {
+            pool.connect_failed(&peer(1).addr);
+        }
+        tokio::time::advance(MAX_BACKOFF).await;
+        assert!(pool.candidates().is_empty());
+    }
+
+    #[test]
+    fn reserved_slots_count_against_limit() {
+        let mut pool = PeerPool::new();
+        pool.insert(peer(1), Source::Tracker);
+        pool.mark_connected(peer(2), PexFlags::default());
+
+        assert!(pool.reserve(peer(1), 3));
+        assert!(pool.reserve(peer(3), 3));
+        assert!(!pool.reserve(peer(4), 3));
+        assert_eq!(pool.get(&peer(3).addr).unwrap().source, Source::Incoming);
+
+        pool.release(&peer(3).addr);
+        assert!(pool.reserve(peer(4), 3));
+        assert!(pool.mark_connected(peer(1), PexFlags::default()));
+        assert_eq!(pool.half_open(), 1);
+    }
+
+    #[test]
+    fn candidates_follow_canonical_priority() {
+        let mut pool = PeerPool::new();
+        let far = Peer::new(Ipv4Addr::new(98, 76, 54, 32), 6881);
+        let near = Peer::new(Ipv4Addr::new(123, 213, 32, 234), 6881);
+        pool.extend([far, near, peer(1)], Source::Tracker);
+        pool.insert(peer(2), Source::Incoming);
+        pool.mark_connected(peer(1), PexFlags::default());
+
+        for voter in 1..=EXTERNAL_IP_VOTES as u8 {
+            pool.vote_external_ip(peer(voter).addr, Ipv4Addr::new(123, 213, 32, 10).into());
+        }
+        // 0x99568189 against 0xec2d7224, see BEP 40
+        assert_eq!(pool.candidates(), vec![far, near]);
+    }
+
+    #[test]
+    fn external_ip_takes_agreeing_peers() {
+        let mut pool = PeerPool::new();
+        let ours = IpAddr::from([123, 213, 32, 10]);
+        for local in [[192, 168, 1, 2], [127, 0, 0, 1], [10, 0, 0, 1]] {
+            for voter in 1..=EXTERNAL_IP_VOTES as u8 {
+                pool.vote_external_ip(peer(voter).addr, local.into());
+            }
+        }
+        assert_eq!(pool.external_ip, None);
+
+        // Same peer telling it over and over is a single vote
+        for _ in 0..EXTERNAL_IP_VOTES {
+            pool.vote_external_ip(peer(1).addr, ours);
+        }
+        assert_eq!(pool.external_ip, None);
+        pool.vote_external_ip(peer(2).addr, ours);
+        pool.vote_external_ip(peer(3).addr, [1, 2, 3, 4].into());
+        pool.vote_external_ip(peer(3).addr, ours);
+        assert_eq!(pool.external_ip, Some(ours));
+    }
+
+    #[test]
+    fn peers_behind_our_address_are_prioritised_by_our_port() {
+        let ours = Ipv4Addr::new(123, 213, 32, 10);
+        let mut pool = PeerPool::new().with_listen_port(6881);
+        for voter in 1..=EXTERNAL_IP_VOTES as u8 {
+            pool.vote_external_ip(peer(voter).addr, ours.into());
+        }
+        let (a, b) = (Peer::new(ours, 6882), Peer::new(ours, 7000));
+        pool.extend([a, b], Source::Tracker);
+
+        let priority = |peer: Peer| canonical_priority(SocketAddr::from((ours, 6881)), peer.addr);
+        let mut expected = vec![a, b];
+        expected.sort_by_key(|peer| std::cmp::Reverse(priority(*peer)));
+        assert_eq!(pool.candidates(), expected);
+        assert_ne!(priority(a), priority(b));
+    }
+}
@@ -0,0 +1,89 @@
+//! # Canonical peer priority
+//! Order both ends of a connection agree on, so that peers of a swarm keep the same connections
+//! rather than each dropping a different half of them.
+//! https://www.bittorrent.org/beps/bep_0040.html
+use std::net::{IpAddr, SocketAddr};
+
+/// Priority of the connection between `ours` and `theirs`, higher ones are connected to first
+pub fn canonical_priority(ours: SocketAddr, theirs: SocketAddr) -> u32 {
+    let (ours_ip, theirs_ip) = (ours.ip().to_canonical(), theirs.ip().to_canonical());
+    if ours_ip == theirs_ip {
+        let mut ports = [ours.port(), theirs.port()];
+        ports.sort();
+        return crc32c(&[ports[0].to_be_bytes(), ports[1].to_be_bytes()].concat());
+    }
+    let (mut ours, mut theirs, base) = match (ours_ip, theirs_ip) {
+        (IpAddr::V4(ours), IpAddr::V4(theirs)) => (ours.octets().to_vec(), theirs.octets().to_vec(), 2),
+        (IpAddr::V6(ours), IpAddr::V6(theirs)) => (ours.octets().to_vec(), theirs.octets().to_vec(), 6),
+        // Peers of either family never share a network, no masking to agree on
+        (ours, theirs) => (bytes(ours), bytes(theirs), 0),
+    };
+    if base > 0 {
+        // Bytes of the network both addresses share are kept whole, plus the one after,
+        // only every other bit of the rest is, so that peers can't pick their priority
+        let shared = ours.iter().zip(&theirs).take_while(|(a, b)| a == b).count();
+        let kept = match shared < base {
+            true => base,
+            false => shared + 1,
+        };
+        for (i, (a, b)) in ours.iter_mut().zip(theirs.iter_mut()).enumerate() {
+            if i >= kept {
+                *a &= 0x55;
+                *b &= 0x55;
+            }
+        }
+    }
+    let (low, high) = match ours <= theirs {
+        true => (ours, theirs),
+        false => (theirs, ours),
+    };
+    crc32c(&[low, high].concat())
+}
+
+fn bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// CRC-32C (Castagnoli), bit by bit, priorities are only ever computed for a handful of peers at once
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82F6_3B78,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn priorities_match_bep_examples() {
+        let priority = |a, b| canonical_priority(addr(a), addr(b));
+        assert_eq!(priority("123.213.32.10:6881", "98.76.54.32:6881"), 0xEC2D_7224);
+        assert_eq!(priority("123.213.32.10:6881", "123.213.32.234:6881"), 0x9956_8189);
+        // Both ends agree
+        assert_eq!(
+            priority("98.76.54.32:6881", "123.213.32.10:6881"),
+            priority("123.213.32.10:6881", "98.76.54.32:6881")
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::sync::RwLock;
+
+use crate::torrent::{Handle, InfoHash};
+
+/// # [`Registry`]
+/// Torrents being served, looked up by info hash.
+/// Lets many torrents share a single listening port.
+#[derive(Clone, Default)]
+pub struct Registry {
+    torrents: Arc<RwLock<HashMap<InfoHash, Handle>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Starts serving a torrent, replaces the previous handle with same info hash (if any)
+    pub async fn register(&self, handle: Handle) {
+        self.torrents.write().await.insert(handle.info_hash, handle);
+    }
+
+    pub async fn remove(&self, info_hash: &InfoHash) -> Option<Handle> {
+        self.torrents.write().await.remove(info_hash)
+    }
+
+    /// Info hashes of every torrent being served, encrypted peers ask for one of them
+    pub async fn info_hashes(&self) -> Vec<InfoHash> {
+        self.torrents.read().await.keys().copied().collect()
+    }
+
+    pub async fn get(&self, info_hash: &InfoHash) -> Option<Handle> {
+        self.torrents.read().await.get(info_hash).cloned()
+    }
+}
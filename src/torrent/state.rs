@@ -2,10 +2,11 @@ use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
 use tokio::sync::Mutex;
 
+use crate::peer::{Bitfield, BitfieldError};
 use crate::torrent::{InfoHash, Metadata};
 use std::io::{self};
 use std::sync::Arc;
-use std::{collections::HashSet, path::PathBuf};
+use std::path::PathBuf;
 use tokio::fs::{self, File, create_dir_all};
 
 use crate::torrent::{self};
@@ -14,14 +15,30 @@ pub type AtomicState = Arc<Mutex<State>>;
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct State {
-    downloaded: usize,
-    pub(crate) bit_field: Vec<u8>,
+    pub(crate) bit_field: Bitfield,
+    info_hash: InfoHash,
+}
+
+/// State as saved before pieces were kept in a [`Bitfield`], read once and saved over on next commit
+#[derive(Deserialize)]
+struct LegacyState {
+    bit_field: Vec<u8>,
     info_hash: InfoHash,
-    #[serde(skip)]
-    in_flight: HashSet<u32>,
     num_pieces: u32,
 }
 
+impl TryFrom<LegacyState> for State {
+    type Error = BitfieldError;
+    fn try_from(legacy: LegacyState) -> Result<Self, Self::Error> {
+        let mut bit_field = Bitfield::new(legacy.num_pieces as usize);
+        bit_field.update_from_peer(legacy.bit_field)?;
+        Ok(Self {
+            bit_field,
+            info_hash: legacy.info_hash,
+        })
+    }
+}
+
 impl State {
     pub fn new() -> Self {
         Default::default()
@@ -95,110 +112,66 @@ impl State {
         Ok(path.join("state.cbor"))
     }
 
-    pub(crate) fn remove_in_flight(&mut self, piece: u32) {
-        self.in_flight.remove(&piece);
-    }
-
-    pub(crate) fn add_in_flight(&mut self, piece: u32) {
-        self.in_flight.insert(piece);
-    }
-
-    pub fn is_in_flight(&self, piece: u32) -> bool {
-        self.in_flight.contains(&piece)
-    }
-
-    pub(crate) fn mark_piece_complete(&mut self, index: u32) {
-        let piece = index as usize;
-        let byte = piece / 8;
-        let bit = piece % 8;
-
-        let mask = 1 << (7 - bit);
-
-        let was_complete = self.bit_field[byte] & mask != 0;
-        if !was_complete {
-            self.bit_field[byte] |= mask;
-
-            if self.in_flight.remove(&index) {
-                self.downloaded += 1;
-            }
-        } else {
-            self.in_flight.remove(&index);
-        }
+    /// Marks piece as complete, returns whether it already was
+    ///
+    /// ## Error
+    /// Fails when piece index is beyond torrent's piece count
+    pub(crate) fn mark_piece_complete(&mut self, index: u32) -> Result<bool, BitfieldError> {
+        self.bit_field.update(index)
     }
 
+    /// Whether piece is complete, pieces torrent doesn't have never are
     pub(crate) fn have_piece(&self, index: u32) -> bool {
-        let byte_index = index as usize / 8;
-        let to_shift = index as usize % 8;
-
-        let cmp_byte = 128 >> to_shift;
-
-        self.bit_field[byte_index] & cmp_byte != 0
+        self.bit_field.has(index as usize).unwrap_or(false)
     }
 
     pub fn num_pieces(&self) -> u32 {
-        return self.num_pieces;
+        self.bit_field.len() as u32
     }
 
     fn completed_pieces(&self) -> usize {
-        return self.downloaded;
+        self.bit_field.count_ones()
     }
 
-    fn is_complete(&self) -> bool {
-        let index = self.num_pieces / 8;
-        let remainder = self.num_pieces % 8;
-
-        if self.bit_field[..index as usize].iter().any(|x| *x != 0xFF) {
-            return false;
-        }
-        if remainder == 0 {
-            return true;
-        }
-        let mask = 0xFF << (8 - remainder);
-        return *self.bit_field.last().unwrap() & mask == mask;
+    pub fn is_complete(&self) -> bool {
+        self.bit_field.is_full()
     }
 }
 
 impl TryFrom<&Metadata> for State {
     type Error = torrent::Error;
     fn try_from(metadata: &Metadata) -> Result<Self, Self::Error> {
-        let downloaded = 0;
-        let in_flight = HashSet::new();
         let num_pieces = if metadata.info.pieces.len() % 20 != 0 {
             return Err(torrent::Error::InvalidTorrent);
         } else {
             metadata.info.pieces.len() / 20
         };
-        let bitfield_size = (num_pieces as f64 / 8.0).ceil() as usize;
-        let bit_field = vec![0u8; bitfield_size];
+        let bit_field = Bitfield::new(num_pieces);
         let info_hash = metadata.info_hash;
 
-        Ok(Self {
-            downloaded,
-            in_flight,
-            info_hash,
-            num_pieces: num_pieces as u32,
-            bit_field,
-        })
+        Ok(Self { info_hash, bit_field })
     }
 }
 
 impl TryFrom<&[u8]> for State {
     type Error = io::Error;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        return match ciborium::from_reader(value) {
-            Ok(x) => Ok(x),
-            Err(x) => Err(io::Error::new(io::ErrorKind::InvalidData, x)),
-        };
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        ciborium::from_reader::<State, _>(value).or_else(|err| {
+            let legacy: LegacyState = ciborium::from_reader(value).map_err(|_| invalid(err.to_string()))?;
+            State::try_from(legacy).map_err(|err| invalid(err.to_string()))
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashSet, env};
+    use std::env;
 
     use serial_test::serial;
     use tokio::fs;
 
+    use crate::peer::Bitfield;
     use crate::torrent::{Metadata, State};
 
     /// Simulating data directory for tests, it's unsafe, better use it within single threaded environments
@@ -223,21 +196,26 @@ mod tests {
         result
     }
 
+    /// Bitfield of given bytes, bits past the last piece dropped
+    fn bitfield<const N: usize>(num_pieces: usize, bytes: [u8; N]) -> Bitfield {
+        let mut bitfield = Bitfield::new(num_pieces);
+        bitfield.update_from_peer(bytes).unwrap();
+        bitfield
+    }
+
     #[tokio::test]
     #[serial]
-    async fn inflight_is_not_persisted() {
+    async fn completed_pieces_are_persisted() {
         with_temp_dir(|| async {
             let metadata = Metadata::fake();
 
             let mut state = State::try_from(&metadata).unwrap();
-            state.add_in_flight(2);
-            state.mark_piece_complete(1);
+            state.mark_piece_complete(0).unwrap();
 
             state.save().await.unwrap();
             let loaded = State::load_or_new(&metadata).await;
 
-            assert!(!loaded.is_in_flight(2));
-            assert!(loaded.have_piece(1));
+            assert!(loaded.have_piece(0));
         })
         .await;
     }
@@ -258,13 +236,11 @@ mod tests {
         let metadata = Metadata::fake();
 
         let mut state = State {
-            bit_field: vec![0; 2],
-            num_pieces: 16,
-            in_flight: HashSet::from([3, 4]),
+            bit_field: Bitfield::new(16),
             ..Default::default()
         };
-        state.mark_piece_complete(4);
-        state.mark_piece_complete(3);
+        state.mark_piece_complete(4).unwrap();
+        state.mark_piece_complete(3).unwrap();
         let loaded_state = with_temp_dir(|| async {
             state.save().await.unwrap();
 
@@ -300,39 +276,37 @@ mod tests {
     #[test]
     fn mark_piece_complete_works_fine() {
         let mut state = State {
-            bit_field: vec![0; 2], // 16 pieces
-            num_pieces: 16,
+            bit_field: Bitfield::new(16),
             ..Default::default()
         };
 
-        state.mark_piece_complete(2);
-        assert_eq!(state.bit_field, [0b0010_0000, 0b0000_0000]);
+        state.mark_piece_complete(2).unwrap();
+        assert_eq!(state.bit_field.as_bytes(), &[0b0010_0000, 0b0000_0000]);
 
-        state.mark_piece_complete(1);
-        assert_eq!(state.bit_field, [0b0110_0000, 0b0000_0000]);
+        state.mark_piece_complete(1).unwrap();
+        assert_eq!(state.bit_field.as_bytes(), &[0b0110_0000, 0b0000_0000]);
 
-        state.mark_piece_complete(8);
-        assert_eq!(state.bit_field, [0b0110_0000, 0b1000_0000]);
+        state.mark_piece_complete(8).unwrap();
+        assert_eq!(state.bit_field.as_bytes(), &[0b0110_0000, 0b1000_0000]);
 
-        state.mark_piece_complete(13);
-        assert_eq!(state.bit_field, [0b0110_0000, 0b1000_0100]);
+        state.mark_piece_complete(13).unwrap();
+        assert_eq!(state.bit_field.as_bytes(), &[0b0110_0000, 0b1000_0100]);
     }
 
     #[test]
     fn have_pieces_works_well_with_marked_pieces() {
         let mut state = State {
-            bit_field: vec![0; 2],
-            num_pieces: 16,
+            bit_field: Bitfield::new(16),
             ..Default::default()
         };
 
-        state.mark_piece_complete(3);
+        state.mark_piece_complete(3).unwrap();
         assert!(state.have_piece(3));
 
-        state.mark_piece_complete(15);
+        state.mark_piece_complete(15).unwrap();
         assert!(state.have_piece(15));
 
-        state.mark_piece_complete(0);
+        state.mark_piece_complete(0).unwrap();
         assert!(state.have_piece(0));
 
         assert!(!state.have_piece(7));
@@ -342,8 +316,7 @@ mod tests {
     #[test]
     fn is_complete_works_on_full_bytes() {
         let state = State {
-            bit_field: vec![0b1111_1111, 0b1111_1111],
-            num_pieces: 16,
+            bit_field: Bitfield::full(16),
             ..Default::default()
         };
 
@@ -353,8 +326,7 @@ mod tests {
     #[test]
     fn is_complete_works_on_partially_filled_bitfield() {
         let state = State {
-            bit_field: vec![0b1111_0111, 0b1111_101],
-            num_pieces: 13,
+            bit_field: bitfield(13, [0b1111_0111, 0b1111_101]),
             ..Default::default()
         };
 
@@ -364,76 +336,97 @@ mod tests {
     #[test]
     fn is_complete_works_with_marker_helper() {
         let mut state = State {
-            bit_field: vec![0b1111_0111, 0b1110_0010],
-            num_pieces: 13,
+            bit_field: bitfield(13, [0b1111_0111, 0b1110_0010]),
             ..Default::default()
         };
 
         assert!(!state.is_complete());
 
-        state.mark_piece_complete(4);
+        state.mark_piece_complete(4).unwrap();
         assert!(!state.is_complete());
 
-        state.mark_piece_complete(11);
+        state.mark_piece_complete(11).unwrap();
         assert!(!state.is_complete());
 
-        state.mark_piece_complete(12);
+        state.mark_piece_complete(12).unwrap();
         assert!(state.is_complete());
     }
 
     #[test]
     fn downloaded_items_are_always_less_than_or_equal_to_num_pieces() {
         let mut state = State {
-            bit_field: vec![0b0000_0000],
-            num_pieces: 14,
+            bit_field: Bitfield::new(14),
             ..Default::default()
         };
 
-        state.in_flight.insert(2);
         for _ in 0..20 {
-            state.mark_piece_complete(2);
+            state.mark_piece_complete(2).unwrap();
         }
 
-        assert!(state.downloaded <= state.num_pieces as usize);
+        assert!(state.completed_pieces() <= state.num_pieces() as usize);
         assert_eq!(state.completed_pieces(), 1);
     }
 
     #[test]
-    #[should_panic]
     fn marking_unrelated_piece_as_complete() {
         let mut state = State {
-            bit_field: vec![0],
-            num_pieces: 8,
+            bit_field: Bitfield::new(8),
             ..Default::default()
         };
-        state.mark_piece_complete(99);
+        assert!(state.mark_piece_complete(99).is_err());
+        assert_eq!(state.completed_pieces(), 0);
     }
 
     #[test]
-    #[should_panic]
     fn looking_for_piece_that_never_will_exist() {
         let state = State {
-            bit_field: vec![0],
-            num_pieces: 8,
+            bit_field: Bitfield::new(8),
             ..Default::default()
         };
-        state.have_piece(10);
+        assert!(!state.have_piece(10));
     }
 
     #[test]
     fn marking_same_piece_twice_does_not_increment_downloaded() {
         let mut state = State {
-            bit_field: vec![0],
-            num_pieces: 8,
+            bit_field: Bitfield::new(8),
             ..Default::default()
         };
 
-        state.mark_piece_complete(3);
+        state.mark_piece_complete(3).unwrap();
         let first = state.completed_pieces();
 
-        state.mark_piece_complete(3);
+        state.mark_piece_complete(3).unwrap();
         let second = state.completed_pieces();
 
         assert_eq!(first, second);
     }
+
+    #[test]
+    fn states_saved_with_raw_bytes_are_migrated() {
+        #[derive(serde::Serialize)]
+        struct Old {
+            downloaded: usize,
+            bit_field: Vec<u8>,
+            info_hash: crate::torrent::InfoHash,
+            num_pieces: u32,
+        }
+        let old = Old {
+            downloaded: 2,
+            bit_field: vec![0b0100_0000, 0b0100_0000],
+            info_hash: Metadata::fake().info_hash,
+            num_pieces: 10,
+        };
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&old, &mut bytes).unwrap();
+
+        let state = State::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(state.num_pieces(), 10);
+        assert_eq!(state.bit_field.iter_set().collect::<Vec<_>>(), [1, 9]);
+        assert_eq!(state.info_hash, old.info_hash);
+
+        // Saved over in the new format
+        let migrated = State::try_from(state.to_bytes().unwrap().as_slice()).unwrap();
+        assert_eq!(migrated.bit_field, state.bit_field);
+    }
 }
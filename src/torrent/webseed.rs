@@ -0,0 +1,510 @@
+//! # Web seeds
+//! HTTP mirrors listed in `url-list`, downloaded from like one more peer having every piece.
+//! https://www.bittorrent.org/beps/bep_0019.html
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use reqwest::{StatusCode, header};
+use tokio::{sync::broadcast::error::TryRecvError, time};
+
+use crate::{
+    peer::{Bitfield, BlockRequest, Piece, PieceError},
+    torrent::{
+        CommitEvent, Handle, commit,
+        info::{FileMode, Info},
+        picker::Stored,
+        pool::DEFAULT_BAN_THRESHOLD,
+    },
+};
+
+/// Wait after the first failed request, doubling with every one after
+const BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// Wait before asking picker again, when there's nothing left for us
+const IDLE_WAIT: Duration = Duration::from_secs(5);
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Request failed : {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Server responded with {0}")]
+    Status(StatusCode),
+
+    /// Server asked us to come back later, BEP 19 has it answer `503` with `Retry-After`
+    #[error("Server is busy, retry in {0:?}")]
+    Busy(Duration),
+
+    #[error("Server sent {got} bytes, {expected} were asked for")]
+    ShortBody { expected: u64, got: u64 },
+
+    #[error("Block doesn't fit its piece : {0}")]
+    Piece(#[from] PieceError),
+
+    #[error("Piece {0} failed its hash check")]
+    HashFailed(u32),
+
+    #[error("Committer is gone")]
+    CommitterGone,
+}
+
+/// File of the torrent, as found on the server
+#[derive(Debug, PartialEq)]
+struct File {
+    url: String,
+    /// Where file starts, within the whole torrent
+    offset: u64,
+    length: u64,
+}
+
+/// # [`WebSeed`]
+/// Downloads pieces from a single HTTP mirror, until torrent is complete.
+///
+/// Takes part in the [`crate::torrent::PiecePicker`] like a fast peer having every piece :
+/// it takes whole pieces to itself, rarest first, and fetches runs of contiguous blocks with range requests,
+/// split across the files they span. Pieces are hash checked, then handed to the committer.
+/// Failed requests put it to sleep for a while, longer with every failure in a row.
+pub struct WebSeed {
+    torrent: Handle,
+    client: reqwest::Client,
+    files: Vec<File>,
+    /// Stands in for a peer address, in the picker
+    addr: SocketAddr,
+    failures: u32,
+    hash_failures: u32,
+    /// Bad pieces it may send before it's given up on
+    max_hash_failures: u32,
+}
+
+impl WebSeed {
+    /// Web seed for the `url` of torrent's `url-list`, `id` tells it apart from the other web seeds of the torrent
+    pub fn new(url: &str, id: u16, torrent: Handle) -> Self {
+        Self {
+            files: files(url, &torrent.info),
+            torrent,
+            client: reqwest::Client::new(),
+            // Discard-only prefix (RFC 6666), no real peer ever has it
+            addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, id)), 0),
+            failures: 0,
+            hash_failures: 0,
+            max_hash_failures: DEFAULT_BAN_THRESHOLD,
+        }
+    }
+
+    /// Gives up on the server once it's sent this many bad pieces, see [`crate::config::Config::max_hash_failures`]
+    pub fn with_max_hash_failures(mut self, max_hash_failures: u32) -> Self {
+        self.max_hash_failures = max_hash_failures;
+        self
+    }
+
+    /// Downloads until torrent is complete, or until the server has sent too many bad pieces
+    pub async fn run(mut self) -> Result<()> {
+        let bitfield = Bitfield::full(self.torrent.info.pieces.len() / 20);
+        let mut commits = self.torrent.commit_events.subscribe();
+        let round = {
+            let mut picker = self.torrent.picker.lock().await;
+            picker.add_peer(&bitfield);
+            picker.blocks_per_piece()
+        };
+
+        let result = loop {
+            loop {
+                match commits.try_recv() {
+                    Ok(event) => self.handle_commit_event(event).await,
+                    Err(TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+            if self.torrent.state.lock().await.is_complete() {
+                break Ok(());
+            }
+
+            let blocks = self
+                .torrent
+                .picker
+                .lock()
+                .await
+                .pick_blocks(self.addr, &bitfield, true, round);
+            if blocks.is_empty() {
+                tokio::select! {
+                    _ = time::sleep(IDLE_WAIT) => {}
+                    Ok(event) = commits.recv() => self.handle_commit_event(event).await,
+                }
+                continue;
+            }
+
+            match self.download(&blocks).await {
+                Ok(()) => self.failures = 0,
+                Err(err) => {
+                    self.torrent.picker.lock().await.abort_peer(self.addr);
+                    if let Error::HashFailed(_) = err {
+                        self.hash_failures += 1;
+                        if self.hash_failures >= self.max_hash_failures {
+                            break Err(err);
+                        }
+                    }
+                    let backoff = self.backoff(&err);
+                    eprintln!("\x1b[33mWEBSEED {} | {err}, retrying in {backoff:?}\x1b[0m", self.addr);
+                    time::sleep(backoff).await;
+                }
+            }
+        };
+
+        let mut picker = self.torrent.picker.lock().await;
+        picker.abort_peer(self.addr);
+        picker.remove_peer(&bitfield);
+        result
+    }
+
+    fn backoff(&mut self, err: &Error) -> Duration {
+        self.failures += 1;
+        match err {
+            Error::Busy(retry_after) => *retry_after,
+            _ => BACKOFF
+                .saturating_mul(1 << (self.failures - 1).min(16))
+                .min(MAX_BACKOFF),
+        }
+    }
+
+    /// No session may be around to keep picker up to date with commits, so we do as well
+    async fn handle_commit_event(&self, event: commit::Event) {
+        let mut picker = self.torrent.picker.lock().await;
+        match event {
+            CommitEvent::PieceCommit(index) => picker.mark_have(index),
+            CommitEvent::FailedCommit(index) => picker.abandon(index),
+        }
+    }
+
+    /// Fetches blocks, contiguous ones in a single go, and stores them into the picker
+    async fn download(&self, blocks: &[BlockRequest]) -> Result<()> {
+        let piece_length = self.torrent.info.piece_length as u64;
+        let start = |block: &BlockRequest| block.index as u64 * piece_length + block.offset as u64;
+
+        let mut runs: Vec<Vec<BlockRequest>> = Vec::new();
+        for block in blocks {
+            match runs.last_mut() {
+                Some(run)
+                    if run
+                        .last()
+                        .is_some_and(|last| start(last) + last.length as u64 == start(block)) =>
+                {
+                    run.push(*block)
+                }
+                _ => runs.push(vec![*block]),
+            }
+        }
+
+        for run in runs {
+            let offset = start(&run[0]);
+            let length = run.iter().map(|block| block.length as u64).sum();
+            let data = self.fetch(offset, length).await?;
+            let mut at = 0;
+            for block in run {
+                let end = at + block.length as usize;
+                self.store(block, &data[at..end]).await?;
+                at = end;
+            }
+        }
+        Ok(())
+    }
+
+    async fn store(&self, block: BlockRequest, data: &[u8]) -> Result<()> {
+        self.torrent.totals.add_downloaded(data.len() as u64);
+        let stored = self
+            .torrent
+            .picker
+            .lock()
+            .await
+            .store(self.addr, block.index, block.offset, data)?;
+        match stored {
+            Stored::Complete(piece) => self.handle_completed_piece(piece).await,
+            Stored::Partial | Stored::Unwanted => Ok(()),
+        }
+    }
+
+    /// Same as sessions do, see [`Handle::check_piece`]
+    async fn handle_completed_piece(&self, piece: Piece) -> Result<()> {
+        let index = piece.index();
+        let blamed = self.torrent.check_piece(piece).await.map_err(|_| Error::CommitterGone)?;
+        match blamed.contains(&self.addr) {
+            true => Err(Error::HashFailed(index)),
+            false => Ok(()),
+        }
+    }
+
+    /// Bytes of the torrent, starting at `offset`, from every file they span
+    async fn fetch(&self, offset: u64, length: u64) -> Result<Bytes> {
+        let end = offset + length;
+        let mut data = Vec::with_capacity(length as usize);
+        for file in &self.files {
+            let (file_start, file_end) = (file.offset, file.offset + file.length);
+            if file_end <= offset || file_start >= end {
+                continue;
+            }
+            let first = offset.max(file_start) - file_start;
+            let last = end.min(file_end) - file_start - 1;
+            data.extend_from_slice(&self.fetch_range(file, first, last).await?);
+        }
+        Ok(data.into())
+    }
+
+    /// Bytes `first..=last` of a single file
+    async fn fetch_range(&self, file: &File, first: u64, last: u64) -> Result<Bytes> {
+        let response = self
+            .client
+            .get(&file.url)
+            .header(header::RANGE, format!("bytes={first}-{last}"))
+            .send()
+            .await?;
+        let expected = last - first + 1;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            // Server ignoring ranges sends the whole file
+            StatusCode::OK => {
+                let body = response.bytes().await?;
+                let got = body.len() as u64;
+                if got <= last {
+                    return Err(Error::ShortBody { expected, got });
+                }
+                return Ok(body.slice(first as usize..=last as usize));
+            }
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let retry_after = response
+                    .headers()
+                    .get(header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok()?.parse().ok())
+                    .map_or(BACKOFF, Duration::from_secs);
+                return Err(Error::Busy(retry_after));
+            }
+            status => return Err(Error::Status(status)),
+        }
+        let body = response.bytes().await?;
+        if body.len() as u64 != expected {
+            return Err(Error::ShortBody {
+                expected,
+                got: body.len() as u64,
+            });
+        }
+        Ok(body)
+    }
+}
+
+/// Torrent's files, as laid out on the server.
+/// Multi-file torrents live in a directory named after the torrent, under `url`, so do single file ones
+/// when `url` ends with a slash. Otherwise `url` is the file itself.
+fn files(url: &str, info: &Info) -> Vec<File> {
+    match info.file_mode.as_ref() {
+        FileMode::Single { length } => {
+            let url = match url.ends_with('/') {
+                true => format!("{url}{}", encode(&info.name)),
+                false => url.to_string(),
+            };
+            vec![File {
+                url,
+                offset: 0,
+                length: *length,
+            }]
+        }
+        FileMode::Multiple { files } => {
+            let root = format!("{}/{}", url.trim_end_matches('/'), encode(&info.name));
+            let mut offset = 0;
+            files
+                .iter()
+                .map(|file| {
+                    let path: Vec<String> = file.path.iter().map(|segment| encode(segment)).collect();
+                    let entry = File {
+                        url: format!("{root}/{}", path.join("/")),
+                        offset,
+                        length: file.length,
+                    };
+                    offset += file.length;
+                    entry
+                })
+                .collect()
+        }
+    }
+}
+
+/// Percent-encodes a path segment, leaving only unreserved characters as they are
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::Ipv4Addr, sync::Arc};
+
+    use serde_bytes::ByteBuf;
+    use sha1::{Digest, Sha1};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::torrent::{Metadata, info::InfoFile};
+
+    const PIECE_LEN: u32 = 16 * 1024;
+
+    /// Multi-file torrent over `files`, pieces hashed from their contents
+    fn info(files: &[(&str, &[u8])]) -> Info {
+        let data: Vec<u8> = files.iter().flat_map(|(_, data)| data.iter().copied()).collect();
+        let pieces: Vec<u8> = data
+            .chunks(PIECE_LEN as usize)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        Info {
+            name: "my torrent".to_string(),
+            piece_length: PIECE_LEN,
+            pieces: ByteBuf::from(pieces),
+            file_mode: FileMode::Multiple {
+                files: files
+                    .iter()
+                    .map(|(path, data)| InfoFile {
+                        length: data.len() as u64,
+                        path: path.split('/').map(str::to_string).collect(),
+                    })
+                    .collect(),
+            },
+            private: None,
+        }
+    }
+
+    /// Serves files over HTTP, honoring ranges, one request per connection.
+    /// Paths not found get the `fallback` status.
+    async fn serve(files: HashMap<String, Vec<u8>>, fallback: u16) -> String {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let files = Arc::new(files);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let files = files.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0];
+                        if stream.read(&mut byte).await.unwrap() == 0 {
+                            return;
+                        }
+                        request.push(byte[0]);
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    let path = request.split(' ').nth(1).unwrap();
+                    let response = match files.get(path) {
+                        Some(data) => {
+                            let range = request
+                                .lines()
+                                .find_map(|line| line.to_lowercase().strip_prefix("range: bytes=").map(str::to_string))
+                                .unwrap();
+                            let (first, last) = range.split_once('-').unwrap();
+                            let body = &data[first.parse::<usize>().unwrap()..=last.parse::<usize>().unwrap()];
+                            let mut response = format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                body.len()
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(body);
+                            response
+                        }
+                        None => format!("HTTP/1.1 {fallback} Nope\r\nContent-Length: 0\r\nRetry-After: 42\r\nConnection: close\r\n\r\n")
+                            .into_bytes(),
+                    };
+                    stream.write_all(&response).await.unwrap();
+                });
+            }
+        });
+        format!("http://{addr}/seed")
+    }
+
+    #[test]
+    fn files_are_mapped_to_urls() {
+        let info = info(&[("a b/c.txt", &[0; 10]), ("d", &[0; 5])]);
+        assert_eq!(
+            files("http://mirror/seed/", &info),
+            vec![
+                File {
+                    url: "http://mirror/seed/my%20torrent/a%20b/c.txt".to_string(),
+                    offset: 0,
+                    length: 10
+                },
+                File {
+                    url: "http://mirror/seed/my%20torrent/d".to_string(),
+                    offset: 10,
+                    length: 5
+                },
+            ]
+        );
+
+        let mut info = Metadata::fake().info;
+        info.name = "fake.iso".to_string();
+        assert_eq!(files("http://mirror/fake.iso", &info)[0].url, "http://mirror/fake.iso");
+        assert_eq!(files("http://mirror/", &info)[0].url, "http://mirror/fake.iso");
+    }
+
+    #[tokio::test]
+    async fn pieces_spanning_files_are_downloaded_and_committed() {
+        let first: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+        let second: Vec<u8> = (0..30_000u32).map(|i| (i * 7) as u8).collect();
+        let url = serve(
+            HashMap::from([
+                ("/seed/my%20torrent/one".to_string(), first.clone()),
+                ("/seed/my%20torrent/dir/two".to_string(), second.clone()),
+            ]),
+            404,
+        )
+        .await;
+        let (handle, mut committer) = Handle::fake_with(info(&[("one", &first), ("dir/two", &second)]));
+
+        let seed = tokio::spawn(WebSeed::new(&url, 0, handle.clone()).run());
+        let data: Vec<u8> = [first, second].concat();
+        let mut committed = Vec::new();
+        for _ in 0..data.len().div_ceil(PIECE_LEN as usize) {
+            let job = committer.next_job().await.unwrap();
+            let start = job.index as usize * PIECE_LEN as usize;
+            assert_eq!(job.bytes, data[start..(start + PIECE_LEN as usize).min(data.len())]);
+            committed.push(job.index);
+        }
+        committed.sort();
+        assert_eq!(committed, vec![0, 1, 2, 3]);
+        seed.abort();
+    }
+
+    #[tokio::test]
+    async fn busy_servers_are_retried_later() {
+        let url = serve(HashMap::new(), 503).await;
+        let (handle, _committer) = Handle::fake_with(info(&[("one", &[1; 100])]));
+        let seed = WebSeed::new(&url, 0, handle.clone());
+
+        let seeded = Bitfield::full(handle.info.pieces.len() / 20);
+        let blocks = handle.picker.lock().await.pick_blocks(seed.addr, &seeded, true, 1);
+        let err = seed.download(&blocks).await.unwrap_err();
+        assert!(matches!(err, Error::Busy(retry_after) if retry_after == Duration::from_secs(42)));
+
+        let mut seed = seed;
+        assert_eq!(seed.backoff(&Error::Status(StatusCode::NOT_FOUND)), BACKOFF);
+        assert_eq!(seed.backoff(&Error::Status(StatusCode::NOT_FOUND)), BACKOFF * 2);
+    }
+
+    #[tokio::test]
+    async fn servers_sending_bad_pieces_are_given_up_on() {
+        let good = vec![7; 100];
+        let url = serve(HashMap::from([("/seed/my%20torrent/one".to_string(), vec![8; 100])]), 404).await;
+        let (handle, _committer) = Handle::fake_with(info(&[("one", &good)]));
+
+        let seed = WebSeed::new(&url, 0, handle.clone()).with_max_hash_failures(1);
+        let result = time::timeout(Duration::from_secs(5), seed.run()).await.unwrap();
+        assert!(matches!(result, Err(Error::HashFailed(0))));
+    }
+}
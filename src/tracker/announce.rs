@@ -0,0 +1,219 @@
+//! # Announce
+//! What we tell a tracker about ourselves and how far along a torrent we are.
+//! https://www.bittorrent.org/beps/bep_0003.html#trackers
+use std::{net::Ipv6Addr, sync::LazyLock};
+
+use reqwest::Url;
+
+use crate::{
+    config,
+    peer::{self, id::Id},
+    torrent::{Handle, Info, InfoHash, State},
+    tracker::{Error, Result},
+};
+
+/// # tracker::KEY
+/// Lets trackers know it's still us when our address changes, initialized once per session like [`peer::ID`]
+pub static KEY: LazyLock<u32> = LazyLock::new(rand::random);
+
+/// Announces out of the regular interval
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// First announce of a torrent
+    Started,
+    /// Torrent just got complete, never sent when it already was on start
+    Completed,
+    /// Torrent is going away
+    Stopped,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+/// # [`Announce`]
+/// Announce request, see [`Announce::from_torrent`] for one out of a running torrent,
+/// and [`Announce::url`] for what's sent.
+#[derive(Clone, Debug)]
+pub struct Announce {
+    pub info_hash: InfoHash,
+    pub peer_id: Id,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Bytes we still need
+    pub left: u64,
+    pub event: Option<Event>,
+    /// Peers we'd like, tracker's default when `None`
+    pub numwant: Option<u32>,
+    pub key: u32,
+    /// Whatever tracker handed out last time, see [`crate::tracker::Response::tracker_id`]
+    pub tracker_id: Option<String>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl Announce {
+    /// Announce of a torrent nothing has moved for yet, with `left` bytes to go
+    pub fn new(info_hash: InfoHash, left: u64) -> Self {
+        Self {
+            info_hash,
+            peer_id: *peer::ID,
+            port: config::DEFAULT_LISTEN_PORT,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: None,
+            numwant: None,
+            key: *KEY,
+            tracker_id: None,
+            ipv6: None,
+        }
+    }
+
+    /// Announce of a torrent as far as `state` has got
+    pub fn from_state(info_hash: InfoHash, info: &Info, state: &State) -> Self {
+        let have: u64 = state
+            .bit_field
+            .iter_set()
+            .map(|index| info.piece_len(index as u32) as u64)
+            .sum();
+        Self::new(info_hash, info.total_length().saturating_sub(have))
+    }
+
+    /// Announce of a running torrent, with what its sessions have moved so far
+    pub async fn from_torrent(torrent: &Handle) -> Self {
+        let mut announce = Self::from_state(torrent.info_hash, &torrent.info, &*torrent.state.lock().await);
+        announce.uploaded = torrent.totals.uploaded();
+        announce.downloaded = torrent.totals.downloaded();
+        announce
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_event(mut self, event: Event) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    pub fn with_numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    pub fn with_tracker_id(mut self, tracker_id: Option<String>) -> Self {
+        self.tracker_id = tracker_id;
+        self
+    }
+
+    pub fn with_ipv6(mut self, ipv6: Option<Ipv6Addr>) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+
+    /// Tracker's `announce` url along with the request, whatever query it already had is kept.
+    /// Peers are always asked for in compact form.
+    pub fn url(&self, announce: &str) -> Result<Url> {
+        let mut url = Url::parse(announce).map_err(|err| Error::InvalidUrl(err.to_string()))?;
+        let encode = |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+
+        let mut pairs = vec![
+            ("info_hash", self.info_hash.to_url_encoded()),
+            ("peer_id", self.peer_id.url_encoded()),
+            ("port", self.port.to_string()),
+            ("uploaded", self.uploaded.to_string()),
+            ("downloaded", self.downloaded.to_string()),
+            ("left", self.left.to_string()),
+            ("compact", "1".to_string()),
+            ("key", format!("{:08X}", self.key)),
+        ];
+        if let Some(event) = self.event {
+            pairs.push(("event", event.as_str().to_string()));
+        }
+        if let Some(numwant) = self.numwant {
+            pairs.push(("numwant", numwant.to_string()));
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            pairs.push(("trackerid", encode(tracker_id)));
+        }
+        if let Some(ip) = self.ipv6 {
+            pairs.push(("ipv6", encode(&ip.to_string())));
+        }
+
+        let query = url
+            .query()
+            .filter(|query| !query.is_empty())
+            .map(str::to_string)
+            .into_iter()
+            .chain(pairs.into_iter().map(|(key, value)| format!("{key}={value}")))
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query(Some(&query));
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Metadata;
+
+    fn announce() -> Announce {
+        Announce::new(InfoHash::from([0xAB; 20]), 1000)
+    }
+
+    #[test]
+    fn announce_url_carries_every_field() {
+        let url = announce()
+            .with_port(51413)
+            .with_event(Event::Started)
+            .with_numwant(50)
+            .with_tracker_id(Some("a b".to_string()))
+            .url("http://tracker.example/announce")
+            .unwrap();
+        let query = url.query().unwrap();
+
+        assert!(query.starts_with(&format!("info_hash={}&peer_id=", "%AB".repeat(20))));
+        for pair in [
+            "port=51413",
+            "uploaded=0",
+            "downloaded=0",
+            "left=1000",
+            "compact=1",
+            "event=started",
+        ] {
+            assert!(query.contains(pair), "{pair} missing from {query}");
+        }
+        assert!(query.contains("numwant=50"));
+        assert!(query.contains("trackerid=a+b"));
+        assert!(query.contains(&format!("key={:08X}", *KEY)));
+    }
+
+    #[test]
+    fn existing_query_is_kept() {
+        let url = announce()
+            .url("http://tracker.example/announce?passkey=secret")
+            .unwrap();
+        assert!(url.query().unwrap().starts_with("passkey=secret&info_hash="));
+        assert!(announce().url("not a url").is_err());
+    }
+
+    #[test]
+    fn left_follows_state() {
+        let metadata = Metadata::fake();
+        let mut state = State::try_from(&metadata).unwrap();
+        let fresh = Announce::from_state(metadata.info_hash, &metadata.info, &state);
+        assert_eq!(fresh.left, metadata.info.total_length());
+
+        state.mark_piece_complete(0).unwrap();
+        assert_eq!(Announce::from_state(metadata.info_hash, &metadata.info, &state).left, 0);
+    }
+}
@@ -0,0 +1,239 @@
+//! # Announcer
+//! Keeps a tracker posted on a running torrent, for as long as it runs.
+use std::{net::Ipv6Addr, time::Duration};
+
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+
+use crate::{
+    config,
+    torrent::{Handle, PeerSource, commit},
+    tracker::{self, Announce, Event, Result},
+};
+
+/// Wait before announcing again, after tracker couldn't be reached
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// # [`Announcer`]
+/// Announces a torrent to a single tracker : `started` first, then again every interval tracker asks for,
+/// `completed` as soon as the last piece is written, and `stopped` on the way out, see [`Announcer::run`].
+/// Each announce carries what the torrent has moved so far, see [`Announce::from_torrent`],
+/// and peers of every response go to torrent's pool.
+pub struct Announcer {
+    url: String,
+    torrent: Handle,
+    port: u16,
+    ipv6: Option<Ipv6Addr>,
+    /// Whatever tracker handed out last, sent back with every announce after
+    tracker_id: Option<String>,
+}
+
+impl Announcer {
+    pub fn new(url: &str, torrent: Handle) -> Self {
+        Self {
+            url: url.to_string(),
+            torrent,
+            port: config::DEFAULT_LISTEN_PORT,
+            ipv6: None,
+            tracker_id: None,
+        }
+    }
+
+    /// Port peers reach us at, see [`crate::config::Config::listen_port`]
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_ipv6(mut self, ipv6: Option<Ipv6Addr>) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+
+    /// Announces until `stop` resolves, then says `stopped`.
+    /// An event tracker couldn't be told about is sent again with the next announce,
+    /// `completed` is never sent for a torrent that was complete already.
+    pub async fn run(mut self, stop: impl Future<Output = ()>) {
+        let mut commits = self.torrent.commit_events.subscribe();
+        let mut complete = self.torrent.state.lock().await.is_complete();
+        let mut event = Some(Event::Started);
+        tokio::pin!(stop);
+
+        loop {
+            let wait = match self.announce(event).await {
+                Ok(interval) => {
+                    event = None;
+                    interval
+                }
+                Err(err) => {
+                    eprintln!("\x1b[33mTRACKER | {} : {err}\x1b[0m", self.url);
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::select! {
+                _ = time::sleep(wait) => {}
+                _ = &mut stop => return self.stop().await,
+                _ = completion(&self.torrent, &mut commits), if !complete => {
+                    complete = true;
+                    // Tracker that hasn't heard of us yet rather gets `started`, with nothing left
+                    event = event.or(Some(Event::Completed));
+                }
+            }
+        }
+    }
+
+    /// Torrent is going away, tracker can forget about us
+    async fn stop(&mut self) {
+        if let Err(err) = self.announce(Some(Event::Stopped)).await {
+            eprintln!("\x1b[33mTRACKER | {} : {err}\x1b[0m", self.url);
+        }
+    }
+
+    /// Announces torrent as it is now, returns when tracker wants to hear from us again
+    async fn announce(&mut self, event: Option<Event>) -> Result<Duration> {
+        let mut request = Announce::from_torrent(&self.torrent)
+            .await
+            .with_port(self.port)
+            .with_ipv6(self.ipv6)
+            .with_tracker_id(self.tracker_id.clone());
+        request.event = event;
+        if event == Some(Event::Stopped) {
+            request = request.with_numwant(0);
+        }
+
+        let response = tracker::announce(&self.url, &request).await?;
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id;
+        }
+        self.torrent
+            .pool
+            .lock()
+            .await
+            .extend(response.peers, PeerSource::Tracker);
+        Ok(Duration::from_secs(response.interval as u64))
+    }
+}
+
+/// Resolves once torrent is complete, as committer writes its last piece
+async fn completion(torrent: &Handle, commits: &mut broadcast::Receiver<commit::Event>) {
+    loop {
+        match commits.recv().await {
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            // Handle holds a sender, never happens
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+        if torrent.state.lock().await.is_complete() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::{mpsc, oneshot},
+        time::timeout,
+    };
+
+    use super::*;
+
+    /// Tracker asking to be announced to every second, handing out a single peer.
+    /// Queries of the announces it gets come out of the receiver.
+    async fn tracker() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut body = b"d8:intervali1e5:peers6:".to_vec();
+            body.extend_from_slice(&[1, 2, 3, 4, 0x1A, 0xE1]);
+            body.extend_from_slice(b"10:tracker id3:abce");
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let read = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..read]).to_string();
+                let query = request.split(' ').nth(1).unwrap_or_default().to_string();
+                let _ = tx.send(query);
+
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    async fn next(announces: &mut mpsc::UnboundedReceiver<String>) -> String {
+        timeout(Duration::from_secs(5), announces.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tracker_hears_every_event_of_a_torrent() {
+        let (url, mut announces) = tracker().await;
+        let torrent = Handle::fake();
+        let (stop, stopped) = oneshot::channel();
+        let announcer = tokio::spawn(Announcer::new(&url, torrent.clone()).with_port(51413).run(async {
+            let _ = stopped.await;
+        }));
+
+        let started = next(&mut announces).await;
+        assert!(started.contains("event=started"));
+        assert!(started.contains("port=51413"));
+        assert!(started.contains(&format!("left={}", 16 * 1024)));
+
+        // Tracker asked for another one a second later, tracker id goes along from now on
+        let regular = next(&mut announces).await;
+        assert!(!regular.contains("event="));
+        assert!(regular.contains("trackerid=abc"));
+        let peer = SocketAddr::from((Ipv4Addr::new(1, 2, 3, 4), 6881));
+        assert!(torrent.pool.lock().await.get(&peer).is_some());
+
+        torrent.totals.add_uploaded(1000);
+        torrent.state.lock().await.mark_piece_complete(0).unwrap();
+        torrent.commit_events.send(commit::Event::PieceCommit(0)).unwrap();
+        let completed = next(&mut announces).await;
+        assert!(completed.contains("event=completed"));
+        assert!(completed.contains("left=0"));
+        assert!(completed.contains("uploaded=1000"));
+
+        stop.send(()).unwrap();
+        assert!(next(&mut announces).await.contains("event=stopped"));
+        timeout(Duration::from_secs(5), announcer).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn complete_torrents_never_say_completed() {
+        let (url, mut announces) = tracker().await;
+        let torrent = Handle::fake();
+        torrent.state.lock().await.mark_piece_complete(0).unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let announcer = tokio::spawn(Announcer::new(&url, torrent.clone()).run(async {
+            let _ = stopped.await;
+        }));
+
+        assert!(next(&mut announces).await.contains("event=started"));
+        torrent.commit_events.send(commit::Event::PieceCommit(0)).unwrap();
+        stop.send(()).unwrap();
+        // Regular announces may come in between
+        loop {
+            let announce = next(&mut announces).await;
+            assert!(!announce.contains("event=completed"));
+            if announce.contains("event=stopped") {
+                break;
+            }
+        }
+        timeout(Duration::from_secs(5), announcer).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,25 @@
+use std::io;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid announce url : {0}")]
+    InvalidUrl(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Bencode(#[from] bendy::serde::Error),
+    /// Tracker answered, but wouldn't have us, with its reason
+    #[error("Tracker refused announce : {0}")]
+    Failure(String),
+    #[error("Tracker response has no interval")]
+    NoInterval,
+    #[error("Tracker sent a malformed response")]
+    Malformed,
+    /// Tracker never answered, retransmissions and all
+    #[error("Tracker timed out")]
+    Timeout,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
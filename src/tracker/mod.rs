@@ -1,62 +1,76 @@
 
 use crate::{
     cache::{Cache, CacheType},
-    peer, tracker,
+    peer,
 };
+pub mod announce;
+mod announcer;
+mod error;
 pub mod response;
+pub mod scrape;
+pub mod udp;
 use crate::torrent::Metadata as Torrent;
+pub use announce::{Announce, Event};
+pub use announcer::Announcer;
 use bytes::Bytes;
+pub use error::{Error, Result};
 pub use response::Response;
-
-pub fn get_url(torrent: &Torrent) -> String {
-    format!(
-        "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}",
-        torrent.announce,
-        torrent.info_hash.to_url_encoded(),
-        peer::ID.url_encoded(),
-        6881,
-        0,
-        0,
-        torrent.info.total_length(),
-    )
-}
-
-pub async fn load_cache_or_fetch_tracker(torrent: &Torrent) -> anyhow::Result<Response> {
-    let cache = Cache::new(CacheType::TrackerResponse, torrent.info_hash)?;
-    if cache.is_empty() {
-        let bytes = fetch_tracker_bytes(get_url(torrent)).await?;
-        let response: tracker::Response = bendy::serde::from_bytes(&bytes)?;
-        cache.update(bytes)?;
-        return Ok(response);
-    } else {
-        let bytes = cache.read();
-        let response: tracker::Response = bendy::serde::from_bytes(&bytes)?;
-        if cache.is_fresher_than(response.interval) {
-            return Ok(response);
-        } else {
-            let bytes = fetch_tracker_bytes(get_url(torrent)).await?;
-            let response: tracker::Response = bendy::serde::from_bytes(&bytes)?;
-            cache.update(bytes)?;
-            return Ok(response);
+pub use scrape::{ScrapeStats, cached_scrape, load_cache_or_scrape, scrape};
+pub use udp::UdpTracker;
+use std::net::{IpAddr, Ipv6Addr, UdpSocket};
+
+/// Sends announce to tracker at `url`, over UDP or HTTP as its scheme says.
+/// Warnings it answers with are printed out.
+pub async fn announce(url: &str, request: &Announce) -> Result<Response> {
+    let response = match url.starts_with("udp://") {
+        true => UdpTracker::new(url).await?.announce(request).await?,
+        false => {
+            let bytes = reqwest::get(request.url(url)?).await?.bytes().await?;
+            Response::try_from(bytes.as_ref())?
         }
+    };
+    if let Some(warning) = &response.warning {
+        eprintln!("\x1b[33mTRACKER | {url} : {warning}\x1b[0m");
     }
+    Ok(response)
 }
 
-pub async fn load_cache_or_fetch_trackerr(torrent: &Torrent) -> anyhow::Result<Response> {
-    let cache = Cache::new(CacheType::TrackerResponse, torrent.info_hash)?;
+/// Our globally routable IPv6 address (if host has one), so trackers can hand it out to IPv6 peers.
+/// Connecting a UDP socket sends nothing, it only makes the OS pick an outgoing address.
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket
+        .connect((Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888), 80))
+        .ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+        _ => None,
+    }
+}
 
-    if !cache.is_empty() {
-        let bytes = cache.read();
-        let response: tracker::Response = bendy::serde::from_bytes(&bytes)?;
+/// Leaves out loopback, link-local, unique local and IPv4-mapped addresses
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !ip.is_loopback()
+        && !ip.is_unspecified()
+        && first & 0xffc0 != 0xfe80
+        && first & 0xfe00 != 0xfc00
+        && ip.to_ipv4_mapped().is_none()
+}
 
-        if cache.is_fresher_than(response.interval) {
-            return Ok(response);
-        }
+/// Response to `request`, from the announce url of the torrent, over UDP or HTTP as [`announce`] does.
+/// Responses are cached as parsed, for as long as the interval tracker asked for.
+pub async fn load_cache_or_fetch_tracker(torrent: &Torrent, request: &Announce) -> anyhow::Result<Response> {
+    let cache = Cache::new(CacheType::TrackerResponse, torrent.info_hash)?;
+    if !cache.is_empty()
+        && let Ok(response) = bendy::serde::from_bytes::<Response>(&cache.read())
+        && cache.is_fresher_than(response.interval)
+    {
+        return Ok(response);
     }
-    let bytes = fetch_tracker_bytes(get_url(torrent)).await?;
-    let response: tracker::Response = bendy::serde::from_bytes(&bytes)?;
-    cache.update(&bytes)?;
 
+    let response = announce(&torrent.announce, request).await?;
+    cache.update(bendy::serde::to_bytes(&response)?)?;
     Ok(response)
 }
 
@@ -96,6 +110,28 @@ mod test {
         (handle, server_addr)
     }
 
+    #[test]
+    fn announce_url_asks_for_compact_peers() {
+        let torrent = crate::torrent::Metadata::fake();
+        let announce = tracker::Announce::new(torrent.info_hash, torrent.info.total_length());
+        let url = announce.url("http://tracker.example/announce").unwrap();
+        assert!(url.as_str().contains("&compact=1"));
+    }
+
+    #[test]
+    fn only_global_ipv6_addresses_are_announced() {
+        use std::net::Ipv6Addr;
+
+        let global: Ipv6Addr = "2a01:4f8::1".parse().unwrap();
+        let link_local: Ipv6Addr = "fe80::1".parse().unwrap();
+        let unique_local: Ipv6Addr = "fd12:3456::1".parse().unwrap();
+
+        assert!(tracker::is_global_ipv6(&global));
+        assert!(!tracker::is_global_ipv6(&link_local));
+        assert!(!tracker::is_global_ipv6(&unique_local));
+        assert!(!tracker::is_global_ipv6(&Ipv6Addr::LOCALHOST));
+    }
+
     #[tokio::test]
     async fn send_get_request_and_parse_response() {
         let (handle, server_addr) = server_that_responds_with_valid_response_body().await;
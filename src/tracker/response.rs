@@ -1,23 +1,163 @@
 
-use crate::{torrent, tracker::peer::Peer};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 
-#[derive(serde::Deserialize, Debug)] //////////////////////////////////////// only now
+use crate::{
+    peer::compact,
+    tracker::{peer::Peer, Error},
+};
+
+/// Serializes back into bencode as trackers send it, peers in compact form, so it can be cached
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(try_from = "RawResponse", into = "RawResponse")]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct Response {
     pub interval: u32,
+    /// Tracker wants no announces sooner than this, but for events
+    pub min_interval: Option<u32>,
+    /// To be sent along with next announces, see [`crate::tracker::Announce::with_tracker_id`]
+    pub tracker_id: Option<String>,
+    /// Seeders
+    pub complete: Option<u32>,
+    /// Leechers
+    pub incomplete: Option<u32>,
+    /// Tracker took the announce, but has something to say about it
+    pub warning: Option<String>,
+    /// Both IPv4 and IPv6 peers, whichever form tracker has sent them in
     pub peers: Vec<Peer>,
 }
 
-impl Response {
-    fn new(torrent : &torrent::Metadata) {
+/// Fails with [`Error::Failure`] when tracker has refused the announce, rather than a parsing error
+impl TryFrom<&[u8]> for Response {
+    type Error = Error;
+    fn try_from(value: &[u8]) -> Result<Self, Error> {
+        let raw: RawResponse = bendy::serde::from_bytes(value)?;
+        raw.try_into()
+    }
+}
+
+/// Response as it's laid out in bencode, keys in sorted order as bencode wants them
+#[derive(Serialize, Deserialize)]
+struct RawResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::bencode::optional")]
+    complete: Option<u32>,
+    #[serde(
+        rename = "failure reason",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::bencode::optional"
+    )]
+    failure_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::bencode::optional")]
+    incomplete: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::bencode::optional")]
+    interval: Option<u32>,
+    #[serde(
+        rename = "min interval",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::bencode::optional"
+    )]
+    min_interval: Option<u32>,
+    #[serde(default)]
+    peers: Peers,
+    /// BEP 7, compact IPv6 peers
+    #[serde(default)]
+    peers6: ByteBuf,
+    #[serde(
+        rename = "tracker id",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::bencode::optional"
+    )]
+    tracker_id: Option<String>,
+    #[serde(
+        rename = "warning message",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::bencode::optional"
+    )]
+    warning_message: Option<String>,
+}
 
+/// Trackers send peers either as a list of dictionaries, or packed in a single string (BEP 23)
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    Dictionary(Vec<DictionaryPeer>),
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Peers::Compact(ByteBuf::new())
     }
 }
 
-impl TryFrom<&[u8]> for Response {
-    type Error = bendy::serde::Error;
-    fn try_from(value: &[u8]) -> Result<Self, bendy::serde::Error> {
-        bendy::serde::from_bytes(value)
+#[derive(Serialize, Deserialize)]
+struct DictionaryPeer {
+    ip: String,
+    #[serde(
+        rename = "peer id",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::bencode::optional"
+    )]
+    id: Option<ByteBuf>,
+    port: u16,
+}
+
+impl DictionaryPeer {
+    /// Peers with a DNS name instead of an address are left out
+    fn into_peer(self) -> Option<Peer> {
+        let mut peer = Peer::new(self.ip.parse::<std::net::IpAddr>().ok()?, self.port);
+        peer.id = self
+            .id
+            .and_then(|id| <[u8; 20]>::try_from(id.as_slice()).ok())
+            .map(Into::into);
+        Some(peer)
+    }
+}
+
+impl TryFrom<RawResponse> for Response {
+    type Error = Error;
+    fn try_from(raw: RawResponse) -> Result<Self, Error> {
+        if let Some(reason) = raw.failure_reason {
+            return Err(Error::Failure(reason));
+        }
+        let interval = raw.interval.ok_or(Error::NoInterval)?;
+        let mut peers = match raw.peers {
+            Peers::Compact(bytes) => compact::decode_v4(&bytes),
+            Peers::Dictionary(peers) => peers.into_iter().filter_map(DictionaryPeer::into_peer).collect(),
+        };
+        peers.extend(compact::decode_v6(&raw.peers6));
+
+        Ok(Self {
+            interval,
+            min_interval: raw.min_interval,
+            tracker_id: raw.tracker_id,
+            complete: raw.complete,
+            incomplete: raw.incomplete,
+            warning: raw.warning_message,
+            peers,
+        })
+    }
+}
+
+/// Peer ids are left out, compact form has no room for them
+impl From<Response> for RawResponse {
+    fn from(response: Response) -> Self {
+        Self {
+            complete: response.complete,
+            failure_reason: None,
+            incomplete: response.incomplete,
+            interval: Some(response.interval),
+            min_interval: response.min_interval,
+            peers: Peers::Compact(ByteBuf::from(compact::encode_v4(&response.peers).to_vec())),
+            peers6: ByteBuf::from(compact::encode_v6(&response.peers).to_vec()),
+            tracker_id: response.tracker_id,
+            warning_message: response.warning,
+        }
     }
 }
 
@@ -25,7 +165,8 @@ impl TryFrom<&[u8]> for Response {
 mod test {
 
     use super::{Response, Peer};
-    use std::net::Ipv4Addr;
+    use crate::tracker::Error;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     #[test]
     fn parsing_valid_response() {
@@ -34,9 +175,10 @@ mod test {
         let expected_response = Response {
             interval: 900,
             peers: vec![
-                Peer { ip : Ipv4Addr::new(0,0,0,0), port : 3421, id : None },
-                Peer { ip : Ipv4Addr::new(1,2,3,4), port : 1234, id : None },
+                Peer::new(Ipv4Addr::new(0,0,0,0), 3421),
+                Peer::new(Ipv4Addr::new(1,2,3,4), 1234),
             ],
+            ..Default::default()
         };
 
         let parsed_response : Response = bendy::serde::from_bytes(response).unwrap();
@@ -52,5 +194,72 @@ mod test {
         assert!(bendy::serde::from_bytes::<Response>(response).is_err());
     }
 
+    #[test]
+    fn parsing_compact_response() {
+        let mut response = b"d8:intervali1800e5:peers12:".to_vec();
+        response.extend_from_slice(&[1, 2, 3, 4, 0x1A, 0xE1, 5, 6, 7, 8, 0, 80]);
+        response.extend_from_slice(b"6:peers618:");
+        response.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        response.extend_from_slice(&[0x1A, 0xE2]);
+        response.push(b'e');
+
+        let parsed: Response = bendy::serde::from_bytes(&response).unwrap();
 
+        assert_eq!(parsed.interval, 1800);
+        assert_eq!(
+            parsed.peers,
+            vec![
+                Peer::new(Ipv4Addr::new(1, 2, 3, 4), 6881),
+                Peer::new(Ipv4Addr::new(5, 6, 7, 8), 80),
+                Peer::new(Ipv6Addr::LOCALHOST, 6882),
+            ]
+        );
+    }
+
+    #[test]
+    fn parsing_dictionary_peers_of_both_families() {
+        let response = b"d8:intervali900e5:peersld2:ip3:::17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip11:example.com4:porti80eed2:ip7:1.2.3.44:porti1234eeee";
+
+        let parsed: Response = bendy::serde::from_bytes(response).unwrap();
+
+        let mut expected = Peer::new(Ipv6Addr::LOCALHOST, 6881);
+        expected.id = Some([b'a'; 20].into());
+        assert_eq!(parsed.peers, vec![expected, Peer::new(Ipv4Addr::new(1, 2, 3, 4), 1234)]);
+    }
+
+    #[test]
+    fn parsing_full_response() {
+        let response = b"d8:completei12e10:incompletei3e8:intervali1800e12:min intervali60e5:peers0:10:tracker id3:abc15:warning message9:slow downe";
+
+        let parsed = Response::try_from(response.as_ref()).unwrap();
+
+        assert_eq!(parsed.min_interval, Some(60));
+        assert_eq!(parsed.tracker_id.as_deref(), Some("abc"));
+        assert_eq!((parsed.complete, parsed.incomplete), (Some(12), Some(3)));
+        assert_eq!(parsed.warning.as_deref(), Some("slow down"));
+    }
+
+    #[test]
+    fn responses_go_back_to_bencode_as_they_came() {
+        let mut response = b"d8:completei12e8:intervali1800e12:min intervali60e5:peers6:".to_vec();
+        response.extend_from_slice(&[1, 2, 3, 4, 0x1A, 0xE1]);
+        response.extend_from_slice(b"6:peers618:");
+        response.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        response.extend_from_slice(&[0x1A, 0xE2]);
+        response.extend_from_slice(b"10:tracker id3:abce");
+
+        let parsed = Response::try_from(response.as_slice()).unwrap();
+        let serialized = bendy::serde::to_bytes(&parsed).unwrap();
+        assert_eq!(serialized, response);
+        assert_eq!(Response::try_from(serialized.as_slice()).unwrap(), parsed);
+    }
+
+    #[test]
+    fn failure_reason_is_an_error() {
+        let response = b"d14:failure reason17:torrent not founde";
+
+        let err = Response::try_from(response.as_ref()).unwrap_err();
+        assert!(matches!(err, Error::Failure(reason) if reason == "torrent not found"));
+        assert!(bendy::serde::from_bytes::<Response>(response).is_err());
+    }
 }
@@ -0,0 +1,290 @@
+//! # Scrape
+//! What trackers know about a torrent's swarm, without announcing ourselves to it.
+//! https://www.bittorrent.org/beps/bep_0048.html
+use std::{collections::HashMap, fmt::Display};
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    cache::{Cache, CacheType},
+    torrent::InfoHash,
+    tracker::{Error, Result, UdpTracker},
+};
+
+/// Info hashes a single HTTP scrape asks for, keeping urls short enough for most servers
+const HTTP_BATCH: usize = 50;
+/// How long a swarm we've scraped is shown before it's scraped again, in seconds
+pub const SCRAPE_TTL: u64 = 30 * 60;
+
+/// Swarm of a single torrent, as a tracker has it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScrapeStats {
+    /// Seeders
+    pub complete: u32,
+    /// Times the torrent got completed, ever
+    pub downloaded: u32,
+    /// Leechers
+    pub incomplete: u32,
+}
+
+impl Display for ScrapeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} seeders, {} leechers, {} downloads",
+            self.complete, self.incomplete, self.downloaded
+        )
+    }
+}
+
+/// Scrape response as it's laid out in bencode, `files` are keyed by raw info hashes
+#[derive(Deserialize)]
+struct RawScrape {
+    #[serde(rename = "failure reason", default, with = "crate::bencode::optional")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// Scrape url of tracker's `announce` url, its last path segment has to start with `announce`.
+/// `None` when tracker doesn't scrape.
+pub fn scrape_url(announce: &str) -> Option<Url> {
+    let mut url = Url::parse(announce).ok()?;
+    let path = url.path();
+    let (base, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    url.set_path(&format!("{base}/scrape{rest}"));
+    Some(url)
+}
+
+/// Swarms of given torrents, over UDP or HTTP as `announce`'s scheme says, torrents tracker doesn't know are left out
+pub async fn scrape(announce: &str, info_hashes: &[InfoHash]) -> Result<HashMap<InfoHash, ScrapeStats>> {
+    if announce.starts_with("udp://") {
+        let stats = UdpTracker::new(announce).await?.scrape(info_hashes).await?;
+        return Ok(info_hashes.iter().copied().zip(stats).collect());
+    }
+    let url = scrape_url(announce).ok_or_else(|| Error::InvalidUrl(announce.to_string()))?;
+    let mut swarms = HashMap::with_capacity(info_hashes.len());
+    for batch in info_hashes.chunks(HTTP_BATCH) {
+        let mut url = url.clone();
+        let query = url
+            .query()
+            .filter(|query| !query.is_empty())
+            .map(str::to_string)
+            .into_iter()
+            .chain(
+                batch
+                    .iter()
+                    .map(|info_hash| format!("info_hash={}", info_hash.to_url_encoded())),
+            )
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query(Some(&query));
+
+        let bytes = reqwest::get(url).await?.bytes().await?;
+        swarms.extend(parse(&bytes)?);
+    }
+    Ok(swarms)
+}
+
+/// Swarm of a torrent as last scraped, unless that's older than [`SCRAPE_TTL`]
+pub fn cached_scrape(info_hash: InfoHash) -> Option<ScrapeStats> {
+    let cache = Cache::new(CacheType::Scrape, info_hash).ok()?;
+    match !cache.is_empty() && cache.is_fresher_than(SCRAPE_TTL) {
+        true => bendy::serde::from_bytes(&cache.read()).ok(),
+        false => None,
+    }
+}
+
+/// Swarms of given torrents, scraped ones cached for [`SCRAPE_TTL`], only those that aren't are asked for
+pub async fn load_cache_or_scrape(
+    announce: &str,
+    info_hashes: &[InfoHash],
+) -> anyhow::Result<HashMap<InfoHash, ScrapeStats>> {
+    let mut swarms = HashMap::with_capacity(info_hashes.len());
+    let mut stale = Vec::new();
+    for &info_hash in info_hashes {
+        match cached_scrape(info_hash) {
+            Some(stats) => {
+                swarms.insert(info_hash, stats);
+            }
+            None => stale.push(info_hash),
+        }
+    }
+    if stale.is_empty() {
+        return Ok(swarms);
+    }
+
+    for (info_hash, stats) in scrape(announce, &stale).await? {
+        Cache::new(CacheType::Scrape, info_hash)?.update(bendy::serde::to_bytes(&stats)?)?;
+        swarms.insert(info_hash, stats);
+    }
+    Ok(swarms)
+}
+
+fn parse(bytes: &[u8]) -> Result<HashMap<InfoHash, ScrapeStats>> {
+    let raw: RawScrape = bendy::serde::from_bytes(bytes)?;
+    if let Some(reason) = raw.failure_reason {
+        return Err(Error::Failure(reason));
+    }
+    Ok(raw
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, stats)| Some((<[u8; 20]>::try_from(info_hash.as_slice()).ok()?.into(), stats)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        net::Ipv4Addr,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use serial_test::serial;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn files_response(info_hashes: &[[u8; 20]]) -> Vec<u8> {
+        let mut response = b"d5:filesd".to_vec();
+        for info_hash in info_hashes {
+            response.extend_from_slice(b"20:");
+            response.extend_from_slice(info_hash);
+            response.extend_from_slice(b"d8:completei5e10:downloadedi9e10:incompletei2ee");
+        }
+        response.extend_from_slice(b"ee");
+        response
+    }
+
+    /// Scrapes every torrent it's asked for, counting requests
+    async fn tracker() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let requests = requests.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    requests.fetch_add(1, Ordering::Relaxed);
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0];
+                        stream.read_exact(&mut byte).await.unwrap();
+                        request.push(byte[0]);
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    let target = request.split(' ').nth(1).unwrap();
+                    assert!(target.starts_with("/scrape?passkey=secret&info_hash="));
+                    let info_hashes: Vec<[u8; 20]> = target
+                        .split("info_hash=")
+                        .skip(1)
+                        .map(|hash| {
+                            let hex: String = hash.trim_end_matches('&').split('%').collect();
+                            hex::decode(hex).unwrap().try_into().unwrap()
+                        })
+                        .collect();
+                    let body = files_response(&info_hashes);
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(&body);
+                    stream.write_all(&response).await.unwrap();
+                }
+            }
+        });
+        (format!("http://{addr}/announce?passkey=secret"), requests)
+    }
+
+    const EXPECTED: ScrapeStats = ScrapeStats {
+        complete: 5,
+        downloaded: 9,
+        incomplete: 2,
+    };
+
+    #[test]
+    fn scrape_urls_follow_bep_48() {
+        let scrape = |announce| scrape_url(announce).map(|url| url.to_string());
+        assert_eq!(
+            scrape("http://example.com/announce").unwrap(),
+            "http://example.com/scrape"
+        );
+        assert_eq!(
+            scrape("http://example.com/x/announce").unwrap(),
+            "http://example.com/x/scrape"
+        );
+        assert_eq!(
+            scrape("http://example.com/announce.php").unwrap(),
+            "http://example.com/scrape.php"
+        );
+        assert_eq!(
+            scrape("http://example.com/announce?x=2/4").unwrap(),
+            "http://example.com/scrape?x=2/4"
+        );
+        assert!(scrape("http://example.com/a").is_none());
+        assert!(scrape("http://example.com/announce/x").is_none());
+        assert!(scrape("http://example.com/x%064announce").is_none());
+    }
+
+    #[test]
+    fn files_are_parsed() {
+        let swarms = parse(&files_response(&[[1; 20], [2; 20]])).unwrap();
+        assert_eq!(swarms.len(), 2);
+        assert_eq!(swarms[&InfoHash::from([2; 20])], EXPECTED);
+
+        let err = parse(b"d14:failure reason8:go away!e").unwrap_err();
+        assert!(matches!(err, Error::Failure(reason) if reason == "go away!"));
+    }
+
+    #[tokio::test]
+    async fn http_scrapes_are_batched() {
+        let (announce, requests) = tracker().await;
+        let info_hashes: Vec<InfoHash> = (0..HTTP_BATCH as u8 + 10).map(|i| InfoHash::from([i; 20])).collect();
+
+        let swarms = scrape(&announce, &info_hashes).await.unwrap();
+
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+        assert_eq!(swarms.len(), info_hashes.len());
+        assert!(swarms.values().all(|stats| *stats == EXPECTED));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn scrapes_are_cached() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let old = env::var_os("XDG_CACHE_HOME");
+        // See std::env::set_var and std::env::remove_var's docs
+        unsafe { env::set_var("XDG_CACHE_HOME", temp_dir.path()) };
+
+        let (announce, requests) = tracker().await;
+        let first = [InfoHash::from([1; 20])];
+        let both = [InfoHash::from([1; 20]), InfoHash::from([2; 20])];
+        load_cache_or_scrape(&announce, &first).await.unwrap();
+        let swarms = load_cache_or_scrape(&announce, &both).await.unwrap();
+        load_cache_or_scrape(&announce, &both).await.unwrap();
+
+        unsafe {
+            match old {
+                Some(old) => env::set_var("XDG_CACHE_HOME", old),
+                None => env::remove_var("XDG_CACHE_HOME"),
+            }
+        }
+        // Second one only asked for the torrent that wasn't cached, third one for none
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+        assert_eq!(swarms.len(), 2);
+        assert_eq!(swarms[&InfoHash::from([1; 20])], EXPECTED);
+    }
+}
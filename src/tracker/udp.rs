@@ -0,0 +1,448 @@
+//! # UDP tracker
+//! Announces and scrapes over UDP, which most public trackers speak rather than HTTP.
+//! https://www.bittorrent.org/beps/bep_0015.html
+//! https://www.bittorrent.org/beps/bep_0041.html
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut};
+use reqwest::Url;
+use tokio::{
+    net::{self, UdpSocket},
+    time::{self, Instant},
+};
+
+use crate::{
+    peer::compact,
+    torrent::InfoHash,
+    tracker::{Announce, Error, Event, Response, Result, ScrapeStats},
+};
+
+/// Magic every connect request starts with
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+/// Wait before first retransmission, doubled on every one after
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+/// Retransmissions before tracker is given up on, the last one is waited on for over an hour
+const MAX_RETRANSMITS: u32 = 8;
+/// How long a connection ID is good for, once we've got it
+const CONNECTION_TTL: Duration = Duration::from_secs(60);
+/// Large enough for an announce response with a few thousand peers
+const MAX_PACKET_LEN: usize = 64 * 1024;
+/// Info hashes a single scrape can ask for, more get split across several
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Connect = 0,
+    Announce = 1,
+    Scrape = 2,
+    Error = 3,
+}
+
+/// BEP 41 option carrying path and query of tracker's url
+const OPTION_URL_DATA: u8 = 2;
+const OPTION_END: u8 = 0;
+
+/// Time to wait on a response before the `n`th retransmission
+fn timeout(n: u32) -> Duration {
+    BASE_TIMEOUT * 2u32.pow(n.min(MAX_RETRANSMITS))
+}
+
+/// # [`UdpTracker`]
+/// Tracker at a `udp://` url, connection ID is kept for as long as it's good for
+pub struct UdpTracker {
+    socket: UdpSocket,
+    /// Path and query of tracker's url, sent along with announces
+    url_data: String,
+    connection: Option<(u64, Instant)>,
+}
+
+impl UdpTracker {
+    /// Resolves tracker of a `udp://host:port/...` url, and binds a socket of the same family to talk to it
+    pub async fn new(url: &str) -> Result<Self> {
+        let invalid = || Error::InvalidUrl(url.to_string());
+        let parsed = Url::parse(url).map_err(|_| invalid())?;
+        let (host, port) = match (parsed.scheme(), parsed.host_str(), parsed.port()) {
+            ("udp", Some(host), Some(port)) => (host.trim_start_matches('[').trim_end_matches(']'), port),
+            _ => return Err(invalid()),
+        };
+        let addr = net::lookup_host((host, port)).await?.next().ok_or_else(invalid)?;
+        let socket = match addr.is_ipv6() {
+            true => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+            false => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        };
+        socket.connect(addr).await?;
+
+        let mut url_data = parsed.path().to_string();
+        if let Some(query) = parsed.query() {
+            url_data.push('?');
+            url_data.push_str(query);
+        }
+        Ok(Self {
+            socket,
+            url_data,
+            connection: None,
+        })
+    }
+
+    /// Announces to tracker, peers come in the family tracker was reached over
+    pub async fn announce(&mut self, announce: &Announce) -> Result<Response> {
+        let mut body = Vec::with_capacity(82 + self.url_data.len() + 2);
+        body.put_slice(announce.info_hash.as_ref());
+        body.put_slice(&announce.peer_id);
+        body.put_u64(announce.downloaded);
+        body.put_u64(announce.left);
+        body.put_u64(announce.uploaded);
+        body.put_u32(match announce.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        });
+        // Tracker takes the address it got the packet from
+        body.put_u32(0);
+        body.put_u32(announce.key);
+        body.put_i32(announce.numwant.map_or(-1, |numwant| numwant as i32));
+        body.put_u16(announce.port);
+        if !self.url_data.is_empty() {
+            for chunk in self.url_data.as_bytes().chunks(u8::MAX as usize) {
+                body.put_u8(OPTION_URL_DATA);
+                body.put_u8(chunk.len() as u8);
+                body.put_slice(chunk);
+            }
+            body.put_u8(OPTION_END);
+        }
+
+        let payload = self.request(Action::Announce, &body).await?;
+        let mut payload = payload.as_slice();
+        if payload.len() < 12 {
+            return Err(Error::Malformed);
+        }
+        let interval = payload.get_u32();
+        let leechers = payload.get_u32();
+        let seeders = payload.get_u32();
+        let peers = match self.socket.peer_addr()?.is_ipv6() {
+            true => compact::decode_v6(payload),
+            false => compact::decode_v4(payload),
+        };
+        Ok(Response {
+            interval,
+            complete: Some(seeders),
+            incomplete: Some(leechers),
+            peers,
+            ..Default::default()
+        })
+    }
+
+    /// Swarms of every torrent, in order, [`MAX_SCRAPE_HASHES`] at a time
+    pub async fn scrape(&mut self, info_hashes: &[InfoHash]) -> Result<Vec<ScrapeStats>> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let body: Vec<u8> = batch.iter().flat_map(|info_hash| info_hash.iter().copied()).collect();
+            let payload = self.request(Action::Scrape, &body).await?;
+            let mut payload = payload.as_slice();
+            if payload.len() < batch.len() * 12 {
+                return Err(Error::Malformed);
+            }
+            for _ in batch {
+                let complete = payload.get_u32();
+                let downloaded = payload.get_u32();
+                let incomplete = payload.get_u32();
+                stats.push(ScrapeStats {
+                    complete,
+                    incomplete,
+                    downloaded,
+                });
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Connection ID, asks for a new one once the last one is too old
+    async fn connection_id(&mut self) -> Result<u64> {
+        if let Some((id, received)) = self.connection
+            && received.elapsed() < CONNECTION_TTL
+        {
+            return Ok(id);
+        }
+        for n in 0..=MAX_RETRANSMITS {
+            if let Some(payload) = self.attempt(PROTOCOL_ID, Action::Connect, &[], timeout(n)).await? {
+                let id = payload.as_slice().try_get_u64().map_err(|_| Error::Malformed)?;
+                self.connection = Some((id, Instant::now()));
+                return Ok(id);
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Sends request until tracker answers it, connecting again whenever connection ID gets too old meanwhile
+    async fn request(&mut self, action: Action, body: &[u8]) -> Result<Vec<u8>> {
+        for n in 0..=MAX_RETRANSMITS {
+            let connection_id = self.connection_id().await?;
+            match self.attempt(connection_id, action, body, timeout(n)).await {
+                Ok(Some(payload)) => return Ok(payload),
+                Ok(None) => {}
+                Err(err) => {
+                    // Tracker may have forgotten us before we did
+                    self.connection = None;
+                    return Err(err);
+                }
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Sends request once, `None` when tracker hasn't answered in time.
+    /// Answers to earlier attempts are ignored, each one has a transaction ID of its own.
+    async fn attempt(
+        &self,
+        connection_id: u64,
+        action: Action,
+        body: &[u8],
+        wait: Duration,
+    ) -> Result<Option<Vec<u8>>> {
+        let transaction_id: u32 = rand::random();
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.put_u64(connection_id);
+        packet.put_u32(action as u32);
+        packet.put_u32(transaction_id);
+        packet.put_slice(body);
+        self.socket.send(&packet).await?;
+
+        let deadline = Instant::now() + wait;
+        let mut buffer = vec![0; MAX_PACKET_LEN];
+        loop {
+            let Ok(len) = time::timeout_at(deadline, self.socket.recv(&mut buffer)).await else {
+                return Ok(None);
+            };
+            let mut packet = &buffer[..len?];
+            if packet.len() < 8 {
+                continue;
+            }
+            let got_action = packet.get_u32();
+            if packet.get_u32() != transaction_id {
+                continue;
+            }
+            return match got_action {
+                got if got == action as u32 => Ok(Some(packet.to_vec())),
+                got if got == Action::Error as u32 => Err(Error::Failure(String::from_utf8_lossy(packet).into_owned())),
+                _ => Err(Error::Malformed),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use super::*;
+    use crate::peer::Peer;
+
+    const CONNECTION_ID: u64 = 0xC0FFEE;
+
+    /// What the fake tracker has seen
+    #[derive(Default)]
+    struct Seen {
+        connects: AtomicUsize,
+        announces: AtomicUsize,
+        /// Requests never answered, first ones first
+        dropped: AtomicUsize,
+        url_data: std::sync::Mutex<Vec<u8>>,
+    }
+
+    /// In-process tracker, it has one peer of its own family, and says every torrent has 5 seeders and 2 leechers.
+    /// The first `drop` requests are never answered.
+    async fn fake_tracker(ip: std::net::IpAddr, drop: usize) -> Option<(String, Arc<Seen>)> {
+        let socket = UdpSocket::bind((ip, 0)).await.ok()?;
+        let addr = socket.local_addr().unwrap();
+        let seen = Arc::new(Seen::default());
+        let url = format!("udp://{}/announce?passkey=secret", SocketAddr::new(ip, addr.port()));
+        tokio::spawn({
+            let seen = seen.clone();
+            async move {
+                let mut buffer = vec![0; 2048];
+                loop {
+                    let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                    if seen.dropped.load(Ordering::Relaxed) < drop {
+                        seen.dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    let mut packet = &buffer[..len];
+                    let connection_id = packet.get_u64();
+                    let action = packet.get_u32();
+                    let transaction_id = packet.get_u32();
+
+                    let mut response = Vec::new();
+                    response.put_u32(action);
+                    response.put_u32(transaction_id);
+                    match action {
+                        0 => {
+                            assert_eq!(connection_id, PROTOCOL_ID);
+                            seen.connects.fetch_add(1, Ordering::Relaxed);
+                            response.put_u64(CONNECTION_ID);
+                        }
+                        _ if connection_id != CONNECTION_ID => {
+                            response.clear();
+                            response.put_u32(3);
+                            response.put_u32(transaction_id);
+                            response.put_slice(b"bad connection id");
+                        }
+                        1 => {
+                            seen.announces.fetch_add(1, Ordering::Relaxed);
+                            *seen.url_data.lock().unwrap() = packet[82..].to_vec();
+                            response.put_u32(1800);
+                            response.put_u32(2);
+                            response.put_u32(5);
+                            let peer = Peer::new(ip, 6881);
+                            match ip.is_ipv6() {
+                                true => response.put_slice(&compact::encode_v6([&peer])),
+                                false => response.put_slice(&compact::encode_v4([&peer])),
+                            }
+                        }
+                        _ => {
+                            for _ in 0..packet.len() / 20 {
+                                response.put_u32(5);
+                                response.put_u32(9);
+                                response.put_u32(2);
+                            }
+                        }
+                    }
+                    socket.send_to(&response, from).await.unwrap();
+                }
+            }
+        });
+        Some((url, seen))
+    }
+
+    fn announce() -> Announce {
+        Announce::new(InfoHash::from([7; 20]), 1000).with_event(Event::Started)
+    }
+
+    #[test]
+    fn timeouts_double_up_to_the_spec_limit() {
+        assert_eq!(timeout(0), Duration::from_secs(15));
+        assert_eq!(timeout(3), Duration::from_secs(120));
+        assert_eq!(timeout(8), Duration::from_secs(3840));
+        assert_eq!(timeout(12), Duration::from_secs(3840));
+    }
+
+    #[tokio::test]
+    async fn announces_over_ipv4_carry_url_data() {
+        let (url, seen) = fake_tracker(Ipv4Addr::LOCALHOST.into(), 0).await.unwrap();
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+
+        let response = tracker.announce(&announce()).await.unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!((response.complete, response.incomplete), (Some(5), Some(2)));
+        assert_eq!(response.peers, [Peer::new(Ipv4Addr::LOCALHOST, 6881)]);
+        let url_data = b"/announce?passkey=secret";
+        let mut expected = vec![OPTION_URL_DATA, url_data.len() as u8];
+        expected.extend_from_slice(url_data);
+        expected.push(OPTION_END);
+        assert_eq!(*seen.url_data.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn announces_over_ipv6_get_ipv6_peers() {
+        // Hosts without IPv6 loopback have nothing to test here
+        let Some((url, _)) = fake_tracker(Ipv6Addr::LOCALHOST.into(), 0).await else {
+            return;
+        };
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+
+        let response = tracker.announce(&announce()).await.unwrap();
+        assert_eq!(response.peers, [Peer::new(Ipv6Addr::LOCALHOST, 6881)]);
+    }
+
+    #[tokio::test]
+    async fn scrapes_are_batched() {
+        let (url, _) = fake_tracker(Ipv4Addr::LOCALHOST.into(), 0).await.unwrap();
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        let info_hashes: Vec<InfoHash> = (0..100).map(|i| InfoHash::from([i; 20])).collect();
+
+        let stats = tracker.scrape(&info_hashes).await.unwrap();
+
+        assert_eq!(stats.len(), 100);
+        let expected = ScrapeStats {
+            complete: 5,
+            incomplete: 2,
+            downloaded: 9,
+        };
+        assert!(stats.iter().all(|stats| *stats == expected));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_ids_are_reused_until_they_expire() {
+        let (url, seen) = fake_tracker(Ipv4Addr::LOCALHOST.into(), 0).await.unwrap();
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+
+        tracker.announce(&announce()).await.unwrap();
+        tracker.announce(&announce()).await.unwrap();
+        assert_eq!(seen.connects.load(Ordering::Relaxed), 1);
+
+        time::advance(CONNECTION_TTL).await;
+        tracker.announce(&announce()).await.unwrap();
+        assert_eq!(seen.connects.load(Ordering::Relaxed), 2);
+        assert_eq!(seen.announces.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_requests_are_retransmitted() {
+        let (url, seen) = fake_tracker(Ipv4Addr::LOCALHOST.into(), 2).await.unwrap();
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        let started = Instant::now();
+
+        tracker.announce(&announce()).await.unwrap();
+
+        assert_eq!(seen.dropped.load(Ordering::Relaxed), 2);
+        assert!(started.elapsed() >= timeout(0) + timeout(1));
+    }
+
+    #[tokio::test]
+    async fn tracker_errors_are_failures() {
+        let (url, _) = fake_tracker(Ipv4Addr::LOCALHOST.into(), 0).await.unwrap();
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        tracker.connection = Some((1, Instant::now()));
+
+        let err = tracker.announce(&announce()).await.unwrap_err();
+        assert!(matches!(err, Error::Failure(reason) if reason == "bad connection id"));
+        assert!(tracker.connection.is_none());
+        assert!(UdpTracker::new("http://tracker.example/announce").await.is_err());
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn udp_announces_are_cached_too() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let old = std::env::var_os("XDG_CACHE_HOME");
+        // See std::env::set_var and std::env::remove_var's docs
+        unsafe { std::env::set_var("XDG_CACHE_HOME", temp_dir.path()) };
+
+        let (url, seen) = fake_tracker(Ipv4Addr::LOCALHOST.into(), 0).await.unwrap();
+        let mut torrent = crate::torrent::Metadata::fake();
+        torrent.announce = url;
+        let request = Announce::new(torrent.info_hash, 1000);
+        let fetched = crate::tracker::load_cache_or_fetch_tracker(&torrent, &request).await.unwrap();
+        let cached = crate::tracker::load_cache_or_fetch_tracker(&torrent, &request).await.unwrap();
+
+        unsafe {
+            match old {
+                Some(old) => std::env::set_var("XDG_CACHE_HOME", old),
+                None => std::env::remove_var("XDG_CACHE_HOME"),
+            }
+        }
+        assert_eq!(seen.announces.load(Ordering::Relaxed), 1);
+        assert_eq!(cached, fetched);
+        assert_eq!(cached.peers, [Peer::new(Ipv4Addr::LOCALHOST, 6881)]);
+    }
+}